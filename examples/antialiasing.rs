@@ -12,7 +12,8 @@ use portrayer::{
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
+    texture::SkyGradient,
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -55,8 +56,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("Rendering with {} samples", samples);
         env::set_var("SAMPLES", samples.to_string());
 
-        image.render::<RenderProgress, _>(&scene, cam,
-            |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+        image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
         image.save()?;
     }
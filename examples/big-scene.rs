@@ -13,7 +13,8 @@ use portrayer::{
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
+    texture::SkyGradient,
 };
 use rand::{
     Rng,
@@ -104,8 +105,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("big-scene.png", 1980, 1020)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
     Ok(image.save()?)
 }
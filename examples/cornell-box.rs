@@ -0,0 +1,19 @@
+//! The Cornell box from the built-in test scenes
+
+use std::error::Error;
+
+use portrayer::{
+    render::Image,
+    reporter::RenderProgress,
+    testscenes::{self, TestScene},
+};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let TestScene {scene, camera, background} = testscenes::cornell_box();
+
+    let mut image = Image::new("cornell-box.png", 256, 256)?;
+
+    image.render::<RenderProgress, _>(&scene, camera, background);
+
+    Ok(image.save()?)
+}
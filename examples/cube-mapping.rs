@@ -7,12 +7,12 @@ use portrayer::{
     scene::{HierScene, SceneNode, Geometry},
     primitive::{Cube, Plane},
     material::Material,
-    texture::{Texture, ImageTexture},
+    texture::{Texture, ImageTexture, SkyGradient},
     light::Light,
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -119,8 +119,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("cube-mapping.png", 910, 512)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
     Ok(image.save()?)
 }
@@ -6,13 +6,14 @@ use std::sync::Arc;
 
 use portrayer::{
     scene::{HierScene, SceneNode, Geometry},
-    primitive::{Sphere, Mesh, MeshData, Shading, Cube},
+    primitive::{Ellipsoid, Mesh, MeshData, Shading, Cube},
     material::Material,
     light::Light,
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
+    texture::SkyGradient,
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -94,52 +95,44 @@ fn main() -> Result<(), Box<dyn Error>> {
             .translated((0.0, 2.7, 0.0))
             .with_children(vec![
                 // left eye
-                SceneNode::from(Geometry::new(Sphere, mat_eyes.clone()))
-                .scaled((0.1, 0.1, 0.05))
+                SceneNode::from(Geometry::new(Ellipsoid::new((0.1, 0.1, 0.05)), mat_eyes.clone()))
                 .translated((0.35, 0.24, 0.8))
                 .into(),
 
                 // right eye
-                SceneNode::from(Geometry::new(Sphere, mat_eyes.clone()))
-                .scaled((0.1, 0.1, 0.05))
+                SceneNode::from(Geometry::new(Ellipsoid::new((0.1, 0.1, 0.05)), mat_eyes.clone()))
                 .translated((-0.35, 0.24, 0.8))
                 .into(),
             ])
             .into(),
 
         // left upper arm
-        SceneNode::from(Geometry::new(Sphere, mat_arms.clone()))
-            .scaled((0.2, 0.63, 0.2))
+        SceneNode::from(Geometry::new(Ellipsoid::new((0.2, 0.63, 0.2)), mat_arms.clone()))
             .rotated_xzy(Vec3::from((161.156, 107.062, -133.944)).map(Radians::from_degrees))
             .translated((-0.388703, 1.715599, -0.2))
             .into(),
         // left lower arm
-        SceneNode::from(Geometry::new(Sphere, mat_arms.clone()))
-            .scaled((0.2, 0.56, 0.2))
+        SceneNode::from(Geometry::new(Ellipsoid::new((0.2, 0.56, 0.2)), mat_arms.clone()))
             .rotated_xzy(Vec3::from((127.221, 42.0695, -104.823)).map(Radians::from_degrees))
             .translated((-0.711297, 1.284401, -1.0))
             .into(),
         // left mirror bubble
-        SceneNode::from(Geometry::new(Sphere, mat_mirror.clone()))
-            .scaled((0.5, 0.5, 0.3))
+        SceneNode::from(Geometry::new(Ellipsoid::new((0.5, 0.5, 0.3)), mat_mirror.clone()))
             .translated((-0.711297, 1.284401, -1.20))
             .into(),
 
         // right upper arm
-        SceneNode::from(Geometry::new(Sphere, mat_arms.clone()))
-            .scaled((0.2, 0.63, 0.2))
+        SceneNode::from(Geometry::new(Ellipsoid::new((0.2, 0.63, 0.2)), mat_arms.clone()))
             .rotated_xzy(Vec3::from((92.3684, -57.6199, 38.2278)).map(Radians::from_degrees))
             .translated((0.581161, 1.984976, -0.2))
             .into(),
         // right lower arm
-        SceneNode::from(Geometry::new(Sphere, mat_arms.clone()))
-            .scaled((0.2, 0.56, 0.2))
+        SceneNode::from(Geometry::new(Ellipsoid::new((0.2, 0.56, 0.2)), mat_arms.clone()))
             .rotated_xzy(Vec3::from((91.5166, -11.239, 28.419)).map(Radians::from_degrees))
             .translated((1.118839, 2.015024, -1.0))
             .into(),
         // right mirror bubble
-        SceneNode::from(Geometry::new(Sphere, mat_mirror.clone()))
-            .scaled((0.5, 0.5, 0.3))
+        SceneNode::from(Geometry::new(Ellipsoid::new((0.5, 0.5, 0.3)), mat_mirror.clone()))
             .translated((1.118839, 2.015024, -1.20))
             .into(),
     ]).into();
@@ -183,8 +176,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("entering-the-mirror-dimension.png", 800, 600)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
     Ok(image.save()?)
 }
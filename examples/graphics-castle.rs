@@ -10,14 +10,14 @@ use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
 use portrayer::{
     scene::{HierScene, SceneNode, Geometry},
     primitive::{Cube, Cylinder, MeshData, Shading},
-    kdtree::KDMesh,
+    kdtree::{KDMesh, KDMeshOptions},
     material::{Material, WATER_REFRACTION_INDEX, WINDOW_GLASS_REFRACTION_INDEX},
-    texture::{Texture, ImageTexture, NormalMap},
+    texture::{Texture, ImageTexture, NormalMap, SkyGradient},
     light::Light,
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Mat3, Rgb, Uv},
+    math::{Radians, Vec3, Mat3, Rgb},
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -53,8 +53,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("graphics-castle.png", 1920, 1080)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.529, g: 0.808, b: 0.922} * (1.0 - uv.v) + Rgb {r: 0.086, g: 0.38, b: 0.745} * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::clear_day());
 
     Ok(image.save()?)
 }
@@ -135,7 +134,11 @@ fn castle() -> Result<SceneNode, Box<dyn Error>> {
 
     Ok(SceneNode::from(vec![
         // Main castle body
-        SceneNode::from(Geometry::new(KDMesh::new(&castle_model, Shading::Flat), mat_castle_walls.clone()))
+        // The castle body is by far the densest mesh, so its tree is tuned for it
+        SceneNode::from(Geometry::new(KDMesh::with_options(&castle_model, Shading::Flat, KDMeshOptions {
+            auto_tune: true,
+            ..KDMeshOptions::default()
+        }), mat_castle_walls.clone()))
             .translated((0.0, 30.0, -30.0))
             .into(),
         SceneNode::from(Geometry::new(KDMesh::new(&castle_window_frames_model, Shading::Flat), mat_castle_window_frames.clone()))
@@ -2,22 +2,21 @@
 //! Author: Sunjay Varma
 
 use std::error::Error;
-use std::iter::once;
 use std::sync::Arc;
-use std::collections::VecDeque;
 
 use rand::{Rng, SeedableRng, rngs::StdRng};
 
 use portrayer::{
     scene::{HierScene, SceneNode, Geometry},
-    primitive::{Cube, Sphere, Cylinder, Mesh, MeshData, Shading},
+    primitive::{Cube, Ellipsoid, Cylinder, Mesh, MeshData, Shading},
     kdtree::KDMesh,
     material::{Material, WATER_REFRACTION_INDEX},
     light::Light,
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Rgb, Uv},
+    math::{Radians, Vec3, Rgb},
+    texture::SkyGradient,
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -67,8 +66,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut image = Image::new("graphics-temple.png", 533, 300)?;
 
     // image.slice_mut((152, 128), (382, 162)).render::<RenderProgress, _>(&scene, cam,
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.529, g: 0.808, b: 0.922} * (1.0 - uv.v) + Rgb {r: 0.086, g: 0.38, b: 0.745} * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::clear_day());
 
     Ok(image.save()?)
 }
@@ -81,7 +79,7 @@ fn hills() -> Result<SceneNode, Box<dyn Error>> {
 
     let grass_model = Arc::new(MeshData::load_obj("assets/tog_grass.obj")?);
 
-    Ok(SceneNode::from(Geometry::new(KDMesh::new(&*grass_model, Shading::Smooth), mat_grass))
+    Ok(SceneNode::from(Geometry::new(KDMesh::new(&grass_model, Shading::Smooth), mat_grass))
         .translated((1.958125, 16.093138, -86.113747)))
 }
 
@@ -111,12 +109,14 @@ fn lake() -> Result<SceneNode, Box<dyn Error>> {
             .into(),
 
         // Flat shaded to speed up rendering since the normals don't super matter for this (not visible)
-        SceneNode::from(Geometry::new(KDMesh::new(&*underwater_land_model, Shading::Flat), mat_dirt))
+        SceneNode::from(Geometry::new(KDMesh::new(&underwater_land_model, Shading::Flat), mat_dirt))
             .translated((0.0, -107.0, 300.0))
             .into(),
     ]))
 }
 
+// Still a work in progress, so some of the values computed here are not used yet
+#[allow(unused)]
 fn temple_floor_1() -> SceneNode {
     // Generates a maze pattern around the entire floor
     let floor_width = 240.0;
@@ -187,7 +187,7 @@ fn temple_floor_2() -> SceneNode {
     let floor_y_offset = 20.0;
     let floor_front_z = floor_length / 2.0;
 
-    let sections = 4;
+    let sections: usize = 4;
     let section_width = 30.0;
 
     let column_scale = 2.0;
@@ -213,7 +213,7 @@ fn temple_floor_2() -> SceneNode {
     let column = Arc::new(cylinder_column(mat_column.clone()));
     for i in 0..sections * 2 {
         // Add section width on odd i
-        let x = section_width * ((i+1)/2) as f64
+        let x = section_width * i.div_ceil(2) as f64
               // Add section spacing on even i
               + section_spacing * (i/2) as f64
               // Center in the image and column size
@@ -311,7 +311,7 @@ fn temple_floor_3() -> Result<SceneNode, Box<dyn Error>> {
     });
 
     let puppet_model = Arc::new(MeshData::load_obj("assets/tog_puppet.obj")?);
-    let puppet = Arc::new(SceneNode::from(Geometry::new(KDMesh::new(&*puppet_model, Shading::Smooth), mat_puppet))
+    let puppet = Arc::new(SceneNode::from(Geometry::new(KDMesh::new(&puppet_model, Shading::Smooth), mat_puppet))
         .translated((0.0, puppet_y_offset, 0.0)));
 
     let mat_ceiling = Arc::new(Material {
@@ -368,14 +368,14 @@ fn temple_floor_4() -> Result<SceneNode, Box<dyn Error>> {
             .into(),
 
         // Teapot
-        SceneNode::from(Geometry::new(KDMesh::new(&*teapot_model, Shading::Smooth), mat_crystal.clone()))
+        SceneNode::from(Geometry::new(KDMesh::new(&teapot_model, Shading::Smooth), mat_crystal.clone()))
             .scaled(0.6)
             .rotated_y(Radians::from_degrees(-55.0))
             .translated((0.0, 59.857296, 0.0))
             .into(),
 
         // Cow
-        SceneNode::from(Geometry::new(KDMesh::new(&*cow_model, Shading::Smooth), mat_crystal.clone()))
+        SceneNode::from(Geometry::new(KDMesh::new(&cow_model, Shading::Smooth), mat_crystal.clone()))
             .scaled(1.5)
             .rotated_y(Radians::from_degrees(-125.0))
             .translated((30.0, 65.31517, 0.0))
@@ -395,12 +395,10 @@ fn cylinder_column(mat_column: Arc<Material>) -> SceneNode {
             .translated((0.0, -3.8, 0.0))
             .into(),
 
-        SceneNode::from(Geometry::new(Sphere, mat_column.clone()))
-            .scaled((1.5, 0.5, 1.5))
+        SceneNode::from(Geometry::new(Ellipsoid::new((1.5, 0.5, 1.5)), mat_column.clone()))
             .translated((0.0, 3.0, 0.0))
             .into(),
-        SceneNode::from(Geometry::new(Sphere, mat_column.clone()))
-            .scaled((1.5, 0.5, 1.5))
+        SceneNode::from(Geometry::new(Ellipsoid::new((1.5, 0.5, 1.5)), mat_column.clone()))
             .translated((0.0, -3.0, 0.0))
             .into(),
 
@@ -5,13 +5,14 @@ use std::sync::Arc;
 
 use portrayer::{
     scene::{HierScene, SceneNode, Geometry},
-    primitive::{Sphere, Mesh, MeshData, Shading, Cube},
+    primitive::{Sphere, Ellipsoid, Mesh, MeshData, Shading, Cube},
     material::Material,
     light::Light,
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
+    texture::SkyGradient,
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -42,8 +43,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             .translated((2.0, 2.0, 0.0))
             .into(),
 
-        SceneNode::from(Geometry::new(Sphere, stone.clone()))
-            .scaled((4.0, 0.6, 0.6))
+        SceneNode::from(Geometry::new(Ellipsoid::new((4.0, 0.6, 0.6)), stone.clone()))
             .translated((0.0, 4.0, 0.0))
             .into(),
     ]).translated((0.0, 0.0, -10.0)));
@@ -90,8 +90,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("instance.png", 256, 256)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
     Ok(image.save()?)
 }
@@ -6,14 +6,14 @@ use std::sync::Arc;
 
 use portrayer::{
     scene::{HierScene, SceneNode, Geometry},
-    primitive::{Cube, Plane, Sphere, Cone, Mesh, MeshData, Shading},
+    primitive::{Cube, Plane, Sphere, Ellipsoid, Cone, Mesh, MeshData, Shading},
     material::{Material, OPTICAL_GLASS_REFRACTION_INDEX, WATER_REFRACTION_INDEX},
-    texture::{Texture, ImageTexture, NormalMap},
+    texture::{Texture, ImageTexture, NormalMap, SkyGradient},
     light::{Light, Parallelogram},
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -65,8 +65,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("monkeys-making-monkeys.png", 1920, 1080)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
     Ok(image.save()?)
 }
@@ -237,8 +236,7 @@ fn computer(monkey_mesh: &Arc<MeshData>) -> Result<SceneNode, Box<dyn Error>> {
             .into(),
 
         // Mouse
-        SceneNode::from(Geometry::new(Sphere, mat_computer.clone()))
-            .scaled((0.28, 0.12, 0.4))
+        SceneNode::from(Geometry::new(Ellipsoid::new((0.28, 0.12, 0.4)), mat_computer.clone()))
             .translated((1.411292, 5.327119, 1.857835))
             .into(),
 
@@ -272,8 +270,7 @@ fn chair() -> SceneNode {
 
     SceneNode::from(vec![
         // Chair back
-        SceneNode::from(Geometry::new(Sphere, mat_chair.clone()))
-            .scaled((1.283107, 1.537732, 0.425492))
+        SceneNode::from(Geometry::new(Ellipsoid::new((1.283107, 1.537732, 0.425492)), mat_chair.clone()))
             .translated((0.0, 5.334378, 5.404959))
             .into(),
     ])
@@ -308,8 +305,7 @@ fn character(monkey_mesh: &Arc<MeshData>) -> Result<SceneNode, Box<dyn Error>> {
             .into(),
 
         // Arm
-        SceneNode::from(Geometry::new(Sphere, mat_torso.clone()))
-            .scaled((0.282782, 1.299079, 0.282782))
+        SceneNode::from(Geometry::new(Ellipsoid::new((0.282782, 1.299079, 0.282782)), mat_torso.clone()))
             .rotated_z(Radians::from_degrees(19.0))
             .translated((0.984683, 5.126376, 4.344858))
             .into(),
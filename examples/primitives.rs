@@ -6,13 +6,14 @@ use std::iter::once;
 
 use portrayer::{
     scene::{HierScene, SceneNode, Geometry},
-    primitive::{Cube, Cylinder, Cone, Sphere, Mesh, MeshData, Shading, Plane},
+    primitive::{Cube, Cylinder, Cone, Ellipsoid, Mesh, MeshData, Shading, Plane},
     material::Material,
     light::Light,
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
+    texture::SkyGradient,
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -59,8 +60,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("primitives.png", 910, 512)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
     Ok(image.save()?)
 }
@@ -118,8 +118,7 @@ fn make_castle() -> Result<SceneNode, Box<dyn Error>> {
 
     // Castle dome
     nodes.push(
-        SceneNode::from(Geometry::new(Sphere, mat_dome.clone()))
-            .scaled((dome_radius, castle_height, dome_radius))
+        SceneNode::from(Geometry::new(Ellipsoid::new((dome_radius, castle_height, dome_radius)), mat_dome.clone()))
             .translated((0.0, castle_height, 0.0))
             .into()
     );
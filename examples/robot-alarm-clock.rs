@@ -9,12 +9,12 @@ use portrayer::{
     primitive::{Mesh, Cube, Plane, MeshData, Shading},
     kdtree::KDMesh,
     material::{Material, OPTICAL_GLASS_REFRACTION_INDEX},
-    texture::{Texture, ImageTexture, NormalMap},
+    texture::{Texture, ImageTexture, NormalMap, SkyGradient},
     light::{Light, Parallelogram},
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Mat3, Rgb, Uv},
+    math::{Radians, Vec3, Rgb, UvTransform},
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -49,8 +49,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("robot-alarm-clock.png", 1920, 1080)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.529, g: 0.808, b: 0.922} * (1.0 - uv.v) + Rgb {r: 0.086, g: 0.38, b: 0.745} * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::clear_day());
 
     Ok(image.save()?)
 }
@@ -62,9 +61,8 @@ fn room() -> Result<SceneNode, Box<dyn Error>> {
         specular: Rgb {r: 0.3, g: 0.3, b: 0.3},
         shininess: 25.0,
         texture: Some(wallpaper),
-        uv_trans: Mat3::scaling_3d(3.0),
         ..Material::default()
-    });
+    }.with_uv_transform(UvTransform {scale: 3.0, ..UvTransform::default()}));
 
     let wood = Arc::new(Texture::from(ImageTexture::open("assets/Wood_018_basecolor_cubemap.jpg")?));
     let wood_normals = Arc::new(NormalMap::open("assets/Wood_018_normal_cubemap.jpg")?);
@@ -8,12 +8,12 @@ use portrayer::{
     primitive::{Cube, Plane, Cylinder, MeshData, Shading},
     kdtree::KDMesh,
     material::{Material, WATER_REFRACTION_INDEX, WINDOW_GLASS_REFRACTION_INDEX},
-    texture::{Texture, ImageTexture, NormalMap},
+    texture::{Texture, ImageTexture, NormalMap, SkyGradient},
     light::Light,
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -60,8 +60,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("transmission-refraction.png", 910, 512)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
     Ok(image.save()?)
 }
@@ -213,7 +212,7 @@ fn water() -> Result<SceneNode, Box<dyn Error>> {
     });
 
     let fish_model = Arc::new(MeshData::load_obj("assets/fish.obj")?);
-    let fish_mesh = KDMesh::new(&*fish_model, Shading::Smooth);
+    let fish_mesh = KDMesh::new(&fish_model, Shading::Smooth);
 
     Ok(SceneNode::from(vec![
         // Water
@@ -7,12 +7,12 @@ use portrayer::{
     scene::{HierScene, SceneNode, Geometry},
     primitive::{Cube, Plane, Cylinder},
     material::{Material, WATER_REFRACTION_INDEX},
-    texture::{Texture, ImageTexture, NormalMap},
+    texture::{Texture, ImageTexture, NormalMap, SkyGradient},
     light::Light,
     render::Image,
     reporter::RenderProgress,
     camera::CameraSettings,
-    math::{Radians, Vec3, Uv, Rgb},
+    math::{Radians, Vec3, Rgb},
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -42,8 +42,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut image = Image::new("water-glass.png", 910, 512)?;
 
-    image.render::<RenderProgress, _>(&scene, cam,
-        |uv: Uv| Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v);
+    image.render::<RenderProgress, _>(&scene, cam, SkyGradient::dusk());
 
     Ok(image.save()?)
 }
@@ -0,0 +1,491 @@
+//! Animating the parameters of a scene over time (e.g. across the frames of a sequence) without
+//! writing code that rebuilds the scene for every frame
+//!
+//! Each animated parameter has a `Track` of keyframes. Times can be in any unit (frame numbers,
+//! seconds, etc.) as long as the same unit is used for every keyframe and for `Animation::scene_at`.
+
+use std::sync::Arc;
+use std::collections::HashMap;
+
+use crate::math::{Vec2, Vec3, Mat3, Uv, Rgb, splitmix64, frame_seed};
+use crate::scene::HierScene;
+use crate::material::Material;
+use crate::light::Light;
+use crate::edit::{NodeSelector, NodeEdit};
+
+/// A value that can be blended between two keyframes
+pub trait Interpolate: Clone {
+    /// Returns the value the given fraction (0.0 to 1.0) of the way from this value to the other
+    fn interpolate(&self, other: &Self, t: f64) -> Self;
+}
+
+impl Interpolate for f64 {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        *self + (*other - *self) * t
+    }
+}
+
+impl Interpolate for Rgb {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        *self + (*other - *self) * t
+    }
+}
+
+impl Interpolate for Uv {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        *self + (*other - *self) * t
+    }
+}
+
+/// Switches are not blended: the value of the earlier keyframe is held until the next keyframe
+impl Interpolate for bool {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        if t >= 1.0 { *other } else { *self }
+    }
+}
+
+/// The value of a parameter at a point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe<T> {
+    pub time: f64,
+    pub value: T,
+}
+
+impl<T> Keyframe<T> {
+    pub fn new(time: f64, value: T) -> Self {
+        Self {time, value}
+    }
+}
+
+/// The keyframes of a single animated parameter
+///
+/// The value is interpolated linearly between keyframes. Before the first keyframe and after the
+/// last keyframe, the value of that keyframe is held.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track<T> {
+    /// Sorted by time
+    keyframes: Vec<Keyframe<T>>,
+}
+
+impl<T: Interpolate> Track<T> {
+    /// Creates a track from the given keyframes (in any order)
+    ///
+    /// Panics if there are no keyframes.
+    pub fn new(mut keyframes: Vec<Keyframe<T>>) -> Self {
+        assert!(!keyframes.is_empty(), "bug: an animation track must have at least one keyframe");
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).expect("bug: keyframe time should not be NaN"));
+        Self {keyframes}
+    }
+
+    /// Creates a track that always has the given value
+    pub fn constant(value: T) -> Self {
+        Self::new(vec![Keyframe::new(0.0, value)])
+    }
+
+    /// Returns the value of the parameter at the given time
+    pub fn at(&self, time: f64) -> T {
+        // The index of the first keyframe after the given time
+        let next = self.keyframes.partition_point(|keyframe| keyframe.time <= time);
+        if next == 0 {
+            return self.keyframes[0].value.clone();
+        }
+        let prev = &self.keyframes[next - 1];
+        match self.keyframes.get(next) {
+            Some(next) => {
+                let t = (time - prev.time) / (next.time - prev.time);
+                prev.value.interpolate(&next.value, t)
+            },
+            None => prev.value.clone(),
+        }
+    }
+}
+
+impl<T: Interpolate> From<Vec<Keyframe<T>>> for Track<T> {
+    fn from(keyframes: Vec<Keyframe<T>>) -> Self {
+        Self::new(keyframes)
+    }
+}
+
+/// Keyframed parameters of a material
+///
+/// Parameters without a track keep the value from the material being animated. Materials do not
+/// emit light, so glowing surfaces (e.g. visible area lights) are animated through the intensity
+/// of their lights instead (see `LightAnimation`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaterialAnimation {
+    pub diffuse: Option<Track<Rgb>>,
+    pub reflectivity: Option<Track<f64>>,
+    /// Added to the texture coordinates after the material's own UV transformation (see
+    /// `Material::uv_trans`), e.g. to scroll a texture across a surface
+    pub uv_offset: Option<Track<Uv>>,
+}
+
+impl MaterialAnimation {
+    /// Creates an animation that does not change anything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Animates the diffuse color, returning the updated animation
+    pub fn with_diffuse<K: Into<Track<Rgb>>>(mut self, track: K) -> Self {
+        self.diffuse = Some(track.into());
+        self
+    }
+
+    /// Animates the reflectivity, returning the updated animation
+    pub fn with_reflectivity<K: Into<Track<f64>>>(mut self, track: K) -> Self {
+        self.reflectivity = Some(track.into());
+        self
+    }
+
+    /// Animates the offset of the texture coordinates, returning the updated animation
+    pub fn with_uv_offset<K: Into<Track<Uv>>>(mut self, track: K) -> Self {
+        self.uv_offset = Some(track.into());
+        self
+    }
+
+    /// Returns a copy of the given material with the animated parameters set to their values at
+    /// the given time
+    pub fn material_at(&self, material: &Material, time: f64) -> Material {
+        let mut material = material.clone();
+        if let Some(diffuse) = &self.diffuse {
+            material.diffuse = diffuse.at(time);
+        }
+        if let Some(reflectivity) = &self.reflectivity {
+            material.reflectivity = reflectivity.at(time);
+        }
+        if let Some(uv_offset) = &self.uv_offset {
+            let offset = uv_offset.at(time);
+            material.uv_trans = Mat3::translation_2d(Vec2::new(offset.u, offset.v)) * material.uv_trans;
+        }
+        material
+    }
+}
+
+/// Procedural, noise-driven flickering of the intensity of a light (e.g. a torch or a candle)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flicker {
+    /// How far the intensity can dip, from 0.0 (steady) to 1.0 (the light can go out entirely)
+    pub strength: f64,
+    /// The number of random changes in intensity per unit of time
+    pub rate: f64,
+    /// Lights with different seeds flicker independently
+    pub seed: u64,
+}
+
+impl Flicker {
+    /// The lively flicker of a torch, assuming that time is measured in seconds
+    pub fn torch(seed: u64) -> Self {
+        Self {strength: 0.35, rate: 6.0, seed}
+    }
+
+    /// The gentle flicker of a candle, assuming that time is measured in seconds
+    pub fn candle(seed: u64) -> Self {
+        Self {strength: 0.15, rate: 3.0, seed}
+    }
+
+    /// Returns the factor that the intensity of the light is multiplied by at the given time, from
+    /// `1.0 - strength` to 1.0
+    ///
+    /// The factor changes smoothly over time and is always the same for the same time and seed.
+    pub fn factor_at(&self, time: f64) -> f64 {
+        // A slow and a fast layer of noise give both large swells and small, quick flickers
+        let noise = 0.65 * value_noise(self.seed, time * self.rate)
+            + 0.35 * value_noise(splitmix64(self.seed), time * self.rate * 2.7);
+        1.0 - self.strength * noise
+    }
+}
+
+/// Returns smooth 1D noise from 0.0 to 1.0 with a new random value at every integer
+fn value_noise(seed: u64, x: f64) -> f64 {
+    let random = |i: f64| {
+        let hash = splitmix64(seed ^ splitmix64(i as i64 as u64));
+        // The top 53 bits fill the mantissa of a float from 0.0 to 1.0
+        (hash >> 11) as f64 / (1u64 << 53) as f64
+    };
+
+    let i = x.floor();
+    let t = x - i;
+    // Smoothstep so that the noise has no corners at the integers
+    let t = t * t * (3.0 - 2.0 * t);
+    random(i).interpolate(&random(i + 1.0), t)
+}
+
+/// Keyframed parameters of a light
+///
+/// Parameters without a track keep the value from the light being animated.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LightAnimation {
+    pub position: Option<Track<Vec3>>,
+    /// Multiplies the color of the light
+    pub intensity: Option<Track<f64>>,
+    /// Multiplies the color of the light (on top of `intensity`)
+    pub flicker: Option<Flicker>,
+}
+
+impl LightAnimation {
+    /// Creates an animation that does not change anything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Animates the position, returning the updated animation
+    pub fn with_position<K: Into<Track<Vec3>>>(mut self, track: K) -> Self {
+        self.position = Some(track.into());
+        self
+    }
+
+    /// Animates the intensity, returning the updated animation
+    pub fn with_intensity<K: Into<Track<f64>>>(mut self, track: K) -> Self {
+        self.intensity = Some(track.into());
+        self
+    }
+
+    /// Makes the light flicker, returning the updated animation
+    pub fn with_flicker(mut self, flicker: Flicker) -> Self {
+        self.flicker = Some(flicker);
+        self
+    }
+
+    /// Returns a copy of the given light with the animated parameters set to their values at the
+    /// given time
+    pub fn light_at(&self, light: &Light, time: f64) -> Light {
+        let mut light = light.clone();
+        if let Some(position) = &self.position {
+            light.position = position.at(time);
+        }
+        if let Some(intensity) = &self.intensity {
+            light.color *= intensity.at(time);
+        }
+        if let Some(flicker) = &self.flicker {
+            light.color *= flicker.factor_at(time);
+        }
+        light
+    }
+}
+
+/// How the noise of a seeded render changes between the frames of an animation
+///
+/// Only used when the render is seeded (see `math::sample_seed`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FrameNoise {
+    /// Every frame is seeded differently (see `math::frame_seed`), so the noise moves from frame
+    /// to frame instead of "boiling" in place over the changing image
+    #[default]
+    Decorrelated,
+    /// Every frame is seeded the same way, so the noise stays in place (fixed-pattern noise)
+    ///
+    /// Some temporal denoisers prefer this since the noise is easier to tell apart from motion.
+    Fixed,
+}
+
+impl FrameNoise {
+    /// Returns the global seed for the given frame of an animation rendered with the given seed
+    pub fn seed(self, global_seed: u64, frame: usize) -> u64 {
+        match self {
+            FrameNoise::Decorrelated => frame_seed(global_seed, frame),
+            FrameNoise::Fixed => global_seed,
+        }
+    }
+}
+
+/// The animated parameters of a scene
+///
+/// Materials are selected by their tag (see `Material::tag`), so every material with a given tag
+/// is animated the same way. Lights are selected by their index in `Scene::lights`. Nodes are
+/// selected the same way as in `HierScene::edited`.
+#[derive(Debug, Clone, Default)]
+pub struct Animation {
+    materials: HashMap<String, MaterialAnimation>,
+    lights: HashMap<usize, LightAnimation>,
+    /// Whether the selected nodes are visible
+    visibility: Vec<(NodeSelector, Track<bool>)>,
+}
+
+impl Animation {
+    /// Creates an animation that does not change anything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Animates every material with the given tag, returning the updated animation
+    pub fn with_material<S: Into<String>>(mut self, tag: S, animation: MaterialAnimation) -> Self {
+        self.materials.insert(tag.into(), animation);
+        self
+    }
+
+    /// Animates the light at the given index in the scene, returning the updated animation
+    pub fn with_light(mut self, index: usize, animation: LightAnimation) -> Self {
+        self.lights.insert(index, animation);
+        self
+    }
+
+    /// Shows or hides the selected node(s) over time, returning the updated animation
+    ///
+    /// Hidden nodes are not seen at all: nothing at or underneath them is rendered. Useful for
+    /// stop motion style changes, e.g. showing a different pose of a model on each frame.
+    pub fn with_visibility<S: Into<NodeSelector>, K: Into<Track<bool>>>(mut self, selector: S, visible: K) -> Self {
+        self.visibility.push((selector.into(), visible.into()));
+        self
+    }
+
+    /// Returns a copy of the given scene with every animated parameter set to its value at the
+    /// given time
+    ///
+    /// Materials and instanced nodes that are shared in the scene remain shared in the copy.
+    pub fn scene_at(&self, scene: &HierScene, time: f64) -> HierScene {
+        let mut materials: HashMap<*const Material, Arc<Material>> = HashMap::new();
+        let mut animated = |material: &Arc<Material>| {
+            let animation = match material.tag.as_ref().and_then(|tag| self.materials.get(tag)) {
+                Some(animation) => animation,
+                None => return material.clone(),
+            };
+            materials.entry(Arc::as_ptr(material))
+                .or_insert_with(|| Arc::new(animation.material_at(material, time)))
+                .clone()
+        };
+
+        let scene = HierScene {
+            root: Arc::new(scene.root.map_materials(&mut animated)),
+            lights: scene.lights.iter().enumerate().map(|(index, light)| match self.lights.get(&index) {
+                Some(animation) => animation.light_at(light, time),
+                None => light.clone(),
+            }).collect(),
+            ambient: scene.ambient,
+        };
+
+        // Selectors that do not match any node have nothing to hide
+        self.visibility.iter()
+            .filter(|(_, visible)| !visible.at(time))
+            .fold(scene, |scene, (selector, _)| scene.edited(selector.clone(), &NodeEdit::Hide).unwrap_or(scene))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::scene::{SceneNode, Geometry};
+    use crate::primitive::Sphere;
+    use crate::ray::Ray;
+    use crate::math::UvTransform;
+
+    #[test]
+    fn tracks_interpolate_and_hold() {
+        let track = Track::new(vec![Keyframe::new(10.0, 2.0), Keyframe::new(0.0, 0.0)]);
+        assert_eq!(track.at(-5.0), 0.0);
+        assert_eq!(track.at(0.0), 0.0);
+        assert_eq!(track.at(2.5), 0.5);
+        assert_eq!(track.at(10.0), 2.0);
+        assert_eq!(track.at(20.0), 2.0);
+        assert_eq!(Track::constant(Vec3::up()).at(3.0), Vec3::up());
+    }
+
+    #[test]
+    fn frame_noise_is_decorrelated_or_fixed() {
+        let seeds: Vec<_> = (0..4).map(|frame| FrameNoise::Decorrelated.seed(42, frame)).collect();
+        assert!(seeds.windows(2).all(|pair| pair[0] != pair[1]));
+        assert_eq!(seeds[3], frame_seed(42, 3));
+
+        assert!((0..4).all(|frame| FrameNoise::Fixed.seed(42, frame) == 42));
+        assert_eq!(FrameNoise::default(), FrameNoise::Decorrelated);
+    }
+
+    #[test]
+    fn flicker_stays_in_range() {
+        let flicker = Flicker::torch(7);
+        let factors: Vec<_> = (0..1000).map(|frame| flicker.factor_at(frame as f64 / 24.0)).collect();
+        assert!(factors.iter().all(|&factor| (1.0 - flicker.strength..=1.0).contains(&factor)));
+        // Actually flickers, and smoothly enough that consecutive frames are similar
+        let (min, max) = factors.iter().fold((1.0f64, 0.0f64), |(min, max), &factor| (min.min(factor), max.max(factor)));
+        assert!(max - min > flicker.strength / 2.0);
+        assert!(factors.windows(2).all(|pair| (pair[1] - pair[0]).abs() < flicker.strength / 2.0));
+
+        // The same for the same seed, but not for a different seed
+        assert_eq!(flicker.factor_at(1.5), Flicker::torch(7).factor_at(1.5));
+        assert_ne!(flicker.factor_at(1.5), Flicker::torch(8).factor_at(1.5));
+    }
+
+    #[test]
+    fn lights_are_animated() {
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![])),
+            lights: vec![
+                Light {color: Rgb::white(), ..Light::default()},
+                Light {color: Rgb::red(), ..Light::default()},
+            ],
+            ambient: Rgb::black(),
+        };
+        let animation = Animation::new().with_light(1, LightAnimation::new()
+            .with_position(vec![Keyframe::new(0.0, Vec3::zero()), Keyframe::new(2.0, Vec3::up() * 2.0)])
+            .with_intensity(Track::constant(0.5)));
+        let frame = animation.scene_at(&scene, 1.0);
+
+        assert_eq!(frame.lights[0].color, Rgb::white());
+        assert_eq!(frame.lights[1].position, Vec3::up());
+        assert_eq!(frame.lights[1].color, Rgb::red() * 0.5);
+    }
+
+    #[test]
+    fn nodes_are_shown_and_hidden() {
+        let mat = Arc::new(Material::default());
+        let pose = |name: &str, x: f64| Arc::new(SceneNode::from(Geometry::new(Sphere, mat.clone()))
+            .translated((x, 0.0, 0.0))
+            .named(name));
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![pose("arms up", 0.0), pose("arms down", 5.0)])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let animation = Animation::new()
+            .with_visibility("arms up", vec![Keyframe::new(0.0, true), Keyframe::new(1.0, false)])
+            .with_visibility("arms down", vec![Keyframe::new(0.0, false), Keyframe::new(1.0, true)]);
+
+        let sees = |scene: &HierScene, x: f64| scene.first_hit(&Ray::new(Vec3::new(x, 0.0, 5.0), -Vec3::unit_z())).is_some();
+        let first = animation.scene_at(&scene, 0.0);
+        assert!(sees(&first, 0.0) && !sees(&first, 5.0));
+        // Switches are held until the next keyframe
+        let between = animation.scene_at(&scene, 0.9);
+        assert!(sees(&between, 0.0) && !sees(&between, 5.0));
+        let second = animation.scene_at(&scene, 1.0);
+        assert!(!sees(&second, 0.0) && sees(&second, 5.0));
+        // The root no longer extends over the hidden node
+        assert!(second.root.parent_bounds().unwrap().min().x > 3.0);
+    }
+
+    #[test]
+    fn tagged_materials_are_animated() {
+        let water = Arc::new(Material {tag: Some("water".to_string()), reflectivity: 0.5, ..Material::default()});
+        let stone = Arc::new(Material {diffuse: Rgb::red(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(Geometry::new(Sphere, water.clone()))),
+                Arc::new(SceneNode::from(Geometry::new(Sphere, water.clone()))),
+                Arc::new(SceneNode::from(Geometry::new(Sphere, stone.clone()))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+
+        let animation = Animation::new().with_material("water", MaterialAnimation::new()
+            .with_diffuse(vec![Keyframe::new(0.0, Rgb::white()), Keyframe::new(1.0, Rgb::black())])
+            .with_uv_offset(Track::constant(Uv {u: 0.25, v: 0.0})));
+        let frame = animation.scene_at(&scene, 0.5);
+
+        let material = |index: usize| frame.root.children()[index].geometry().unwrap().material.clone();
+        assert_eq!(material(0).diffuse, Rgb::from(0.5));
+        // Only the animated parameters change
+        assert_eq!(material(0).reflectivity, 0.5);
+        let offset = UvTransform {offset: Uv {u: 0.25, v: 0.0}, ..UvTransform::default()};
+        assert_eq!(material(0).uv_trans, Mat3::from(offset));
+        // Materials that were shared are still shared
+        assert!(Arc::ptr_eq(&material(0), &material(1)));
+        assert!(Arc::ptr_eq(&material(2), &stone));
+    }
+}
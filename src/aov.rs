@@ -0,0 +1,294 @@
+//! Extra passes ("arbitrary output variables") of the geometry seen by the camera for use in
+//! compositing, e.g. masks for edge wear or cavity shading in stylized renders
+//!
+//! Only one ray is cast through the center of each pixel, so the passes are not antialiased.
+
+use std::fmt;
+use std::error::Error;
+use std::str::FromStr;
+
+use rayon::prelude::*;
+
+use crate::math::{EPSILON, Vec3};
+use crate::scene::Scene;
+use crate::ray::RayCast;
+use crate::camera::{CameraSettings, Camera};
+
+/// The largest difference in depth (relative to the depth of a pixel) between two neighboring
+/// pixels for them to be considered part of the same surface when estimating curvature
+const MAX_SURFACE_DEPTH_RATIO: f64 = 0.1;
+
+/// An error produced when parsing the name of an AOV fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseAovError {
+    name: String,
+}
+
+impl fmt::Display for ParseAovError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid AOV '{}': expected 'position', 'normal', 'depth', or 'curvature'", self.name)
+    }
+}
+
+impl Error for ParseAovError {}
+
+/// One of the passes that can be output for compositing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Aov {
+    /// The position (in world space) of the surface seen by each pixel
+    ///
+    /// Each axis is scaled to fit between the smallest and largest positions seen in the image.
+    Position,
+    /// The shading normal (in world space) of the surface seen by each pixel, facing the camera
+    ///
+    /// Each component is scaled from -1.0 to 1.0 to the range 0.0 to 1.0.
+    Normal,
+    /// The distance from the camera to the surface seen by each pixel
+    ///
+    /// Surfaces at the camera are white and fade to black at the furthest surface seen.
+    Depth,
+    /// An estimate of the curvature of the surface seen by each pixel
+    ///
+    /// Flat surfaces are middle grey, convex surfaces and edges (e.g. where paint wears off) are
+    /// brighter, and concave surfaces and crevices (e.g. where dirt collects) are darker.
+    Curvature,
+}
+
+impl FromStr for Aov {
+    type Err = ParseAovError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match &*name.to_lowercase() {
+            "position" => Ok(Aov::Position),
+            "normal" => Ok(Aov::Normal),
+            "depth" => Ok(Aov::Depth),
+            "curvature" => Ok(Aov::Curvature),
+            _ => Err(ParseAovError {name: name.to_string()}),
+        }
+    }
+}
+
+/// The surface seen by a single pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AovHit {
+    position: Vec3,
+    /// Normalized and facing the camera
+    normal: Vec3,
+    depth: f64,
+}
+
+/// The geometry seen through the center of every pixel of an image, from which each `Aov` pass
+/// can be produced
+#[derive(Debug, Clone)]
+pub struct AovPasses {
+    width: usize,
+    height: usize,
+    /// The surface seen by each pixel (if any), row by row
+    hits: Vec<Option<AovHit>>,
+    /// The estimated curvature at each pixel, row by row (see `AovPasses::curvature`)
+    curvatures: Vec<f64>,
+}
+
+impl AovPasses {
+    /// Casts a primary ray through the center of each pixel of an image with the given size and
+    /// records the surface that it hits
+    pub fn new<R: RayCast + Sync>(scene: &Scene<R>, camera: CameraSettings, size: (usize, usize)) -> Self {
+        let camera = Camera::new(camera, (size.0 as f64, size.1 as f64));
+        Self::from_camera(scene, &camera, size)
+    }
+
+    pub(crate) fn from_camera<R: RayCast + Sync>(scene: &Scene<R>, camera: &Camera, (width, height): (usize, usize)) -> Self {
+        let hits: Vec<_> = (0..width * height).into_par_iter().map(|i| {
+            let (x, y) = ((i % width) as f64 + 0.5, (i / width) as f64 + 0.5);
+            let ray = camera.ray_at((x, y));
+
+            let mut t_range = ray.t_range();
+            scene.root.ray_cast(&ray, &mut t_range).map(|(hit, _)| {
+                let normal = hit.normal.normalized();
+                AovHit {
+                    position: hit.hit_point,
+                    // Two-sided surfaces can be hit from behind
+                    normal: if normal.dot(ray.direction()) > 0.0 { -normal } else { normal },
+                    depth: (hit.hit_point - ray.origin()).magnitude(),
+                }
+            })
+        }).collect();
+
+        let curvatures = (0..width * height).into_par_iter()
+            .map(|i| screen_space_curvature(&hits, width, height, (i % width, i / width)))
+            .collect();
+
+        Self {width, height, hits, curvatures}
+    }
+
+    /// Returns the width of the passes
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the height of the passes
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the position (in world space) of the surface seen by the given pixel, or None if
+    /// the pixel sees nothing
+    pub fn position(&self, (x, y): (usize, usize)) -> Option<Vec3> {
+        self.hits[y * self.width + x].map(|hit| hit.position)
+    }
+
+    /// Returns the normal (in world space, facing the camera) of the surface seen by the given
+    /// pixel, or None if the pixel sees nothing
+    pub fn normal(&self, (x, y): (usize, usize)) -> Option<Vec3> {
+        self.hits[y * self.width + x].map(|hit| hit.normal)
+    }
+
+    /// Returns the distance from the camera to the surface seen by the given pixel, or None if the
+    /// pixel sees nothing
+    pub fn depth(&self, (x, y): (usize, usize)) -> Option<f64> {
+        self.hits[y * self.width + x].map(|hit| hit.depth)
+    }
+
+    /// Returns the estimated curvature of the surface seen by the given pixel
+    ///
+    /// This is the screen-space derivative of the normals: how much the normal turns towards (or
+    /// away from) the direction of the neighboring pixels for every pixel moved. It is positive for
+    /// convex surfaces, negative for concave surfaces, and zero for flat surfaces and for pixels
+    /// that see nothing. Neighbors that are much closer or further away than the pixel are assumed
+    /// to be part of a different surface and are ignored.
+    pub fn curvature(&self, (x, y): (usize, usize)) -> f64 {
+        self.curvatures[y * self.width + x]
+    }
+
+    /// Returns the given pass as an image
+    ///
+    /// The values are stored without gamma correction since they are data, not colors. Pixels that
+    /// see nothing are black (middle grey for `Aov::Curvature`).
+    pub fn image(&self, aov: Aov) -> image::RgbImage {
+        let (min_position, max_position) = self.hits.iter().flatten()
+            .fold((Vec3::broadcast(f64::INFINITY), Vec3::broadcast(-f64::INFINITY)), |(min, max), hit| {
+                (Vec3::partial_min(min, hit.position), Vec3::partial_max(max, hit.position))
+            });
+        let position_size = Vec3::partial_max(max_position - min_position, Vec3::broadcast(EPSILON));
+        let max_depth = self.hits.iter().flatten().map(|hit| hit.depth).fold(EPSILON, f64::max);
+
+        image::RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let pixel = (x as usize, y as usize);
+            let value = match (aov, self.hits[pixel.1 * self.width + pixel.0]) {
+                (Aov::Curvature, _) => Vec3::broadcast(0.5 + 0.5 * self.curvature(pixel)),
+                (_, None) => Vec3::zero(),
+                (Aov::Position, Some(hit)) => (hit.position - min_position) / position_size,
+                (Aov::Normal, Some(hit)) => hit.normal * 0.5 + 0.5,
+                (Aov::Depth, Some(hit)) => Vec3::broadcast(1.0 - hit.depth / max_depth),
+            };
+
+            let value = value.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            image::Rgb([value.x, value.y, value.z])
+        })
+    }
+}
+
+/// Estimates the curvature at the given pixel from the positions and normals of its neighbors
+/// (see `AovPasses::curvature`)
+fn screen_space_curvature(hits: &[Option<AovHit>], width: usize, height: usize, (x, y): (usize, usize)) -> f64 {
+    let center = match hits[y * width + x] {
+        Some(hit) => hit,
+        None => return 0.0,
+    };
+
+    // Returns the neighbor at the given offset if it is part of the same surface as the center
+    let neighbor = |dx: isize, dy: isize| {
+        let (nx, ny) = (x as isize + dx, y as isize + dy);
+        if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+            return None;
+        }
+
+        hits[ny as usize * width + nx as usize]
+            .filter(|hit| (hit.depth - center.depth).abs() <= MAX_SURFACE_DEPTH_RATIO * center.depth)
+    };
+
+    // The change in the normal along the direction between two hits, per pixel between them
+    let derivative = |before: Option<AovHit>, after: Option<AovHit>| {
+        let (before, after, pixels) = match (before, after) {
+            (Some(before), Some(after)) => (before, after, 2.0),
+            (Some(before), None) => (before, center, 1.0),
+            (None, Some(after)) => (center, after, 1.0),
+            (None, None) => return None,
+        };
+
+        let position_change = after.position - before.position;
+        let distance = position_change.magnitude();
+        if distance <= EPSILON {
+            return None;
+        }
+        Some((after.normal - before.normal).dot(position_change) / distance / pixels)
+    };
+
+    let derivatives: Vec<_> = [derivative(neighbor(-1, 0), neighbor(1, 0)), derivative(neighbor(0, -1), neighbor(0, 1))]
+        .iter().flatten().copied().collect();
+    if derivatives.is_empty() {
+        0.0
+    } else {
+        derivatives.iter().sum::<f64>() / derivatives.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::math::{Radians, Rgb};
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::material::Material;
+    use crate::primitive::{Sphere, Plane};
+
+    #[test]
+    fn passes_of_sphere_on_floor() {
+        let mat = Arc::new(Material::default());
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(Geometry::new(Sphere, mat.clone()))),
+                Arc::new(SceneNode::from(Geometry::new(Plane, mat.clone())).scaled(10.0).translated((0.0, -1.0, 0.0))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let camera = CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 5.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        };
+        let passes = AovPasses::new(&scene, camera, (32, 32));
+
+        // The center of the image sees the front of the sphere
+        let center = (16, 16);
+        let position = passes.position(center).unwrap();
+        assert!((position - Vec3::new(0.0, 0.0, 1.0)).magnitude() < 0.1, "{:?}", position);
+        assert!(passes.normal(center).unwrap().dot(Vec3::unit_z()) > 0.99);
+        assert!((passes.depth(center).unwrap() - 4.0).abs() < 0.1);
+        // The corners of the image see nothing
+        assert_eq!(passes.position((0, 0)), None);
+
+        // The sphere is convex and the floor is flat
+        assert!(passes.curvature(center) > 0.0);
+        assert!(passes.curvature((16, 31)).abs() < 1e-6);
+        assert_eq!(passes.curvature((0, 0)), 0.0);
+
+        let curvature = passes.image(Aov::Curvature);
+        assert!(curvature.get_pixel(16, 16).data[0] > 128);
+        assert_eq!(curvature.get_pixel(0, 0).data, [128, 128, 128]);
+        let normals = passes.image(Aov::Normal);
+        assert_eq!(normals.get_pixel(16, 16).data[2], 255);
+        assert_eq!(normals.get_pixel(0, 0).data, [0, 0, 0]);
+    }
+
+    #[test]
+    fn aov_names_are_parsed() {
+        assert_eq!("position".parse(), Ok(Aov::Position));
+        assert_eq!("Curvature".parse(), Ok(Aov::Curvature));
+        assert!("albedo".parse::<Aov>().is_err());
+    }
+}
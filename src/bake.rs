@@ -0,0 +1,478 @@
+//! Offline baking of static lighting into textures
+
+use rayon::prelude::*;
+
+use crate::math::{EPSILON, Mat4, Vec3, Vec3Ext, Uv, Rgb, sample_uniform_sphere, sample_seed, seed_sample_rng, sample_rng, splitmix64};
+use crate::scene::Scene;
+use crate::ray::{Ray, RayCast};
+use crate::primitive::{MeshData, Shading, Triangle};
+use crate::texture::{Texture, TextureSource, UvChannel};
+
+/// A texture storing the direct lighting (in linear space) that reaches each point of a surface
+///
+/// Lightmaps are sampled with nearest neighbour filtering and texture coordinates outside of 0.0
+/// to 1.0 are clamped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lightmap {
+    width: usize,
+    height: usize,
+    /// The lighting of each texel, row by row
+    texels: Vec<Rgb>,
+}
+
+impl Lightmap {
+    /// Returns the width of the lightmap in texels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the height of the lightmap in texels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Converts this lightmap into a texture that can be used in a material
+    pub fn into_texture(self) -> Texture {
+        Texture::from(move |uv| self.at(uv))
+    }
+}
+
+impl TextureSource for Lightmap {
+    fn at(&self, uv: Uv) -> Rgb {
+        let x = ((uv.u * self.width as f64) as isize).clamp(0, self.width as isize - 1) as usize;
+        let y = ((uv.v * self.height as f64) as isize).clamp(0, self.height as isize - 1) as usize;
+        self.texels[y * self.width + x]
+    }
+}
+
+/// The light arriving at a single point from each of the 6 axis directions
+///
+/// The light arriving at a surface with any other normal is a blend of the 3 nearest axis
+/// directions. This is the "ambient cube" from Valve's Source engine: cheap to store and sample,
+/// but only able to represent low-frequency lighting.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct AmbientCube {
+    /// The cosine-weighted average of the light arriving from the hemisphere around +x, -x, +y,
+    /// -y, +z, and -z respectively
+    faces: [Rgb; 6],
+}
+
+impl AmbientCube {
+    /// The unit vector of each face
+    const AXES: [Vec3; 6] = [
+        Vec3 {x: 1.0, y: 0.0, z: 0.0},
+        Vec3 {x: -1.0, y: 0.0, z: 0.0},
+        Vec3 {x: 0.0, y: 1.0, z: 0.0},
+        Vec3 {x: 0.0, y: -1.0, z: 0.0},
+        Vec3 {x: 0.0, y: 0.0, z: 1.0},
+        Vec3 {x: 0.0, y: 0.0, z: -1.0},
+    ];
+
+    /// Computes an ambient cube from the light arriving from each of the given directions
+    fn from_samples<I: IntoIterator<Item=(Vec3, Rgb)>>(samples: I) -> Self {
+        let mut totals = [Rgb::black(); 6];
+        let mut weights = [0.0; 6];
+        for (dir, light) in samples {
+            for (face, axis) in Self::AXES.iter().enumerate() {
+                let cos_theta = dir.dot(*axis);
+                if cos_theta > 0.0 {
+                    totals[face] += light * cos_theta;
+                    weights[face] += cos_theta;
+                }
+            }
+        }
+
+        let mut faces = [Rgb::black(); 6];
+        for (face, (&total, &weight)) in faces.iter_mut().zip(totals.iter().zip(weights.iter())) {
+            if weight > 0.0 {
+                *face = total / weight;
+            }
+        }
+        Self {faces}
+    }
+
+    /// Returns the light arriving at a surface with the given unit normal
+    fn at(&self, normal: Vec3) -> Rgb {
+        // The squared components of a unit vector add up to 1.0
+        let face = |positive: f64, face: usize| if positive >= 0.0 { face } else { face + 1 };
+        self.faces[face(normal.x, 0)] * (normal.x * normal.x)
+            + self.faces[face(normal.y, 2)] * (normal.y * normal.y)
+            + self.faces[face(normal.z, 4)] * (normal.z * normal.z)
+    }
+
+    /// Linearly interpolates between two ambient cubes
+    fn lerp(&self, other: &Self, t: f64) -> Self {
+        let mut faces = self.faces;
+        for (face, &other) in faces.iter_mut().zip(other.faces.iter()) {
+            *face = Rgb::lerp(*face, other, t);
+        }
+        Self {faces}
+    }
+}
+
+/// A grid of probes storing the low-frequency lighting throughout a region of a scene
+///
+/// Created with `bake_radiance_probes`. Use it as the `radiance_probes` of a material to shade
+/// deep bounces quickly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RadianceProbes {
+    /// The corners of the region covered by the grid
+    min: Vec3,
+    max: Vec3,
+    /// The number of probes along x, y, and z
+    resolution: (usize, usize, usize),
+    /// The lighting at each probe, with x changing fastest, then y, then z
+    probes: Vec<AmbientCube>,
+}
+
+impl RadianceProbes {
+    /// Returns the number of probes along x, y, and z
+    pub fn resolution(&self) -> (usize, usize, usize) {
+        self.resolution
+    }
+
+    /// Returns the light arriving at a surface at the given point with the given normal,
+    /// interpolated between the nearest probes
+    ///
+    /// Points outside of the grid use the lighting of the nearest point on its boundary.
+    pub fn irradiance_at(&self, point: Vec3, normal: Vec3) -> Rgb {
+        let (nx, ny, nz) = self.resolution;
+        let (x0, x1, tx) = grid_cell(point.x, self.min.x, self.max.x, nx);
+        let (y0, y1, ty) = grid_cell(point.y, self.min.y, self.max.y, ny);
+        let (z0, z1, tz) = grid_cell(point.z, self.min.z, self.max.z, nz);
+
+        let probe = |x: usize, y: usize, z: usize| &self.probes[(z * ny + y) * nx + x];
+        let along_x = |y: usize, z: usize| probe(x0, y, z).lerp(probe(x1, y, z), tx);
+        let along_y = |z: usize| along_x(y0, z).lerp(&along_x(y1, z), ty);
+        let cube = along_y(z0).lerp(&along_y(z1), tz);
+
+        cube.at(normal.normalized())
+    }
+}
+
+/// Returns the indexes of the probes on either side of the given coordinate along one axis of a
+/// grid, along with how far the coordinate is between them (0.0 to 1.0)
+fn grid_cell(value: f64, min: f64, max: f64, probes: usize) -> (usize, usize, f64) {
+    if probes < 2 || max - min <= 0.0 {
+        return (0, 0, 0.0);
+    }
+
+    let coord = ((value - min) / (max - min)).clamp(0.0, 1.0) * (probes - 1) as f64;
+    let start = (coord.floor() as usize).min(probes - 2);
+    (start, start + 1, coord - start as f64)
+}
+
+/// Returns the position of the probe with the given index along one axis of a grid
+fn grid_position(index: usize, min: f64, max: f64, probes: usize) -> f64 {
+    if probes < 2 {
+        (min + max) / 2.0
+    } else {
+        min + (max - min) * index as f64 / (probes - 1) as f64
+    }
+}
+
+/// Returns the barycentric coordinates of p in the 2D triangle (a, b, c), or None if the triangle
+/// is degenerate
+fn barycentric(p: Uv, a: Uv, b: Uv, c: Uv) -> Option<(f64, f64, f64)> {
+    let v0 = b - a;
+    let v1 = c - a;
+    let v2 = p - a;
+
+    let det = v0.u * v1.v - v1.u * v0.v;
+    if det.abs() < EPSILON * EPSILON {
+        return None;
+    }
+
+    let beta = (v2.u * v1.v - v1.u * v2.v) / det;
+    let gamma = (v0.u * v2.v - v2.u * v0.v) / det;
+    Some((1.0 - beta - gamma, beta, gamma))
+}
+
+/// Mixed into the global seed when baking so that texels and probes do not reuse the random numbers
+/// of the pixels with the same coordinates
+const BAKE_SEED_SALT: u64 = 0x6261_6b65;
+
+/// Returns the seed that the seeds of baked texels and probes are derived from (with
+/// `sample_seed`) for the given global seed
+fn bake_seed(global_seed: u64) -> u64 {
+    splitmix64(global_seed ^ BAKE_SEED_SALT)
+}
+
+/// Computes the direct lighting (diffuse only) at the given point using the given number of
+/// shadow rays per light
+fn direct_lighting<R: RayCast>(scene: &Scene<R>, point: Vec3, normal: Vec3, samples: usize) -> Rgb {
+    let mut rng = sample_rng();
+
+    let mut total = Rgb::black();
+    for light in &scene.lights {
+        // Point lights always produce the same shadow ray, so one sample is enough
+        let light_samples = if light.area.is_empty() { 1 } else { samples.max(1) };
+
+        let mut light_total = Rgb::black();
+        for _ in 0..light_samples {
+            let light_pos = match light.sample_position_towards(point, &mut rng) {
+                Some(light_pos) => light_pos,
+                None => continue,
+            };
+
+            let hit_to_light = light_pos - point;
+            let light_dist = hit_to_light.magnitude();
+            let light_dir = hit_to_light / light_dist;
+
+            let normal_light = normal.dot(light_dir);
+            if normal_light <= 0.0 {
+                continue;
+            }
+
+            let shadow_ray = Ray::new(point, light_dir);
+            let mut shadow_t_range = shadow_ray.t_range();
+            shadow_t_range.end = light_dist;
+            if scene.root.ray_cast(&shadow_ray, &mut shadow_t_range).is_none() {
+                let attenuation = light.falloff.at_distance(light_dist);
+                let light_color = light.color_at(light_pos, -light_dir) * light.caustics_at(light_pos, point);
+                light_total += light_color * normal_light / attenuation;
+            }
+        }
+
+        total += light_total / light_samples as f64;
+    }
+
+    total
+}
+
+/// Bakes the direct lighting from every light in the scene onto a mesh, producing a lightmap
+///
+/// The mesh must already be part of the scene (so that it can shadow itself) with the given
+/// transform (model space to world space). Texels are mapped onto the mesh using the texture
+/// coordinates of the given UV channel. These coordinates should not overlap, otherwise texels
+/// will be shared between multiple parts of the mesh. Texels that are not covered by any triangle
+/// are left black.
+///
+/// Area lights are sampled with the given number of shadow rays per texel. If a seed is given, the
+/// random numbers of each texel are seeded from it so the same lightmap is baked every time.
+///
+/// Use the result as the `lightmap` of a material to get very fast (but static) shading for
+/// previews.
+pub fn bake_lightmap<R: RayCast + Sync>(
+    scene: &Scene<R>,
+    mesh: &MeshData,
+    trans: Mat4,
+    channel: UvChannel,
+    (width, height): (usize, usize),
+    samples: usize,
+    seed: Option<u64>,
+) -> Lightmap {
+    let normal_trans = trans.inverted().transposed();
+
+    // Find the point and normal on the mesh covered by each texel
+    let mut surface: Vec<Option<(Vec3, Vec3)>> = vec![None; width * height];
+    let shading = if mesh.has_normals() { Shading::Smooth } else { Shading::Flat };
+    for tri in mesh.triangles(shading) {
+        let Triangle {a, b, c, normals, ..} = tri;
+        let tex_coords = match channel {
+            UvChannel::Primary => tri.tex_coords,
+            UvChannel::Secondary => tri.secondary_tex_coords,
+        };
+        let (uv_a, uv_b, uv_c) = match tex_coords {
+            // Texture coordinates are flipped vertically when sampling textures (see Triangle)
+            Some((uv_a, uv_b, uv_c)) => {
+                let flip = |uv: Uv| Uv {u: uv.u, v: 1.0 - uv.v};
+                (flip(uv_a), flip(uv_b), flip(uv_c))
+            },
+            None => panic!("Baking a lightmap requires texture coordinates in the {:?} UV channel", channel),
+        };
+
+        // Only check the texels within the bounding box of the triangle
+        let min = Uv::partial_min(uv_a, Uv::partial_min(uv_b, uv_c));
+        let max = Uv::partial_max(uv_a, Uv::partial_max(uv_b, uv_c));
+        let texel_range = |min: f64, max: f64, size: usize| {
+            let start = ((min * size as f64).floor().max(0.0) as usize).min(size);
+            let end = ((max * size as f64).ceil().max(0.0) as usize).min(size);
+            start..end
+        };
+
+        for y in texel_range(min.v, max.v, height) {
+            for x in texel_range(min.u, max.u, width) {
+                let texel_center = Uv {
+                    u: (x as f64 + 0.5) / width as f64,
+                    v: (y as f64 + 0.5) / height as f64,
+                };
+                let (alpha, beta, gamma) = match barycentric(texel_center, uv_a, uv_b, uv_c) {
+                    Some(coords) => coords,
+                    None => continue,
+                };
+                if alpha < 0.0 || beta < 0.0 || gamma < 0.0 {
+                    continue;
+                }
+
+                let point = a * alpha + b * beta + c * gamma;
+                let normal = match normals {
+                    Some((na, nb, nc)) => na * alpha + nb * beta + nc * gamma,
+                    None => (b - a).cross(c - a),
+                };
+
+                surface[y * width + x] = Some((
+                    point.transformed_point(trans),
+                    normal.transformed_direction(normal_trans).normalized(),
+                ));
+            }
+        }
+    }
+
+    let seed = seed.map(bake_seed);
+    let texels = surface.into_par_iter().enumerate().map(|(index, texel)| match texel {
+        Some((point, normal)) => {
+            seed_sample_rng(seed.map(|seed| sample_seed(seed, (index % width, index / width), 0)));
+            direct_lighting(scene, point, normal, samples)
+        },
+        None => Rgb::black(),
+    }).collect();
+
+    Lightmap {width, height, texels}
+}
+
+/// Bakes a grid of radiance probes spanning the given region (from its min to its max corner)
+/// with the given number of probes along x, y, and z
+///
+/// Each probe traces the given number of rays in random directions and records their colors.
+/// Rays that escape the scene see the given background color. The rays are traced with the
+/// materials currently in the scene, so the probes should be baked before they are given to any
+/// material. If a seed is given, the random numbers of each probe are seeded from it so the same
+/// probes are baked every time.
+pub fn bake_radiance_probes<R: RayCast + Sync>(
+    scene: &Scene<R>,
+    (min, max): (Vec3, Vec3),
+    (nx, ny, nz): (usize, usize, usize),
+    background: Rgb,
+    samples: usize,
+    seed: Option<u64>,
+) -> RadianceProbes {
+    assert!(nx > 0 && ny > 0 && nz > 0, "The probe grid must have at least one probe along each axis");
+
+    let seed = seed.map(bake_seed);
+
+    let probes = (0..nx * ny * nz).into_par_iter().map(|index| {
+        let (x, y, z) = (index % nx, (index / nx) % ny, index / (nx * ny));
+        let position = Vec3 {
+            x: grid_position(x, min.x, max.x, nx),
+            y: grid_position(y, min.y, max.y, ny),
+            z: grid_position(z, min.z, max.z, nz),
+        };
+
+        seed_sample_rng(seed.map(|seed| sample_seed(seed, (index, 0), 0)));
+        let mut rng = sample_rng();
+        AmbientCube::from_samples((0..samples.max(1)).map(|_| {
+            let dir = sample_uniform_sphere(&mut rng);
+            (dir, Ray::new(position, dir).color(scene, background, 0))
+        }))
+    }).collect();
+
+    RadianceProbes {min, max, resolution: (nx, ny, nz), probes}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::material::Material;
+    use crate::light::Light;
+    use crate::primitive::{Mesh, Cube};
+
+    #[test]
+    fn occluded_texels_are_dark() {
+        // A 2x2 quad on the ground with a cube hovering over half of it
+        let quad = Arc::new(MeshData::new(
+            vec![
+                Vec3::new(-1.0, 0.0, 1.0),
+                Vec3::new(1.0, 0.0, 1.0),
+                Vec3::new(1.0, 0.0, -1.0),
+                Vec3::new(-1.0, 0.0, -1.0),
+            ],
+            vec![(0, 1, 2), (0, 2, 3)],
+            Vec::new(),
+            vec![Uv::new(0.0, 0.0), Uv::new(1.0, 0.0), Uv::new(1.0, 1.0), Uv::new(0.0, 1.0)],
+        ));
+        let mat = Arc::new(Material::default());
+
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(Geometry::new(Mesh::new(quad.clone(), Shading::Flat), mat.clone()))),
+                // Covers x in 0.1..1.1, directly under the light
+                Arc::new(SceneNode::from(Geometry::new(Cube, mat.clone())).translated((0.6, 1.0, 0.0))),
+            ])),
+            lights: vec![Light {
+                position: Vec3::new(0.0, 10.0, 0.0),
+                color: Rgb::white(),
+                ..Light::default()
+            }],
+            ambient: Rgb::black(),
+        };
+
+        let lightmap = bake_lightmap(&scene, &quad, Mat4::identity(), UvChannel::Primary, (8, 8), 1, None);
+        // The left side of the quad (low u) is lit and the right side (high u) is shadowed
+        assert!(lightmap.at(Uv::new(0.1, 0.5)).r > 0.9);
+        assert_eq!(lightmap.at(Uv::new(0.9, 0.5)), Rgb::black());
+    }
+
+    #[test]
+    fn geometry_behind_the_light_does_not_shadow() {
+        // A large slab above the light, on the other side of it from the point being lit
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Cube, Arc::new(Material::default())))
+                .scaled((100.0, 1.0, 100.0)).translated((0.0, 5.0, 0.0))),
+            lights: vec![Light {
+                position: Vec3::new(0.0, 2.0, 0.0),
+                color: Rgb::white(),
+                ..Light::default()
+            }],
+            ambient: Rgb::black(),
+        };
+
+        assert_eq!(direct_lighting(&scene, Vec3::zero(), Vec3::up(), 1), Rgb::white());
+    }
+
+    #[test]
+    fn radiance_probes_see_lit_floor() {
+        let mat = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Cube, mat))
+                .scaled((100.0, 1.0, 100.0)).translated((0.0, -1.0, 0.0))),
+            lights: vec![Light {
+                position: Vec3::new(0.0, 10.0, 0.0),
+                color: Rgb::white(),
+                ..Light::default()
+            }],
+            ambient: Rgb::black(),
+        };
+
+        let bounds = (Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 1.0, 1.0));
+        let probes = bake_radiance_probes(&scene, bounds, (2, 2, 2), Rgb::black(), 256, None);
+        assert_eq!(probes.resolution(), (2, 2, 2));
+
+        // Looking down sees the lit floor and looking up sees the black background
+        for &point in &[Vec3::zero(), Vec3::new(0.5, 0.5, 0.5), Vec3::new(5.0, -5.0, 5.0)] {
+            let below = probes.irradiance_at(point, Vec3::down());
+            assert!(below.r > 0.1, "{:?}", below);
+            assert_eq!(probes.irradiance_at(point, Vec3::up()), Rgb::black());
+        }
+
+        // Seeded probes are the same every time they are baked
+        let seeded = || bake_radiance_probes(&scene, bounds, (2, 2, 2), Rgb::black(), 16, Some(1045));
+        assert_eq!(seeded(), seeded());
+        assert_ne!(seeded(), bake_radiance_probes(&scene, bounds, (2, 2, 2), Rgb::black(), 16, Some(1046)));
+
+        // Surfaces past the probe depth are shaded with the probes
+        let probes = Arc::new(probes);
+        let probe_root = scene.root.with_radiance_probes(&probes, 0);
+        let mat = &probe_root.geometry().unwrap().material;
+        assert_eq!(mat.radiance_probes.as_ref(), Some(&probes));
+        let shade = |depth| mat.hit_color(&scene, Rgb::black(), Vec3::up(), Vec3::zero(), Vec3::zero(),
+            1.0, Vec3::down(), None, None, None, &Default::default(), 1.0, depth);
+        assert_eq!(shade(1), probes.irradiance_at(Vec3::zero(), Vec3::down()));
+        // The surface faces away from the light, so it is black when shaded normally
+        assert_eq!(shade(0), Rgb::black());
+    }
+}
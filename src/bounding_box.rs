@@ -15,7 +15,7 @@ pub trait Bounds {
 
 impl<T: Bounds> Bounds for Arc<T> {
     fn bounds(&self) -> BoundingBox {
-        (&*self as &T).bounds()
+        (**self).bounds()
     }
 }
 
@@ -91,6 +91,11 @@ impl BoundingBox {
         self.max
     }
 
+    /// Returns the smallest bounding box that contains both this bounding box and the other one
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox::new(Vec3::partial_min(self.min, other.min), Vec3::partial_max(self.max, other.max))
+    }
+
     /// Returns the maximum distance between any two points within the bounding box
     pub fn extent(&self) -> f64 {
         //HACK: Using magnitude_squared instead of magnitude or else the k-d tree will miss points.
@@ -98,6 +103,12 @@ impl BoundingBox {
         (self.max - self.min).magnitude_squared()
     }
 
+    /// Returns the total area of the six faces of the bounding box
+    pub fn surface_area(&self) -> f64 {
+        let size = self.max - self.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
     /// Returns the ray parameter value for which this bounding box will be hit by the given ray
     ///
     /// If the ray at t_range.start is inside the bounding box, t_range.start will be returned.
@@ -156,6 +167,7 @@ impl RayHit for BoundingBox {
         Cube.ray_hit(&local_ray, t_range).map(|mut hit| {
             // Need to transform hit_point and normal back so they render properly
             hit.hit_point = hit.hit_point.transformed_point(self.trans);
+            hit.shadow_origin = hit.shadow_origin.map(|p| p.transformed_point(self.trans));
             hit.normal = hit.normal.transformed_direction(self.normal_trans);
             hit
         })
@@ -1,5 +1,9 @@
-use crate::math::{Vec3, Vec3Ext, Mat4, Radians};
-use crate::ray::Ray;
+use std::sync::Arc;
+
+use crate::math::{Vec3, Vec3Ext, Mat4, Rgb, Radians, Curve, CatmullRom, ArcLength};
+use crate::ray::{Ray, RayCast};
+use crate::scene::{Scene, SceneNode};
+use crate::fog::Fog;
 
 #[derive(Debug, Clone, Copy)]
 pub struct CameraSettings {
@@ -13,6 +17,19 @@ pub struct CameraSettings {
     pub fovy: Radians,
 }
 
+impl CameraSettings {
+    /// Returns the distance from the eye to the nearest surface seen at the given pixel (x, y)
+    /// position of an image with the given size, or None if nothing is seen there
+    ///
+    /// Works like tap-to-focus: pick the pixel showing the subject instead of measuring the
+    /// distance to it by hand. The camera is currently a pinhole camera with no focal distance to
+    /// set, so this only finds the distance. It is still useful for anything that depends on the
+    /// distance to the subject (e.g. the `distance_fade` of materials).
+    pub fn focus_distance_at<R: RayCast>(&self, pixel: (f64, f64), size: (f64, f64), scene: &Scene<R>) -> Option<f64> {
+        Camera::new(*self, size).ray_at(pixel).length(scene)
+    }
+}
+
 #[derive(Debug)]
 pub struct Camera {
     /// The position of the camera in world space
@@ -28,6 +45,12 @@ pub struct Camera {
     width: f64,
     // The height of the target
     height: f64,
+    /// Geometry attached to the camera that blocks primary rays (e.g. a lens hood or matte box)
+    ///
+    /// Stored in view space: the eye is at the origin, looking down the -z axis with +y up.
+    occluder: Option<Arc<SceneNode>>,
+    /// Fog between the camera and the scene, seen by primary rays
+    fog: Option<Fog>,
 }
 
 impl Camera {
@@ -41,11 +64,71 @@ impl Camera {
             aspect_ratio: width / height,
             width,
             height,
+            occluder: None,
+            fog: None,
         }
     }
 
+    /// Attaches the given geometry to the front of the camera so that it blocks the view, returning
+    /// the updated camera
+    ///
+    /// Useful for framing effects like a lens hood, matte box, or vignette. The geometry is given
+    /// in view space, where the eye is at the origin looking down the -z axis with +y up and the
+    /// image plane is 1.0 unit in front of the eye, so it moves with the camera. It is only seen by
+    /// primary rays (it casts no shadows and does not show up in reflections) and it is not lit:
+    /// each pixel that sees it gets the flat diffuse color of its material.
+    pub fn with_occluder(mut self, occluder: Arc<SceneNode>) -> Self {
+        self.occluder = Some(occluder);
+        self
+    }
+
+    /// Fills the space in front of the camera with the given fog, returning the updated camera
+    ///
+    /// Only primary rays pass through the fog (see `Fog`).
+    pub fn with_fog(mut self, fog: Fog) -> Self {
+        self.fog = Some(fog);
+        self
+    }
+
+    /// Returns the fog in front of the camera, if any
+    pub fn fog(&self) -> Option<&Fog> {
+        self.fog.as_ref()
+    }
+
     /// Returns the primary ray at the given pixel (x, y) position
     pub fn ray_at(&self, (x, y): (f64, f64)) -> Ray {
+        // Transform to world coordinates from camera space
+        let pixel_world = self.pixel_view((x, y)).transformed_point(self.view_to_world);
+        // The ray goes from the eye to the pixel_world coordinate
+        let ray_dir = (pixel_world - self.eye).normalized();
+
+        Ray::new(self.eye, ray_dir)
+    }
+
+    /// Returns the pixel (x, y) position that the given point (in world space) appears at, or None
+    /// if the point is not in front of the camera
+    ///
+    /// This is the opposite of `ray_at`: the ray at the returned position passes through the
+    /// point. The position may be outside of the image if the point is outside the field of view.
+    pub fn project(&self, point: Vec3) -> Option<(f64, f64)> {
+        let view_point = point.transformed_point(self.view_to_world.inverted());
+        // The camera looks down the -z axis
+        if view_point.z >= 0.0 {
+            return None;
+        }
+
+        // Scale the point onto the image plane, 1.0 unit in front of the eye
+        let pixel_view_x = view_point.x / -view_point.z;
+        let pixel_view_y = view_point.y / -view_point.z;
+
+        // Undo each step of `pixel_view`
+        let pixel_ndc_x = (pixel_view_x / (self.aspect_ratio * self.fov_factor) + 1.0) / 2.0;
+        let pixel_ndc_y = (1.0 - pixel_view_y / self.fov_factor) / 2.0;
+        Some((pixel_ndc_x * self.width, pixel_ndc_y * self.height))
+    }
+
+    /// Returns the position of the given pixel (x, y) position on the image plane in view space
+    fn pixel_view(&self, (x, y): (f64, f64)) -> Vec3 {
         // NDC = Normalized Device Coordinates
 
         // This function goes through 4 coordinate systems:
@@ -74,12 +157,195 @@ impl Camera {
 
         // Image plane is 1.0 unit ahead of the camera/eye in camera/view space.
         // Using -1.0 because view space is right-handed.
-        let pixel_view = Vec3::new(pixel_view_x, pixel_view_y, -1.0);
-        // Transform to world coordinates from camera space
-        let pixel_world = pixel_view.transformed_point(self.view_to_world);
-        // The ray goes from the eye to the pixel_world coordinate
-        let ray_dir = (pixel_world - self.eye).normalized();
+        Vec3::new(pixel_view_x, pixel_view_y, -1.0)
+    }
 
-        Ray::new(self.eye, ray_dir)
+    /// Returns the color of the occluder attached to the camera at the given pixel (x, y)
+    /// position, or None if there is no occluder or if it does not block that pixel
+    pub fn occluder_color_at(&self, pixel: (f64, f64)) -> Option<Rgb> {
+        let occluder = self.occluder.as_ref()?;
+
+        let ray = Ray::new(Vec3::zero(), self.pixel_view(pixel).normalized());
+        let mut t_range = ray.t_range();
+        occluder.ray_cast(&ray, &mut t_range).map(|(_, mat)| mat.diffuse)
+    }
+}
+
+/// A position of the camera along a fly-through, like a keyframe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Waypoint {
+    /// The position of the camera in world space
+    pub eye: Vec3,
+    /// The position that the camera is looking at in world space
+    pub center: Vec3,
+}
+
+/// A smooth camera path through a series of waypoints, useful for rendering fly-throughs
+///
+/// The camera passes through every waypoint and moves at a constant speed along the path. The
+/// point that the camera looks at is smoothly interpolated between waypoints too, arriving at
+/// the center of each waypoint at the same time as the camera arrives at its eye position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraPath {
+    /// The path of the eye, parameterized by arc length for constant speed
+    eyes: ArcLength<CatmullRom>,
+    /// The path of the look-at position (uses the same parameter as the eye path)
+    centers: CatmullRom,
+    /// The "up" direction of the camera along the entire path
+    up: Vec3,
+    /// The field-of-view angle along the y-axis of the camera along the entire path
+    fovy: Radians,
+}
+
+impl CameraPath {
+    /// The number of samples used to approximate the length of the path
+    const ARC_LENGTH_SAMPLES: usize = 1000;
+
+    /// Creates a camera path through the given waypoints (in order)
+    ///
+    /// Panics if fewer than two waypoints are provided.
+    pub fn new(waypoints: &[Waypoint], up: Vec3, fovy: Radians) -> Self {
+        assert!(waypoints.len() >= 2, "A camera path must have at least two waypoints");
+
+        let eyes = CatmullRom::new(waypoints.iter().map(|waypoint| waypoint.eye).collect());
+        let centers = CatmullRom::new(waypoints.iter().map(|waypoint| waypoint.center).collect());
+
+        Self {
+            eyes: ArcLength::new(eyes, Self::ARC_LENGTH_SAMPLES),
+            centers,
+            up,
+            fovy,
+        }
+    }
+
+    /// Returns the total distance travelled by the camera along the path
+    pub fn length(&self) -> f64 {
+        self.eyes.length()
+    }
+
+    /// Returns the camera at the given fraction (between 0.0 and 1.0) of the way along the path
+    pub fn at(&self, fraction: f64) -> CameraSettings {
+        let t = self.eyes.param_at_distance(fraction * self.length());
+
+        CameraSettings {
+            eye: self.eyes.curve().at(t),
+            center: self.centers.at(t),
+            up: self.up,
+            fovy: self.fovy,
+        }
+    }
+
+    /// Returns the camera for each frame of a fly-through with the given number of frames
+    ///
+    /// The first frame is at the start of the path and the last frame is at the end of the path.
+    pub fn frames(&self, frames: usize) -> Vec<CameraSettings> {
+        match frames {
+            0 => Vec::new(),
+            1 => vec![self.at(0.0)],
+            _ => (0..frames).map(|i| self.at(i as f64 / (frames - 1) as f64)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::scene::{HierScene, Geometry};
+    use crate::material::Material;
+    use crate::primitive::{Cube, Sphere};
+
+    #[test]
+    fn camera_path_moves_at_constant_speed_through_waypoints() {
+        let waypoints = [
+            Waypoint {eye: Vec3::new(0.0, 1.0, 0.0), center: Vec3::new(0.0, 1.0, -1.0)},
+            // Waypoints are unevenly spaced, but the speed should not change
+            Waypoint {eye: Vec3::new(0.0, 1.0, -1.0), center: Vec3::new(1.0, 1.0, -1.0)},
+            Waypoint {eye: Vec3::new(8.0, 1.0, -1.0), center: Vec3::new(8.0, 1.0, -2.0)},
+        ];
+        let path = CameraPath::new(&waypoints, Vec3::up(), Radians::from_degrees(45.0));
+
+        let frames = path.frames(50);
+        assert_eq!(frames.len(), 50);
+        assert!((frames[0].eye - waypoints[0].eye).magnitude() < 1e-9);
+        assert!((frames[0].center - waypoints[0].center).magnitude() < 1e-9);
+        assert!((frames[49].eye - waypoints[2].eye).magnitude() < 1e-9);
+        assert!((frames[49].center - waypoints[2].center).magnitude() < 1e-9);
+
+        let step = path.length() / 49.0;
+        for pair in frames.windows(2) {
+            let dist = (pair[1].eye - pair[0].eye).magnitude();
+            // Consecutive frames are chords of the curve, so they are slightly shorter than the
+            // distance along the curve
+            assert!((dist - step).abs() < step * 0.05, "{} != {}", dist, step);
+        }
+    }
+
+    #[test]
+    fn occluder_blocks_the_edges_of_the_view() {
+        let settings = CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 10.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(90.0),
+        };
+        let camera = Camera::new(settings, (100.0, 100.0));
+        assert_eq!(camera.occluder_color_at((5.0, 50.0)), None);
+
+        // A flap covering the left side of the view
+        let hood_color = Rgb {r: 0.1, g: 0.1, b: 0.1};
+        let hood = Arc::new(SceneNode::from(Geometry::new(Cube, Arc::new(Material {
+            diffuse: hood_color,
+            ..Material::default()
+        }))).translated((-1.0, 0.0, -1.0)));
+        let camera = camera.with_occluder(hood);
+
+        // The center and right of the view are clear and the left edge is blocked
+        assert_eq!(camera.occluder_color_at((50.0, 50.0)), None);
+        assert_eq!(camera.occluder_color_at((95.0, 50.0)), None);
+        assert_eq!(camera.occluder_color_at((5.0, 50.0)), Some(hood_color));
+        assert_eq!(camera.occluder_color_at((5.0, 95.0)), Some(hood_color));
+        // The ray through the pixel is unchanged
+        assert_eq!(camera.ray_at((5.0, 50.0)).origin(), settings.eye);
+    }
+
+    #[test]
+    fn projection_is_the_inverse_of_primary_rays() {
+        let camera = Camera::new(CameraSettings {
+            eye: Vec3::new(1.0, 2.0, 5.0),
+            center: Vec3::new(0.0, 0.5, -1.0),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(60.0),
+        }, (320.0, 200.0));
+
+        for &pixel in &[(0.0, 0.0), (160.0, 100.0), (300.5, 12.25), (-40.0, 250.0)] {
+            let point = camera.ray_at(pixel).at(7.5);
+            let (x, y) = camera.project(point).unwrap();
+            assert!((x - pixel.0).abs() < 1e-9 && (y - pixel.1).abs() < 1e-9, "{:?} != {:?}", (x, y), pixel);
+        }
+
+        // Points behind the camera are not on screen
+        assert_eq!(camera.project(Vec3::new(1.0, 2.0, 10.0)), None);
+    }
+
+    #[test]
+    fn focus_distance_is_found_by_probe_ray() {
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, Arc::new(Material::default())))
+                .translated((0.0, 0.0, -2.0))),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let settings = CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 3.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(60.0),
+        };
+
+        // The front of the sphere is 4 units from the eye
+        let distance = settings.focus_distance_at((50.0, 50.0), (100.0, 100.0), &scene).unwrap();
+        assert!((distance - 4.0).abs() < 1e-6, "{}", distance);
+        assert_eq!(settings.focus_distance_at((0.0, 0.0), (100.0, 100.0), &scene), None);
     }
 }
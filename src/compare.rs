@@ -0,0 +1,242 @@
+//! Helpers for comparing two renders of the same scene (A/B testing)
+//!
+//! Useful for checking that an optimization (e.g. the kdtree feature) or a new sampling strategy
+//! produces the same image as before, and for measuring how much faster it is.
+
+use std::fmt;
+use std::path::Path;
+use std::time::{Instant, Duration};
+
+use crate::math::Rgb;
+use crate::render::{Image, ImageMode};
+
+/// How the two renders are combined into the output image of a comparison
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComparisonLayout {
+    /// Render A on the left and render B on the right
+    SideBySide,
+    /// The absolute difference between the renders in each color channel, scaled so that even
+    /// small differences are visible
+    Difference,
+}
+
+/// The amount that differences are scaled by in `ComparisonLayout::Difference`
+const DIFFERENCE_SCALE: f64 = 10.0;
+
+/// How much two images differ (see `Image::difference`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageDifference {
+    /// The root mean square error between the HDR colors of the two images (over every channel
+    /// of every pixel)
+    pub rmse: f64,
+    /// The largest difference in any channel of any pixel
+    pub max_error: f64,
+    /// The peak signal-to-noise ratio (in decibels) of the colors of the two images after they are
+    /// clamped to the range that can be saved (0.0 to 1.0)
+    ///
+    /// Higher is more similar. Infinite if the clamped colors are identical.
+    pub psnr: f64,
+}
+
+impl fmt::Display for ImageDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RMSE:      {:.6}", self.rmse)?;
+        writeln!(f, "Max Error: {:.6}", self.max_error)?;
+        write!(f, "PSNR:      {:.2} dB", self.psnr)
+    }
+}
+
+impl Image {
+    /// Measures how much this image differs from the given image
+    ///
+    /// Useful for checking slices rendered separately (e.g. on different machines) against a
+    /// reference render. Panics if the images are not the same size.
+    pub fn difference(&self, other: &Image) -> ImageDifference {
+        assert_eq!((self.width(), self.height()), (other.width(), other.height()),
+            "Only images with the same dimensions can be compared");
+
+        let (rmse, max_error) = difference_stats(self.hdr_pixels(), other.hdr_pixels());
+
+        let clamped = |colors: &[Rgb]| colors.iter().map(|color| color.map(|c| c.clamp(0.0, 1.0))).collect::<Vec<_>>();
+        let (clamped_rmse, _) = difference_stats(&clamped(self.hdr_pixels()), &clamped(other.hdr_pixels()));
+        // The peak value of the clamped colors is 1.0
+        let psnr = -20.0 * clamped_rmse.log10();
+
+        ImageDifference {rmse, max_error, psnr}
+    }
+
+    /// Returns an image (saved at the given path) of the absolute difference between this image
+    /// and the given image in each color channel, scaled so that even small differences are
+    /// visible
+    ///
+    /// Panics if the images are not the same size.
+    pub fn difference_image<P: AsRef<Path>>(&self, other: &Image, path: P) -> image::ImageResult<Image> {
+        assert_eq!((self.width(), self.height()), (other.width(), other.height()),
+            "Only images with the same dimensions can be compared");
+
+        let mut output = Image::with_mode(path, self.width(), self.height(), ImageMode::Overwrite)?;
+        let colors: Vec<_> = self.hdr_pixels().iter().zip(other.hdr_pixels())
+            .map(|(&a, &b)| (a - b).map(f64::abs) * DIFFERENCE_SCALE)
+            .collect();
+        output.set_hdr_pixels(&colors);
+        Ok(output)
+    }
+}
+
+/// Statistics about how two renders differ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    /// The root mean square error between the HDR colors of the two renders (over every channel
+    /// of every pixel)
+    pub rmse: f64,
+    /// The largest difference in any channel of any pixel
+    pub max_error: f64,
+    /// The time taken by render A
+    pub time_a: Duration,
+    /// The time taken by render B
+    pub time_b: Duration,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RMSE:      {:.6}", self.rmse)?;
+        writeln!(f, "Max Error: {:.6}", self.max_error)?;
+        writeln!(f, "Time A:    {:.3}s", self.time_a.as_secs_f64())?;
+        write!(f, "Time B:    {:.3}s", self.time_b.as_secs_f64())
+    }
+}
+
+/// Renders two images of the given size with the given functions and saves a comparison of them
+/// to the given path
+///
+/// Each function should render the entire image it is given (e.g. with `Image::render`). They
+/// are run one after the other so that their times can be compared. Since the settings of the
+/// renderer are controlled by environment variables, each function can set the variables it
+/// needs before rendering (remember to remove them afterwards).
+pub fn render_ab<P, A, B>(
+    path: P,
+    (width, height): (usize, usize),
+    layout: ComparisonLayout,
+    render_a: A,
+    render_b: B,
+) -> image::ImageResult<Comparison>
+    where P: AsRef<Path>,
+          A: FnOnce(&mut Image),
+          B: FnOnce(&mut Image),
+{
+    let path = path.as_ref();
+
+    let (image_a, time_a) = timed_render(path, (width, height), render_a)?;
+    let (image_b, time_b) = timed_render(path, (width, height), render_b)?;
+
+    let (rmse, max_error) = difference_stats(image_a.hdr_pixels(), image_b.hdr_pixels());
+
+    let output = match layout {
+        ComparisonLayout::SideBySide => {
+            let mut output = Image::new(path, width * 2, height)?;
+            let colors: Vec<_> = image_a.hdr_pixels().chunks(width)
+                .zip(image_b.hdr_pixels().chunks(width))
+                .flat_map(|(row_a, row_b)| row_a.iter().chain(row_b).copied())
+                .collect();
+            output.set_hdr_pixels(&colors);
+            output
+        },
+        ComparisonLayout::Difference => image_a.difference_image(&image_b, path)?,
+    };
+    output.save()?;
+
+    Ok(Comparison {rmse, max_error, time_a, time_b})
+}
+
+/// Renders a blank image of the given size with the given function and returns the image and the
+/// time it took to render
+///
+/// The image is not saved, so the path is only used to create the image. A blank image is used so
+/// that no pixels are reused from an existing image at that path.
+fn timed_render<F: FnOnce(&mut Image)>(
+    path: &Path,
+    (width, height): (usize, usize),
+    render: F,
+) -> image::ImageResult<(Image, Duration)> {
+    let mut image = Image::new(path, width, height)?;
+    image.set_hdr_pixels(&vec![Rgb::black(); width * height]);
+
+    let start = Instant::now();
+    render(&mut image);
+    Ok((image, start.elapsed()))
+}
+
+/// Returns the root mean square error and the maximum error between two lists of colors
+///
+/// Panics if the lists are not the same length.
+pub fn difference_stats(a: &[Rgb], b: &[Rgb]) -> (f64, f64) {
+    assert_eq!(a.len(), b.len(), "Only images with the same number of pixels can be compared");
+
+    let (total_squared_error, max_error) = a.iter().zip(b).fold((0.0, 0.0f64), |(total, max), (&a, &b)| {
+        let error = (a - b).map(f64::abs);
+        (total + error.map(|e| e * e).sum(), max.max(error.reduce_partial_max()))
+    });
+
+    // Three channels per pixel
+    let samples = (a.len() * 3).max(1) as f64;
+    ((total_squared_error / samples).sqrt(), max_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn compares_renders_side_by_side() {
+        let path = env::temp_dir().join(format!("portrayer-compare-{}.png", std::process::id()));
+        let fill = |color: Rgb| move |image: &mut Image| {
+            let colors = vec![color; image.width() * image.height()];
+            image.set_hdr_pixels(&colors);
+        };
+
+        let grey = Rgb {r: 0.5, g: 0.5, b: 0.5};
+        let comparison = render_ab(&path, (4, 3), ComparisonLayout::SideBySide,
+            fill(grey), fill(Rgb {r: 0.5, g: 0.5, b: 0.8})).unwrap();
+        assert!((comparison.rmse - (0.09f64 / 3.0).sqrt()).abs() < 1e-12);
+        assert!((comparison.max_error - 0.3).abs() < 1e-12);
+
+        let output = image::open(&path).unwrap().to_rgb();
+        assert_eq!(output.dimensions(), (8, 3));
+        assert_ne!(output.get_pixel(0, 1), output.get_pixel(7, 1));
+
+        // Identical renders have no error
+        let comparison = render_ab(&path, (4, 3), ComparisonLayout::Difference, fill(grey), fill(grey)).unwrap();
+        assert_eq!((comparison.rmse, comparison.max_error), (0.0, 0.0));
+        let output = image::open(&path).unwrap().to_rgb();
+        assert_eq!(output.dimensions(), (4, 3));
+        assert!(output.pixels().all(|pixel| pixel.data == [0, 0, 0]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn image_difference_and_psnr() {
+        let image = |color: Rgb| {
+            let mut image = Image::with_mode("does-not-exist/difference.png", 4, 2, ImageMode::Overwrite).unwrap();
+            image.set_hdr_pixels(&[color; 8]);
+            image
+        };
+
+        let grey = image(Rgb::from(0.5));
+        assert_eq!(grey.difference(&grey).psnr, f64::INFINITY);
+
+        let difference = grey.difference(&image(Rgb::from(0.6)));
+        assert!((difference.rmse - 0.1).abs() < 1e-12);
+        assert!((difference.psnr - 20.0).abs() < 1e-9);
+
+        // Colors brighter than white are only different before they are clamped
+        let difference = image(Rgb::from(2.0)).difference(&image(Rgb::from(3.0)));
+        assert_eq!(difference.max_error, 1.0);
+        assert_eq!(difference.psnr, f64::INFINITY);
+
+        let output = grey.difference_image(&image(Rgb::from(0.55)), "does-not-exist/diff.png").unwrap();
+        assert!((output.hdr_pixels()[0] - Rgb::from(0.5)).map(f64::abs).reduce_partial_max() < 1e-12);
+    }
+}
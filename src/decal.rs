@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use crate::math::{Mat4, Vec3, Vec3Ext, Uv, Rgb};
+use crate::texture::{Texture, TextureSource};
+
+/// A texture projected onto any surface within a box, like a sticker or a slide projector
+///
+/// Decals allow things like posters, dirt, and signage to be added on top of a material without
+/// having to edit the texture coordinates of the underlying geometry. The decal box is the unit
+/// cube centered at the origin, transformed by the decal's transform. The texture is projected
+/// down the -Y axis of the box, with the top face of the box mapped to uv (0, 0) to (1, 1).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decal {
+    /// The texture to project
+    pub texture: Arc<Texture>,
+    /// If provided, the red channel of this texture is used as the opacity of the decal at each
+    /// point. This allows decals that are not rectangular.
+    pub mask: Option<Arc<Texture>>,
+    /// The overall opacity of the decal (0.0 is invisible, 1.0 completely covers the surface)
+    pub opacity: f64,
+    /// Transforms from world space to the coordinate system of the decal box
+    invtrans: Mat4,
+    /// The direction (in world space) that the decal is projected from
+    up: Vec3,
+}
+
+impl Decal {
+    /// Creates a decal with the given texture inside the unit cube transformed by the given
+    /// transform (decal space to world space)
+    pub fn new(texture: Arc<Texture>, trans: Mat4) -> Self {
+        Self {
+            texture,
+            mask: None,
+            opacity: 1.0,
+            invtrans: trans.inverted(),
+            up: Vec3::up().transformed_direction(trans).normalized(),
+        }
+    }
+
+    /// Returns the color of the decal at the given point and the opacity it should be blended
+    /// with, or None if the point is outside of the decal
+    ///
+    /// Surfaces facing away from the direction the decal is projected from are not affected,
+    /// and surfaces at an angle to it are faded out.
+    pub fn color_at(&self, point: Vec3, normal: Vec3) -> Option<(Rgb, f64)> {
+        let local = point.transformed_point(self.invtrans);
+        if local.map(|c| c.abs()).reduce_partial_max() > 0.5 {
+            return None;
+        }
+
+        let facing = normal.normalized().dot(self.up);
+        if facing <= 0.0 {
+            return None;
+        }
+
+        let uv = Uv {u: local.x + 0.5, v: local.z + 0.5};
+        let mask = self.mask.as_ref().map(|mask| mask.at(uv).r).unwrap_or(1.0);
+
+        Some((self.texture.at(uv), self.opacity * mask * facing))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decal_projects_within_box() {
+        let red = Rgb::red();
+        let texture = Arc::new(Texture::from(move |_| red));
+        // A 2x2 decal that projects downwards onto the ground from a box 1 unit tall
+        let decal = Decal::new(texture, Mat4::scaling_3d(Vec3::new(2.0, 1.0, 2.0)));
+
+        assert_eq!(decal.color_at(Vec3::new(0.5, 0.0, -0.5), Vec3::up()), Some((red, 1.0)));
+        // Outside of the box
+        assert_eq!(decal.color_at(Vec3::new(1.5, 0.0, 0.0), Vec3::up()), None);
+        assert_eq!(decal.color_at(Vec3::new(0.0, 0.6, 0.0), Vec3::up()), None);
+        // Facing away from the projection direction
+        assert_eq!(decal.color_at(Vec3::zero(), Vec3::down()), None);
+    }
+}
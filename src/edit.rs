@@ -0,0 +1,219 @@
+//! Making modified copies of scenes without rebuilding them from scratch
+//!
+//! Only the nodes on the way down from the root to an edited node are copied. Every other subtree
+//! is shared between the original scene and the copy, so many variations of a large scene (e.g.
+//! for parameter sweeps or A/B renders) can be made cheaply.
+
+use std::sync::Arc;
+use std::collections::HashMap;
+
+use crate::math::Mat4;
+use crate::scene::{HierScene, SceneNode};
+use crate::material::Material;
+use crate::validate::NodePath;
+
+/// Identifies the node(s) in a scene that should be edited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeSelector {
+    /// The node found by taking the child at each index on the way down from the root (empty for
+    /// the root)
+    Path(Vec<usize>),
+    /// Every node with the given name
+    Name(String),
+}
+
+impl From<Vec<usize>> for NodeSelector {
+    fn from(path: Vec<usize>) -> Self {
+        NodeSelector::Path(path)
+    }
+}
+
+impl<'a> From<&'a [usize]> for NodeSelector {
+    fn from(path: &'a [usize]) -> Self {
+        NodeSelector::Path(path.to_vec())
+    }
+}
+
+impl<'a> From<&'a NodePath> for NodeSelector {
+    fn from(path: &'a NodePath) -> Self {
+        NodeSelector::Path(path.indices.clone())
+    }
+}
+
+impl<'a> From<&'a str> for NodeSelector {
+    fn from(name: &'a str) -> Self {
+        NodeSelector::Name(name.to_string())
+    }
+}
+
+impl From<String> for NodeSelector {
+    fn from(name: String) -> Self {
+        NodeSelector::Name(name)
+    }
+}
+
+/// A change made to a selected node
+#[derive(Debug, Clone)]
+pub enum NodeEdit {
+    /// Replaces the material of every geometry at or underneath the node (including the second
+    /// material of any checker pattern, which is removed)
+    Material(Arc<Material>),
+    /// Replaces the transform of the node
+    Transform(Mat4),
+    /// Removes the geometry and children of the node so that nothing at or underneath it is seen
+    Hide,
+}
+
+impl NodeEdit {
+    /// Returns an edited copy of the given node
+    fn apply(&self, node: &SceneNode) -> SceneNode {
+        match self {
+            NodeEdit::Material(material) => node.with_material_override(material, &[]),
+            NodeEdit::Transform(trans) => {
+                let mut node = node.with_replaced_children(node.children().to_vec());
+                node.set_transform(*trans);
+                node
+            },
+            NodeEdit::Hide => node.emptied(),
+        }
+    }
+}
+
+impl HierScene {
+    /// Returns a copy of this scene with the given edit applied to the selected node(s)
+    ///
+    /// Subtrees that do not contain a selected node are shared with this scene rather than copied.
+    /// Instanced nodes that are selected by name are edited once and remain shared in the copy.
+    /// Returns None if no node was selected (e.g. the path does not exist).
+    pub fn edited<S: Into<NodeSelector>>(&self, selector: S, edit: &NodeEdit) -> Option<Self> {
+        let root = match selector.into() {
+            NodeSelector::Path(path) => edit_path(&self.root, &path, edit)?,
+            NodeSelector::Name(name) => {
+                let root = edit_named(&self.root, &name, edit, &mut HashMap::new());
+                if Arc::ptr_eq(&root, &self.root) {
+                    return None;
+                }
+                root
+            },
+        };
+
+        Some(Self {
+            root,
+            lights: self.lights.clone(),
+            ambient: self.ambient,
+        })
+    }
+}
+
+/// Edits the node at the given path underneath the given node, copying every node along the way
+fn edit_path(node: &Arc<SceneNode>, path: &[usize], edit: &NodeEdit) -> Option<Arc<SceneNode>> {
+    let (&index, rest) = match path.split_first() {
+        Some(next) => next,
+        None => return Some(Arc::new(edit.apply(node))),
+    };
+
+    let child = edit_path(node.children().get(index)?, rest, edit)?;
+    let mut children = node.children().to_vec();
+    children[index] = child;
+    Some(Arc::new(node.with_replaced_children(children)))
+}
+
+/// Edits every node with the given name at or underneath the given node
+///
+/// Returns the given node itself if nothing underneath it was edited. The edited copies made so
+/// far are stored by the address of the original node so that instanced nodes are only edited
+/// once.
+fn edit_named(
+    node: &Arc<SceneNode>,
+    name: &str,
+    edit: &NodeEdit,
+    edits: &mut HashMap<*const SceneNode, Arc<SceneNode>>,
+) -> Arc<SceneNode> {
+    let key = Arc::as_ptr(node);
+    if let Some(edited) = edits.get(&key) {
+        return edited.clone();
+    }
+
+    let children: Vec<_> = node.children().iter()
+        .map(|child| edit_named(child, name, edit, edits))
+        .collect();
+    let children_changed = children.iter().zip(node.children())
+        .any(|(child, original)| !Arc::ptr_eq(child, original));
+
+    let mut edited = if children_changed {
+        Arc::new(node.with_replaced_children(children))
+    } else {
+        node.clone()
+    };
+    if node.name() == Some(name) {
+        edited = Arc::new(edit.apply(&edited));
+    }
+
+    edits.insert(key, edited.clone());
+    edited
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ops::Range;
+
+    use crate::math::{Vec3, Rgb, EPSILON, INFINITY};
+    use crate::ray::{Ray, RayCast};
+    use crate::scene::Geometry;
+    use crate::primitive::Sphere;
+
+    fn test_scene() -> HierScene {
+        let red = Arc::new(Material {diffuse: Rgb::red(), ..Material::default()});
+        let ball = Arc::new(SceneNode::from(Geometry::new(Sphere, red)).named("ball"));
+        let wheel = Arc::new(SceneNode::from(ball.clone()).named("wheel"));
+
+        HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(wheel.clone()).translated((-2.0, 0.0, 0.0))),
+                Arc::new(SceneNode::from(wheel).translated((2.0, 0.0, 0.0))),
+                Arc::new(SceneNode::from(ball).translated((0.0, 3.0, 0.0))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        }
+    }
+
+    #[test]
+    fn edit_by_name_shares_untouched_subtrees() {
+        let scene = test_scene();
+        let clay = Arc::new(Material::clay());
+        let edited = scene.edited("wheel", &NodeEdit::Material(clay.clone())).unwrap();
+
+        let (original, copy) = (scene.root.children(), edited.root.children());
+        // The instanced wheel is only edited once
+        let wheel = &copy[0].children()[0];
+        assert!(Arc::ptr_eq(wheel, &copy[1].children()[0]));
+        assert_eq!(wheel.children()[0].geometry().unwrap().material, clay);
+        // The ball outside of the wheels is shared with the original scene
+        assert!(Arc::ptr_eq(&original[2], &copy[2]));
+        // The original scene is unchanged
+        assert_ne!(original[0].children()[0].children()[0].geometry().unwrap().material, clay);
+
+        assert!(scene.edited("missing", &NodeEdit::Material(clay)).is_none());
+    }
+
+    #[test]
+    fn edit_by_path_updates_bounds() {
+        let scene = test_scene();
+        let moved = NodeEdit::Transform(Mat4::translation_3d((0.0, -3.0, 0.0)));
+        let edited = scene.edited(vec![2], &moved).unwrap();
+
+        assert!(Arc::ptr_eq(&scene.root.children()[0], &edited.root.children()[0]));
+        assert!(Arc::ptr_eq(&scene.root.children()[1], &edited.root.children()[1]));
+
+        // The ball is now underneath the origin, outside of the original bounds of the root
+        let ray = Ray::new(Vec3::new(0.0, -10.0, 0.0), Vec3::up());
+        let mut t_range = Range {start: EPSILON, end: INFINITY};
+        let (hit, _) = edited.root.ray_cast(&ray, &mut t_range).unwrap();
+        assert!((hit.hit_point - Vec3::new(0.0, -4.0, 0.0)).magnitude() < EPSILON, "{:?}", hit.hit_point);
+
+        assert!(scene.edited(vec![2, 0, 0], &moved).is_none());
+    }
+}
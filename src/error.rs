@@ -0,0 +1,153 @@
+//! Machine-readable classification of the errors and warnings produced while rendering
+//!
+//! Every warning printed by the renderer is prefixed with one of the codes in `ErrorCode` (e.g.
+//! `warning[E0100]: ...`) so that log scrapers can classify failures without parsing the
+//! (possibly translated) message. If the `ASCII_OUTPUT` environment variable is set, any
+//! non-ASCII characters in the output (e.g. in file paths or node names) are escaped.
+
+use std::io;
+use std::env;
+use std::fmt;
+use std::error::Error;
+
+/// The kinds of failures that can happen while loading or rendering a scene
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// An asset (e.g. a texture or mesh) could not be found
+    MissingAsset,
+    /// An asset was found but could not be decoded
+    InvalidAsset,
+    /// Ran out of memory while loading an asset or rendering
+    OutOfMemory,
+    /// The scene is malformed or contains something that will likely produce artifacts
+    InvalidScene,
+    /// Any other error while reading or writing a file
+    Io,
+    /// Some of the requested render settings (e.g. environment variables) can not be used together
+    InvalidSettings,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ErrorCode::*;
+        match self {
+            MissingAsset => write!(f, "missing asset"),
+            InvalidAsset => write!(f, "invalid asset"),
+            OutOfMemory => write!(f, "out of memory"),
+            InvalidScene => write!(f, "invalid scene"),
+            Io => write!(f, "I/O error"),
+            InvalidSettings => write!(f, "invalid settings"),
+        }
+    }
+}
+
+impl ErrorCode {
+    /// Returns the stable code for this kind of failure (e.g. `E0100`)
+    ///
+    /// These codes never change between releases, so they are safe to match on in scripts.
+    pub fn code(self) -> &'static str {
+        use ErrorCode::*;
+        match self {
+            MissingAsset => "E0100",
+            InvalidAsset => "E0101",
+            OutOfMemory => "E0200",
+            InvalidScene => "E0300",
+            Io => "E0400",
+            InvalidSettings => "E0500",
+        }
+    }
+
+    /// Classifies an I/O error
+    pub fn from_io_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => ErrorCode::MissingAsset,
+            io::ErrorKind::OutOfMemory => ErrorCode::OutOfMemory,
+            _ => ErrorCode::Io,
+        }
+    }
+
+    /// Classifies an error from loading or saving an image
+    pub fn from_image_error(err: &image::ImageError) -> Self {
+        match err {
+            image::ImageError::IoError(err) => Self::from_io_error(err),
+            image::ImageError::InsufficientMemory => ErrorCode::OutOfMemory,
+            _ => ErrorCode::InvalidAsset,
+        }
+    }
+
+    /// Classifies an error from loading a mesh
+    pub fn from_load_error(err: &tobj::LoadError) -> Self {
+        match err {
+            tobj::LoadError::OpenFileFailed => ErrorCode::MissingAsset,
+            tobj::LoadError::ReadError => ErrorCode::Io,
+            _ => ErrorCode::InvalidAsset,
+        }
+    }
+
+    /// Classifies any error returned by this crate (e.g. the error returned from `main`)
+    ///
+    /// Errors that are not recognized are classified as `ErrorCode::Io`.
+    pub fn from_error(err: &(dyn Error + 'static)) -> Self {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            Self::from_io_error(err)
+        } else if let Some(err) = err.downcast_ref::<image::ImageError>() {
+            Self::from_image_error(err)
+        } else if let Some(err) = err.downcast_ref::<tobj::LoadError>() {
+            Self::from_load_error(err)
+        } else {
+            ErrorCode::Io
+        }
+    }
+}
+
+/// Returns true if the `ASCII_OUTPUT` environment variable is set
+pub fn ascii_output() -> bool {
+    env::var("ASCII_OUTPUT").is_ok()
+}
+
+/// Replaces every non-ASCII character in the given text with a `\u{...}` escape
+pub fn ascii_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if ch.is_ascii() {
+            escaped.push(ch);
+        } else {
+            escaped.extend(ch.escape_unicode());
+        }
+    }
+    escaped
+}
+
+/// Formats a warning with the given code, escaping it if `ASCII_OUTPUT` is set
+pub fn format_warning(code: ErrorCode, message: &str) -> String {
+    let warning = format!("warning[{}]: {}", code.code(), message);
+    if ascii_output() { ascii_escape(&warning) } else { warning }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_classified() {
+        let not_found = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        assert_eq!(ErrorCode::from_io_error(&not_found), ErrorCode::MissingAsset);
+
+        let image_err = image::open("does/not/exist.png").err().unwrap();
+        assert_eq!(ErrorCode::from_image_error(&image_err), ErrorCode::MissingAsset);
+        assert_eq!(ErrorCode::from_image_error(&image::ImageError::InsufficientMemory), ErrorCode::OutOfMemory);
+        assert_eq!(ErrorCode::from_image_error(&image::ImageError::FormatError("bad".to_string())),
+            ErrorCode::InvalidAsset);
+
+        let boxed: Box<dyn Error> = Box::new(image_err);
+        assert_eq!(ErrorCode::from_error(&*boxed), ErrorCode::MissingAsset);
+        assert_eq!(ErrorCode::from_error(&tobj::LoadError::FaceParseError), ErrorCode::InvalidAsset);
+    }
+
+    #[test]
+    fn non_ascii_text_is_escaped() {
+        assert_eq!(ascii_escape("textures/brick.png"), "textures/brick.png");
+        assert_eq!(ascii_escape("текстура.png"), "\\u{442}\\u{435}\\u{43a}\\u{441}\\u{442}\\u{443}\\u{440}\\u{430}.png");
+        assert!(ascii_escape("нет файла").is_ascii());
+    }
+}
@@ -2,17 +2,18 @@ use std::sync::Arc;
 use std::ops::Range;
 use std::collections::VecDeque;
 
-use crate::math::{Mat4, Vec3Ext};
+use crate::math::{Mat4, Vec3Ext, UvTransform};
 use crate::material::Material;
-use crate::scene::{Scene, HierScene, Geometry};
+use crate::scene::{Scene, HierScene, Geometry, apply_uv_transform};
 use crate::ray::{RayCast, RayHit, Ray, RayIntersection};
 use crate::bounding_box::{BoundingBox, Bounds};
 
 /// A completely non-hierarchical representation of the scene. Note that this potentially uses
-/// more memory since all structures that were previously benefiting from instancing will get
-/// cloned. The process of converting a hierarchical scene to a flat scene assumes that the scene
-/// is a tree. If any cycles do exist, the flattening structure will never terminate and will
-/// consume all memory.
+/// more memory since a separate node is created for every instance of an instanced node. The
+/// geometry of each node is cloned, but any large data it contains (mesh data, k-d trees,
+/// materials, etc.) is stored in an Arc and remains shared between instances. The process of
+/// converting a hierarchical scene to a flat scene assumes that the scene is a tree. If any cycles
+/// do exist, the flattening structure will never terminate and will consume all memory.
 pub type FlatScene = Scene<Vec<FlatSceneNode>>;
 
 impl<'a> From<&'a HierScene> for FlatScene {
@@ -20,20 +21,28 @@ impl<'a> From<&'a HierScene> for FlatScene {
         // Performing a breadth first traversal through the tree
         // Note that no cycle checking occurs here. We are assuming that the scene is a tree.
         let mut nodes = Vec::new();
-        // Contains (parent transform, node) pairs
+        // Contains (parent transform, parent UV transform, node) tuples
         let mut remaining = VecDeque::new();
-        remaining.push_back((Mat4::identity(), hier_scene.root.clone()));
+        remaining.push_back((Mat4::identity(), None, hier_scene.root.clone()));
 
-        while let Some((parent_trans, node)) = remaining.pop_front() {
+        while let Some((parent_trans, parent_uv_transform, node)) = remaining.pop_front() {
             // The total transformation so far
             let total_trans = parent_trans * node.trans();
+            // The UV transforms of the children are applied before the UV transforms of their
+            // parents
+            let total_uv_transform = match (node.uv_transform(), parent_uv_transform) {
+                (Some(uv_transform), Some(parent)) => Some(uv_transform.then(&parent)),
+                (uv_transform, parent) => uv_transform.copied().or(parent),
+            };
 
             if let Some(geometry) = node.geometry() {
-                nodes.push(FlatSceneNode::new(geometry.clone(), total_trans));
+                let mut flat_node = FlatSceneNode::new(geometry.clone(), total_trans);
+                flat_node.uv_transform = total_uv_transform;
+                nodes.push(flat_node);
             }
 
             for child in node.children() {
-                remaining.push_back((total_trans, child.clone()));
+                remaining.push_back((total_trans, total_uv_transform, child.clone()));
             }
         }
 
@@ -58,6 +67,8 @@ pub struct FlatSceneNode {
     invtrans: Mat4,
     /// The inverse transpose of trans, used for transforming normals
     normal_trans: Mat4,
+    /// The combined UV transforms of this node and its ancestors in the hierarchical scene
+    uv_transform: Option<UvTransform>,
 }
 
 impl Bounds for FlatSceneNode {
@@ -80,18 +91,27 @@ impl RayCast for FlatSceneNode {
         let normal_trans = self.normal_trans();
 
         // Check if the ray intersects this node's geometry
-        let Geometry {primitive, material} = &self.geometry;
-        match primitive.ray_hit(&local_ray, t_range) {
+        match self.geometry.ray_hit(&local_ray, t_range) {
             Some(mut hit) => {
+                let material = self.geometry.material_at(&hit).clone();
+
                 // Bring the found hit point back into the right coordinate system
                 hit.hit_point = hit.hit_point.transformed_point(trans);
+                hit.shadow_origin = hit.shadow_origin.map(|p| p.transformed_point(trans));
                 hit.normal = hit.normal.transformed_direction(normal_trans);
+                if let Some(uv_transform) = &self.uv_transform {
+                    apply_uv_transform(&mut hit, uv_transform);
+                }
+
+                #[cfg(feature = "nan_checks")]
+                hit.assert_finite(format_args!("{} in flat scene node with transform {:?}",
+                    self.geometry.primitive.kind_name(), trans));
 
                 // Only allow further intersections if they are closer to the ray origin
                 // than this one
                 t_range.end = hit.ray_parameter;
 
-                Some((hit, material.clone()))
+                Some((hit, material))
             },
             None => None,
         }
@@ -104,7 +124,7 @@ impl FlatSceneNode {
         let invtrans = trans.inverted();
         let normal_trans = invtrans.transposed();
 
-        Self {geometry, trans, invtrans, normal_trans}
+        Self {geometry, trans, invtrans, normal_trans, uv_transform: None}
     }
 
     /// Return the geometry stored at this node
@@ -0,0 +1,189 @@
+//! Uniform fog that light shafts ("god rays") can be seen through
+//!
+//! Only single scattering along primary rays is simulated: light from each light in the scene
+//! scatters once off of the fog towards the camera, unless something casts a shadow on that part
+//! of the fog. Reflected and refracted rays pass through the fog unaffected.
+//!
+//! Each primary ray takes one sample of the light scattered along it for every light in the
+//! scene. The sample is placed with equiangular sampling: points along the ray are picked evenly
+//! by the angle that they make with the light, so most samples land close to the light where the
+//! scattered light is brightest. This makes the shafts converge with far fewer samples than
+//! picking points evenly along the ray.
+
+use rand::Rng;
+
+use crate::math::{EPSILON, INFINITY, Vec3, Rgb, sample_rng};
+use crate::scene::Scene;
+use crate::ray::{Ray, RayCast};
+
+/// A uniform fog around the camera
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fog {
+    /// The fraction of light absorbed or scattered by the fog per unit of distance
+    ///
+    /// Small values (e.g. 0.01 to 0.1 for a scene that is 10 units across) give visible shafts
+    /// without hiding the rest of the scene.
+    pub density: f64,
+    /// The fraction of the light scattered by the fog in each color channel (the rest is absorbed)
+    pub albedo: Rgb,
+    /// The distance from the camera that the fog extends to
+    ///
+    /// Rays that hit nothing (e.g. the sky) only pass through this much fog.
+    pub max_distance: f64,
+}
+
+/// The effect of the fog along a single ray
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FogSample {
+    /// The fraction of the light from behind the fog that makes it through
+    pub transmittance: f64,
+    /// The light scattered by the fog towards the start of the ray
+    pub light: Rgb,
+}
+
+impl FogSample {
+    /// Returns the given color as seen through the fog
+    pub fn apply(&self, color: Rgb) -> Rgb {
+        color * self.transmittance + self.light
+    }
+}
+
+impl Fog {
+    /// Returns the fraction of light that makes it through the given distance of fog
+    pub fn transmittance(&self, distance: f64) -> f64 {
+        (-self.density * distance).exp()
+    }
+
+    /// Samples the effect of the fog between the start of the given ray and the nearest surface
+    /// that it hits
+    pub fn sample<R: RayCast>(&self, scene: &Scene<R>, ray: &Ray) -> FogSample {
+        let distance = ray.length(scene).unwrap_or(INFINITY).min(self.max_distance);
+
+        let mut rng = sample_rng();
+        let light = scene.lights.iter().fold(Rgb::black(), |total, light| {
+            // Light from a beam comes through a different point of the area depending on where
+            // the point in the fog is, so the samples crowd around the center of the area instead
+            let target = if light.area.is_empty() || light.beam.is_some() {
+                light.position
+            } else {
+                light.sample_position(&mut rng)
+            };
+
+            let (t, pdf) = equiangular_sample(ray, distance, target, rng.gen());
+            if pdf <= 0.0 {
+                return total;
+            }
+            let point = ray.at(t);
+            let light_pos = match light.beam {
+                Some(_) => match light.sample_position_towards(point, &mut rng) {
+                    Some(light_pos) => light_pos,
+                    None => return total,
+                },
+                None => target,
+            };
+            if !scene.visible(point, light_pos) {
+                return total;
+            }
+
+            // The fog scatters light like a diffuse surface facing the light, so light shafts are
+            // as bright as the surfaces lit by the same light
+            let light_dist = (light_pos - point).magnitude();
+            let light_color = light.color_at(light_pos, (point - light_pos) / light_dist.max(EPSILON))
+                * light.caustics_at(light_pos, point) / light.falloff.at_distance(light_dist);
+            let scattered = light_color * self.albedo * self.density
+                * self.transmittance(light_dist) * self.transmittance(t);
+
+            total + scattered / pdf
+        });
+
+        FogSample {transmittance: self.transmittance(distance), light}
+    }
+}
+
+/// Picks a point along the given ray (up to the given distance) evenly by the angle that it makes
+/// with the given point, using the given random number from 0.0 to 1.0
+///
+/// Returns the ray parameter of the point and the probability density of picking it. See:
+/// Christopher Kulla and Marcos Fajardo. 2012. Importance Sampling Techniques for Path Tracing in
+/// Participating Media.
+pub fn equiangular_sample(ray: &Ray, distance: f64, point: Vec3, random: f64) -> (f64, f64) {
+    // The ray parameter of the point on the ray closest to the given point
+    let closest = (point - ray.origin()).dot(ray.direction());
+    // The distance from the given point to the ray, which must not be zero or every angle is the
+    // same
+    let offset = (ray.at(closest) - point).magnitude().max(EPSILON);
+
+    let angle_start = (-closest / offset).atan();
+    // Rays that go on forever end at an angle of 90 degrees
+    let angle_end = ((distance - closest) / offset).atan();
+    if angle_end <= angle_start {
+        return (0.0, 0.0);
+    }
+
+    let angle = angle_start + random * (angle_end - angle_start);
+    let along = offset * angle.tan();
+    let pdf = offset / ((angle_end - angle_start) * (offset * offset + along * along));
+    (closest + along, pdf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::light::Light;
+    use crate::material::Material;
+    use crate::primitive::Cube;
+    use crate::math::{sample_seed, seed_sample_rng};
+
+    #[test]
+    fn equiangular_samples_integrate_to_one() {
+        let ray = Ray::new(Vec3::zero(), Vec3::unit_x());
+        let light = Vec3::new(3.0, 1.0, 0.0);
+
+        // Integrating 1 / pdf over the random numbers gives the length of the ray
+        let steps = 10_000;
+        let length: f64 = (0..steps).map(|i| {
+            let (t, pdf) = equiangular_sample(&ray, 10.0, light, (i as f64 + 0.5) / steps as f64);
+            assert!((0.0..=10.0).contains(&t));
+            1.0 / pdf / steps as f64
+        }).sum();
+        assert!((length - 10.0).abs() < 1e-3, "{}", length);
+
+        // Samples crowd around the light
+        let (middle, _) = equiangular_sample(&ray, 10.0, light, 0.5);
+        assert!((middle - 3.0).abs() < 1.0, "{}", middle);
+    }
+
+    #[test]
+    fn shadows_cut_light_shafts() {
+        let light = Light {position: Vec3::new(0.0, 5.0, 0.0), color: Rgb::white(), ..Light::default()};
+        let open = HierScene {
+            root: Arc::new(SceneNode::from(vec![])),
+            lights: vec![light.clone()],
+            ambient: Rgb::black(),
+        };
+        // A wide slab between the light and the ray
+        let blocked = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Cube, Arc::new(Material::default())))
+                .scaled((100.0, 0.1, 100.0))
+                .translated((0.0, 2.0, 0.0))),
+            lights: vec![light],
+            ambient: Rgb::black(),
+        };
+        let fog = Fog {density: 0.1, albedo: Rgb::white(), max_distance: 20.0};
+        let ray = Ray::new(Vec3::new(-10.0, 0.0, 0.0), Vec3::unit_x());
+
+        seed_sample_rng(Some(sample_seed(1053, (0, 0), 0)));
+        let lit = fog.sample(&open, &ray);
+        let shadowed = fog.sample(&blocked, &ray);
+        seed_sample_rng(None);
+
+        assert!((lit.transmittance - (-2.0f64).exp()).abs() < 1e-12);
+        assert!(lit.light.r > 0.0);
+        assert_eq!(shadowed.light, Rgb::black());
+        assert_eq!(lit.apply(Rgb::black()), lit.light);
+    }
+}
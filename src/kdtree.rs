@@ -7,9 +7,13 @@ mod kdscene;
 mod kdmesh;
 mod leaf;
 mod node;
+#[cfg(feature = "kdtree")]
+mod refit;
 
 #[cfg(feature = "kdtree")]
 pub(crate) use kdscene::*;
 pub use kdmesh::*;
 pub(crate) use leaf::*;
 pub(crate) use node::*;
+#[cfg(feature = "kdtree")]
+pub use refit::*;
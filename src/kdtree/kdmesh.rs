@@ -1,18 +1,81 @@
-use std::env;
-use std::sync::Arc;
+use std::sync::{Arc, Weak, Mutex};
 use std::ops::Range;
 
-use crate::math::Vec3;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use rayon::prelude::*;
+
+use crate::math::{Vec3, sample_uniform_sphere};
 use crate::bounding_box::{BoundingBox, Bounds};
 use crate::primitive::{MeshData, Shading, Triangle};
 use crate::ray::{RayHit, Ray, RayIntersection};
 
 use super::{KDTreeNode, KDLeaf, PartitionConfig, NodeBounds};
 
-/// The maximum depth of any k-d tree
+/// The number of levels added to log2 of the number of triangles to get the default maximum depth
+/// of the k-d tree of a mesh
 ///
-/// Can be set via the KD_MESH_DEPTH environment variable
-const MAX_TREE_DEPTH: usize = 10;
+/// Nodes that cross a separating plane end up on both sides of it, so a few extra levels past a
+/// perfectly balanced tree are needed to get down to the target number of triangles per leaf.
+const EXTRA_TREE_DEPTH: usize = 2;
+
+/// The partition configuration used when auto-tuning is disabled
+const DEFAULT_PARTITION_CONFIG: PartitionConfig = PartitionConfig {
+    target_max_nodes: 3,
+    target_max_merit: 3,
+    max_tries: 10,
+};
+
+/// The partition configurations tried when auto-tuning (see `KDMeshOptions::auto_tune`)
+const TUNING_CANDIDATES: [PartitionConfig; 6] = [
+    DEFAULT_PARTITION_CONFIG,
+    PartitionConfig {target_max_nodes: 1, target_max_merit: 3, max_tries: 10},
+    PartitionConfig {target_max_nodes: 8, target_max_merit: 3, max_tries: 10},
+    PartitionConfig {target_max_nodes: 3, target_max_merit: 1, max_tries: 20},
+    PartitionConfig {target_max_nodes: 3, target_max_merit: 10, max_tries: 5},
+    PartitionConfig {target_max_nodes: 16, target_max_merit: 10, max_tries: 5},
+];
+
+/// The number of rays cast through each candidate tree to estimate its traversal cost
+const PROBE_RAYS: usize = 512;
+
+/// The cost of visiting a leaf of the tree relative to the cost of testing a single triangle
+const LEAF_VISIT_COST: f64 = 1.0;
+
+/// A k-d tree built for some mesh data, kept so that other meshes created from the same data can
+/// share it (see `KDMesh::with_options`)
+struct CachedTree {
+    data: Weak<MeshData>,
+    options: KDMeshOptions,
+    tree: Weak<KDTreeNode<Triangle>>,
+}
+
+/// Every k-d tree built by `KDMesh::with_options` that is still used by some mesh
+static TREE_CACHE: Mutex<Vec<CachedTree>> = Mutex::new(Vec::new());
+
+/// Options for building the k-d tree of a `KDMesh`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KDMeshOptions {
+    /// The maximum depth of the k-d tree
+    ///
+    /// Deeper trees have fewer triangles in each leaf but use more memory. If None, the depth is
+    /// chosen based on the number of triangles in the mesh (see `KDMeshOptions::default_max_depth`).
+    pub max_depth: Option<usize>,
+    /// If true, the tree is built with a few different partitioning settings and whichever one is
+    /// cheapest to traverse for this particular mesh is kept
+    ///
+    /// Each tree is measured by casting the same set of probe rays through it and counting the
+    /// leaves visited and the triangles tested. This takes several times longer than building a
+    /// single tree, but can speed up rendering meshes that are unusually dense or sparse.
+    pub auto_tune: bool,
+}
+
+impl KDMeshOptions {
+    /// Returns the maximum depth used for a mesh with the given number of triangles when
+    /// `max_depth` is not set
+    pub fn default_max_depth(triangle_count: usize) -> usize {
+        (triangle_count.max(1) as f64).log2().ceil() as usize + EXTRA_TREE_DEPTH
+    }
+}
 
 /// A Mesh backed by a k-d tree to store the triangles
 #[derive(Debug, Clone, PartialEq)]
@@ -20,7 +83,14 @@ pub struct KDMesh {
     // Storing the triangles in an Arc to make this cheap to clone without duplicating the tree.
     // This is very important in case the node containing this primitive is instanced and then
     // flattened. It's the same reason why Mesh stores Arc<MeshData>.
+    //
+    // The triangles always keep the vertex normals of the mesh data (if it has any) so that the
+    // same tree can be shared between meshes with different shading (see `with_shading`).
     triangles: Arc<KDTreeNode<Triangle>>,
+    /// The mode to use when computing the normal of each face
+    shading: Shading,
+    /// True if the triangles in the tree have vertex normals (required for smooth shading)
+    has_normals: bool,
 }
 
 impl Bounds for KDMesh {
@@ -34,30 +104,142 @@ impl KDMesh {
     ///
     /// Note that this does not store the given mesh data. Instead it copies the data into the
     /// nodes of a k-d tree.
-    pub fn new(data: &MeshData, shading: Shading) -> Self {
-        // Turn all of the mesh triangles into a single, unpartitioned leaf node
-        let nodes: Vec<_> = data.triangles(shading)
-            .map(|node| NodeBounds::from(node).into())
-            .collect();
-
-        let leaf = KDLeaf {bounds: nodes.bounds(), nodes};
-        let part_conf = PartitionConfig {
-            target_max_nodes: 3,
-            target_max_merit: 3,
-            max_tries: 10,
+    ///
+    /// The tree is built with the default options. Use `with_options` to tune it for this mesh
+    /// (see `KDMeshOptions::auto_tune`).
+    pub fn new(data: &Arc<MeshData>, shading: Shading) -> Self {
+        Self::with_options(data, shading, KDMeshOptions::default())
+    }
+
+    /// Creates a new mesh from the given mesh data and with the given shading, building its k-d
+    /// tree with the given options
+    ///
+    /// Meshes created from the same `Arc<MeshData>` with the same options share a single tree,
+    /// regardless of their shading. The tree is only built again once every mesh using it has been
+    /// dropped.
+    pub fn with_options(data: &Arc<MeshData>, shading: Shading, options: KDMeshOptions) -> Self {
+        let has_normals = data.has_normals();
+        if shading == Shading::Smooth {
+            assert!(has_normals,
+                "Meshes must have a vertex normal for each vertex if they are to be used with smooth shading");
+        }
+
+        if let Some(tree) = cached_tree(data, options) {
+            return Self {triangles: tree, shading, has_normals};
+        }
+
+        // Normals do not affect partitioning, so the tree can be built once with the vertex
+        // normals and then used with either kind of shading
+        let leaf = unpartitioned_leaf(data, if has_normals { Shading::Smooth } else { Shading::Flat });
+        let max_depth = options.max_depth
+            .unwrap_or_else(|| KDMeshOptions::default_max_depth(data.triangle_count()));
+
+        let root = if options.auto_tune {
+            let probes = probe_rays(&leaf.bounds);
+
+            let (root, _) = TUNING_CANDIDATES.par_iter().map(|&part_conf| {
+                let leaf = KDLeaf {bounds: leaf.bounds.clone(), nodes: leaf.nodes.clone()};
+                let root = leaf.partitioned(Vec3::unit_x(), max_depth, part_conf);
+                let cost = traversal_cost(&root, &probes);
+                (root, cost)
+            }).min_by(|(_, cost1), (_, cost2)| cost1.partial_cmp(cost2)
+                .expect("bug: traversal cost should never be NaN"))
+                .expect("bug: there should be at least one tuning candidate");
+            root
+
+        } else {
+            leaf.partitioned(Vec3::unit_x(), max_depth, DEFAULT_PARTITION_CONFIG)
         };
 
-        // Allow overriding the max tree depth for bigger scenes
-        let max_tree_depth = env::var("KD_MESH_DEPTH").ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(MAX_TREE_DEPTH);
+        let triangles = Arc::new(root);
+        TREE_CACHE.lock().expect("bug: k-d tree cache should not be poisoned").push(CachedTree {
+            data: Arc::downgrade(data),
+            options,
+            tree: Arc::downgrade(&triangles),
+        });
 
-        let root = leaf.partitioned(Vec3::unit_x(), max_tree_depth, part_conf);
+        Self {triangles, shading, has_normals}
+    }
 
-        Self {triangles: Arc::new(root)}
+    /// Returns a mesh with the same triangles as this mesh but with the given shading
+    ///
+    /// The k-d tree is shared with this mesh instead of being built again, so this is much
+    /// cheaper than creating a new mesh from the same mesh data (e.g. to use both flat and smooth
+    /// shaded versions of a model in the same scene).
+    pub fn with_shading(&self, shading: Shading) -> Self {
+        if shading == Shading::Smooth {
+            assert!(self.has_normals,
+                "Meshes must have a vertex normal for each vertex if they are to be used with smooth shading");
+        }
+
+        Self {shading, ..self.clone()}
+    }
+
+    /// Returns the shading used by this mesh
+    pub fn shading(&self) -> Shading {
+        self.shading
+    }
+
+    /// Returns the number of triangles stored in this mesh
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.node_count()
+    }
+
+    /// Returns the k-d tree that stores the triangles of this mesh
+    pub(crate) fn tree(&self) -> &Arc<KDTreeNode<Triangle>> {
+        &self.triangles
     }
 }
 
+/// Returns the tree already built for the given mesh data with the given options (if it is still
+/// in use), removing any trees that are no longer used from the cache
+fn cached_tree(data: &Arc<MeshData>, options: KDMeshOptions) -> Option<Arc<KDTreeNode<Triangle>>> {
+    let mut cache = TREE_CACHE.lock().expect("bug: k-d tree cache should not be poisoned");
+    // Dropping the mesh data frees its address for new data, so its trees must be forgotten
+    cache.retain(|entry| entry.data.strong_count() > 0 && entry.tree.strong_count() > 0);
+
+    cache.iter()
+        .find(|entry| entry.options == options && entry.data.as_ptr() == Arc::as_ptr(data))
+        .and_then(|entry| entry.tree.upgrade())
+}
+
+/// Turns all of the mesh triangles into a single, unpartitioned leaf node
+fn unpartitioned_leaf(data: &MeshData, shading: Shading) -> KDLeaf<Triangle> {
+    let nodes: Vec<_> = data.triangles(shading)
+        .map(|node| NodeBounds::from(node).into())
+        .collect();
+
+    KDLeaf {bounds: nodes.bounds(), nodes}
+}
+
+/// Generates rays from points around the given bounding box towards random points inside it
+///
+/// The same rays are always generated for the same bounding box so that the trees built for a
+/// mesh are all measured with the same rays.
+fn probe_rays(bounds: &BoundingBox) -> Vec<Ray> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (min, max) = (bounds.min(), bounds.max());
+    let center = (min + max) / 2.0;
+    // Far enough away that every origin is outside of the bounding box
+    let radius = (max - min).magnitude().max(1.0);
+
+    (0..PROBE_RAYS).map(|_| {
+        let origin = center + sample_uniform_sphere(&mut rng) * radius;
+        let target = min + (max - min) * Vec3::new(rng.gen(), rng.gen(), rng.gen());
+        Ray::new(origin, (target - origin).normalized())
+    }).collect()
+}
+
+/// Returns the average cost of casting each of the given rays through the given tree
+fn traversal_cost(tree: &KDTreeNode<Triangle>, probes: &[Ray]) -> f64 {
+    let total: f64 = probes.iter().map(|ray| {
+        let (leaves, tested) = tree.traversal_counts(ray);
+        leaves as f64 * LEAF_VISIT_COST + tested as f64
+    }).sum();
+
+    total / probes.len() as f64
+}
+
 #[cfg(not(feature = "render_bounding_volumes"))]
 impl RayHit for KDMesh {
     fn ray_hit(&self, ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
@@ -65,11 +247,12 @@ impl RayHit for KDMesh {
         // we would have spent traversing the mesh triangles. This is important for the KDMesh but
         // not the KDTreeScene because it's far less likely that a ray would miss the entire scene
         // than it is that a ray would miss a given mesh.
-        if self.triangles.bounds().test_hit(ray, t_range).is_none() {
-            return None;
-        }
+        self.triangles.bounds().test_hit(ray, t_range)?;
 
-        self.triangles.ray_hit(ray, t_range)
+        match self.shading {
+            Shading::Flat => self.triangles.ray_hit_with(ray, t_range, Triangle::flat_ray_hit),
+            Shading::Smooth => self.triangles.ray_hit(ray, t_range),
+        }
     }
 }
 
@@ -87,8 +270,6 @@ mod tests {
 
     use std::error::Error;
 
-    use rayon::prelude::*;
-
     use crate::math::{Rgb, Radians};
     use crate::primitive::{Mesh, MeshData, Shading};
     use crate::material::Material;
@@ -164,4 +345,96 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn auto_tuned_mesh_is_cheaper_and_equivalent() {
+        let model = Arc::new(MeshData::torus_knot(2, 3, 1.0, 0.3, 120, 12));
+        let kd_mesh = KDMesh::new(&model, Shading::Flat);
+        let tuned_mesh = KDMesh::with_options(&model, Shading::Flat, KDMeshOptions {
+            auto_tune: true,
+            ..KDMeshOptions::default()
+        });
+
+        let probes = probe_rays(kd_mesh.tree().bounds());
+        assert!(traversal_cost(tuned_mesh.tree(), &probes) <= traversal_cost(kd_mesh.tree(), &probes));
+        assert_eq!(tuned_mesh.triangle_count(), kd_mesh.triangle_count());
+
+        // Different rays than the ones used for tuning
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let origin = sample_uniform_sphere(&mut rng) * 5.0;
+            let target = sample_uniform_sphere(&mut rng) * rng.gen::<f64>();
+            let ray = Ray::new(origin, (target - origin).normalized());
+            let t_range = ray.t_range();
+
+            let expected = kd_mesh.ray_hit(&ray, &t_range).map(|hit| hit.ray_parameter);
+            let actual = tuned_mesh.ray_hit(&ray, &t_range).map(|hit| hit.ray_parameter);
+            match (expected, actual) {
+                (Some(expected), Some(actual)) => assert!((expected - actual).abs() < 1e-9),
+                _ => assert_eq!(expected, actual),
+            }
+        }
+    }
+
+    #[test]
+    fn max_depth_grows_with_triangle_count() {
+        assert_eq!(KDMeshOptions::default_max_depth(0), EXTRA_TREE_DEPTH);
+        assert_eq!(KDMeshOptions::default_max_depth(1000), 10 + EXTRA_TREE_DEPTH);
+        assert_eq!(KDMeshOptions::default_max_depth(1024), 10 + EXTRA_TREE_DEPTH);
+        assert_eq!(KDMeshOptions::default_max_depth(1025), 11 + EXTRA_TREE_DEPTH);
+
+        // An explicit depth overrides the default
+        let model = Arc::new(MeshData::torus_knot(2, 3, 1.0, 0.3, 60, 8));
+        let flat_mesh = KDMesh::with_options(&model, Shading::Flat, KDMeshOptions {
+            max_depth: Some(0),
+            ..KDMeshOptions::default()
+        });
+        match &**flat_mesh.tree() {
+            KDTreeNode::Leaf(leaf) => assert_eq!(leaf.nodes.len(), model.triangle_count()),
+            KDTreeNode::Split {..} => panic!("a tree with a max depth of 0 should not be split"),
+        }
+    }
+
+    #[test]
+    fn shading_variants_share_tree() {
+        let model = Arc::new(MeshData::torus_knot(2, 3, 1.0, 0.3, 60, 8));
+        assert!(model.has_normals());
+        let flat_mesh = KDMesh::new(&model, Shading::Flat);
+        let smooth_mesh = flat_mesh.with_shading(Shading::Smooth);
+        assert!(Arc::ptr_eq(flat_mesh.tree(), smooth_mesh.tree()));
+        assert_eq!(smooth_mesh.shading(), Shading::Smooth);
+
+        // Creating another mesh from the same data reuses the tree, but not with other options or
+        // from an equal copy of the data
+        assert!(Arc::ptr_eq(KDMesh::new(&model, Shading::Smooth).tree(), flat_mesh.tree()));
+        let shallow = KDMesh::with_options(&model, Shading::Flat, KDMeshOptions {max_depth: Some(1), ..KDMeshOptions::default()});
+        assert!(!Arc::ptr_eq(shallow.tree(), flat_mesh.tree()));
+        let copy = Arc::new(MeshData::torus_knot(2, 3, 1.0, 0.3, 60, 8));
+        assert!(!Arc::ptr_eq(KDMesh::new(&copy, Shading::Flat).tree(), flat_mesh.tree()));
+
+        // Both variants match the plain mesh with the same shading
+        let meshes = [
+            (flat_mesh, Mesh::new(model.clone(), Shading::Flat)),
+            (smooth_mesh, Mesh::new(model.clone(), Shading::Smooth)),
+        ];
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..500 {
+            let origin = sample_uniform_sphere(&mut rng) * 5.0;
+            let target = sample_uniform_sphere(&mut rng) * rng.gen::<f64>();
+            let ray = Ray::new(origin, (target - origin).normalized());
+            let t_range = ray.t_range();
+
+            for (kd_mesh, mesh) in &meshes {
+                let expected = mesh.ray_hit(&ray, &t_range);
+                let actual = kd_mesh.ray_hit(&ray, &t_range);
+                match (expected, actual) {
+                    (Some(expected), Some(actual)) => {
+                        assert!((expected.ray_parameter - actual.ray_parameter).abs() < 1e-9);
+                        assert!((expected.normal.normalized() - actual.normal.normalized()).magnitude() < 1e-9);
+                    },
+                    (expected, actual) => assert_eq!(expected.is_some(), actual.is_some()),
+                }
+            }
+        }
+    }
 }
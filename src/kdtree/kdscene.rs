@@ -1,4 +1,5 @@
 use std::env;
+use std::sync::Arc;
 
 use crate::scene::Scene;
 use crate::math::Vec3;
@@ -20,25 +21,29 @@ impl From<FlatScene> for KDTreeScene {
     fn from(flat_scene: FlatScene) -> Self {
         let FlatScene {root: flat_nodes, lights, ambient} = flat_scene;
 
-        // Turn the entire scene into a single, unpartitioned leaf node
-        let nodes: Vec<_> = flat_nodes.into_iter()
+        let nodes = flat_nodes.into_iter()
             .map(|node| NodeBounds::from(node).into())
             .collect();
-
-        let leaf = KDLeaf {bounds: nodes.bounds(), nodes};
-        let part_conf = PartitionConfig {
-            target_max_nodes: 3,
-            target_max_merit: 3,
-            max_tries: 10,
-        };
-
-        // Allow overriding the max tree depth for bigger scenes
-        let max_tree_depth = env::var("KD_DEPTH").ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(MAX_TREE_DEPTH);
-
-        let root = leaf.partitioned(Vec3::unit_x(), max_tree_depth, part_conf);
+        let root = build_tree(nodes);
 
         Self {root, lights, ambient}
     }
 }
+
+/// Partitions the given nodes into a k-d tree
+pub(super) fn build_tree(nodes: Vec<Arc<NodeBounds<FlatSceneNode>>>) -> KDTreeNode<FlatSceneNode> {
+    // Turn the entire scene into a single, unpartitioned leaf node
+    let leaf = KDLeaf {bounds: nodes.bounds(), nodes};
+    let part_conf = PartitionConfig {
+        target_max_nodes: 3,
+        target_max_merit: 3,
+        max_tries: 10,
+    };
+
+    // Allow overriding the max tree depth for bigger scenes
+    let max_tree_depth = env::var("KD_DEPTH").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(MAX_TREE_DEPTH);
+
+    leaf.partitioned(Vec3::unit_x(), max_tree_depth, part_conf)
+}
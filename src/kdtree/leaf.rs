@@ -51,6 +51,34 @@ impl<T: RayHit> RayHit for NodeBounds<T> {
     }
 }
 
+/// Which side(s) of a separating plane a node is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Partition {
+    Front,
+    Back,
+    Shared,
+}
+
+/// Tests which side of the separating plane a given node is on. The node may be on both sides.
+pub(super) fn partition_node<T>(
+    node: &Arc<NodeBounds<T>>,
+    sep_plane: &InfinitePlane,
+) -> Partition {
+    use PlaneSide::*;
+
+    let node_min = node.bounds.min();
+    let node_max = node.bounds.max();
+
+    match (sep_plane.which_side(node_min), sep_plane.which_side(node_max)) {
+        // Node is entirely in front of the separating plane
+        (Front, Front) => Partition::Front,
+        // Node is entirely behind the separating plane
+        (Back, Back) => Partition::Back,
+        // Node is both in front and behind
+        (Front, Back) | (Back, Front) => Partition::Shared,
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(super) struct PartitionConfig {
     /// The target maximum number of nodes allowed in a leaf node. A leaf may have more or less
@@ -102,34 +130,6 @@ impl<T> KDLeaf<T> {
             axis
         }
 
-        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-        enum Partition {
-            Front,
-            Back,
-            Shared,
-        }
-
-        /// Tests which side of the separating plane a given node is on. The node may be on both
-        /// sides. Returns (front, back) where each is true if the node is on that side.
-        fn partition_node<T>(
-            node: &Arc<NodeBounds<T>>,
-            sep_plane: &InfinitePlane,
-        ) -> Partition {
-            use PlaneSide::*;
-
-            let node_min = node.bounds.min();
-            let node_max = node.bounds.max();
-
-            match (sep_plane.which_side(node_min), sep_plane.which_side(node_max)) {
-                // Node is entirely in front of the separating plane
-                (Front, Front) => Partition::Front,
-                // Node is entirely behind the separating plane
-                (Back, Back) => Partition::Back,
-                // Node is both in front and behind
-                (Front, Back) | (Back, Front) => Partition::Shared,
-            }
-        }
-
         let KDLeaf {bounds, nodes} = self;
 
         // Find the center of the bounding box along the given axis
@@ -1,7 +1,9 @@
+use std::mem;
 use std::sync::Arc;
 use std::ops::Range;
+use std::collections::HashSet;
 
-use crate::math::EPSILON;
+use crate::math::tolerance;
 use crate::material::Material;
 use crate::primitive::{InfinitePlane, PlaneSide};
 use crate::bounding_box::BoundingBox;
@@ -32,25 +34,58 @@ impl<T: RayCast> RayCast for KDTreeNode<T> {
 
 impl<T: RayHit> RayHit for KDTreeNode<T> {
     fn ray_hit(&self, ray: &Ray, init_t_range: &Range<f64>) -> Option<RayIntersection> {
+        self.ray_hit_with(ray, init_t_range, T::ray_hit)
+    }
+}
+
+impl<T: RayHit> KDTreeNode<T> {
+    /// Casts the given ray through this tree and returns the number of leaves visited and the
+    /// total number of nodes tested for intersection in those leaves
+    ///
+    /// Used to measure how expensive a tree is to traverse without depending on timing.
+    pub(in super) fn traversal_counts(&self, ray: &Ray) -> (usize, usize) {
+        let mut t_range = ray.t_range();
+        if self.bounds().test_hit(ray, &t_range).is_none() {
+            return (0, 0);
+        }
+
+        let mut leaves = 0;
+        let mut tested = 0;
+        self.ray_cast_impl(ray, &mut t_range, self.extent(), &mut |nodes, ray, t_range| {
+            leaves += 1;
+            tested += nodes.len();
+
+            let hit = nodes.ray_hit(ray, t_range)?;
+            t_range.end = hit.ray_parameter;
+            Some(hit)
+        });
+
+        (leaves, tested)
+    }
+}
+
+impl<T> KDTreeNode<T> {
+    /// Finds the nearest hit in this tree, testing each node in its leaves with the given
+    /// function instead of `RayHit::ray_hit`
+    pub(in super) fn ray_hit_with<F>(&self, ray: &Ray, init_t_range: &Range<f64>, hit_node: F) -> Option<RayIntersection>
+        where F: Fn(&T, &Ray, &Range<f64>) -> Option<RayIntersection> {
         // Need to emulate RayCast here and modify a range so that we can ensure we get the nearest
         // intersection possible. This is also important because ray_cast_impl expects the given
         // function to provide the same guarantees as RayCast about updating the t_range.
         let mut t_range = init_t_range.clone();
         self.ray_cast_impl(ray, &mut t_range, self.extent(), &mut |nodes, ray, t_range| {
-            match nodes.ray_hit(ray, t_range) {
+            nodes.iter().fold(None, |hit, node| match hit_node(&node.node, ray, t_range) {
                 Some(hit) => {
                     // Only allow further intersections if they are closer to the ray origin
                     // than this one
                     t_range.end = hit.ray_parameter;
                     Some(hit)
                 },
-                None => None,
-            }
+                None => hit,
+            })
         })
     }
-}
 
-impl<T> KDTreeNode<T> {
     pub(in super) fn bounds(&self) -> &BoundingBox {
         use KDTreeNode::*;
         match self {
@@ -59,6 +94,49 @@ impl<T> KDTreeNode<T> {
         }
     }
 
+    /// Returns the number of unique nodes stored in the leaves of this tree
+    ///
+    /// Nodes shared between multiple leaves are only counted once.
+    pub(crate) fn node_count(&self) -> usize {
+        fn node_count_impl<T>(tree: &KDTreeNode<T>, seen: &mut HashSet<*const NodeBounds<T>>) -> usize {
+            use KDTreeNode::*;
+            match tree {
+                Split {front_nodes, back_nodes, ..} => {
+                    node_count_impl(front_nodes, seen) + node_count_impl(back_nodes, seen)
+                },
+                Leaf(KDLeaf {nodes, ..}) => {
+                    nodes.iter().filter(|node| seen.insert(Arc::as_ptr(node))).count()
+                },
+            }
+        }
+
+        node_count_impl(self, &mut HashSet::new())
+    }
+
+    /// Returns the estimated number of bytes used by this tree, including the nodes stored in
+    /// its leaves
+    ///
+    /// Nodes shared between multiple leaves are only counted once.
+    pub(crate) fn memory_size(&self) -> usize {
+        self.memory_size_impl(&mut HashSet::new())
+    }
+
+    fn memory_size_impl(&self, seen: &mut HashSet<*const NodeBounds<T>>) -> usize {
+        use KDTreeNode::*;
+        let own_size = mem::size_of::<Self>();
+        match self {
+            Split {front_nodes, back_nodes, ..} => {
+                own_size + front_nodes.memory_size_impl(seen) + back_nodes.memory_size_impl(seen)
+            },
+            Leaf(KDLeaf {nodes, ..}) => {
+                let unique_nodes = nodes.iter().filter(|node| seen.insert(Arc::as_ptr(node))).count();
+                own_size
+                    + nodes.capacity() * mem::size_of::<Arc<NodeBounds<T>>>()
+                    + unique_nodes * mem::size_of::<NodeBounds<T>>()
+            },
+        }
+    }
+
     fn extent(&self) -> f64 {
         self.bounds().extent()
     }
@@ -118,11 +196,11 @@ impl<T> KDTreeNode<T> {
                 // the bounds extent may not be enough.
                 let t_max = t_range.start + extent;
                 // Must still be a value in the valid range
-                // Need to subtract EPSILON since range is exclusive
-                let t_max = if t_range.contains(&t_max) { t_max } else { t_range.end - EPSILON };
+                // Need to subtract a tolerance since range is exclusive
+                let t_max = if t_range.contains(&t_max) { t_max } else { t_range.end - tolerance::at_scale(t_range.end) };
                 // The two "end points" of the ray make a "ray segment"
-                // Need to add EPSILON because range is exclusive
-                let t_min = t_range.start + EPSILON;
+                // Need to add a tolerance because range is exclusive
+                let t_min = t_range.start + tolerance::at_scale(t_range.start);
                 let ray_start = ray.at(t_min);
                 let ray_end = ray.at(t_max);
 
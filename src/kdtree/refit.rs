@@ -0,0 +1,271 @@
+//! Refitting the k-d tree of a scene after only the transforms of its nodes have changed
+//!
+//! Building a k-d tree means partitioning the nodes of the scene over and over again. When only
+//! the transforms of the nodes change between renders (e.g. the frames of an animation or small
+//! interactive tweaks), the separating planes of the previous tree are usually still good enough,
+//! so only the bounding boxes need to be updated.
+
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+
+use crate::math::EPSILON;
+use crate::bounding_box::Bounds;
+use crate::flat_scene::{FlatScene, FlatSceneNode};
+
+use super::{KDTreeNode, KDTreeScene, KDLeaf, NodeBounds, Partition, partition_node, build_tree};
+
+/// The largest ratio between the estimated cost of a refitted tree and the cost of the tree when
+/// it was last built before the tree is rebuilt instead of refitted
+const MAX_REFIT_COST_RATIO: f64 = 1.5;
+
+/// How `KDTreeCache` got the k-d tree of the last scene it was given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeUpdate {
+    /// The tree was built from scratch since nothing was cached yet
+    Built,
+    /// The bounding boxes of the cached tree were updated to fit the new scene
+    Refit,
+    /// The cached tree could not be refitted (or would have been too slow to trace rays through
+    /// after refitting), so a new tree was built
+    Rebuilt,
+}
+
+/// Keeps the k-d tree of the last scene rendered so that it can be refitted instead of rebuilt
+/// when only the transforms of the nodes in the scene change between renders
+///
+/// A tree can only be refitted if the flattened scene has the same number of nodes with geometry
+/// as the last scene. Nodes are matched up in the order they are found in the scene. The tree is
+/// rebuilt whenever a node moves across one of the separating planes of the tree or the estimated
+/// cost of tracing rays through the refitted tree grows too much since the tree was last built.
+///
+/// Pass the same cache to every render with `ImageSliceMut::with_kdtree_cache`.
+#[derive(Debug, Default)]
+pub struct KDTreeCache {
+    cached: Mutex<Option<CachedTree>>,
+}
+
+#[derive(Debug)]
+struct CachedTree {
+    scene: Arc<KDTreeScene>,
+    /// The nodes in the tree, in the same order as the flattened scene they came from
+    nodes: Vec<Arc<NodeBounds<FlatSceneNode>>>,
+    /// The estimated cost of the tree when it was last built (see `KDTreeNode::cost`)
+    built_cost: f64,
+    last_update: TreeUpdate,
+}
+
+impl KDTreeCache {
+    /// Creates an empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns how the k-d tree of the last scene was found, or None if no scene has been given
+    /// to this cache yet
+    pub fn last_update(&self) -> Option<TreeUpdate> {
+        self.cached.lock().expect("bug: k-d tree cache lock was poisoned").as_ref()
+            .map(|cached| cached.last_update)
+    }
+
+    /// Returns a k-d tree of the given scene, refitting the cached tree if possible
+    pub(crate) fn update(&self, flat_scene: FlatScene) -> Arc<KDTreeScene> {
+        let mut cached = self.cached.lock().expect("bug: k-d tree cache lock was poisoned");
+
+        let FlatScene {root: flat_nodes, lights, ambient} = flat_scene;
+        let nodes: Vec<_> = flat_nodes.into_iter()
+            .map(|node| Arc::new(NodeBounds::from(node)))
+            .collect();
+
+        let refitted = cached.as_ref()
+            .filter(|prev| prev.nodes.len() == nodes.len())
+            .and_then(|prev| {
+                let replacements = prev.nodes.iter().zip(&nodes)
+                    .map(|(prev_node, node)| (Arc::as_ptr(prev_node), node.clone()))
+                    .collect();
+                let root = prev.scene.root.refitted(&replacements)?;
+                if root.cost() > prev.built_cost * MAX_REFIT_COST_RATIO {
+                    return None;
+                }
+                Some((root, prev.built_cost))
+            });
+
+        let (root, built_cost, last_update) = match refitted {
+            Some((root, built_cost)) => (root, built_cost, TreeUpdate::Refit),
+            None => {
+                let root = build_tree(nodes.clone());
+                let cost = root.cost();
+                let update = if cached.is_some() { TreeUpdate::Rebuilt } else { TreeUpdate::Built };
+                (root, cost, update)
+            },
+        };
+
+        let scene = Arc::new(KDTreeScene {root, lights, ambient});
+        *cached = Some(CachedTree {scene: scene.clone(), nodes, built_cost, last_update});
+        scene
+    }
+}
+
+impl<T> KDTreeNode<T> {
+    /// Returns the estimated cost of casting a ray through this tree
+    ///
+    /// Uses the surface area heuristic: the chance that a ray passing through the tree also passes
+    /// through a leaf is the ratio of their surface areas, and every node in the leaf has to be
+    /// tested when it does. Since the cost is relative to the size of the whole tree, trees of
+    /// scenes at different scales can be compared.
+    pub(in super) fn cost(&self) -> f64 {
+        fn leaf_cost<T>(tree: &KDTreeNode<T>) -> f64 {
+            use KDTreeNode::*;
+            match tree {
+                Split {front_nodes, back_nodes, ..} => leaf_cost(front_nodes) + leaf_cost(back_nodes),
+                Leaf(KDLeaf {bounds, nodes}) => bounds.surface_area() * nodes.len() as f64,
+            }
+        }
+
+        leaf_cost(self) / self.bounds().surface_area().max(EPSILON)
+    }
+
+    /// Returns a copy of this tree with the same separating planes, but with every node replaced
+    /// by the node it maps to and every bounding box recomputed to fit the replaced nodes
+    ///
+    /// Much cheaper than building a new tree when only the transforms of the nodes have changed.
+    /// Returns None if a replaced node is no longer entirely on the side(s) of a separating plane
+    /// that the original node was put on, since hits on that node would then be missed. The tree
+    /// must be rebuilt in that case. Panics if a node in this tree has no replacement.
+    pub(in super) fn refitted(&self, replacements: &HashMap<*const NodeBounds<T>, Arc<NodeBounds<T>>>) -> Option<Self> {
+        self.refitted_impl(replacements).map(|(tree, _)| tree)
+    }
+
+    /// Returns the refitted tree along with the unique replaced nodes in it
+    fn refitted_impl(
+        &self,
+        replacements: &HashMap<*const NodeBounds<T>, Arc<NodeBounds<T>>>,
+    ) -> Option<(Self, Vec<Arc<NodeBounds<T>>>)> {
+        use KDTreeNode::*;
+        match self {
+            Leaf(KDLeaf {nodes, ..}) => {
+                let nodes: Vec<_> = nodes.iter()
+                    .map(|node| replacements.get(&Arc::as_ptr(node)).expect("bug: no replacement for node in k-d tree").clone())
+                    .collect();
+                let leaf = KDLeaf {bounds: nodes.bounds(), nodes: nodes.clone()};
+                Some((Leaf(leaf), nodes))
+            },
+            Split {sep_plane, front_nodes, back_nodes, ..} => {
+                let (front_tree, front) = front_nodes.refitted_impl(replacements)?;
+                let (back_tree, back) = back_nodes.refitted_impl(replacements)?;
+
+                // Nodes that were only put on one side of the plane must still be entirely on that
+                // side. Nodes on both sides can go anywhere.
+                let front_ptrs: HashSet<_> = front.iter().map(Arc::as_ptr).collect();
+                let back_ptrs: HashSet<_> = back.iter().map(Arc::as_ptr).collect();
+                let front_only_moved = front.iter().any(|node| !back_ptrs.contains(&Arc::as_ptr(node))
+                    && partition_node(node, sep_plane) != Partition::Front);
+                let back_only_moved = back.iter().any(|node| !front_ptrs.contains(&Arc::as_ptr(node))
+                    && partition_node(node, sep_plane) != Partition::Back);
+                if front_only_moved || back_only_moved {
+                    return None;
+                }
+
+                let mut nodes = front;
+                nodes.extend(back.into_iter().filter(|node| !front_ptrs.contains(&Arc::as_ptr(node))));
+                let tree = Split {
+                    sep_plane: sep_plane.clone(),
+                    bounds: nodes.bounds(),
+                    front_nodes: Box::new(front_tree),
+                    back_nodes: Box::new(back_tree),
+                };
+                Some((tree, nodes))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::math::{Mat4, Vec3, Rgb};
+    use crate::ray::{Ray, RayCast};
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::material::Material;
+    use crate::primitive::{Plane, Sphere};
+    use crate::primitive::InfinitePlane;
+
+    /// A row of spheres along x with the given offset added to the position of each one
+    fn spheres(offsets: &[(f64, f64, f64)]) -> FlatScene {
+        let mat = Arc::new(Material {diffuse: Rgb::red(), ..Material::default()});
+        let children = offsets.iter().enumerate().map(|(i, &(x, y, z))| {
+            Arc::new(SceneNode::from(Geometry::new(Sphere, mat.clone()))
+                .scaled(0.5).translated((i as f64 * 3.0 + x, y, z)))
+        }).collect::<Vec<_>>();
+
+        FlatScene::from(&HierScene {
+            root: Arc::new(SceneNode::from(children)),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        })
+    }
+
+    #[test]
+    fn refit_updates_bounds_and_keeps_planes() {
+        let mat = Arc::new(Material::default());
+        let make_node_bounds = |x, y| {
+            let node = FlatSceneNode::new(Geometry::new(Plane, mat.clone()),
+                Mat4::rotation_z(90.0f64.to_radians()).translated_3d((x, y, 0.0)));
+            Arc::new(NodeBounds {bounds: node.bounds(), node})
+        };
+
+        let xs = [-8.0, -5.0, 3.0, 5.0, 8.0];
+        let nodes: Vec<_> = xs.iter().map(|&x| make_node_bounds(x, 0.0)).collect();
+        let tree = build_tree(nodes.clone());
+
+        // Moving every node along y keeps them on the same side of the plane at x = 0
+        let moved: Vec<_> = xs.iter().map(|&x| make_node_bounds(x, 2.0)).collect();
+        let replacements = nodes.iter().zip(&moved)
+            .map(|(node, moved)| (Arc::as_ptr(node), moved.clone()))
+            .collect();
+        let refitted = tree.refitted(&replacements).expect("tree should be refitted");
+        assert_eq!(refitted.bounds(), &moved.bounds());
+        match (&tree, &refitted) {
+            (KDTreeNode::Split {sep_plane, ..}, KDTreeNode::Split {sep_plane: refit_plane, ..}) => {
+                assert_eq!(sep_plane, refit_plane);
+                assert_eq!(sep_plane, &InfinitePlane {normal: Vec3::unit_x(), point: Vec3::zero()});
+            },
+            _ => panic!("expected the root of both trees to be split"),
+        }
+
+        // Moving a node across the plane means that the tree can no longer be used
+        let crossed: Vec<_> = xs.iter().map(|&x| make_node_bounds(if x == 3.0 { -3.0 } else { x }, 0.0)).collect();
+        let replacements = nodes.iter().zip(&crossed)
+            .map(|(node, crossed)| (Arc::as_ptr(node), crossed.clone()))
+            .collect();
+        assert!(tree.refitted(&replacements).is_none());
+    }
+
+    #[test]
+    fn cache_refits_or_rebuilds() {
+        let cache = KDTreeCache::new();
+        assert_eq!(cache.last_update(), None);
+
+        cache.update(spheres(&[(0.0, 0.0, 0.0); 6]));
+        assert_eq!(cache.last_update(), Some(TreeUpdate::Built));
+
+        // Small movements can be refitted and the refitted tree still finds the moved spheres
+        let scene = cache.update(spheres(&[(0.0, 0.2, 0.0); 6]));
+        assert_eq!(cache.last_update(), Some(TreeUpdate::Refit));
+        let ray = Ray::new(Vec3::new(6.0, 0.6, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let (hit, _) = scene.root.ray_cast(&ray, &mut ray.t_range()).expect("ray should hit sphere");
+        let (expected, _) = KDTreeScene::from(spheres(&[(0.0, 0.2, 0.0); 6])).root
+            .ray_cast(&ray, &mut ray.t_range()).expect("ray should hit sphere");
+        assert_eq!(hit.hit_point, expected.hit_point);
+
+        // Moving a sphere to the other end of the row is too much
+        let mut offsets = [(0.0, 0.0, 0.0); 6];
+        offsets[0].0 = 20.0;
+        cache.update(spheres(&offsets));
+        assert_eq!(cache.last_update(), Some(TreeUpdate::Rebuilt));
+
+        // So is adding another sphere
+        cache.update(spheres(&[(0.0, 0.0, 0.0); 7]));
+        assert_eq!(cache.last_update(), Some(TreeUpdate::Rebuilt));
+    }
+}
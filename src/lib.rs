@@ -1,3 +1,9 @@
+// The INFINITY constant is re-exported from the math module so it can be imported alongside
+// EPSILON and the other math constants
+#![allow(clippy::legacy_numeric_constants)]
+// `is_multiple_of` is too recent to use without raising the minimum supported Rust version
+#![allow(clippy::manual_is_multiple_of)]
+
 pub mod math;
 pub mod ray;
 pub mod light;
@@ -9,6 +15,26 @@ pub mod render;
 pub mod texture;
 pub mod reporter;
 pub mod kdtree;
+pub mod stats;
+pub mod staging;
+pub mod decal;
+pub mod terrain;
+pub mod bake;
+pub mod shade_cache;
+pub mod pixel_order;
+pub mod sequence;
+pub mod compare;
+pub mod shadow_map;
+pub mod validate;
+pub mod overlay;
+pub mod edit;
+pub mod voxel;
+pub mod error;
+pub mod aov;
+pub mod upsample;
+pub mod testscenes;
+pub mod animation;
+pub mod fog;
 
 mod flat_scene;
 mod bounding_box;
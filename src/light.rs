@@ -1,6 +1,13 @@
+use std::sync::Arc;
+use std::ops::Range;
+use std::f64::consts::PI;
+
 use rand::Rng;
 
-use crate::math::{Vec3, Rgb};
+use crate::math::{EPSILON, Vec2, Vec3, Rgb, Uv, Radians, OrthonormalBasis, sample_square};
+use crate::texture::{Texture, TextureSource};
+use crate::ray::Ray;
+use crate::shadow_map::ShadowMap;
 
 /// The light "fall off" value, used for attenuation
 ///
@@ -58,18 +65,156 @@ impl Parallelogram {
         self.a.cross(self.b)
     }
 
+    /// Returns the coordinates (between -1 and 1 if inside) of the given offset from the center
+    /// of the parallelogram in terms of the basis vectors a and b
+    ///
+    /// The offset is assumed to be on the plane of the parallelogram.
+    fn coordinates(&self, offset: Vec3) -> (f64, f64) {
+        let Parallelogram {a, b} = *self;
+
+        // Solving offset = a_coord * a + b_coord * b using the dot product with a and b since
+        // a and b are not necessarily perpendicular
+        let a_dot_a = a.dot(a);
+        let b_dot_b = b.dot(b);
+        let a_dot_b = a.dot(b);
+        let offset_dot_a = offset.dot(a);
+        let offset_dot_b = offset.dot(b);
+        let det = a_dot_a * b_dot_b - a_dot_b * a_dot_b;
+
+        let a_coord = (offset_dot_a * b_dot_b - offset_dot_b * a_dot_b) / det;
+        let b_coord = (offset_dot_b * a_dot_a - offset_dot_a * a_dot_b) / det;
+        (a_coord, b_coord)
+    }
+
     /// Sample a random point within the parallelogram
     pub fn sample_point<R: Rng>(&self, mut rng: R) -> Vec3 {
         let Parallelogram {a, b} = *self;
 
         // Compute two coordinates between -1 and 1
-        let a_coord = 2.0 * rng.gen::<f64>() - 1.0;
-        let b_coord = 2.0 * rng.gen::<f64>() - 1.0;
+        let Vec2 {x: a_coord, y: b_coord} = sample_square(&mut rng);
 
         a_coord * a + b_coord * b
     }
 }
 
+/// A texture projected from the light in order to pattern its emission (also known as a "gobo")
+///
+/// The texture is projected onto an imaginary image plane in front of the light, similar to how
+/// the camera projects the image onto the scene. Light is only emitted in directions that pass
+/// through the texture. This can be used to produce patterned light (e.g. window frame shadows)
+/// without adding any extra geometry to the scene.
+#[derive(Debug, Clone)]
+pub struct Gobo {
+    /// The texture used to modulate the emitted light
+    pub texture: Arc<Texture>,
+    /// The direction that the center of the texture is projected towards
+    pub direction: Vec3,
+    /// The "up" direction of the texture (towards v = 0.0)
+    pub up: Vec3,
+    /// The field-of-view angle along the vertical axis of the texture. The texture is assumed to
+    /// be square, so this is also the angle along the horizontal axis.
+    pub fov: Radians,
+}
+
+impl Gobo {
+    /// Returns the factor that the light emitted in the given direction (not necessarily
+    /// normalized) should be multiplied by
+    pub fn at_direction(&self, dir: Vec3) -> Rgb {
+        // Create an orthonormal basis from the projection direction. If the up direction is
+        // parallel to it, the rotation of the texture around the direction is arbitrary.
+        let forward = self.direction.normalized();
+        let right = forward.cross(self.up);
+        let right = if right.magnitude_squared() > EPSILON * EPSILON {
+            right.normalized()
+        } else {
+            OrthonormalBasis::from_normal(forward).tangent
+        };
+        let up = right.cross(forward);
+
+        // No light is emitted behind the projection
+        let dist = dir.dot(forward);
+        if dist <= 0.0 {
+            return Rgb::black();
+        }
+
+        // Find the coordinate on the image plane 1.0 unit ahead of the light (between -1 and 1)
+        let fov_factor = (self.fov.get() / 2.0).tan();
+        let x = dir.dot(right) / dist / fov_factor;
+        let y = dir.dot(up) / dist / fov_factor;
+        if !(-1.0..=1.0).contains(&x) || !(-1.0..=1.0).contains(&y) {
+            return Rgb::black();
+        }
+
+        // Map to texture coordinates with +u to the right and +v down
+        self.texture.at(Uv {
+            u: (x + 1.0) / 2.0,
+            v: (1.0 - y) / 2.0,
+        })
+    }
+}
+
+/// A procedural pattern of bright, moving lines that a light projects onto everything underneath
+/// a horizontal water surface
+///
+/// This is a cheap stand-in for real caustics (the light focused by the waves of the water). Only
+/// the light reaching points below the surface is changed, so underwater floors look plausible
+/// without tracing any light through the water. Advance `time` between the frames of an
+/// animation to make the pattern move.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caustics {
+    /// The height (y) of the water surface in world space
+    pub surface_height: f64,
+    /// The approximate size (in world space) of one cell of the pattern
+    pub scale: f64,
+    /// How much the pattern changes the light, from 0.0 (no change) to 1.0 (the light varies
+    /// between black and twice as bright)
+    pub strength: f64,
+    /// The current time in seconds
+    pub time: f64,
+    /// How fast the pattern moves (in cells per second)
+    pub speed: f64,
+}
+
+impl Default for Caustics {
+    fn default() -> Self {
+        Self {
+            surface_height: 0.0,
+            scale: 1.0,
+            strength: 0.5,
+            time: 0.0,
+            speed: 0.2,
+        }
+    }
+}
+
+impl Caustics {
+    /// Returns the factor that the light travelling from the given position on the light to the
+    /// given point should be multiplied by
+    ///
+    /// Points that are not underneath the surface (as seen from the light) are unaffected.
+    pub fn factor_at(&self, light_pos: Vec3, point: Vec3) -> f64 {
+        if light_pos.y <= self.surface_height || point.y >= self.surface_height {
+            return 1.0;
+        }
+
+        // Find where the light crossed the water surface and look up the pattern there
+        let t = (self.surface_height - light_pos.y) / (point.y - light_pos.y);
+        let surface_point = light_pos + (point - light_pos) * t;
+
+        let phase = self.time * self.speed * 2.0 * PI;
+        let x = surface_point.x / self.scale * 2.0 * PI;
+        let z = surface_point.z / self.scale * 2.0 * PI;
+        // A few overlapping waves travelling in different directions
+        let waves = ((x + phase).sin()
+            + (z * 1.3 + x * 0.4 - phase * 0.8).sin()
+            + ((x - z) * 0.7 + phase * 1.1).sin()) / 3.0;
+        // Bright lines where the waves cancel out
+        let lines = (1.0 - waves.abs()).powi(8);
+
+        1.0 - self.strength + 2.0 * self.strength * lines
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Light {
     /// The position of the center of the light
@@ -81,11 +226,274 @@ pub struct Light {
     /// The area of the light. If zero, the light is a point light. If non-zero, this area will be
     /// used to sample random points on the light and soften shadows.
     pub area: Parallelogram,
+    /// A texture used to pattern the light emitted in each direction (if any)
+    pub gobo: Option<Gobo>,
+    /// An image displayed across the area of the light (if any), e.g. to model a screen or an
+    /// emissive display
+    ///
+    /// The color emitted from each point of the area is multiplied by the texture at that point,
+    /// with u increasing along `area.a` and v increasing along `area.b`. Since points are sampled
+    /// uniformly across the area, the image is also what lights the scene. Ignored for point
+    /// lights.
+    pub texture: Option<Arc<Texture>>,
+    /// The direction that all of the light from the area travels in (if any), e.g. sunlight
+    /// shining through a stained glass window
+    ///
+    /// Each point in the scene is only lit through the single point of the area found by looking
+    /// back along this direction, so the texture is projected sharply onto the scene instead of
+    /// being blurred into a single average color. Points that the area is not in front of (when
+    /// looking back along this direction) get no light. Need not be normalized. Ignored for point
+    /// lights.
+    pub beam: Option<Vec3>,
+    /// If true and the light has a non-zero area, the light will be directly visible to camera
+    /// and reflection rays. Rays that hit the light will see the color emitted by the light.
+    ///
+    /// The light still does not cast any shadows.
+    pub visible: bool,
+    /// If provided, shadows from this light are looked up in this shadow map instead of being
+    /// found by casting shadow rays
+    ///
+    /// Much faster, but only approximate (see the `shadow_map` module), so this is meant for
+    /// preview renders.
+    pub shadow_map: Option<Arc<ShadowMap>>,
+    /// A caustic pattern projected under a water surface (if any)
+    pub caustics: Option<Caustics>,
 }
 
 impl Light {
+    /// Returns the color and intensity of the light emitted in the given direction (from the
+    /// light towards some point in the scene)
+    pub fn color_towards(&self, dir: Vec3) -> Rgb {
+        match &self.gobo {
+            None => self.color,
+            Some(gobo) => self.color * gobo.at_direction(dir),
+        }
+    }
+
+    /// Returns the color and intensity of the light emitted from the given point on the light in
+    /// the given direction (from the light towards some point in the scene)
+    ///
+    /// The point is assumed to be on the area of the light (e.g. from `sample_position`).
+    pub fn color_at(&self, point: Vec3, dir: Vec3) -> Rgb {
+        let color = self.color_towards(dir);
+        match &self.texture {
+            Some(texture) if !self.area.is_empty() => {
+                let (a_coord, b_coord) = self.area.coordinates(point - self.position);
+                color * texture.at(Uv {
+                    u: ((a_coord + 1.0) / 2.0).clamp(0.0, 1.0),
+                    v: ((b_coord + 1.0) / 2.0).clamp(0.0, 1.0),
+                })
+            },
+            _ => color,
+        }
+    }
+
+    /// Returns the factor that the light travelling from the given position on the light to the
+    /// given point should be multiplied by due to caustics (see `Caustics`)
+    pub fn caustics_at(&self, light_pos: Vec3, point: Vec3) -> f64 {
+        match &self.caustics {
+            Some(caustics) => caustics.factor_at(light_pos, point),
+            None => 1.0,
+        }
+    }
+
+    /// Returns the ray parameter value for which the given ray hits the area of this light, or
+    /// None if the light is not visible or if the ray does not hit it in the given range
+    pub fn ray_hit(&self, ray: &Ray, t_range: &Range<f64>) -> Option<f64> {
+        if !self.visible || self.area.is_empty() {
+            return None;
+        }
+
+        // Intersect with the plane of the parallelogram (lights are two-sided)
+        let normal = self.area.normal();
+        let t = (self.position - ray.origin()).dot(normal) / ray.direction().dot(normal);
+        if !t_range.contains(&t) {
+            return None;
+        }
+
+        let (a_coord, b_coord) = self.area.coordinates(ray.at(t) - self.position);
+        if (-1.0..=1.0).contains(&a_coord) && (-1.0..=1.0).contains(&b_coord) {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    /// Returns an upper bound on the intensity (in any color channel) of the light that can reach
+    /// the given point after it is attenuated
+    ///
+    /// Assumes that the falloff never decreases with distance and that gobos and textures never
+    /// brighten the light.
+    pub fn max_intensity_at(&self, point: Vec3) -> f64 {
+        // The closest that any point on the area of the light could be to the given point
+        let extent = self.area.a.magnitude() + self.area.b.magnitude();
+        let min_dist = ((point - self.position).magnitude() - extent).max(0.0);
+        // Caustics can focus up to 1.0 + strength times as much light onto a point
+        let max_caustics = self.caustics.as_ref().map(|caustics| 1.0 + caustics.strength.max(0.0)).unwrap_or(1.0);
+
+        self.color.reduce_partial_max() * max_caustics / self.falloff.at_distance(min_dist)
+    }
+
     /// Return a random position within the area of the light
     pub fn sample_position<R: Rng>(&self, rng: R) -> Vec3 {
         self.position + self.area.sample_point(rng)
     }
+
+    /// Returns a position on the light that the given point receives light from, or None if no
+    /// light from this light can reach the given point
+    ///
+    /// Point lights always return their position. Area lights with a `beam` return the point on
+    /// the area that is directly back along the beam from the given point. Other area lights
+    /// return a random position within their area (see `sample_position`).
+    pub fn sample_position_towards<R: Rng>(&self, point: Vec3, rng: R) -> Option<Vec3> {
+        if self.area.is_empty() {
+            return Some(self.position);
+        }
+
+        let beam = match self.beam {
+            Some(beam) => beam,
+            None => return Some(self.sample_position(rng)),
+        };
+
+        // Intersect the ray going back along the beam with the plane of the area
+        let normal = self.area.normal();
+        let beam_normal = beam.dot(normal);
+        if beam_normal.abs() < EPSILON {
+            return None;
+        }
+        let t = (point - self.position).dot(normal) / beam_normal;
+        if t <= 0.0 {
+            return None;
+        }
+        let light_pos = point - beam * t;
+
+        let (a_coord, b_coord) = self.area.coordinates(light_pos - self.position);
+        if (-1.0..=1.0).contains(&a_coord) && (-1.0..=1.0).contains(&b_coord) {
+            Some(light_pos)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gobo_pointing_along_its_up_direction_still_projects() {
+        let center = Texture::from(|uv: Uv| if (uv.u - 0.5).abs() < 0.1 && (uv.v - 0.5).abs() < 0.1 { Rgb::white() } else { Rgb::red() });
+        let gobo = Gobo {
+            texture: Arc::new(center),
+            direction: Vec3::down(),
+            up: Vec3::up(),
+            fov: Radians::from_degrees(90.0),
+        };
+
+        assert_eq!(gobo.at_direction(Vec3::down()), Rgb::white());
+        assert_eq!(gobo.at_direction(Vec3::new(0.5, -1.0, 0.5)), Rgb::red());
+        assert_eq!(gobo.at_direction(Vec3::up()), Rgb::black());
+    }
+
+    #[test]
+    fn caustics_only_affect_points_under_water() {
+        let caustics = Caustics {surface_height: 1.0, strength: 1.0, ..Caustics::default()};
+        let light_pos = Vec3::new(0.0, 10.0, 0.0);
+
+        // Above the water
+        assert_eq!(caustics.factor_at(light_pos, Vec3::new(0.3, 2.0, 0.7)), 1.0);
+        // Lights under the water are not focused by it
+        assert_eq!(caustics.factor_at(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.3, -2.0, 0.7)), 1.0);
+
+        // Under the water, the light varies within the range allowed by the strength
+        let factors: Vec<_> = (0..100).map(|i| {
+            let point = Vec3::new(i as f64 * 0.137, -1.0, i as f64 * 0.071);
+            caustics.factor_at(light_pos, point)
+        }).collect();
+        assert!(factors.iter().all(|&factor| (0.0..=2.0).contains(&factor)));
+        assert!(factors.iter().any(|&factor| factor > 1.0));
+        assert!(factors.iter().any(|&factor| factor < 1.0));
+
+        // The pattern moves over time
+        let point = Vec3::new(0.4, -1.0, 0.2);
+        let later = Caustics {time: 1.0, ..caustics.clone()};
+        assert_ne!(caustics.factor_at(light_pos, point), later.factor_at(light_pos, point));
+    }
+
+    #[test]
+    fn textured_light_emits_its_image() {
+        // Red on the left half of the screen and blue on the right half
+        let screen = Texture::from(|uv: Uv| if uv.u < 0.5 { Rgb::red() } else { Rgb::blue() });
+        let light = Light {
+            position: Vec3::new(0.0, 1.0, 0.0),
+            color: Rgb::white() * 2.0,
+            area: Parallelogram {a: Vec3::unit_x(), b: Vec3::unit_z()},
+            texture: Some(Arc::new(screen)),
+            visible: true,
+            ..Light::default()
+        };
+
+        assert_eq!(light.color_at(Vec3::new(-0.5, 1.0, 0.3), Vec3::down()), Rgb::red() * 2.0);
+        assert_eq!(light.color_at(Vec3::new(0.5, 1.0, -0.3), Vec3::down()), Rgb::blue() * 2.0);
+
+        // Camera rays that hit the screen see the image
+        let ray = Ray::new(Vec3::new(0.7, 0.0, 0.0), Vec3::up());
+        let t = light.ray_hit(&ray, &(0.0..f64::INFINITY)).unwrap();
+        assert_eq!(light.color_at(ray.at(t), ray.direction()), Rgb::blue() * 2.0);
+
+        // Point lights ignore the texture
+        let point_light = Light {area: Parallelogram::default(), ..light};
+        assert_eq!(point_light.color_at(point_light.position, Vec3::down()), Rgb::white() * 2.0);
+    }
+
+    #[test]
+    fn beams_project_the_texture_sharply() {
+        // A window in the wall at x = 0 with sunlight shining down through it at 45 degrees
+        let glass = Texture::from(|uv: Uv| if uv.u < 0.5 { Rgb::red() } else { Rgb::blue() });
+        let window = Light {
+            position: Vec3::new(0.0, 2.0, 0.0),
+            color: Rgb::white(),
+            area: Parallelogram {a: Vec3::unit_z(), b: Vec3::unit_y()},
+            texture: Some(Arc::new(glass)),
+            beam: Some(Vec3::new(1.0, -1.0, 0.0)),
+            ..Light::default()
+        };
+        let mut rng = rand::thread_rng();
+
+        // The floor two units in front of the wall is lit through the window, every time through
+        // the same point
+        let mut lit = |z| {
+            let point = Vec3::new(2.0, 0.0, z);
+            let light_pos = window.sample_position_towards(point, &mut rng).unwrap();
+            assert!((light_pos - Vec3::new(0.0, 2.0, z)).magnitude() < EPSILON);
+            window.color_at(light_pos, (point - light_pos).normalized())
+        };
+        assert_eq!(lit(-0.5), Rgb::red());
+        assert_eq!(lit(0.5), Rgb::blue());
+
+        // Points outside of the beam and behind the window get no light
+        assert_eq!(window.sample_position_towards(Vec3::new(5.0, 0.0, 0.0), &mut rng), None);
+        assert_eq!(window.sample_position_towards(Vec3::new(2.0, 0.0, 3.0), &mut rng), None);
+        assert_eq!(window.sample_position_towards(Vec3::new(-2.0, 4.0, 0.0), &mut rng), None);
+    }
+
+    #[test]
+    fn max_intensity_bounds_attenuated_light() {
+        let light = Light {
+            position: Vec3::zero(),
+            color: Rgb {r: 0.5, g: 2.0, b: 1.0},
+            falloff: Falloff {c0: 1.0, c1: 0.0, c2: 1.0},
+            area: Parallelogram {a: Vec3::unit_x(), b: Vec3::unit_z()},
+            ..Light::default()
+        };
+
+        // Points within reach of the area are bounded by the unattenuated intensity
+        assert_eq!(light.max_intensity_at(Vec3::new(1.0, 0.5, 0.0)), 2.0);
+        // The bound is never less than the light from the closest corner of the area
+        let point = Vec3::new(5.0, 0.0, 5.0);
+        let corner = Vec3::new(1.0, 0.0, 1.0);
+        let corner_intensity = 2.0 / light.falloff.at_distance((point - corner).magnitude());
+        assert!(light.max_intensity_at(point) >= corner_intensity);
+        assert!(light.max_intensity_at(point * 100.0) < 1e-4);
+    }
 }
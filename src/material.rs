@@ -1,16 +1,35 @@
-use std::ops::Range;
-use std::sync::Arc;
+use std::fmt;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
 
-use rand::{Rng, thread_rng};
+use rand::Rng;
 
-use crate::math::{EPSILON, INFINITY, Vec3, Vec2, Mat3, Uv, Rgb};
+use crate::math::{EPSILON, Vec3, Vec2, Mat3, Uv, Rgb, OrthonormalBasis, UvTransform, sample_rng, sample_square};
 use crate::scene::Scene;
 use crate::ray::{Ray, RayCast};
-use crate::texture::{Texture, NormalMap, TextureSource};
+use crate::texture::{Texture, NormalMap, TextureSource, UvChannel};
+use crate::decal::Decal;
+use crate::terrain::TerrainBlend;
+use crate::bake::RadianceProbes;
 
 /// Controls the maximum ray recursion depth
 const MAX_RECURSION_DEPTH: u32 = 10;
 
+/// Reflected and refracted rays are not cast if they would contribute less than this fraction of
+/// their color to the final color of the pixel
+///
+/// Small enough that the skipped color of a ray would change the output pixel by less than one
+/// level unless it was much brighter than white. Saves time in scenes with long chains of dim
+/// reflections (e.g. many surfaces with a low reflectivity).
+const MIN_RAY_THROUGHPUT: f64 = 1e-3;
+
+/// Lights that can contribute less than this amount (in any color channel) to a hit point are
+/// skipped without casting a shadow ray
+///
+/// Small enough that even after gamma correction, a skipped light would change the output pixel
+/// by less than one level. This mostly helps scenes with many attenuated local lights.
+const LIGHT_CULLING_THRESHOLD: f64 = 1e-6;
+
 /// Index of refraction of air
 pub const AIR_REFRACTION_INDEX: f64 = 1.00;
 /// Index of refraction of water
@@ -24,15 +43,12 @@ pub const DIAMOND_REFRACTION_INDEX: f64 = 2.42;
 
 /// Returns the direction of the transmitted / refracted ray (normalized) or None if there is
 /// total internal reflection
-fn refracted_direction(ray_dir: Vec3, normal: Vec3, refraction_index: f64) -> Option<Vec3> {
+///
+/// The ray travels from a medium with the index of refraction `eta_outside` into a medium with
+/// the index of refraction `eta`. The normal must point towards the outside medium.
+fn refracted_direction(ray_dir: Vec3, normal: Vec3, eta_outside: f64, eta: f64) -> Option<Vec3> {
     // This formula is from section 13.1 in Fundamentals of Computer Graphics, 4th Ed.
-
     // The greek letter "eta" is used for the refraction index
-    let eta = refraction_index;
-
-    // We are assuming that outside the surface is air
-    let eta_outside = AIR_REFRACTION_INDEX;
-
     let ray_dot_norm = ray_dir.dot(normal);
     let under_sqrt = 1.0 - eta_outside*eta_outside * (1.0 - ray_dot_norm*ray_dot_norm)/(eta*eta);
     if under_sqrt < 0.0 {
@@ -47,6 +63,217 @@ fn refracted_direction(ray_dir: Vec3, normal: Vec3, refraction_index: f64) -> Op
     Some(refracted_dir_1 - refracted_dir_2)
 }
 
+/// The texture coordinates available at a hit point, one for each UV channel
+#[derive(Debug, Clone, Copy)]
+struct TexCoords {
+    primary: Option<Uv>,
+    secondary: Option<Uv>,
+}
+
+impl TexCoords {
+    /// Returns the texture coordinate for the given channel or panics if the primitive does not
+    /// support that channel
+    fn get(&self, channel: UvChannel) -> Uv {
+        let tex_coord = match channel {
+            UvChannel::Primary => self.primary,
+            UvChannel::Secondary => self.secondary,
+        };
+
+        match tex_coord {
+            Some(tex_coord) => tex_coord,
+            None => panic!("Texture mapping with the {:?} UV channel is not supported for this primitive!", channel),
+        }
+    }
+}
+
+/// Samples a texture used to vary a material parameter across a surface
+fn sample_map(tex: &Texture, tex_coords: TexCoords, channel: UvChannel) -> Rgb {
+    tex.at(tex_coords.get(channel))
+}
+
+/// The distance along the reflection ray to the glossy reflection rectangle
+///
+/// This is chosen to match the scale of the glossy perturbation used before the perturbation
+/// basis was made orthonormal so that existing scenes continue to look the same.
+const GLOSSY_RECT_DISTANCE: f64 = 10.0;
+
+/// Returns the given reflection direction (MUST be normalized) randomly perturbed within a square
+/// of the given side length. The square is perpendicular to the reflection direction.
+fn glossy_direction<R: Rng>(reflect_dir: Vec3, side_length: f64, rng: &mut R) -> Vec3 {
+    // Create a basis u, v from the ideal reflection ray
+    let basis = OrthonormalBasis::from_normal(reflect_dir);
+
+    // Generate a random coordinate on the rectangle
+    let Vec2 {x: u_coord, y: v_coord} = sample_square(rng) * (side_length / 2.0);
+
+    (reflect_dir * GLOSSY_RECT_DISTANCE + u_coord*basis.tangent + v_coord*basis.bitangent).normalized()
+}
+
+/// A dielectric medium that a ray is inside of
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Medium {
+    /// The address of the material of the medium, used to identify it
+    id: usize,
+    priority: u32,
+    refraction_index: f64,
+}
+
+impl Medium {
+    fn of(material: &Material) -> Self {
+        Self {
+            id: material as *const Material as usize,
+            priority: material.priority,
+            refraction_index: material.refraction_index,
+        }
+    }
+}
+
+/// The dielectric media (e.g. glass and water) that a ray is currently inside of
+///
+/// Where the surfaces of two media overlap (e.g. water touching the inside of a glass tank), the
+/// medium with the highest `priority` wins. Surfaces of the other medium inside of it are ignored
+/// so that only the interface between the two media is rendered. The default is air.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Media {
+    /// The media in the order they were entered
+    media: Vec<Medium>,
+}
+
+impl Media {
+    /// Returns the medium that the ray is actually traveling through: the one with the highest
+    /// priority, with ties going to the most recently entered medium
+    fn current(&self) -> Option<&Medium> {
+        self.media.iter().rev().max_by_key(|medium| medium.priority)
+    }
+
+    /// Returns the index of refraction of the medium that the ray is traveling through
+    pub fn refraction_index(&self) -> f64 {
+        self.current().map(|medium| medium.refraction_index).unwrap_or(AIR_REFRACTION_INDEX)
+    }
+
+    /// Returns these media with the given material added
+    fn entered(&self, material: &Material) -> Self {
+        let mut media = self.media.clone();
+        media.push(Medium::of(material));
+        Self {media}
+    }
+
+    /// Returns these media with the given material removed (if it was entered)
+    fn exited(&self, material: &Material) -> Self {
+        let id = Medium::of(material).id;
+        let mut media = self.media.clone();
+        if let Some(index) = media.iter().rposition(|medium| medium.id == id) {
+            media.remove(index);
+        }
+        Self {media}
+    }
+
+    /// If the given surface hit should be ignored because a medium with a higher priority is
+    /// present on the other side of it, returns the media for the ray that continues past the
+    /// surface
+    ///
+    /// `entering` is true if the ray is going into the surface (against its normal).
+    pub fn skip_surface(&self, material: &Material, entering: bool) -> Option<Self> {
+        if !material.is_dielectric() {
+            return None;
+        }
+
+        let outranked = |media: &Media| media.current()
+            .map(|medium| medium.priority > material.priority)
+            .unwrap_or(false);
+        if entering {
+            if outranked(self) {
+                return Some(self.entered(material));
+            }
+        } else {
+            let exited = self.exited(material);
+            if exited != *self && outranked(&exited) {
+                return Some(exited);
+            }
+        }
+
+        None
+    }
+}
+
+/// Fades the color of a surface into a fixed color based on its distance along the ray that hit
+/// it, for stylized depth cueing (e.g. distant objects fading into a hazy sky)
+///
+/// This is not true fog: only the color of the surface is faded, so the background and any
+/// visible lights are unaffected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceFade {
+    /// The ray length at which the fade starts
+    pub near: f64,
+    /// The ray length at which the surface has completely faded into the fade color
+    pub far: f64,
+    /// The color that the surface fades into
+    pub color: Rgb,
+}
+
+impl DistanceFade {
+    /// Returns the amount of fade at the given ray length, from 0.0 (not faded) to 1.0 (entirely
+    /// the fade color)
+    pub fn amount(&self, ray_length: f64) -> f64 {
+        if ray_length <= self.near {
+            return 0.0;
+        }
+        if ray_length >= self.far {
+            return 1.0;
+        }
+
+        (ray_length - self.near) / (self.far - self.near)
+    }
+}
+
+/// Which parts of the light reflected by a surface to compute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightComponents {
+    /// Both the direct and the indirect light
+    All,
+    /// Only the direct light, without casting any reflected or refracted rays
+    Direct,
+    /// Only the indirect light, without sampling any of the lights in the scene
+    Indirect,
+}
+
+impl LightComponents {
+    fn direct(self) -> bool {
+        self != LightComponents::Indirect
+    }
+
+    fn indirect(self) -> bool {
+        self != LightComponents::Direct
+    }
+}
+
+/// The light reflected by a surface, split into the light that arrives straight from the lights
+/// in the scene and the light that arrives after bouncing off of other surfaces
+///
+/// The ambient light, lightmaps, radiance probes, the background, and any visible lights all count
+/// as direct light. The only indirect light is from reflected and refracted rays.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SplitColor {
+    pub direct: Rgb,
+    pub indirect: Rgb,
+}
+
+impl SplitColor {
+    /// Returns the light from the given components
+    pub fn select(self, components: LightComponents) -> Rgb {
+        match components {
+            LightComponents::All => self.total(),
+            LightComponents::Direct => self.direct,
+            LightComponents::Indirect => self.indirect,
+        }
+    }
+
+    /// Returns the sum of the direct and indirect light
+    pub fn total(self) -> Rgb {
+        self.direct + self.indirect
+    }
+}
+
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Material {
     /// The diffuse color and intensity of the material
@@ -55,6 +282,13 @@ pub struct Material {
     pub diffuse: Rgb,
     /// The specular reflection constant
     pub specular: Rgb,
+    /// The color and intensity of the diffuse light transmitted through the surface from lights
+    /// behind it (on the opposite side from its normal)
+    ///
+    /// Models thin surfaces like leaves, paper, and lampshades that glow when lit from behind. The
+    /// light is not refracted or blurred: a light behind the surface contributes as if it was in
+    /// front of the surface, facing the flipped normal.
+    pub translucency: Rgb,
     /// The Phong exponent (shininess)
     ///
     /// * 10 - "eggshell"
@@ -69,52 +303,380 @@ pub struct Material {
     pub reflectivity: f64,
     /// The side length of the glossy reflection rectangle
     pub glossy_side_length: f64,
+    /// The number of perturbed reflection rays to average for glossy reflection
+    ///
+    /// Values of 0 and 1 both result in a single ray. More rays result in less noise at the cost
+    /// of rendering time. Note that each reflected ray may itself hit a glossy surface and cast
+    /// more rays, so large values can get very slow.
+    pub glossy_samples: usize,
     /// The index of refraction inside the surface with this material
     ///
-    /// It is assumed that the outside of the surface has index of refraction = 1.0 (air)
+    /// The outside of the surface is assumed to be air (index of refraction = 1.0) unless the ray
+    /// is inside of another refractive surface.
     pub refraction_index: f64,
+    /// Decides which medium wins where this material overlaps with another refractive material
+    ///
+    /// Inside of a medium with a higher priority, the surfaces of this material are ignored (e.g.
+    /// a water surface inside the walls of a glass tank). Give the container a higher priority
+    /// than its contents and make the contents overlap slightly with the container to avoid gaps.
+    pub priority: u32,
     /// The texture to sample the diffuse color from
     pub texture: Option<Arc<Texture>>,
+    /// The UV channel used to sample the diffuse texture
+    pub texture_uv_channel: UvChannel,
     /// An additional transform to apply to the texture coordinate uv before sampling the texture
     ///
-    /// This can be used to tweak the UV mapping on a per material basis.
+    /// This can be used to tweak the UV mapping on a per material basis. Usually easier to set
+    /// with `with_uv_transform` than by hand.
     ///
     /// Note: this will change the sampled coordinate for both texture mapping and normal mapping.
+    /// Normal maps are rotated along with the texture coordinates.
     pub uv_trans: Mat3,
     /// The texture to sample the shading normal from
+    ///
+    /// Normal maps are always sampled with the primary UV channel since the tangent space used
+    /// to transform the sampled normals is computed from the primary texture coordinates.
     pub normals: Option<Arc<NormalMap>>,
+    /// A texture used to vary the glossy side length across the surface
+    ///
+    /// The red channel of the texture is multiplied by glossy_side_length. This allows some parts
+    /// of a surface to have blurrier reflections than others (e.g. rippled regions of water).
+    pub glossy_map: Option<Arc<Texture>>,
+    /// The UV channel used to sample the glossy map
+    pub glossy_map_uv_channel: UvChannel,
+    /// A texture used to vary the index of refraction across the surface
+    ///
+    /// The red channel of the texture is used to interpolate between the index of refraction of
+    /// air (0.0) and refraction_index (1.0).
+    pub refraction_map: Option<Arc<Texture>>,
+    /// The UV channel used to sample the refraction map
+    pub refraction_map_uv_channel: UvChannel,
+    /// Layers blended over the diffuse color based on the height and slope of the surface (e.g.
+    /// grass, rock, and snow on a hill), applied before any decals
+    pub terrain: Option<Arc<TerrainBlend>>,
+    /// Textures projected onto the surface on top of the diffuse color, applied in order
+    pub decals: Vec<Arc<Decal>>,
+    /// Pre-computed direct lighting for this surface (e.g. from `bake::bake_lightmap`)
+    ///
+    /// If provided, the lightmap is multiplied by the diffuse color instead of casting shadow
+    /// rays to every light. This is much faster, but the lighting will not change if the scene
+    /// changes and there will be no specular highlights. Reflection and refraction still work.
+    ///
+    /// Note: uv_trans is applied before sampling the lightmap, but not while baking it.
+    pub lightmap: Option<Arc<Texture>>,
+    /// The UV channel used to sample the lightmap
+    pub lightmap_uv_channel: UvChannel,
+    /// Pre-computed low-frequency lighting of the scene (e.g. from `bake::bake_radiance_probes`)
+    ///
+    /// If provided, surfaces with this material that are reached after more than `probe_depth`
+    /// bounces are shaded with only their diffuse color and the lighting from the probes. No
+    /// shadow, reflected, or refracted rays are cast from them. This is much faster and less
+    /// biased than cutting off the ray at the maximum recursion depth, at the cost of losing
+    /// detail in reflections of reflections.
+    pub radiance_probes: Option<Arc<RadianceProbes>>,
+    /// The number of bounces after which the radiance probes are used (0 means that only the
+    /// surfaces seen directly from the camera are fully shaded)
+    pub probe_depth: u32,
+    /// Fades the final color of this material into another color based on the length of the ray
+    /// that hit it
+    ///
+    /// Note: reflected and refracted rays are faded based on their own length, not the total
+    /// length of the path from the camera.
+    pub distance_fade: Option<DistanceFade>,
+    /// A name shared by related materials (e.g. "castle_stone") so that they can all be replaced
+    /// at once with `MaterialOverrides`
+    pub tag: Option<String>,
+}
+
+/// A table of replacement materials, looked up by the tag of the material being replaced
+///
+/// Useful for look development: every material with a given tag can be swapped out for a render
+/// (see `ImageSliceMut::with_material_overrides`) without editing the code that builds the scene.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialOverrides {
+    by_tag: HashMap<String, Arc<Material>>,
+}
+
+impl MaterialOverrides {
+    /// Creates an empty table that does not replace any materials
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces every material with the given tag with the given material, returning the updated
+    /// table
+    pub fn with_override<S: Into<String>>(mut self, tag: S, material: Arc<Material>) -> Self {
+        self.by_tag.insert(tag.into(), material);
+        self
+    }
+
+    /// Returns true if this table does not replace any materials
+    pub fn is_empty(&self) -> bool {
+        self.by_tag.is_empty()
+    }
+
+    /// Returns the material that should be used instead of the given material (if any)
+    pub fn get(&self, material: &Material) -> Option<&Arc<Material>> {
+        material.tag.as_ref().and_then(|tag| self.by_tag.get(tag))
+    }
 }
 
+/// A collection of shared materials where materials with identical contents are only stored once
+///
+/// Building a scene often creates the same material many times (e.g. once for every generated
+/// block of a wall). Interning those materials through a registry means that every geometry
+/// shares a single allocation. Materials can also be given names so that they can be looked up
+/// later on in the code that builds the scene.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialRegistry {
+    /// Every unique material in the registry
+    materials: Vec<Arc<Material>>,
+    by_name: HashMap<String, Arc<Material>>,
+}
+
+impl MaterialRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the material in the registry that is equal to the given material, adding the
+    /// given material if there is no such material yet
+    pub fn intern(&mut self, material: Material) -> Arc<Material> {
+        // Materials can not be hashed (they contain floats), but the number of unique materials
+        // in a scene is usually small enough that a linear search is fast enough
+        if let Some(existing) = self.materials.iter().find(|existing| ***existing == material) {
+            return existing.clone();
+        }
+
+        let material = Arc::new(material);
+        self.materials.push(material.clone());
+        material
+    }
+
+    /// Interns the given material (see `intern`) and gives it the given name, replacing any
+    /// material that previously had that name
+    pub fn insert<S: Into<String>>(&mut self, name: S, material: Material) -> Arc<Material> {
+        let material = self.intern(material);
+        self.by_name.insert(name.into(), material.clone());
+        material
+    }
+
+    /// Returns the material with the given name (if any)
+    pub fn get(&self, name: &str) -> Option<&Arc<Material>> {
+        self.by_name.get(name)
+    }
+
+    /// Returns the number of unique materials in the registry
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    /// Returns true if the registry does not contain any materials
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+}
+
+/// A way that a material reflects more light than it receives (or absorbs a negative amount)
+///
+/// The diffuse, specular, transmitted, and reflected colors of a material are added together, so
+/// unless they add up to at most 1.0, surfaces can end up brighter than the light hitting them.
+/// This is especially noticeable between mirrors, where the extra light is amplified by every
+/// bounce.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnergyViolation {
+    /// The given property has a negative value
+    Negative(&'static str),
+    /// The diffuse, specular, translucency, and reflectivity of at least one color channel add up
+    /// to the given amount, which is more than 1.0
+    ExceedsOne(f64),
+}
+
+impl fmt::Display for EnergyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use EnergyViolation::*;
+        match self {
+            Negative(property) => write!(f, "{} is negative", property),
+            ExceedsOne(total) => write!(f, "diffuse + specular + translucency + reflectivity is {:.3} (more than 1.0)", total),
+        }
+    }
+}
+
+/// The material used by geometry that was created without one
+static DEFAULT_MATERIAL: OnceLock<Arc<Material>> = OnceLock::new();
+
 impl Material {
+    /// Returns the material shared by all geometry that was created without a material (see
+    /// `Geometry::without_material`)
+    ///
+    /// This is the same as `Material::default()` until it is replaced for a particular scene with
+    /// `HierScene::with_default_material`.
+    pub fn scene_default() -> Arc<Material> {
+        DEFAULT_MATERIAL.get_or_init(|| Arc::new(Material::default())).clone()
+    }
+
+    /// Returns true if the given material is the material used by geometry that was created
+    /// without a material (see `Material::scene_default`)
+    pub fn is_scene_default(material: &Arc<Material>) -> bool {
+        DEFAULT_MATERIAL.get().is_some_and(|default| Arc::ptr_eq(default, material))
+    }
+
+    /// A matte grey material with soft highlights, like a clay model
+    ///
+    /// Useful for checking the shapes and lighting of a scene without being distracted by its
+    /// materials (see `SceneNode::with_material_override`).
+    pub fn clay() -> Self {
+        Self {
+            diffuse: Rgb {r: 0.6, g: 0.6, b: 0.6},
+            specular: Rgb {r: 0.15, g: 0.15, b: 0.15},
+            shininess: 20.0,
+            ..Self::default()
+        }
+    }
+
+    /// Sets the transform applied to texture coordinates before sampling any texture of this
+    /// material (see `uv_trans`), returning the updated material
+    pub fn with_uv_transform(mut self, transform: UvTransform) -> Self {
+        self.uv_trans = transform.into();
+        self
+    }
+
+    /// Returns every way that this material does not conserve energy (empty if it does)
+    ///
+    /// The diffuse color is ignored if the material has a texture since the texture replaces it.
+    pub fn energy_violations(&self) -> Vec<EnergyViolation> {
+        let mut violations = Vec::new();
+        if self.diffuse.iter().any(|&c| c < 0.0) {
+            violations.push(EnergyViolation::Negative("diffuse"));
+        }
+        if self.specular.iter().any(|&c| c < 0.0) {
+            violations.push(EnergyViolation::Negative("specular"));
+        }
+        if self.translucency.iter().any(|&c| c < 0.0) {
+            violations.push(EnergyViolation::Negative("translucency"));
+        }
+        if self.reflectivity < 0.0 {
+            violations.push(EnergyViolation::Negative("reflectivity"));
+        }
+
+        let total = self.energy_totals().reduce_partial_max();
+        if total > 1.0 + EPSILON {
+            violations.push(EnergyViolation::ExceedsOne(total));
+        }
+
+        violations
+    }
+
+    /// Returns a copy of this material that conserves energy
+    ///
+    /// Negative values are clamped to zero and reflectivity is clamped to at most 1.0. The
+    /// diffuse and specular colors are then scaled down in each color channel where they would
+    /// reflect more light than what is left over after the reflection. The reflectivity is kept
+    /// since mirrors usually look more correct than an overly bright diffuse color.
+    pub fn energy_conserving(&self) -> Self {
+        let reflectivity = self.reflectivity.clamp(0.0, 1.0);
+        let mut diffuse = self.diffuse.map(|c| c.max(0.0));
+        let mut specular = self.specular.map(|c| c.max(0.0));
+        let mut translucency = self.translucency.map(|c| c.max(0.0));
+
+        let remaining = 1.0 - reflectivity;
+        let local = if self.texture.is_some() { specular } else { diffuse + specular } + translucency;
+        let scale = local.map(|total| if total > remaining { remaining / total } else { 1.0 });
+        if self.texture.is_none() {
+            diffuse *= scale;
+        }
+        specular *= scale;
+        translucency *= scale;
+
+        Self {
+            diffuse,
+            specular,
+            translucency,
+            reflectivity,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the total amount of light reflected by each color channel
+    fn energy_totals(&self) -> Rgb {
+        let diffuse = if self.texture.is_some() { Rgb::black() } else { self.diffuse };
+        diffuse + self.specular + self.translucency + Rgb::from(self.reflectivity)
+    }
+
+    /// Returns true if this material refracts light
+    pub fn is_dielectric(&self) -> bool {
+        self.reflectivity > 0.0 && self.refraction_index > 0.0
+    }
+
     /// Compute the color of a ray intersection using the lighting model of this material, possibly
     /// casting further rays to simulate things like reflection/refraction/etc.
+    #[allow(clippy::too_many_arguments)]
     pub fn hit_color<R: RayCast>(
         &self,
         scene: &Scene<R>,
         background: Rgb,
         ray_dir: Vec3,
         hit_point: Vec3,
+        shadow_origin: Vec3,
+        ray_length: f64,
         normal: Vec3,
         tex_coord: Option<Uv>,
+        secondary_tex_coord: Option<Uv>,
         normal_map_transform: Option<Mat3>,
+        media: &Media,
+        throughput: f64,
         recursion_depth: u32,
     ) -> Rgb {
+        self.split_hit_color(scene, background, ray_dir, hit_point, shadow_origin, ray_length, normal,
+            tex_coord, secondary_tex_coord, normal_map_transform, media, throughput, recursion_depth,
+            LightComponents::All).total()
+    }
+
+    /// Compute the color of a ray intersection like `Material::hit_color`, but split into its
+    /// direct and indirect light (see `SplitColor`)
+    ///
+    /// Only the given components are computed. The other component is black, except for light
+    /// that can only be computed as a whole (e.g. from radiance probes), which is always direct.
+    #[allow(clippy::too_many_arguments)]
+    pub fn split_hit_color<R: RayCast>(
+        &self,
+        scene: &Scene<R>,
+        background: Rgb,
+        ray_dir: Vec3,
+        hit_point: Vec3,
+        shadow_origin: Vec3,
+        ray_length: f64,
+        normal: Vec3,
+        tex_coord: Option<Uv>,
+        secondary_tex_coord: Option<Uv>,
+        normal_map_transform: Option<Mat3>,
+        media: &Media,
+        throughput: f64,
+        recursion_depth: u32,
+        components: LightComponents,
+    ) -> SplitColor {
         if recursion_depth > MAX_RECURSION_DEPTH {
-            return background;
+            return SplitColor {direct: background, indirect: Rgb::black()};
         }
 
-        let mut rng = thread_rng();
+        let mut rng = sample_rng();
 
         // Vector from hit point to the eye (ray origin)
         // Note that this is the same as -ray.direction() since the ray intersects with the
         // hit point
         let view = -ray_dir;
 
-        // Apply any UV transformation
-        let tex_coord = tex_coord.map(|uv| {
+        // Apply any UV transformation (to every UV channel)
+        let transform_uv = |uv: Uv| {
             let uv_vec = Vec3::from_point_2d(Vec2::from(uv.into_array()));
             Uv::from(Vec2::from(self.uv_trans * uv_vec))
-        });
+        };
+        let tex_coords = TexCoords {
+            primary: tex_coord.map(transform_uv),
+            secondary: secondary_tex_coord.map(transform_uv),
+        };
+        let tex_coord = tex_coords.primary;
 
         // Surface normal of hit point
         //
@@ -126,6 +688,8 @@ impl Material {
             Some(tex) => match (tex_coord, normal_map_transform) {
                 (Some(tex_coord), Some(norm_trans)) => {
                     let tex_norm = tex.normal_at(tex_coord);
+                    // The tangent space needs to follow any rotation of the texture coordinates
+                    let norm_trans = UvTransform::from_mat3(self.uv_trans).apply_normal_map_transform(norm_trans);
                     // Need to normalize because normal from texture map may not be normalized and
                     // norm_trans may also potentially result in a non-normalized vector
                     norm_trans * tex_norm.normalized()
@@ -136,21 +700,66 @@ impl Material {
 
         let diffuse_color = match &self.texture {
             None => self.diffuse,
-            Some(tex) => match tex_coord {
-                Some(tex_coord) => tex.at(tex_coord),
-                None => panic!("Texture mapping is not supported for this primitive!"),
+            Some(tex) => sample_map(tex, tex_coords, self.texture_uv_channel),
+        };
+        let diffuse_color = match &self.terrain {
+            None => diffuse_color,
+            Some(terrain) => terrain.color_at(diffuse_color, hit_point, normal, tex_coord),
+        };
+        let diffuse_color = self.decals.iter().fold(diffuse_color, |color, decal| {
+            match decal.color_at(hit_point, normal) {
+                Some((decal_color, opacity)) => Rgb::lerp(color, decal_color, opacity),
+                None => color,
+            }
+        });
+
+        // Sample any textures that vary the reflection/refraction parameters across the surface
+        let glossy_side_length = match &self.glossy_map {
+            None => self.glossy_side_length,
+            Some(tex) => self.glossy_side_length * sample_map(tex, tex_coords, self.glossy_map_uv_channel).r,
+        };
+        let refraction_index = match &self.refraction_map {
+            None => self.refraction_index,
+            Some(tex) => {
+                let factor = sample_map(tex, tex_coords, self.refraction_map_uv_channel).r;
+                AIR_REFRACTION_INDEX + (self.refraction_index - AIR_REFRACTION_INDEX) * factor
             },
         };
 
+        // Past the probe depth, use the pre-computed lighting instead of casting any more rays
+        if let Some(probes) = &self.radiance_probes {
+            if recursion_depth > self.probe_depth {
+                let color = diffuse_color * (scene.ambient + probes.irradiance_at(hit_point, normal));
+                return SplitColor {direct: self.faded(color, ray_length), indirect: Rgb::black()};
+            }
+        }
+
         // Start with the ambient color since that is always added
         // Need to multiply by the diffuse color because the ambient light is still affected by the
         // color of the object
         let mut color = scene.ambient * diffuse_color;
-        for light in &scene.lights {
-            let light_pos = if light.area.is_empty() {
-                light.position
-            } else {
-                light.sample_position(&mut rng)
+
+        // Lightmaps already contain the lighting from every light in the scene
+        let lights = match &self.lightmap {
+            _ if !components.direct() => &[],
+            Some(lightmap) => {
+                color += diffuse_color * sample_map(lightmap, tex_coords, self.lightmap_uv_channel);
+                &[]
+            },
+            None => &scene.lights[..],
+        };
+        // The most light that the diffuse and specular terms can reflect (per unit of light)
+        let max_reflectance = (diffuse_color + self.specular + self.translucency).reduce_partial_max();
+        let is_translucent = self.translucency.iter().any(|&v| v > EPSILON);
+        for light in lights {
+            // Skip lights that are too far away (or too dim) to make a visible difference
+            if light.max_intensity_at(hit_point) * max_reflectance < LIGHT_CULLING_THRESHOLD {
+                continue;
+            }
+
+            let light_pos = match light.sample_position_towards(hit_point, &mut rng) {
+                Some(light_pos) => light_pos,
+                None => continue,
             };
 
             // Vector from hit point to the light source
@@ -171,18 +780,35 @@ impl Material {
             // Cast a ray to the light to determine if anything is between this point and the light
             // If there is something, this point must be in "shadow" since it cannot be hit by the
             // light directly.
-            let shadow_ray = Ray::new(hit_point, light_dir);
-            // The EPSILON helps avoid self-intersections (and "shadow acne")
-            let mut shadow_t_range = Range {start: EPSILON, end: INFINITY};
+            // Smooth shaded surfaces cast shadow rays from slightly above the hit point to avoid
+            // shadow terminator artifacts
+            // Light transmitted from behind the surface must not be blocked by the surface itself
+            let back_light = -normal.dot(light_dir);
+            let transmits = is_translucent && back_light > 0.0;
+            let shadow_ray = Ray::new(if transmits { hit_point } else { shadow_origin }, light_dir);
+            // Starting slightly after the hit point helps avoid self-intersections (and "shadow acne")
+            let mut shadow_t_range = shadow_ray.t_range();
+            // Anything on the other side of the light (e.g. the ceiling above a light in a room)
+            // does not cast a shadow
+            shadow_t_range.end = light_dist;
+
+            let is_shadowed = match &light.shadow_map {
+                Some(shadow_map) => shadow_map.is_shadowed(hit_point),
+                None => scene.root.ray_cast(&shadow_ray, &mut shadow_t_range).is_some(),
+            };
 
             // Only add diffuse if not shadowed by another object
-            if scene.root.ray_cast(&shadow_ray, &mut shadow_t_range).is_none() {
+            if !is_shadowed {
+                // The light emitted towards the hit point (opposite to the light direction)
+                let light_color = light.color_at(light_pos, -light_dir)
+                    * light.caustics_at(light_pos, hit_point);
+
                 // Want the max diffuse when the light is directly aligned with the surface normal.
                 // Using normal.dot(light_dir) == cos(angle between normal and light)
                 // we can accomplish this effect.
                 // Need to max with zero so we can ignore backface contributions
                 let normal_light = normal.dot(light_dir).max(0.0);
-                let diffuse = diffuse_color * light.color * normal_light;
+                let diffuse = diffuse_color * light_color * normal_light;
 
                 // Check if there is any specular component of the material. Allows us to avoid
                 // some calculations for non-specular materials.
@@ -201,46 +827,55 @@ impl Material {
                     // Source: https://learnopengl.com/Advanced-Lighting/Advanced-Lighting
                     let normal_half_shiny = normal.dot(half).max(0.0).powf(4.0 * self.shininess);
 
-                    self.specular * light.color * normal_half_shiny
+                    self.specular * light_color * normal_half_shiny
                 } else {
                     Rgb::from(0.0)
                 };
 
+                // Thin surfaces let some of the light behind them through
+                let translucent = if transmits {
+                    self.translucency * light_color * back_light
+                } else {
+                    Rgb::black()
+                };
+
                 // Attenuate light contribution before adding to the final color
-                color += (diffuse + specular) / attenuation;
+                color += (diffuse + specular + translucent) / attenuation;
             }
         }
 
+        // The fraction of the color of reflected/refracted rays that ends up in the pixel. The
+        // Fresnel reflectivity of dielectrics is at most 1.0, so this is also an upper bound for
+        // both the reflected and refracted rays of a dielectric.
+        let ray_throughput = throughput * self.reflectivity;
+
+        // The light from the reflected and refracted rays
+        let mut indirect = Rgb::black();
+
         // Check if there is any reflective component of the material.
-        // Allows us to avoid some recursion for non-reflective materials.
-        if self.reflectivity > 0.0 {
+        // Allows us to avoid some recursion for non-reflective materials and rays that would not
+        // make a visible difference.
+        if components.indirect() && self.reflectivity > 0.0 && ray_throughput >= MIN_RAY_THROUGHPUT {
             // r = v - 2N(v dot N) where v = ray direction, N = normal
-            let mut reflect_dir = ray_dir - normal * 2.0 * ray_dir.dot(normal);
-
-            // Perturb the reflection ray if we are using glossy reflection
-            if self.glossy_side_length > 0.0 {
-                // Create a basis u, v from the ideal reflection ray
-                // This is a technique for creating a basis from a single vector:
-                let offset_vector = if reflect_dir.x.abs() < EPSILON && reflect_dir.y.abs() < EPSILON {
-                    // Edge case: reflection direction is aligned with z axis, so the offset in the
-                    // else case would result in a collinear vector
-                    reflect_dir + Vec3 {x: 0.0, y: 0.1, z: 0.0}
-                } else {
-                    reflect_dir + Vec3 {x: 0.0, y: 0.0, z: 0.1}
-                };
-                let u_basis = reflect_dir.cross(offset_vector);
-                let v_basis = reflect_dir.cross(u_basis);
+            let reflect_dir = ray_dir - normal * 2.0 * ray_dir.dot(normal);
 
-                // Generate a random coordinate on the rectangle
-                let u_coord = -self.glossy_side_length / 2.0 + rng.gen::<f64>() * self.glossy_side_length;
-                let v_coord = -self.glossy_side_length / 2.0 + rng.gen::<f64>() * self.glossy_side_length;
+            // Add reflection via recursive ray tracing
+            let reflected_color = if glossy_side_length > 0.0 {
+                // Perturb the reflection ray since we are using glossy reflection. Averaging
+                // several perturbed rays reduces the noise from each individual ray.
+                let samples = self.glossy_samples.max(1);
+                let total_color = (0..samples).fold(Rgb::black(), |total_color, _| {
+                    let glossy_dir = glossy_direction(reflect_dir, glossy_side_length, &mut rng);
+                    let reflected_ray = Ray::new(hit_point, glossy_dir);
+                    total_color + reflected_ray.color_in(scene, background, media, ray_throughput, recursion_depth + 1)
+                });
 
-                reflect_dir += u_coord*u_basis + v_coord*v_basis;
-            }
+                total_color / samples as f64
 
-            // Add reflection via recursive ray tracing
-            let reflected_ray = Ray::new(hit_point, reflect_dir);
-            let reflected_color = reflected_ray.color(scene, background, recursion_depth + 1);
+            } else {
+                let reflected_ray = Ray::new(hit_point, reflect_dir);
+                reflected_ray.color_in(scene, background, media, ray_throughput, recursion_depth + 1)
+            };
 
             // This code is translated from pseudo code in Section 13.1 of
             // Fundamentals of Computer Graphics, 4th Ed.
@@ -252,45 +887,50 @@ impl Material {
                 // incident angle.
                 let refract_dir_cos_incident = if ray_dir.dot(normal) < 0.0 {
                     // Ray is going into the surface
+                    let eta_outside = media.refraction_index();
 
-                    // Refracted / transmitted ray
-                    let refract_dir = refracted_direction(ray_dir, normal, self.refraction_index)
-                        .expect("bug: should not have total internal reflection when casting inside surface");
-                    // Incident angle here is the angle between the ray and the normal. Ray is
-                    // reversed because it is currently pointing towards the surface and we want
-                    // the other angle.
-                    let cos_incident = (-ray_dir).dot(normal);
-
-                    Some((refract_dir, cos_incident))
-                } else if let Some(refract_dir) = refracted_direction(ray_dir, -normal, 1.0/self.refraction_index) {
-                    // Ray is heading outside the surface
-
-                    // Since the ray is coming from inside the surface, the light (which is on the
-                    // outside) is actually incident with the refracted ray. Note that we don't
-                    // need to flip the refracted direction in this branch because it is already
-                    // pointing away from the surface (unlike the ray direction in the previous
-                    // case).
-                    let cos_incident = refract_dir.dot(normal);
-
-                    Some((refract_dir, cos_incident))
+                    // Refracted / transmitted ray (total internal reflection is possible if the
+                    // ray is coming from a denser medium)
+                    refracted_direction(ray_dir, normal, eta_outside, refraction_index).map(|refract_dir| {
+                        // Incident angle here is the angle between the ray and the normal. Ray is
+                        // reversed because it is currently pointing towards the surface and we
+                        // want the other angle.
+                        let cos_incident = (-ray_dir).dot(normal);
+
+                        (refract_dir, cos_incident, eta_outside, media.entered(self))
+                    })
                 } else {
-                    // Total internal reflection
+                    // Ray is heading outside the surface into whichever medium is left
+                    let outside_media = media.exited(self);
+                    let eta_outside = outside_media.refraction_index();
 
-                    // Since there is only reflection, this code is the same as the reflective-only case
-                    color += self.reflectivity * reflected_color;
+                    refracted_direction(ray_dir, -normal, refraction_index, eta_outside).map(|refract_dir| {
+                        // Since the ray is coming from inside the surface, the light (which is on
+                        // the outside) is actually incident with the refracted ray. Note that we
+                        // don't need to flip the refracted direction in this branch because it is
+                        // already pointing away from the surface (unlike the ray direction in the
+                        // previous case).
+                        let cos_incident = refract_dir.dot(normal);
 
-                    // Finish processing if this is the case
-                    None
+                        (refract_dir, cos_incident, eta_outside, outside_media)
+                    })
                 };
 
+                if refract_dir_cos_incident.is_none() {
+                    // Total internal reflection
+
+                    // Since there is only reflection, this code is the same as the reflective-only case
+                    indirect += self.reflectivity * reflected_color;
+                }
+
                 // Only continue if there was not total internal reflection
-                if let Some((refract_dir, cos_incident)) = refract_dir_cos_incident {
+                if let Some((refract_dir, cos_incident, eta_outside, refracted_media)) = refract_dir_cos_incident {
                     // Compute the reflectivity using the Schlick approximation
 
                     // The reflectivity at normal incidence
-                    // r0 = (eta - 1)^2/(eta + 1)^2
-                    let r0 = (self.refraction_index - 1.0)*(self.refraction_index - 1.0);
-                    let r0 = r0 / ((self.refraction_index + 1.0)*(self.refraction_index + 1.0));
+                    // r0 = (eta - eta_outside)^2/(eta + eta_outside)^2
+                    let r0 = (refraction_index - eta_outside)*(refraction_index - eta_outside);
+                    let r0 = r0 / ((refraction_index + eta_outside)*(refraction_index + eta_outside));
                     // The reflectivity according to the approximation, distinct from the property
                     // in the material
                     let reflectivity = r0 + (1.0 - r0) * (1.0 - cos_incident).powi(5);
@@ -300,22 +940,390 @@ impl Material {
 
                     // Cast the transmitted ray and determine the color
                     let refracted_ray = Ray::new(hit_point, refract_dir);
-                    let refracted_color = refracted_ray.color(scene, background, recursion_depth + 1);
+                    let refracted_color = refracted_ray.color_in(scene, background, &refracted_media,
+                        ray_throughput, recursion_depth + 1);
 
                     // The total color uses the result of Fresnel/Schlick to mix the reflected and
                     // refracted/transmitted colors
                     let total_color = reflectivity * reflected_color + transmittance * refracted_color;
                     // Mix in the total color using the material reflectivity coefficient
-                    color += self.reflectivity * total_color;
+                    indirect += self.reflectivity * total_color;
                 }
 
             } else {
                 // Reflective-only material
 
-                color += self.reflectivity * reflected_color;
+                indirect += self.reflectivity * reflected_color;
             }
         }
 
-        color
+        if !components.direct() {
+            color = Rgb::black();
+        }
+        self.faded_split(color, indirect, ray_length)
+    }
+
+    /// Applies the distance fade of this material (if any) to the given color
+    fn faded(&self, color: Rgb, ray_length: f64) -> Rgb {
+        match &self.distance_fade {
+            Some(fade) => Rgb::lerp(color, fade.color, fade.amount(ray_length)),
+            None => color,
+        }
+    }
+
+    /// Applies the distance fade of this material (if any) to the given direct and indirect light
+    ///
+    /// The fade color is added to the direct light, so the total is the same as fading the total.
+    fn faded_split(&self, direct: Rgb, indirect: Rgb, ray_length: f64) -> SplitColor {
+        match &self.distance_fade {
+            Some(fade) => {
+                let amount = fade.amount(ray_length);
+                SplitColor {direct: Rgb::lerp(direct, fade.color, amount), indirect: indirect * (1.0 - amount)}
+            },
+            None => SplitColor {direct, indirect},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::light::{Light, Parallelogram};
+    use crate::primitive::{Cube, Plane, Mesh, MeshData, Shading};
+
+    /// The maximum error allowed when comparing traced colors to analytic expectations
+    const COLOR_TOLERANCE: f64 = 1e-9;
+
+    /// A small, visible, horizontal area light used as a target for rays
+    fn target_light(position: Vec3) -> Light {
+        Light {
+            position,
+            color: Rgb::red(),
+            area: Parallelogram {
+                a: Vec3::unit_x() * 0.05,
+                b: Vec3::unit_z() * 0.05,
+            },
+            visible: true,
+            ..Light::default()
+        }
+    }
+
+    fn scene_with_target(node: SceneNode, target: Vec3) -> HierScene {
+        HierScene {
+            root: Arc::new(SceneNode::from(vec![Arc::new(node)])),
+            lights: vec![target_light(target)],
+            ambient: Rgb::black(),
+        }
+    }
+
+    #[test]
+    fn registry_interns_identical_materials() {
+        let mut registry = MaterialRegistry::new();
+        let red = || Material {diffuse: Rgb::red(), ..Material::default()};
+
+        let first = registry.insert("red", red());
+        let blocks: Vec<_> = (0..10).map(|_| registry.intern(red())).collect();
+        assert!(blocks.iter().all(|block| Arc::ptr_eq(block, &first)));
+        let clay = registry.intern(Material::clay());
+        assert!(!Arc::ptr_eq(&clay, &first));
+
+        assert_eq!(registry.len(), 2);
+        assert!(Arc::ptr_eq(registry.get("red").unwrap(), &first));
+        assert!(registry.get("blue").is_none());
+    }
+
+    #[test]
+    fn registry_interns_materials_with_procedural_textures() {
+        let mut registry = MaterialRegistry::new();
+        let stripes = Arc::new(Texture::from(|uv: Uv| if uv.u < 0.5 { Rgb::red() } else { Rgb::white() }));
+        let striped = |texture: &Arc<Texture>| Material {texture: Some(texture.clone()), ..Material::default()};
+
+        let first = registry.intern(striped(&stripes));
+        assert!(Arc::ptr_eq(&registry.intern(striped(&stripes)), &first));
+
+        // A different function is never equal, even if it computes the same colors
+        let other_stripes = Arc::new(Texture::from(|uv: Uv| if uv.u < 0.5 { Rgb::red() } else { Rgb::white() }));
+        assert!(!Arc::ptr_eq(&registry.intern(striped(&other_stripes)), &first));
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[test]
+    fn geometry_behind_the_light_does_not_shadow() {
+        let mat = Arc::new(Material {diffuse: Rgb::from(0.5), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                // The floor and a ceiling above the light
+                Arc::new(SceneNode::from(Geometry::new(Plane, mat.clone()))),
+                Arc::new(SceneNode::from(Geometry::new(Cube, mat)).scaled((100.0, 1.0, 100.0)).translated((0.0, 5.0, 0.0))),
+            ])),
+            lights: vec![Light {position: Vec3::new(0.0, 2.0, 0.0), color: Rgb::white(), ..Light::default()}],
+            ambient: Rgb::black(),
+        };
+
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::down());
+        let color = ray.color(&scene, Rgb::black(), 0);
+        assert!((color - Rgb::from(0.5)).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE, "{:?}", color);
+    }
+
+    #[test]
+    fn textures_can_be_sampled_with_secondary_uvs() {
+        // A 2x2 quad on the ground whose UV sets cover opposite halves of the texture
+        let quad = Arc::new(MeshData::new(
+            vec![
+                Vec3::new(-1.0, 0.0, 1.0),
+                Vec3::new(1.0, 0.0, 1.0),
+                Vec3::new(1.0, 0.0, -1.0),
+                Vec3::new(-1.0, 0.0, -1.0),
+            ],
+            vec![(0, 1, 2), (0, 2, 3)],
+            Vec::new(),
+            vec![Uv::new(0.0, 0.0), Uv::new(0.4, 0.0), Uv::new(0.4, 1.0), Uv::new(0.0, 1.0)],
+        ).with_secondary_tex_coords(
+            vec![Uv::new(0.6, 0.0), Uv::new(1.0, 0.0), Uv::new(1.0, 1.0), Uv::new(0.6, 1.0)],
+        ));
+        let halves = Arc::new(Texture::from(|uv: Uv| if uv.u < 0.5 { Rgb::red() } else { Rgb::green() }));
+        let scene = |texture_uv_channel| HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Mesh::new(quad.clone(), Shading::Flat), Arc::new(Material {
+                texture: Some(halves.clone()),
+                texture_uv_channel,
+                ..Material::default()
+            })))),
+            lights: vec![Light {position: Vec3::new(0.0, 2.0, 0.0), color: Rgb::white(), ..Light::default()}],
+            ambient: Rgb::black(),
+        };
+
+        let ray = Ray::new(Vec3::new(0.3, 1.0, 0.2), Vec3::down());
+        let primary = ray.color(&scene(UvChannel::Primary), Rgb::black(), 0);
+        let secondary = ray.color(&scene(UvChannel::Secondary), Rgb::black(), 0);
+        assert!(primary.r > 0.0 && primary.g == 0.0, "{:?}", primary);
+        assert!(secondary.g > 0.0 && secondary.r == 0.0, "{:?}", secondary);
+    }
+
+    #[test]
+    fn split_color_adds_up_to_color() {
+        let mat = Arc::new(Material {
+            diffuse: Rgb::red(),
+            reflectivity: 0.5,
+            distance_fade: Some(DistanceFade {near: 0.5, far: 8.5, color: Rgb::green()}),
+            ..Material::default()
+        });
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![Arc::new(SceneNode::from(Geometry::new(Cube, mat)))])),
+            lights: vec![Light {position: Vec3::new(0.0, 3.0, 0.0), ..Light::default()}],
+            ambient: Rgb::from(0.1),
+        };
+
+        // The reflected ray misses everything and sees the background
+        let ray = Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::down());
+        let split = ray.split_color(&scene, Rgb::blue(), LightComponents::All);
+        assert!(split.direct.r > 0.0);
+        // Faded halfway
+        assert!((split.indirect - Rgb::blue() * 0.5 * 0.5).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE);
+        assert!((split.total() - ray.color(&scene, Rgb::blue(), 0)).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE);
+
+        let direct = ray.split_color(&scene, Rgb::blue(), LightComponents::Direct);
+        assert_eq!(direct, SplitColor {direct: split.direct, indirect: Rgb::black()});
+        let indirect = ray.split_color(&scene, Rgb::blue(), LightComponents::Indirect);
+        assert_eq!(indirect.select(LightComponents::Indirect), split.indirect);
+    }
+
+    #[test]
+    fn translucent_surfaces_glow_when_lit_from_behind() {
+        let leaf = |translucency| Arc::new(Material {
+            diffuse: Rgb::from(0.2),
+            translucency,
+            ..Material::default()
+        });
+        let scene = |mat, light_y| HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Plane, mat))),
+            lights: vec![Light {position: Vec3::new(0.0, light_y, 0.0), color: Rgb::white(), ..Light::default()}],
+            ambient: Rgb::black(),
+        };
+        let green = Rgb::new(0.1, 0.5, 0.1);
+        let ray = Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::down());
+
+        // Lit from behind, only the transmitted light is seen
+        let backlit = ray.color(&scene(leaf(green), -3.0), Rgb::black(), 0);
+        assert!((backlit - green).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE, "{:?}", backlit);
+        assert_eq!(ray.color(&scene(leaf(Rgb::black()), -3.0), Rgb::black(), 0), Rgb::black());
+
+        // Lit from the front, translucency adds nothing
+        let frontlit = ray.color(&scene(leaf(green), 3.0), Rgb::black(), 0);
+        assert!((frontlit - Rgb::from(0.2)).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE, "{:?}", frontlit);
+
+        // Transmitted light counts towards the energy of the material
+        let bright = Material {diffuse: Rgb::from(0.6), translucency: Rgb::from(0.6), ..Material::default()};
+        assert!(!bright.energy_violations().is_empty());
+        assert!(bright.energy_conserving().energy_violations().is_empty());
+    }
+
+    #[test]
+    fn parabolic_mirror_focuses_parallel_rays() {
+        // A parabolic dish y = r^2 / (4f) reflects every ray parallel to its axis through its
+        // focal point (0, f, 0)
+        let focal_length = 1.0;
+        let profile: Vec<_> = (0..=400).map(|i| {
+            let r = i as f64 / 400.0 * 1.5;
+            Vec2::new(r, r * r / (4.0 * focal_length))
+        }).collect();
+        let dish = Arc::new(MeshData::lathe(&profile, 256));
+        let mirror = Arc::new(Material {
+            reflectivity: 1.0,
+            ..Material::default()
+        });
+        let node = || SceneNode::from(Geometry::new(Mesh::new(dish.clone(), Shading::Smooth), mirror.clone()));
+
+        let focus = Vec3::new(0.0, focal_length, 0.0);
+        let focused = scene_with_target(node(), focus);
+        // Control: the same dish with the target away from the focal point
+        let unfocused = scene_with_target(node(), focus * 1.5);
+
+        for &(x, z) in &[(0.3, 0.0), (0.0, -0.6), (-0.9, 0.1), (0.7, 0.7)] {
+            let ray = Ray::new(Vec3::new(x, 5.0, z), Vec3::down());
+            let color = ray.color(&focused, Rgb::black(), 0);
+            assert!((color - Rgb::red()).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE,
+                "ray at ({}, {}) was not reflected through the focal point: {:?}", x, z, color);
+            assert_eq!(ray.color(&unfocused, Rgb::black(), 0), Rgb::black());
+        }
+    }
+
+    #[test]
+    fn refraction_follows_snells_law() {
+        let refraction_index = WINDOW_GLASS_REFRACTION_INDEX;
+        let glass = Arc::new(Material {
+            reflectivity: 1.0,
+            refraction_index,
+            ..Material::default()
+        });
+        // A large glass block with its top face at y = 0
+        let node = || SceneNode::from(Geometry::new(Cube, glass.clone()))
+            .scaled(10.0)
+            .translated((0.0, -5.0, 0.0));
+
+        for &incident_angle in &[0.2f64, 0.5, 0.8, 1.2] {
+            // Snell's law: sin(incident) * n_air = sin(transmitted) * n_glass
+            let transmitted_angle = (incident_angle.sin() * AIR_REFRACTION_INDEX / refraction_index).asin();
+            let incident_dir = Vec3::new(incident_angle.sin(), -incident_angle.cos(), 0.0);
+            let transmitted_dir = Vec3::new(transmitted_angle.sin(), -transmitted_angle.cos(), 0.0);
+
+            // Schlick's approximation gives the fraction of light transmitted into the glass
+            let r0 = ((refraction_index - 1.0) / (refraction_index + 1.0)).powi(2);
+            let transmittance = 1.0 - (r0 + (1.0 - r0) * (1.0 - incident_angle.cos()).powi(5));
+
+            let ray = Ray::new(-incident_dir * 5.0, incident_dir);
+
+            let refracted = scene_with_target(node(), transmitted_dir * 2.0);
+            let color = ray.color(&refracted, Rgb::black(), 0);
+            assert!((color - Rgb::red() * transmittance).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE,
+                "ray at {} radians was not refracted according to Snell's law: {:?}", incident_angle, color);
+
+            // Control: the target along the unrefracted direction should not be seen
+            let straight = scene_with_target(node(), incident_dir * 2.0);
+            assert_eq!(ray.color(&straight, Rgb::black(), 0), Rgb::black());
+        }
+    }
+
+    #[test]
+    fn dim_reflections_are_not_traced() {
+        let reflectivity = 0.02;
+        let mirror = Arc::new(Material {
+            reflectivity,
+            ..Material::default()
+        });
+        // A mirror facing up at y = 0 that reflects the ray into the target
+        let scene = scene_with_target(SceneNode::from(Geometry::new(Cube, mirror))
+            .scaled(10.0).translated((0.0, -5.0, 0.0)), Vec3::new(2.0, 2.0, 0.0));
+        let ray = Ray::new(Vec3::new(-2.0, 2.0, 0.0), Vec3::new(1.0, -1.0, 0.0).normalized());
+
+        let color = ray.color(&scene, Rgb::black(), 0);
+        assert!((color - Rgb::red() * reflectivity).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE);
+
+        // The same ray after an earlier dim reflection would contribute too little to be traced
+        let color = ray.color_in(&scene, Rgb::black(), &Media::default(), 0.04, 0);
+        assert_eq!(color, Rgb::black());
+    }
+
+    #[test]
+    fn distance_fade_blends_by_ray_length() {
+        let fade = DistanceFade {near: 2.0, far: 6.0, color: Rgb::blue()};
+        assert_eq!(fade.amount(1.0), 0.0);
+        assert_eq!(fade.amount(3.0), 0.25);
+        assert_eq!(fade.amount(10.0), 1.0);
+
+        let mat = Arc::new(Material {
+            diffuse: Rgb::red(),
+            distance_fade: Some(fade),
+            ..Material::default()
+        });
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Cube, mat))),
+            lights: Vec::new(),
+            ambient: Rgb::white(),
+        };
+
+        // The front face of the cube is at z = 0.5
+        let color_from = |z: f64| Ray::new(Vec3::new(0.0, 0.0, z), -Vec3::unit_z()).color(&scene, Rgb::black(), 0);
+        assert_eq!(color_from(1.5), Rgb::red());
+        assert!((color_from(4.5) - Rgb::lerp(Rgb::red(), Rgb::blue(), 0.5)).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE);
+        assert_eq!(color_from(20.0), Rgb::blue());
+    }
+
+    #[test]
+    fn overlapping_media_are_resolved_by_priority() {
+        let glass_index = WINDOW_GLASS_REFRACTION_INDEX;
+        let water_index = WATER_REFRACTION_INDEX;
+        let incident_angle = 0.8f64;
+        let incident_dir = Vec3::new(incident_angle.sin(), -incident_angle.cos(), 0.0);
+        let ray = Ray::new(-incident_dir * 5.0, incident_dir);
+
+        // Schlick's approximation of the fraction of light transmitted through an interface
+        let transmittance = |eta_outside: f64, eta: f64, cos_incident: f64| {
+            let r0 = ((eta - eta_outside) / (eta + eta_outside)).powi(2);
+            1.0 - (r0 + (1.0 - r0) * (1.0 - cos_incident).powi(5))
+        };
+
+        // The ray enters the glass at the origin and then reaches the top of the water at y = -1
+        let glass_angle = (incident_angle.sin() / glass_index).asin();
+        let glass_dir = Vec3::new(glass_angle.sin(), -glass_angle.cos(), 0.0);
+        let water_top = glass_dir / glass_angle.cos();
+
+        // A glass block with its top face at y = 0 filled with water up to y = -1
+        let tank = |glass_priority, water_priority| {
+            let glass = Arc::new(Material {
+                reflectivity: 1.0,
+                refraction_index: glass_index,
+                priority: glass_priority,
+                ..Material::default()
+            });
+            let water = Arc::new(Material {
+                reflectivity: 1.0,
+                refraction_index: water_index,
+                priority: water_priority,
+                ..Material::default()
+            });
+            SceneNode::from(vec![
+                Arc::new(SceneNode::from(Geometry::new(Cube, glass)).scaled(10.0).translated((0.0, -5.0, 0.0))),
+                Arc::new(SceneNode::from(Geometry::new(Cube, water)).scaled((9.8, 9.0, 9.8)).translated((0.0, -5.5, 0.0))),
+            ])
+        };
+
+        // If the water has priority, the ray refracts from the glass into the water
+        let water_angle = (glass_angle.sin() * glass_index / water_index).asin();
+        let water_dir = Vec3::new(water_angle.sin(), -water_angle.cos(), 0.0);
+        let scene = scene_with_target(tank(1, 2), water_top + water_dir * 2.0);
+        let expected = transmittance(AIR_REFRACTION_INDEX, glass_index, incident_angle.cos())
+            * transmittance(glass_index, water_index, glass_angle.cos());
+        let color = ray.color(&scene, Rgb::black(), 0);
+        assert!((color - Rgb::red() * expected).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE,
+            "ray was not refracted from glass into water: {:?}", color);
+
+        // If the glass has priority, the surface of the water inside of it is ignored
+        let scene = scene_with_target(tank(2, 1), water_top + glass_dir * 2.0);
+        let expected = transmittance(AIR_REFRACTION_INDEX, glass_index, incident_angle.cos());
+        let color = ray.color(&scene, Rgb::black(), 0);
+        assert!((color - Rgb::red() * expected).map(f64::abs).reduce_partial_max() < COLOR_TOLERANCE,
+            "water surface inside of the glass was not ignored: {:?}", color);
     }
 }
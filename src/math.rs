@@ -4,7 +4,24 @@
 //! to use it with floats. This module exports type aliases that allow us to not have to specify
 //! that we are using "f64" all the time.
 
+mod sampling;
+mod basis;
+mod color;
+mod spline;
+mod uv_transform;
+mod summation;
+mod seed;
+
+pub mod tolerance;
+
 pub use std::f64::INFINITY;
+pub use sampling::*;
+pub use basis::*;
+pub use color::*;
+pub use spline::*;
+pub use uv_transform::*;
+pub use summation::*;
+pub use seed::*;
 
 use std::ops::Range;
 
@@ -85,6 +102,11 @@ impl Solutions {
         self.0.as_ref().len()
     }
 
+    /// Returns true if there are no solutions
+    pub fn is_empty(&self) -> bool {
+        self.0.as_ref().is_empty()
+    }
+
     /// Provides an iterator over the solutions, from smallest to largest
     pub fn iter(&self) -> impl Iterator<Item=f64> + '_ {
         self.0.as_ref().iter().cloned()
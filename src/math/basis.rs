@@ -0,0 +1,108 @@
+use super::{Vec3, Mat3};
+
+/// Three mutually perpendicular unit vectors that form a local coordinate system around a normal
+///
+/// The local coordinate system is right-handed and y-up, with the normal along the +y axis. This
+/// matches the coordinate system used for normal mapping and by the sampling functions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrthonormalBasis {
+    /// The local +x axis
+    pub tangent: Vec3,
+    /// The local +y axis
+    pub normal: Vec3,
+    /// The local +z axis
+    pub bitangent: Vec3,
+}
+
+impl OrthonormalBasis {
+    /// Creates a basis around the given normal (MUST be a unit vector)
+    ///
+    /// The tangent and bitangent are chosen arbitrarily, but are guaranteed to be valid for every
+    /// possible normal direction. Uses the branchless method from Duff et al., "Building an
+    /// Orthonormal Basis, Revisited" (2017).
+    pub fn from_normal(normal: Vec3) -> Self {
+        let sign = 1.0f64.copysign(normal.z);
+        let a = -1.0 / (sign + normal.z);
+        let b = normal.x * normal.y * a;
+
+        // b1 x b2 = normal
+        let b1 = Vec3 {
+            x: 1.0 + sign * normal.x * normal.x * a,
+            y: sign * b,
+            z: -sign * normal.x,
+        };
+        let b2 = Vec3 {
+            x: b,
+            y: sign + normal.y * normal.y * a,
+            z: -normal.y,
+        };
+
+        // Need tangent x normal = bitangent for a right-handed y-up coordinate system
+        Self {
+            tangent: b2,
+            normal,
+            bitangent: b1,
+        }
+    }
+
+    /// Transforms a vector from the local coordinate system of this basis to world space
+    pub fn to_world(&self, local: Vec3) -> Vec3 {
+        local.x * self.tangent + local.y * self.normal + local.z * self.bitangent
+    }
+
+    /// Transforms a vector from world space to the local coordinate system of this basis
+    pub fn to_local(&self, world: Vec3) -> Vec3 {
+        Vec3 {
+            x: world.dot(self.tangent),
+            y: world.dot(self.normal),
+            z: world.dot(self.bitangent),
+        }
+    }
+
+    /// Returns the matrix that performs the same transformation as to_world
+    pub fn to_mat3(&self) -> Mat3 {
+        Mat3::from_col_arrays([
+            self.tangent.into_array(),
+            self.normal.into_array(),
+            self.bitangent.into_array(),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn orthonormal_for_all_directions() {
+        let normals = [
+            Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z(),
+            -Vec3::unit_x(), -Vec3::unit_y(), -Vec3::unit_z(),
+            Vec3::new(1.0, 1.0, 1.0).normalized(),
+            Vec3::new(-0.3, 0.2, -0.9).normalized(),
+            // Nearly -z is the edge case for the ad hoc offset vector technique
+            Vec3::new(0.0, 0.00001, -1.0).normalized(),
+        ];
+
+        for &normal in &normals {
+            let basis = OrthonormalBasis::from_normal(normal);
+            assert_approx_eq!(basis.tangent.magnitude(), 1.0);
+            assert_approx_eq!(basis.bitangent.magnitude(), 1.0);
+            assert_approx_eq!(basis.tangent.dot(basis.normal), 0.0);
+            assert_approx_eq!(basis.bitangent.dot(basis.normal), 0.0);
+            assert_approx_eq!(basis.tangent.dot(basis.bitangent), 0.0);
+
+            // Right-handed with y-up
+            let cross = basis.tangent.cross(basis.normal);
+            assert_approx_eq!((cross - basis.bitangent).magnitude(), 0.0);
+
+            // Local +y is the normal and transformations should round-trip
+            assert_approx_eq!((basis.to_world(Vec3::unit_y()) - normal).magnitude(), 0.0);
+            let v = Vec3::new(0.2, -0.5, 0.7);
+            assert_approx_eq!((basis.to_local(basis.to_world(v)) - v).magnitude(), 0.0);
+            assert_approx_eq!((basis.to_mat3() * v - basis.to_world(v)).magnitude(), 0.0);
+        }
+    }
+}
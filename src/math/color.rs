@@ -0,0 +1,247 @@
+use std::fmt;
+use std::error::Error;
+
+use super::{GAMMA, Rgb};
+
+/// An error produced when parsing a hex color string fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseHexColorError {
+    hex: String,
+}
+
+impl fmt::Display for ParseHexColorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid hex color '{}': expected a color like '#ff8800' or '#f80'", self.hex)
+    }
+}
+
+impl Error for ParseHexColorError {}
+
+/// This is an "extension trait". It allows me to add methods to structs I did not define.
+pub trait RgbExt: Sized {
+    /// Parses a color from a hex string like "#ff8800", "ff8800", or "#f80"
+    ///
+    /// Hex colors are assumed to be in sRGB space (like the colors in a color picker) and are
+    /// converted to linear space so that they can be used directly in the scene.
+    fn from_hex(hex: &str) -> Result<Self, ParseHexColorError>;
+
+    /// Returns the color of a black body at the given temperature (in Kelvin)
+    ///
+    /// Useful for creating realistic light colors: candles are ~1900K, incandescent bulbs are
+    /// ~2700K, and daylight is ~6500K. The result is normalized so that the brightest channel is
+    /// 1.0 and is in linear space.
+    ///
+    /// Uses the curve fit from Tanner Helland, which is accurate from 1000K to 40000K.
+    fn from_temperature(kelvin: f64) -> Self;
+
+    /// Returns the relative luminance of this color (assuming it is in linear space)
+    ///
+    /// Uses the coefficients from ITU-R BT.709.
+    fn luminance(self) -> f64;
+}
+
+impl RgbExt for Rgb {
+    fn from_hex(hex: &str) -> Result<Self, ParseHexColorError> {
+        let err = || ParseHexColorError {hex: hex.to_string()};
+
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(err());
+        }
+
+        let channel = |digits: &str| u8::from_str_radix(digits, 16).map_err(|_| err());
+        let (r, g, b) = match digits.len() {
+            6 => (channel(&digits[0..2])?, channel(&digits[2..4])?, channel(&digits[4..6])?),
+            // Shorthand: each digit is repeated (e.g. "f80" is "ff8800")
+            3 => (channel(&digits[0..1])? * 17, channel(&digits[1..2])? * 17, channel(&digits[2..3])? * 17),
+            _ => return Err(err()),
+        };
+
+        Ok(Rgb {
+            r: r as f64 / 255.0,
+            g: g as f64 / 255.0,
+            b: b as f64 / 255.0,
+        }.map(|c| c.powf(GAMMA)))
+    }
+
+    fn from_temperature(kelvin: f64) -> Self {
+        // The formulas use the temperature in hundreds of Kelvin
+        let temp = kelvin.clamp(1000.0, 40000.0) / 100.0;
+
+        let r = if temp <= 66.0 {
+            255.0
+        } else {
+            329.698727446 * (temp - 60.0).powf(-0.1332047592)
+        };
+
+        let g = if temp <= 66.0 {
+            99.4708025861 * temp.ln() - 161.1195681661
+        } else {
+            288.1221695283 * (temp - 60.0).powf(-0.0755148492)
+        };
+
+        let b = if temp >= 66.0 {
+            255.0
+        } else if temp <= 19.0 {
+            0.0
+        } else {
+            138.5177312231 * (temp - 10.0).ln() - 305.0447927307
+        };
+
+        // The formulas produce sRGB values between 0 and 255
+        Rgb {r, g, b}.map(|c| (c.clamp(0.0, 255.0) / 255.0).powf(GAMMA))
+    }
+
+    fn luminance(self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+}
+
+/// A color represented by hue, saturation, and value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hsv {
+    /// The hue in degrees (0.0 to 360.0)
+    pub h: f64,
+    /// The saturation (0.0 to 1.0)
+    pub s: f64,
+    /// The value (0.0 to 1.0)
+    pub v: f64,
+}
+
+/// A color represented by hue, saturation, and lightness
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hsl {
+    /// The hue in degrees (0.0 to 360.0)
+    pub h: f64,
+    /// The saturation (0.0 to 1.0)
+    pub s: f64,
+    /// The lightness (0.0 to 1.0)
+    pub l: f64,
+}
+
+/// Returns the hue (in degrees), the maximum channel value, and the range of channel values
+fn hue_max_range(Rgb {r, g, b}: Rgb) -> (f64, f64, f64) {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let range = max - min;
+
+    let hue = if range == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / range).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / range + 2.0)
+    } else {
+        60.0 * ((r - g) / range + 4.0)
+    };
+
+    (hue, max, range)
+}
+
+/// Returns the color with the given hue (in degrees), chroma, and amount to add to every channel
+fn from_hue_chroma(hue: f64, chroma: f64, offset: f64) -> Rgb {
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+
+    Rgb {r: r + offset, g: g + offset, b: b + offset}
+}
+
+impl From<Hsv> for Rgb {
+    fn from(Hsv {h, s, v}: Hsv) -> Self {
+        let chroma = v * s;
+        from_hue_chroma(h, chroma, v - chroma)
+    }
+}
+
+impl From<Rgb> for Hsv {
+    fn from(color: Rgb) -> Self {
+        let (h, max, range) = hue_max_range(color);
+        let s = if max == 0.0 { 0.0 } else { range / max };
+        Hsv {h, s, v: max}
+    }
+}
+
+impl From<Hsl> for Rgb {
+    fn from(Hsl {h, s, l}: Hsl) -> Self {
+        let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        from_hue_chroma(h, chroma, l - chroma / 2.0)
+    }
+}
+
+impl From<Rgb> for Hsl {
+    fn from(color: Rgb) -> Self {
+        let (h, max, range) = hue_max_range(color);
+        let l = max - range / 2.0;
+        let s = if l == 0.0 || l == 1.0 { 0.0 } else { range / (1.0 - (2.0 * l - 1.0).abs()) };
+        Hsl {h, s, l}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_approx_eq::assert_approx_eq;
+
+    fn assert_rgb_eq(expected: Rgb, actual: Rgb) {
+        assert_approx_eq!(expected.r, actual.r);
+        assert_approx_eq!(expected.g, actual.g);
+        assert_approx_eq!(expected.b, actual.b);
+    }
+
+    #[test]
+    fn parse_hex() {
+        assert_rgb_eq(Rgb::red(), Rgb::from_hex("#ff0000").unwrap());
+        assert_rgb_eq(Rgb::white(), Rgb::from_hex("FFFFFF").unwrap());
+        assert_rgb_eq(Rgb::from_hex("#ff8800").unwrap(), Rgb::from_hex("#f80").unwrap());
+        // Converted to linear space
+        assert_approx_eq!(Rgb::from_hex("#808080").unwrap().g, (128.0f64 / 255.0).powf(GAMMA));
+
+        assert!(Rgb::from_hex("#ff00").is_err());
+        assert!(Rgb::from_hex("#gg0000").is_err());
+        assert!(Rgb::from_hex("#ff00ü").is_err());
+    }
+
+    #[test]
+    fn hsv_hsl_round_trip() {
+        let colors = [
+            Rgb::red(), Rgb::green(), Rgb::blue(), Rgb::white(), Rgb::black(),
+            Rgb {r: 0.2, g: 0.5, b: 0.9},
+            Rgb {r: 0.9, g: 0.1, b: 0.4},
+            Rgb {r: 0.3, g: 0.3, b: 0.1},
+        ];
+
+        for &color in &colors {
+            assert_rgb_eq(color, Rgb::from(Hsv::from(color)));
+            assert_rgb_eq(color, Rgb::from(Hsl::from(color)));
+        }
+
+        assert_rgb_eq(Rgb {r: 0.0, g: 1.0, b: 1.0}, Rgb::from(Hsv {h: 180.0, s: 1.0, v: 1.0}));
+        assert_rgb_eq(Rgb {r: 0.5, g: 0.5, b: 0.5}, Rgb::from(Hsl {h: 42.0, s: 0.0, l: 0.5}));
+    }
+
+    #[test]
+    fn luminance_and_temperature() {
+        assert_approx_eq!(Rgb::white().luminance(), 1.0);
+        assert_approx_eq!(Rgb::black().luminance(), 0.0);
+
+        // Daylight is roughly white
+        let daylight = Rgb::from_temperature(6600.0);
+        assert_approx_eq!(daylight.r, 1.0, 0.01);
+        assert_approx_eq!(daylight.g, 1.0, 0.05);
+        assert_approx_eq!(daylight.b, 1.0, 0.01);
+
+        // Candle light is much more red than blue
+        let candle = Rgb::from_temperature(1900.0);
+        assert!(candle.r > candle.g && candle.g > candle.b);
+    }
+}
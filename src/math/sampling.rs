@@ -0,0 +1,241 @@
+//! Utilities for generating random samples over common domains.
+//!
+//! All directions are generated in a local, right-handed, y-up coordinate system where the
+//! surface normal is (0.0, 1.0, 0.0). This matches the coordinate system used for normal mapping.
+
+use std::f64::consts::PI;
+
+use rand::Rng;
+
+use super::{Vec2, Vec3};
+
+/// Samples a point uniformly distributed over the square from (-1, -1) to (1, 1)
+///
+/// Used for rectangular domains like the area of a light or the glossy reflection rectangle.
+pub fn sample_square<R: Rng>(rng: &mut R) -> Vec2 {
+    Vec2 {
+        x: 2.0 * rng.gen::<f64>() - 1.0,
+        y: 2.0 * rng.gen::<f64>() - 1.0,
+    }
+}
+
+/// Samples a point uniformly distributed over the unit disk
+///
+/// Uses the concentric mapping from Shirley and Chiu, "A Low Distortion Map Between Disk and
+/// Square" (1997). This mapping preserves the stratification of the input samples much better
+/// than the naive polar mapping.
+pub fn sample_concentric_disk<R: Rng>(rng: &mut R) -> Vec2 {
+    // Map the uniform random numbers to [-1, 1]
+    let offset = sample_square(rng);
+
+    // Handle the degenerate case at the origin
+    if offset.x == 0.0 && offset.y == 0.0 {
+        return Vec2::zero();
+    }
+
+    // Map the square to concentric circles
+    let (radius, theta) = if offset.x.abs() > offset.y.abs() {
+        (offset.x, PI / 4.0 * (offset.y / offset.x))
+    } else {
+        (offset.y, PI / 2.0 - PI / 4.0 * (offset.x / offset.y))
+    };
+
+    Vec2 {
+        x: radius * theta.cos(),
+        y: radius * theta.sin(),
+    }
+}
+
+/// Samples a direction over the hemisphere around the +y axis with a probability density
+/// proportional to the cosine of the angle between the direction and the +y axis
+///
+/// This is the ideal distribution for sampling the light reflected from a diffuse surface. Uses
+/// Malley's method: uniformly sample the disk and then project up onto the hemisphere.
+pub fn sample_cosine_hemisphere<R: Rng>(rng: &mut R) -> Vec3 {
+    let Vec2 {x, y: z} = sample_concentric_disk(rng);
+    // Need to max with zero because floating point error could make this slightly negative
+    let y = (1.0 - x*x - z*z).max(0.0).sqrt();
+    Vec3 {x, y, z}
+}
+
+/// Returns the probability density of the given direction being produced by
+/// sample_cosine_hemisphere, given the cosine of the angle between it and the +y axis
+pub fn cosine_hemisphere_pdf(cos_theta: f64) -> f64 {
+    cos_theta.max(0.0) / PI
+}
+
+/// Samples a direction uniformly distributed over the entire unit sphere
+pub fn sample_uniform_sphere<R: Rng>(rng: &mut R) -> Vec3 {
+    // Choosing y uniformly in [-1, 1] results in a uniform distribution over the sphere
+    // (Archimedes' hat-box theorem)
+    let y = 1.0 - 2.0 * rng.gen::<f64>();
+    let radius = (1.0 - y*y).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+
+    Vec3 {
+        x: radius * phi.cos(),
+        y,
+        z: radius * phi.sin(),
+    }
+}
+
+/// Returns the probability density of any direction being produced by sample_uniform_sphere
+pub fn uniform_sphere_pdf() -> f64 {
+    1.0 / (4.0 * PI)
+}
+
+/// Samples a microfacet normal (half-vector) from the GGX / Trowbridge-Reitz distribution with
+/// the given roughness
+///
+/// The roughness alpha is typically the square of the "perceptual" roughness of a material. An
+/// alpha near 0.0 produces half-vectors close to the +y axis (mirror-like), while an alpha of 1.0
+/// spreads them out over the entire hemisphere.
+pub fn sample_ggx_half_vector<R: Rng>(alpha: f64, rng: &mut R) -> Vec3 {
+    let u1 = rng.gen::<f64>();
+    let u2 = rng.gen::<f64>();
+
+    // Inverting the CDF of the GGX distribution (Walter et al. 2007)
+    let tan_theta_sqr = alpha*alpha * u1 / (1.0 - u1);
+    let cos_theta = 1.0 / (1.0 + tan_theta_sqr).sqrt();
+    let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+
+    Vec3 {
+        x: sin_theta * phi.cos(),
+        y: cos_theta,
+        z: sin_theta * phi.sin(),
+    }
+}
+
+/// Returns the probability density of the given half-vector being produced by
+/// sample_ggx_half_vector, given the cosine of the angle between it and the +y axis
+pub fn ggx_half_vector_pdf(alpha: f64, cos_theta: f64) -> f64 {
+    if cos_theta <= 0.0 {
+        return 0.0;
+    }
+
+    // pdf = D(h) * cos(theta) where D is the GGX normal distribution function
+    let alpha_sqr = alpha * alpha;
+    let cos_theta_sqr = cos_theta * cos_theta;
+    let denom = cos_theta_sqr * (alpha_sqr - 1.0) + 1.0;
+    let distribution = alpha_sqr / (PI * denom * denom);
+
+    distribution * cos_theta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{SeedableRng, rngs::StdRng};
+    use assert_approx_eq::assert_approx_eq;
+
+    use crate::math::EPSILON;
+
+    const SAMPLES: usize = 100_000;
+
+    #[test]
+    fn square_is_uniform() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut mean = Vec2::zero();
+        let mut left = 0;
+        for _ in 0..SAMPLES {
+            let point = sample_square(&mut rng);
+            assert!(point.x.abs() <= 1.0 && point.y.abs() <= 1.0, "{:?} is outside the square", point);
+            mean += point;
+            if point.x < -0.5 {
+                left += 1;
+            }
+        }
+
+        let mean = mean / SAMPLES as f64;
+        assert_approx_eq!(mean.x, 0.0, 0.01);
+        assert_approx_eq!(mean.y, 0.0, 0.01);
+        // A quarter of the square is to the left of x = -0.5
+        assert_approx_eq!(left as f64 / SAMPLES as f64, 0.25, 0.01);
+    }
+
+    #[test]
+    fn concentric_disk_in_unit_disk() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut mean = Vec2::zero();
+        for _ in 0..SAMPLES {
+            let point = sample_concentric_disk(&mut rng);
+            assert!(point.magnitude() <= 1.0 + EPSILON, "{:?} is outside the unit disk", point);
+            mean += point;
+        }
+
+        // Uniformly distributed over the disk means that the mean should be at the center
+        let mean = mean / SAMPLES as f64;
+        assert_approx_eq!(mean.x, 0.0, 0.01);
+        assert_approx_eq!(mean.y, 0.0, 0.01);
+    }
+
+    #[test]
+    fn cosine_hemisphere_distribution() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut mean_y = 0.0;
+        for _ in 0..SAMPLES {
+            let dir = sample_cosine_hemisphere(&mut rng);
+            assert_approx_eq!(dir.magnitude(), 1.0);
+            assert!(dir.y >= 0.0, "{:?} is not in the +y hemisphere", dir);
+            mean_y += dir.y;
+        }
+
+        // The expected value of cos(theta) for a cosine-weighted hemisphere is 2/3
+        assert_approx_eq!(mean_y / SAMPLES as f64, 2.0 / 3.0, 0.01);
+    }
+
+    #[test]
+    fn uniform_sphere_distribution() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut mean = Vec3::zero();
+        let mut mean_abs_y = 0.0;
+        for _ in 0..SAMPLES {
+            let dir = sample_uniform_sphere(&mut rng);
+            assert_approx_eq!(dir.magnitude(), 1.0);
+            mean += dir;
+            mean_abs_y += dir.y.abs();
+        }
+
+        let mean = mean / SAMPLES as f64;
+        assert_approx_eq!(mean.x, 0.0, 0.01);
+        assert_approx_eq!(mean.y, 0.0, 0.01);
+        assert_approx_eq!(mean.z, 0.0, 0.01);
+        // The expected value of |cos(theta)| over the sphere is 1/2
+        assert_approx_eq!(mean_abs_y / SAMPLES as f64, 0.5, 0.01);
+    }
+
+    #[test]
+    fn ggx_half_vector_roughness() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mean_y = |alpha, rng: &mut StdRng| {
+            (0..SAMPLES).map(|_| {
+                let half = sample_ggx_half_vector(alpha, rng);
+                assert_approx_eq!(half.magnitude(), 1.0);
+                assert!(half.y >= 0.0, "{:?} is not in the +y hemisphere", half);
+                half.y
+            }).sum::<f64>() / SAMPLES as f64
+        };
+
+        // Smoother surfaces should produce half-vectors closer to the normal
+        let smooth = mean_y(0.01, &mut rng);
+        let rough = mean_y(0.8, &mut rng);
+        assert!(smooth > 0.99, "smooth GGX half-vectors were not near the normal: {}", smooth);
+        assert!(rough < smooth);
+    }
+
+    #[test]
+    fn ggx_pdf_integrates_to_one() {
+        // Integrate the pdf over the hemisphere: 2*pi * integral of pdf(theta) sin(theta) dtheta
+        let alpha = 0.5;
+        let steps = 100_000;
+        let dtheta = (PI / 2.0) / steps as f64;
+        let integral: f64 = (0..steps).map(|i| {
+            let theta = (i as f64 + 0.5) * dtheta;
+            ggx_half_vector_pdf(alpha, theta.cos()) * theta.sin() * dtheta
+        }).sum::<f64>() * 2.0 * PI;
+
+        assert_approx_eq!(integral, 1.0, 0.001);
+    }
+}
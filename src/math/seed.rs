@@ -0,0 +1,146 @@
+//! Seeding the random numbers used by each sample so that renders can be reproduced exactly
+//!
+//! The seed of every sample only depends on the global seed, the pixel, and the index of the
+//! sample. It never depends on which thread or machine traces the sample or on which part of the
+//! image is being rendered, so slices of an image rendered by different workers match a render of
+//! the whole image.
+//!
+//! Each frame of an animation should use its own global seed (see `frame_seed`) so that the noise
+//! changes from frame to frame instead of "boiling" in place. Rendering every frame with the same
+//! global seed gives fixed-pattern noise instead, which some temporal denoisers prefer.
+
+use std::cell::RefCell;
+
+use rand::{RngCore, SeedableRng, thread_rng};
+use rand::rngs::StdRng;
+
+/// The width and height of the tiles used by `sample_seed`
+///
+/// Part of the stable seed derivation, so this never changes (even if the tiles used for
+/// rendering do).
+pub const SEED_TILE_SIZE: usize = 16;
+
+/// Returns the seed of the given sample of the given (x, y) pixel for the given global seed
+///
+/// This is a pure function and a stable API: the same inputs will always produce the same seed.
+/// The pixel is split into the coordinates of its tile, `(x / SEED_TILE_SIZE, y / SEED_TILE_SIZE)`,
+/// and its coordinates within that tile, `(x % SEED_TILE_SIZE, y % SEED_TILE_SIZE)`. Starting with
+/// `splitmix64(global_seed)`, the tile x, tile y, pixel x, pixel y, and sample index are mixed in
+/// (in that order) by XOR-ing each one into the state and applying `splitmix64` again.
+pub fn sample_seed(global_seed: u64, (x, y): (usize, usize), sample: usize) -> u64 {
+    let tile = (x / SEED_TILE_SIZE, y / SEED_TILE_SIZE);
+    let pixel = (x % SEED_TILE_SIZE, y % SEED_TILE_SIZE);
+
+    [tile.0, tile.1, pixel.0, pixel.1, sample].iter()
+        .fold(splitmix64(global_seed), |state, &value| splitmix64(state ^ value as u64))
+}
+
+/// Returns the global seed of the given frame of an animation rendered with the given global seed
+///
+/// This is a pure function and a stable API: the frame is XOR-ed into `splitmix64(global_seed)` and
+/// the result is scrambled with `splitmix64` again.
+pub fn frame_seed(global_seed: u64, frame: usize) -> u64 {
+    splitmix64(splitmix64(global_seed) ^ frame as u64)
+}
+
+/// The output function of the SplitMix64 generator, which scrambles all of the bits of its input
+///
+/// See: Guy L. Steele Jr., Doug Lea, and Christine H. Flood. 2014. Fast Splittable Pseudorandom
+/// Number Generators.
+pub(crate) fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+thread_local! {
+    /// The random number generator of the sample currently being traced on this thread (if any)
+    static SAMPLE_RNG: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// Seeds the random numbers returned by `sample_rng` on the current thread with the given seed
+///
+/// Must be called before every sample. If the seed is None, `sample_rng` uses the unseeded thread
+/// local generator from `rand` instead.
+pub(crate) fn seed_sample_rng(seed: Option<u64>) {
+    SAMPLE_RNG.with(|rng| *rng.borrow_mut() = seed.map(StdRng::seed_from_u64));
+}
+
+/// Returns a handle to the random number generator of the sample being traced on the current
+/// thread (see `seed_sample_rng`)
+///
+/// Everything that uses random numbers while tracing a sample must use this instead of
+/// `thread_rng` or renders will not be reproducible.
+pub(crate) fn sample_rng() -> SampleRng {
+    SampleRng
+}
+
+/// A handle to the random number generator of the sample being traced on the current thread
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SampleRng;
+
+impl SampleRng {
+    fn with<T>(&mut self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        SAMPLE_RNG.with(|rng| match &mut *rng.borrow_mut() {
+            Some(rng) => f(rng),
+            None => f(&mut thread_rng()),
+        })
+    }
+}
+
+impl RngCore for SampleRng {
+    fn next_u32(&mut self) -> u32 {
+        self.with(|rng| rng.next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.with(|rng| rng.next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.with(|rng| rng.fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.with(|rng| rng.try_fill_bytes(dest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::Rng;
+
+    #[test]
+    fn sample_seeds_are_stable() {
+        // These values must never change or old renders can no longer be reproduced
+        assert_eq!(sample_seed(0, (0, 0), 0), 0xcbd3_7ad2_9b93_b094);
+        assert_eq!(sample_seed(42, (100, 37), 7), 0xb618_796d_7056_db6b);
+
+        // Every input changes the seed
+        let seed = sample_seed(42, (100, 37), 7);
+        assert_ne!(sample_seed(43, (100, 37), 7), seed);
+        assert_ne!(sample_seed(42, (101, 37), 7), seed);
+        assert_ne!(sample_seed(42, (100, 38), 7), seed);
+        assert_ne!(sample_seed(42, (100, 37), 8), seed);
+        // Pixels at the same position in different tiles are different
+        assert_ne!(sample_seed(42, (0, 0), 0), sample_seed(42, (SEED_TILE_SIZE, 0), 0));
+
+        assert_eq!(frame_seed(42, 3), splitmix64(splitmix64(42) ^ 3));
+        assert_ne!(frame_seed(42, 3), frame_seed(42, 4));
+        assert_ne!(frame_seed(42, 0), 42);
+    }
+
+    #[test]
+    fn seeded_samples_repeat() {
+        seed_sample_rng(Some(sample_seed(1, (2, 3), 4)));
+        let first: Vec<f64> = (0..5).map(|_| sample_rng().gen()).collect();
+        seed_sample_rng(Some(sample_seed(1, (2, 3), 4)));
+        let second: Vec<f64> = (0..5).map(|_| sample_rng().gen()).collect();
+        seed_sample_rng(None);
+
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,217 @@
+use super::Vec3;
+
+/// A parametric curve in 3D space
+///
+/// The curve is defined for parameter values t between 0.0 (the start of the curve) and 1.0 (the
+/// end of the curve). Note that equal steps in t are NOT guaranteed to be equal steps in distance
+/// along the curve. Use `ArcLength` if that is needed.
+pub trait Curve {
+    /// Returns the point on the curve at the given parameter value
+    fn at(&self, t: f64) -> Vec3;
+
+    /// Returns the derivative of the curve at the given parameter value
+    ///
+    /// This is tangent to the curve, but is NOT normalized.
+    fn tangent(&self, t: f64) -> Vec3;
+}
+
+impl<C: Curve> Curve for &C {
+    fn at(&self, t: f64) -> Vec3 {
+        (**self).at(t)
+    }
+
+    fn tangent(&self, t: f64) -> Vec3 {
+        (**self).tangent(t)
+    }
+}
+
+/// A cubic Bezier curve that starts at p0, ends at p3, and is shaped by the control points p1
+/// and p2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CubicBezier {
+    pub p0: Vec3,
+    pub p1: Vec3,
+    pub p2: Vec3,
+    pub p3: Vec3,
+}
+
+impl Curve for CubicBezier {
+    fn at(&self, t: f64) -> Vec3 {
+        let Self {p0, p1, p2, p3} = *self;
+        let s = 1.0 - t;
+        p0*s*s*s + p1*3.0*s*s*t + p2*3.0*s*t*t + p3*t*t*t
+    }
+
+    fn tangent(&self, t: f64) -> Vec3 {
+        let Self {p0, p1, p2, p3} = *self;
+        let s = 1.0 - t;
+        (p1 - p0)*3.0*s*s + (p2 - p1)*6.0*s*t + (p3 - p2)*3.0*t*t
+    }
+}
+
+/// A (uniform) Catmull-Rom spline that passes through every one of its points
+///
+/// The first and last points are repeated so that the spline starts and ends at those points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatmullRom {
+    points: Vec<Vec3>,
+}
+
+impl CatmullRom {
+    /// Creates a spline that passes through the given points in order
+    ///
+    /// Panics if fewer than two points are provided.
+    pub fn new(points: Vec<Vec3>) -> Self {
+        assert!(points.len() >= 2, "Catmull-Rom splines must have at least two points");
+        Self {points}
+    }
+
+    /// Returns the points that the spline passes through
+    pub fn points(&self) -> &[Vec3] {
+        &self.points
+    }
+
+    /// Returns the four points that control the segment containing t as well as the parameter
+    /// value within that segment
+    fn segment(&self, t: f64) -> ([Vec3; 4], f64) {
+        let segments = self.points.len() - 1;
+        let t = t.clamp(0.0, 1.0) * segments as f64;
+        // The end of the curve (t = 1.0) is part of the last segment
+        let index = (t.floor() as usize).min(segments - 1);
+        let u = t - index as f64;
+
+        let point = |i: isize| {
+            let i = i.clamp(0, self.points.len() as isize - 1);
+            self.points[i as usize]
+        };
+        let index = index as isize;
+        ([point(index - 1), point(index), point(index + 1), point(index + 2)], u)
+    }
+}
+
+impl Curve for CatmullRom {
+    fn at(&self, t: f64) -> Vec3 {
+        let ([p0, p1, p2, p3], u) = self.segment(t);
+        (p1*2.0 + (p2 - p0)*u + (p0*2.0 - p1*5.0 + p2*4.0 - p3)*u*u + (p1*3.0 - p0 - p2*3.0 + p3)*u*u*u) * 0.5
+    }
+
+    fn tangent(&self, t: f64) -> Vec3 {
+        let ([p0, p1, p2, p3], u) = self.segment(t);
+        let segments = (self.points.len() - 1) as f64;
+        // Multiplied by the number of segments because u changes that much faster than t
+        ((p2 - p0) + (p0*2.0 - p1*5.0 + p2*4.0 - p3)*2.0*u + (p1*3.0 - p0 - p2*3.0 + p3)*3.0*u*u) * 0.5 * segments
+    }
+}
+
+/// Reparameterizes a curve by arc length so that points can be evaluated at a given distance
+/// along the curve
+///
+/// The arc length is approximated by sampling the curve at evenly spaced parameter values. More
+/// samples result in a more accurate approximation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArcLength<C: Curve> {
+    curve: C,
+    /// The distance along the curve at each sample. The sample i is at t = i / (samples - 1).
+    lengths: Vec<f64>,
+}
+
+impl<C: Curve> ArcLength<C> {
+    /// Approximates the arc length of the given curve using the given number of samples
+    ///
+    /// Panics if fewer than two samples are requested.
+    pub fn new(curve: C, samples: usize) -> Self {
+        assert!(samples >= 2, "At least two samples are required to approximate arc length");
+
+        let mut lengths = Vec::with_capacity(samples);
+        let mut length = 0.0;
+        let mut prev = curve.at(0.0);
+        lengths.push(length);
+        for i in 1..samples {
+            let point = curve.at(i as f64 / (samples - 1) as f64);
+            length += (point - prev).magnitude();
+            lengths.push(length);
+            prev = point;
+        }
+
+        Self {curve, lengths}
+    }
+
+    /// Returns the curve being reparameterized
+    pub fn curve(&self) -> &C {
+        &self.curve
+    }
+
+    /// Returns the total length of the curve
+    pub fn length(&self) -> f64 {
+        *self.lengths.last().expect("bug: arc length table should not be empty")
+    }
+
+    /// Returns the parameter value of the curve at the given distance along it
+    ///
+    /// Distances outside of the curve are clamped to the start or end of the curve.
+    pub fn param_at_distance(&self, distance: f64) -> f64 {
+        let distance = distance.clamp(0.0, self.length());
+        let segments = (self.lengths.len() - 1) as f64;
+
+        // Find the first sample that is past the given distance
+        let next = self.lengths.partition_point(|&len| len < distance).clamp(1, self.lengths.len() - 1);
+        let start = self.lengths[next - 1];
+        let end = self.lengths[next];
+
+        // Linearly interpolate between the two samples
+        let fraction = if end > start { (distance - start) / (end - start) } else { 0.0 };
+        (next as f64 - 1.0 + fraction) / segments
+    }
+
+    /// Returns the point at the given distance along the curve
+    pub fn at_distance(&self, distance: f64) -> Vec3 {
+        self.curve.at(self.param_at_distance(distance))
+    }
+
+    /// Returns the point at the given fraction (between 0.0 and 1.0) of the total length of the
+    /// curve. Unlike `Curve::at`, equal steps in the fraction are equal steps along the curve.
+    pub fn at_fraction(&self, fraction: f64) -> Vec3 {
+        self.at_distance(fraction * self.length())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::math::EPSILON;
+
+    #[test]
+    fn catmull_rom_passes_through_points() {
+        let points = vec![
+            Vec3::zero(),
+            Vec3::new(1.0, 2.0, 0.0),
+            Vec3::new(3.0, 2.0, 1.0),
+            Vec3::new(4.0, 0.0, -1.0),
+        ];
+        let spline = CatmullRom::new(points.clone());
+
+        for (i, &point) in points.iter().enumerate() {
+            let t = i as f64 / (points.len() - 1) as f64;
+            assert!((spline.at(t) - point).magnitude() < EPSILON, "{:?} != {:?}", spline.at(t), point);
+        }
+    }
+
+    #[test]
+    fn arc_length_of_line() {
+        // A bezier curve with unevenly spaced control points on a line moves at a non-uniform
+        // speed, but the arc length parameterization should still be uniform
+        let curve = CubicBezier {
+            p0: Vec3::zero(),
+            p1: Vec3::new(0.1, 0.0, 0.0),
+            p2: Vec3::new(0.2, 0.0, 0.0),
+            p3: Vec3::new(4.0, 0.0, 0.0),
+        };
+        let arc = ArcLength::new(curve, 1000);
+
+        assert!((arc.length() - 4.0).abs() < EPSILON);
+        assert!((arc.at_fraction(0.25) - Vec3::new(1.0, 0.0, 0.0)).magnitude() < 0.01);
+        assert!((arc.at_fraction(1.0) - curve.p3).magnitude() < EPSILON);
+        assert!((arc.at_distance(-1.0) - curve.p0).magnitude() < EPSILON);
+    }
+}
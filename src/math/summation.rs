@@ -0,0 +1,128 @@
+use std::iter::FromIterator;
+
+use super::Rgb;
+
+/// A sum of floating point values that keeps track of the error lost to rounding
+///
+/// Adding a small value to a much larger total loses the low bits of the small value. With enough
+/// samples (e.g. thousands of samples of a bright pixel), this error adds up. This uses the
+/// Kahan-Babuška (Neumaier) algorithm to carry the lost bits separately and add them back in at the
+/// end, so the result is as accurate as if the sum had been computed with twice the precision.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompensatedSum {
+    sum: f64,
+    /// The running total of the error lost to rounding in `sum`
+    compensation: f64,
+}
+
+impl From<f64> for CompensatedSum {
+    fn from(value: f64) -> Self {
+        Self {sum: value, compensation: 0.0}
+    }
+}
+
+impl FromIterator<f64> for CompensatedSum {
+    fn from_iter<I: IntoIterator<Item=f64>>(iter: I) -> Self {
+        let mut sum = Self::default();
+        for value in iter {
+            sum.add(value);
+        }
+        sum
+    }
+}
+
+impl CompensatedSum {
+    /// Adds the given value to this sum
+    pub fn add(&mut self, value: f64) {
+        let total = self.sum + value;
+        // Whichever of the two values is smaller is the one that lost bits
+        if self.sum.abs() >= value.abs() {
+            self.compensation += (self.sum - total) + value;
+        } else {
+            self.compensation += (value - total) + self.sum;
+        }
+        self.sum = total;
+    }
+
+    /// Combines this sum with the given sum (e.g. from another thread)
+    pub fn merged(mut self, other: Self) -> Self {
+        self.add(other.sum);
+        self.compensation += other.compensation;
+        self
+    }
+
+    /// Returns the value of the sum
+    pub fn value(&self) -> f64 {
+        self.sum + self.compensation
+    }
+}
+
+/// A compensated sum of each channel of a color (see `CompensatedSum`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompensatedRgb {
+    r: CompensatedSum,
+    g: CompensatedSum,
+    b: CompensatedSum,
+}
+
+impl From<Rgb> for CompensatedRgb {
+    fn from(color: Rgb) -> Self {
+        Self {r: color.r.into(), g: color.g.into(), b: color.b.into()}
+    }
+}
+
+impl CompensatedRgb {
+    /// Adds the given color to this sum
+    pub fn add(&mut self, color: Rgb) {
+        self.r.add(color.r);
+        self.g.add(color.g);
+        self.b.add(color.b);
+    }
+
+    /// Combines this sum with the given sum (e.g. from another thread)
+    pub fn merged(self, other: Self) -> Self {
+        Self {
+            r: self.r.merged(other.r),
+            g: self.g.merged(other.g),
+            b: self.b.merged(other.b),
+        }
+    }
+
+    /// Returns the value of the sum
+    pub fn value(&self) -> Rgb {
+        Rgb {r: self.r.value(), g: self.g.value(), b: self.b.value()}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compensated_sum_keeps_small_values() {
+        let values = [1e16, 1.0, -1e16];
+        assert_eq!(values.iter().sum::<f64>(), 0.0);
+        assert_eq!(values.iter().copied().collect::<CompensatedSum>().value(), 1.0);
+
+        // Many small samples added to a bright total
+        let mut naive = 1e8;
+        let mut sum = CompensatedSum::from(1e8);
+        for _ in 0..100_000 {
+            naive += 1e-9;
+            sum.add(1e-9);
+        }
+        let expected = 1e8 + 1e-4;
+        assert!((sum.value() - expected).abs() < (naive - expected).abs());
+        assert!((sum.value() - expected).abs() <= 1.5e-8);
+
+        // Merging keeps the error of both sums
+        let merged = CompensatedSum::from(1e16).merged(CompensatedSum::from(1.0))
+            .merged(CompensatedSum::from(-1e16));
+        assert_eq!(merged.value(), 1.0);
+
+        let mut color = CompensatedRgb::from(Rgb {r: 1e16, g: 0.0, b: 1.0});
+        color.add(Rgb::white());
+        color.add(Rgb {r: -1e16, g: 0.0, b: 0.0});
+        assert_eq!(color.value(), Rgb {r: 1.0, g: 1.0, b: 2.0});
+    }
+}
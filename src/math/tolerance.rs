@@ -0,0 +1,106 @@
+//! The policy used throughout the ray tracer to account for floating point error
+//!
+//! Floating point error grows with the magnitude of the values involved, so a single fixed
+//! tolerance (like `EPSILON`) is too large for very small scenes (small objects get skipped
+//! entirely) and too small for very large scenes ("shadow acne" from surfaces intersecting
+//! themselves). Tolerances are instead computed relative to the scale of the values being
+//! compared, with an absolute minimum for values near zero.
+
+use super::{EPSILON, Vec3};
+
+/// The tolerance per unit of scale
+///
+/// Equal to `EPSILON` so that scenes with a scale of about 1.0 behave exactly as they always have.
+pub const RELATIVE: f64 = EPSILON;
+
+/// The smallest tolerance ever used, regardless of scale
+///
+/// Needed for values at (or very close to) zero, where the relative tolerance would be zero.
+pub const ABSOLUTE: f64 = 1e-10;
+
+/// Returns the tolerance for values with the given magnitude
+pub fn at_scale(scale: f64) -> f64 {
+    (RELATIVE * scale.abs()).max(ABSOLUTE)
+}
+
+/// Returns the tolerance for calculations involving the given point
+///
+/// The scale of a point is the largest magnitude of any of its components since that component
+/// has the least floating point precision.
+pub fn at_point(point: Vec3) -> f64 {
+    at_scale(point.map(f64::abs).reduce_partial_max())
+}
+
+/// Returns true if the two values are equal within the tolerance for their scale
+pub fn approx_eq(a: f64, b: f64) -> bool {
+    (a - b).abs() <= at_scale(a.abs().max(b.abs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::math::Rgb;
+    use crate::ray::Ray;
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::material::Material;
+    use crate::light::Light;
+    use crate::primitive::{Primitive, Plane, Cube, Cylinder, Cone};
+
+    #[test]
+    fn tolerance_scales_with_magnitude() {
+        assert_eq!(at_scale(1.0), EPSILON);
+        assert_eq!(at_scale(-1e6), 1e6 * EPSILON);
+        assert_eq!(at_scale(0.0), ABSOLUTE);
+        assert_eq!(at_point(Vec3::new(1.0, -1e3, 2.0)), at_scale(1e3));
+
+        assert!(approx_eq(1e12, 1e12 + 1.0));
+        assert!(!approx_eq(1e-9, 2e-9));
+        assert!(approx_eq(0.0, ABSOLUTE / 2.0));
+    }
+
+    /// Renders a point on the ground that should be lit and a point on the ground that should be
+    /// in the shadow of the given primitive, with the entire scene scaled by the given amount
+    fn lit_and_shadowed(scale: f64, occluder: Primitive) -> (Rgb, Rgb) {
+        let mat = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(Geometry::new(Plane, mat.clone())).scaled(10.0)),
+                // The occluder is a unit sized primitive floating 1.0 unit above the ground
+                Arc::new(SceneNode::from(Geometry::new(occluder, mat)).translated((0.0, 1.0, 0.0))),
+            ]).scaled(scale)),
+            lights: vec![Light {
+                position: Vec3::new(0.0, 10.0, 0.0) * scale,
+                color: Rgb::white(),
+                ..Light::default()
+            }],
+            ambient: Rgb::black(),
+        };
+
+        // Look at each point from the side so the rays pass under the occluder
+        let color_at = |point: Vec3| {
+            let origin = Vec3::new(5.0, 0.2, 0.1) * scale;
+            let target = point * scale;
+            Ray::new(origin, (target - origin).normalized()).color(&scene, Rgb::black(), 0)
+        };
+
+        (color_at(Vec3::new(3.0, 0.0, 0.0)), color_at(Vec3::new(0.0, 0.0, 0.1)))
+    }
+
+    #[test]
+    fn no_acne_or_light_leaks_at_extreme_scales() {
+        let occluders: [Primitive; 3] = [Cube.into(), Cylinder.into(), Cone.into()];
+
+        for &scale in &[1e-6, 1.0, 1e12] {
+            for occluder in &occluders {
+                let (lit, shadowed) = lit_and_shadowed(scale, occluder.clone());
+                // A lit point with acne would be black
+                assert!(lit.r > 0.5, "lit point is dark at scale {} with {:?}: {:?}", scale, occluder, lit);
+                // A shadowed point with a light leak would be lit
+                assert_eq!(shadowed, Rgb::black(), "shadowed point is lit at scale {} with {:?}", scale, occluder);
+            }
+        }
+    }
+}
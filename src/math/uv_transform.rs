@@ -0,0 +1,167 @@
+use rand::Rng;
+
+use super::{Uv, Mat3, Radians};
+
+/// An affine transformation of texture coordinates: a uniform scale, then a rotation
+/// (counterclockwise from +u towards +v), then an offset
+///
+/// Attached to scene nodes to vary how a shared textured material is mapped onto each instance
+/// (see `SceneNode::with_uv_transform`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvTransform {
+    /// Added to the texture coordinates after they are scaled and rotated
+    pub offset: Uv,
+    /// The angle that the texture coordinates are rotated by around (0, 0)
+    pub rotation: Radians,
+    /// The amount that the texture coordinates are scaled by (must be greater than zero)
+    ///
+    /// Values greater than 1.0 make the texture repeat more often.
+    pub scale: f64,
+}
+
+impl Default for UvTransform {
+    fn default() -> Self {
+        Self {
+            offset: Uv::zero(),
+            rotation: Radians::from_radians(0.0),
+            scale: 1.0,
+        }
+    }
+}
+
+impl UvTransform {
+    /// Generates a random offset (between 0.0 and 1.0 in each direction) and a random rotation
+    /// of up to the given angle in either direction
+    ///
+    /// Useful for breaking up the repetition of a tiling texture shared by many instances.
+    /// Textures are repeated outside of the 0.0 to 1.0 range, so any offset keeps the texture
+    /// seamless.
+    pub fn random<R: Rng>(rng: &mut R, max_rotation: Radians) -> Self {
+        Self {
+            offset: Uv {u: rng.gen(), v: rng.gen()},
+            rotation: Radians::from_radians((rng.gen::<f64>() * 2.0 - 1.0) * max_rotation.get()),
+            ..Self::default()
+        }
+    }
+
+    /// Recovers the transformation represented by the given matrix (see `Material::uv_trans`)
+    ///
+    /// The matrix is assumed to only scale uniformly, rotate, and translate. Any other scaling is
+    /// approximated by the amount that the matrix scales u by.
+    pub fn from_mat3(matrix: Mat3) -> Self {
+        let [u_axis, _, offset] = matrix.cols.into_array();
+        Self {
+            offset: Uv {u: offset.x, v: offset.y},
+            rotation: Radians::from_radians(u_axis.y.atan2(u_axis.x)),
+            scale: (u_axis.x * u_axis.x + u_axis.y * u_axis.y).sqrt(),
+        }
+    }
+
+    /// Applies this transformation to the given texture coordinate
+    pub fn apply(&self, uv: Uv) -> Uv {
+        let (sin, cos) = self.rotation.get().sin_cos();
+        Uv {
+            u: (cos * uv.u - sin * uv.v) * self.scale + self.offset.u,
+            v: (sin * uv.u + cos * uv.v) * self.scale + self.offset.v,
+        }
+    }
+
+    /// Returns the transformation that applies this transformation and then the given one
+    pub fn then(&self, next: &UvTransform) -> Self {
+        let offset = next.apply(self.offset);
+        Self {
+            offset,
+            rotation: Radians::from_radians(self.rotation.get() + next.rotation.get()),
+            scale: self.scale * next.scale,
+        }
+    }
+
+    /// Updates a normal map transform (see `RayIntersection::normal_map_transform`) so that it
+    /// matches the texture coordinates after this transformation is applied
+    ///
+    /// The tangent and bitangent follow the direction that u and v increase along the surface, so
+    /// they are rotated along with the texture coordinates. The scale and offset do not change
+    /// their directions.
+    pub fn apply_normal_map_transform(&self, normal_map_transform: Mat3) -> Mat3 {
+        let (sin, cos) = self.rotation.get().sin_cos();
+        // Rotates the x-axis (tangent) and z-axis (bitangent) of the y-up tangent space
+        let rotation = Mat3::from_col_arrays([
+            [cos, 0.0, -sin],
+            [0.0, 1.0, 0.0],
+            [sin, 0.0, cos],
+        ]);
+        normal_map_transform * rotation
+    }
+}
+
+impl From<UvTransform> for Mat3 {
+    /// Returns the matrix that applies the given transformation to a texture coordinate (u, v, 1)
+    fn from(transform: UvTransform) -> Self {
+        let UvTransform {offset, rotation, scale} = transform;
+        let (sin, cos) = rotation.get().sin_cos();
+        Mat3::from_col_arrays([
+            [cos * scale, sin * scale, 0.0],
+            [-sin * scale, cos * scale, 0.0],
+            [offset.u, offset.v, 1.0],
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use crate::math::Vec3;
+
+    fn assert_uv_eq(a: Uv, b: Uv) {
+        assert!((a - b).map(f64::abs).reduce_partial_max() < 1e-12, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn composed_transforms_apply_in_order() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let first = UvTransform {scale: 2.0, ..UvTransform::random(&mut rng, Radians::from_degrees(180.0))};
+        let second = UvTransform {scale: 0.3, ..UvTransform::random(&mut rng, Radians::from_degrees(180.0))};
+
+        let combined = first.then(&second);
+        for &uv in &[Uv::zero(), Uv {u: 1.0, v: 0.0}, Uv {u: 0.25, v: -3.0}] {
+            assert_uv_eq(combined.apply(uv), second.apply(first.apply(uv)));
+        }
+    }
+
+    #[test]
+    fn matrix_matches_transform() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let transform = UvTransform {scale: 1.7, ..UvTransform::random(&mut rng, Radians::from_degrees(90.0))};
+        let matrix = Mat3::from(transform);
+        for &uv in &[Uv::zero(), Uv {u: 1.0, v: 0.0}, Uv {u: 0.25, v: -3.0}] {
+            let uv_vec = matrix * Vec3::new(uv.u, uv.v, 1.0);
+            assert_uv_eq(Uv {u: uv_vec.x, v: uv_vec.y}, transform.apply(uv));
+        }
+
+        let recovered = UvTransform::from_mat3(matrix);
+        assert_uv_eq(recovered.offset, transform.offset);
+        assert!((recovered.rotation.get() - transform.rotation.get()).abs() < 1e-12);
+        assert!((recovered.scale - transform.scale).abs() < 1e-12);
+    }
+
+    #[test]
+    fn normal_map_tangent_follows_rotated_uvs() {
+        // On the unit plane, u increases along +x and v increases along +z
+        let transform = UvTransform {rotation: Radians::from_degrees(30.0), ..UvTransform::default()};
+        let uv_at = |x: f64, z: f64| transform.apply(Uv {u: x, v: z});
+
+        let norm_trans = transform.apply_normal_map_transform(Mat3::identity());
+        let tangent = norm_trans.cols[0];
+        let bitangent = norm_trans.cols[2];
+
+        // Moving along the tangent only increases u and moving along the bitangent only
+        // increases v
+        let step = uv_at(tangent.x, tangent.z) - uv_at(0.0, 0.0);
+        assert_uv_eq(step, Uv {u: 1.0, v: 0.0});
+        let step = uv_at(bitangent.x, bitangent.z) - uv_at(0.0, 0.0);
+        assert_uv_eq(step, Uv {u: 0.0, v: 1.0});
+    }
+}
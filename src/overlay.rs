@@ -0,0 +1,314 @@
+//! Draws diagnostic markers (text, rectangles, and crosses) on top of rendered images
+
+use crate::math::{Vec3, Vec3Ext, Mat4, Rgb};
+use crate::camera::Camera;
+use crate::light::Light;
+use crate::scene::SceneNode;
+use crate::bounding_box::Bounds;
+use crate::pixel_order::TILE_SIZE;
+use crate::render::to_pixel;
+
+/// The width of each character of the built-in font (in font pixels)
+const GLYPH_WIDTH: i64 = 3;
+/// The height of each character of the built-in font (in font pixels)
+const GLYPH_HEIGHT: i64 = 5;
+
+/// A shape drawn on top of an image, positioned in pixels
+#[derive(Debug, Clone, PartialEq)]
+pub enum Marker {
+    /// The outline of the rectangle between the given top left and bottom right corners
+    Rect {top_left: (f64, f64), bottom_right: (f64, f64)},
+    /// A cross centered at the given position, with arms that extend the given number of pixels
+    /// out from the center
+    Cross {center: (f64, f64), size: f64},
+    /// Text drawn with a tiny built-in font, with its top left corner at the given position
+    ///
+    /// Only digits, letters (drawn in uppercase), spaces, and a few punctuation characters are
+    /// supported. Any other characters are drawn as a filled box. Each line is separated by `\n`.
+    Text {position: (f64, f64), text: String},
+}
+
+/// A list of markers that can be drawn on top of an image after it is rendered
+///
+/// Useful for debugging (e.g. checking where the lights are) and for making figures that explain
+/// how the renderer works.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlay {
+    markers: Vec<(Marker, Rgb)>,
+    /// The size (in image pixels) of each pixel of the built-in font
+    pub text_scale: usize,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Self {
+            markers: Vec::new(),
+            text_scale: 1,
+        }
+    }
+}
+
+impl Overlay {
+    /// Returns the markers that will be drawn and their colors, in the order they will be drawn
+    pub fn markers(&self) -> &[(Marker, Rgb)] {
+        &self.markers
+    }
+
+    /// Adds a marker with the given color (in linear space, just like the colors of the scene)
+    pub fn add(&mut self, marker: Marker, color: Rgb) {
+        self.markers.push((marker, color));
+    }
+
+    /// Marks the position of each light on screen with a cross and its index in the list of
+    /// lights (lights behind the camera are skipped)
+    pub fn add_lights(&mut self, lights: &[Light], camera: &Camera) {
+        for (i, light) in lights.iter().enumerate() {
+            let (x, y) = match camera.project(light.position) {
+                Some(pos) => pos,
+                None => continue,
+            };
+
+            let color = light.color;
+            self.add(Marker::Cross {center: (x, y), size: 4.0}, color);
+            self.add(Marker::Text {position: (x + 6.0, y + 2.0), text: format!("L{}", i)}, color);
+        }
+    }
+
+    /// Outlines each of the tiles that an image with the given size is divided into when
+    /// rendering in tile order (see `PixelOrder::Tiles`)
+    pub fn add_tile_boundaries(&mut self, (width, height): (usize, usize), color: Rgb) {
+        for y in (0..height).step_by(TILE_SIZE) {
+            for x in (0..width).step_by(TILE_SIZE) {
+                let bottom_right = ((x + TILE_SIZE).min(width) - 1, (y + TILE_SIZE).min(height) - 1);
+                self.add(Marker::Rect {
+                    top_left: (x as f64, y as f64),
+                    bottom_right: (bottom_right.0 as f64, bottom_right.1 as f64),
+                }, color);
+            }
+        }
+    }
+
+    /// Outlines the area of the screen covered by the bounding box of the geometry of the given
+    /// node and each of its descendants
+    ///
+    /// Geometry with any part of its bounding box behind the camera is skipped.
+    pub fn add_node_bounds(&mut self, node: &SceneNode, camera: &Camera, color: Rgb) {
+        self.add_node_bounds_impl(node, Mat4::identity(), camera, color);
+    }
+
+    fn add_node_bounds_impl(&mut self, node: &SceneNode, parent_trans: Mat4, camera: &Camera, color: Rgb) {
+        let trans = parent_trans * node.trans();
+
+        if let Some(geometry) = node.geometry() {
+            let bounds = geometry.primitive.bounds();
+            let (min, max) = (bounds.min(), bounds.max());
+            let corners = (0..8).map(|i| Vec3 {
+                x: if i & 1 == 0 { min.x } else { max.x },
+                y: if i & 2 == 0 { min.y } else { max.y },
+                z: if i & 4 == 0 { min.z } else { max.z },
+            }.transformed_point(trans));
+
+            let projected: Option<Vec<_>> = corners.map(|corner| camera.project(corner)).collect();
+            if let Some(projected) = projected {
+                let top_left = projected.iter().fold((f64::INFINITY, f64::INFINITY),
+                    |(x1, y1), &(x, y)| (x1.min(x), y1.min(y)));
+                let bottom_right = projected.iter().fold((f64::NEG_INFINITY, f64::NEG_INFINITY),
+                    |(x2, y2), &(x, y)| (x2.max(x), y2.max(y)));
+                self.add(Marker::Rect {top_left, bottom_right}, color);
+            }
+        }
+
+        for child in node.children() {
+            self.add_node_bounds_impl(child, trans, camera, color);
+        }
+    }
+
+    /// Draws every marker onto the given image, in the order they were added
+    pub fn draw(&self, image: &mut image::RgbImage) {
+        let mut canvas = Canvas {image};
+        for (marker, color) in &self.markers {
+            let pixel = to_pixel(*color);
+            match marker {
+                &Marker::Rect {top_left, bottom_right} => {
+                    let (x1, y1) = (top_left.0.round() as i64, top_left.1.round() as i64);
+                    let (x2, y2) = (bottom_right.0.round() as i64, bottom_right.1.round() as i64);
+                    canvas.fill(x1, y1, x2, y1, pixel);
+                    canvas.fill(x1, y2, x2, y2, pixel);
+                    canvas.fill(x1, y1, x1, y2, pixel);
+                    canvas.fill(x2, y1, x2, y2, pixel);
+                },
+
+                &Marker::Cross {center, size} => {
+                    let (x, y) = (center.0.round() as i64, center.1.round() as i64);
+                    let size = size.round() as i64;
+                    canvas.fill(x - size, y, x + size, y, pixel);
+                    canvas.fill(x, y - size, x, y + size, pixel);
+                },
+
+                Marker::Text {position, text} => {
+                    let scale = self.text_scale.max(1) as i64;
+                    let (left, top) = (position.0.round() as i64, position.1.round() as i64);
+                    for (line_index, line) in text.lines().enumerate() {
+                        let line_top = top + line_index as i64 * (GLYPH_HEIGHT + 1) * scale;
+                        for (char_index, ch) in line.chars().enumerate() {
+                            let char_left = left + char_index as i64 * (GLYPH_WIDTH + 1) * scale;
+                            for (row, bits) in glyph(ch).iter().enumerate() {
+                                for col in 0..GLYPH_WIDTH {
+                                    if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                                        continue;
+                                    }
+
+                                    let x = char_left + col * scale;
+                                    let y = line_top + row as i64 * scale;
+                                    canvas.fill(x, y, x + scale - 1, y + scale - 1, pixel);
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Draws onto an image, ignoring any pixels outside of it
+struct Canvas<'a> {
+    image: &'a mut image::RgbImage,
+}
+
+impl<'a> Canvas<'a> {
+    /// Fills every pixel between (x1, y1) and (x2, y2), inclusive
+    fn fill(&mut self, x1: i64, y1: i64, x2: i64, y2: i64, pixel: image::Rgb<u8>) {
+        let width = self.image.width() as i64;
+        let height = self.image.height() as i64;
+        for y in y1.max(0)..=y2.min(height - 1) {
+            for x in x1.max(0)..=x2.min(width - 1) {
+                self.image.put_pixel(x as u32, y as u32, pixel);
+            }
+        }
+    }
+}
+
+/// Returns the rows (from top to bottom) of the given character in the built-in font
+///
+/// Each row has one bit for each column, with the leftmost column in the most significant bit.
+fn glyph(ch: char) -> [u8; GLYPH_HEIGHT as usize] {
+    match ch.to_ascii_uppercase() {
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        _ => [0b111, 0b111, 0b111, 0b111, 0b111],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::math::Radians;
+    use crate::camera::CameraSettings;
+    use crate::scene::Geometry;
+    use crate::material::Material;
+    use crate::primitive::Cube;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const BLACK: [u8; 3] = [0, 0, 0];
+
+    #[test]
+    fn markers_are_drawn_and_clipped() {
+        let mut overlay = Overlay::default();
+        overlay.add(Marker::Cross {center: (5.0, 5.0), size: 2.0}, Rgb::white());
+        // Partially outside of the image
+        overlay.add(Marker::Rect {top_left: (12.0, 12.0), bottom_right: (30.0, 30.0)}, Rgb::white());
+        overlay.add(Marker::Text {position: (0.0, 10.0), text: "1".to_string()}, Rgb::white());
+
+        let mut image = image::RgbImage::new(16, 16);
+        overlay.draw(&mut image);
+
+        assert_eq!(image.get_pixel(5, 5).data, WHITE);
+        assert_eq!(image.get_pixel(3, 5).data, WHITE);
+        assert_eq!(image.get_pixel(5, 7).data, WHITE);
+        assert_eq!(image.get_pixel(6, 6).data, BLACK);
+
+        assert_eq!(image.get_pixel(12, 15).data, WHITE);
+        assert_eq!(image.get_pixel(15, 12).data, WHITE);
+        assert_eq!(image.get_pixel(14, 14).data, BLACK);
+
+        // The "1" glyph: a vertical stroke in the middle column with a base
+        assert_eq!(image.get_pixel(1, 10).data, WHITE);
+        assert_eq!(image.get_pixel(0, 10).data, BLACK);
+        assert_eq!(image.get_pixel(0, 14).data, WHITE);
+    }
+
+    #[test]
+    fn node_bounds_surround_projected_geometry() {
+        let camera = Camera::new(CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 10.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        }, (64.0, 64.0));
+        let cube = Arc::new(SceneNode::from(Geometry::new(Cube, Arc::new(Material::default()))));
+        let root = SceneNode::from(vec![cube]).translated((1.0, 0.0, 0.0));
+
+        let mut overlay = Overlay::default();
+        overlay.add_node_bounds(&root, &camera, Rgb::white());
+        let (top_left, bottom_right) = match overlay.markers() {
+            [(Marker::Rect {top_left, bottom_right}, _)] => (*top_left, *bottom_right),
+            markers => panic!("expected a single rectangle, found {:?}", markers),
+        };
+
+        // The cube is to the right of the center of the image
+        let center = camera.project(Vec3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!(top_left.0 < center.0 && center.0 < bottom_right.0);
+        assert!(top_left.1 < center.1 && center.1 < bottom_right.1);
+        assert!(center.0 > 32.0);
+    }
+}
@@ -0,0 +1,221 @@
+//! Strategies for choosing the order that the pixels of an image are rendered in
+
+use std::fmt;
+use std::error::Error;
+use std::str::FromStr;
+
+/// The width and height of each tile in `PixelOrder::Tiles`
+pub(crate) const TILE_SIZE: usize = 16;
+
+/// An error produced when parsing the name of a pixel order fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsePixelOrderError {
+    name: String,
+}
+
+impl fmt::Display for ParsePixelOrderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid pixel order '{}': expected 'scanline', 'tiles', 'morton', or 'spiral'", self.name)
+    }
+}
+
+impl Error for ParsePixelOrderError {}
+
+/// An error produced when parsing a render focus fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseRenderFocusError {
+    value: String,
+}
+
+impl fmt::Display for ParseRenderFocusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid render focus '{}': expected 'center' or a rectangle like '10,20,100,200'", self.value)
+    }
+}
+
+impl Error for ParseRenderFocusError {}
+
+/// The order that pixels are rendered in
+///
+/// Rendering is parallel, so pixels are only *started* in roughly this order. Pixels that are
+/// near each other in the order tend to hit the same parts of the scene, so orders that keep
+/// nearby pixels together make better use of the CPU cache when traversing the scene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PixelOrder {
+    /// Row by row, from left to right and top to bottom
+    #[default]
+    Scanline,
+    /// Square tiles of pixels (row by row within each tile), with the tiles in scanline order
+    Tiles,
+    /// Z-order curve (Morton order), which keeps pixels that are near each other in 2D near each
+    /// other in the order at every scale
+    Morton,
+    /// Outwards from the center in a spiral, so the middle of the image (usually the subject)
+    /// finishes first. Great for previews.
+    Spiral,
+}
+
+impl FromStr for PixelOrder {
+    type Err = ParsePixelOrderError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match &*name.to_lowercase() {
+            "scanline" => Ok(PixelOrder::Scanline),
+            "tiles" => Ok(PixelOrder::Tiles),
+            "morton" => Ok(PixelOrder::Morton),
+            "spiral" => Ok(PixelOrder::Spiral),
+            _ => Err(ParsePixelOrderError {name: name.to_string()}),
+        }
+    }
+}
+
+impl PixelOrder {
+    /// Returns every (x, y) pixel between the given top left and bottom right pixels (inclusive)
+    /// in this order
+    pub fn pixels(self, (x1, y1): (usize, usize), (x2, y2): (usize, usize)) -> Vec<(usize, usize)> {
+        let mut pixels: Vec<_> = (y1..=y2).flat_map(|y| (x1..=x2).map(move |x| (x, y))).collect();
+
+        use PixelOrder::*;
+        match self {
+            Scanline => {},
+            Tiles => pixels.sort_by_key(|&(x, y)| {
+                let (tile_x, tile_y) = ((x - x1) / TILE_SIZE, (y - y1) / TILE_SIZE);
+                (tile_y, tile_x, y, x)
+            }),
+            Morton => pixels.sort_by_key(|&(x, y)| morton_code((x - x1) as u32, (y - y1) as u32)),
+            Spiral => {
+                let center_x = (x1 + x2) as f64 / 2.0;
+                let center_y = (y1 + y2) as f64 / 2.0;
+                // Each "ring" of the spiral is a square around the center, ordered by angle
+                let ring_angle = |(x, y): (usize, usize)| {
+                    let dx = x as f64 - center_x;
+                    let dy = y as f64 - center_y;
+                    (dx.abs().max(dy.abs()).round(), dy.atan2(dx))
+                };
+                pixels.sort_by(|&a, &b| ring_angle(a).partial_cmp(&ring_angle(b))
+                    .expect("bug: pixel positions should never be NaN"));
+            },
+        }
+
+        pixels
+    }
+}
+
+/// A part of the image that is rendered before the rest of the image, regardless of the pixel
+/// order
+///
+/// Useful for long renders so that the subject of the image is finished early. Within the focus
+/// (and within the rest of the image), pixels are still rendered in the chosen pixel order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderFocus {
+    /// The middle of the image (half of its width and half of its height)
+    Center,
+    /// The pixels between the given top left and bottom right (x, y) pixels (inclusive)
+    Rect {
+        top_left: (usize, usize),
+        bottom_right: (usize, usize),
+    },
+}
+
+impl FromStr for RenderFocus {
+    type Err = ParseRenderFocusError;
+
+    /// Parses either "center" or a rectangle "x1,y1,x2,y2" (top left and bottom right pixels)
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let err = || ParseRenderFocusError {value: value.to_string()};
+
+        if value.eq_ignore_ascii_case("center") {
+            return Ok(RenderFocus::Center);
+        }
+
+        let coords = value.split(',')
+            .map(|coord| coord.trim().parse::<usize>().map_err(|_| err()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match coords[..] {
+            [x1, y1, x2, y2] => Ok(RenderFocus::Rect {top_left: (x1, y1), bottom_right: (x2, y2)}),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl RenderFocus {
+    /// Returns true if the given pixel is in the focus of an image with the given size
+    pub fn contains(self, (x, y): (usize, usize), (width, height): (usize, usize)) -> bool {
+        let ((x1, y1), (x2, y2)) = match self {
+            RenderFocus::Center => ((width / 4, height / 4), (width * 3 / 4, height * 3 / 4)),
+            RenderFocus::Rect {top_left, bottom_right} => (top_left, bottom_right),
+        };
+
+        x1 <= x && x <= x2 && y1 <= y && y <= y2
+    }
+
+    /// Moves every pixel in the focus of an image with the given size to the front, otherwise
+    /// keeping the pixels in the same order
+    pub fn prioritize(self, pixels: &mut [(usize, usize)], size: (usize, usize)) {
+        // Stable sort, so the order within and outside of the focus is preserved
+        pixels.sort_by_key(|&pixel| !self.contains(pixel, size));
+    }
+}
+
+/// Interleaves the bits of x and y to produce the position of (x, y) along a Z-order curve
+fn morton_code(x: u32, y: u32) -> u64 {
+    // Spreads out the bits of the value so there is a zero between each bit
+    fn spread(value: u32) -> u64 {
+        let mut value = value as u64;
+        value = (value | (value << 16)) & 0x0000_FFFF_0000_FFFF;
+        value = (value | (value << 8)) & 0x00FF_00FF_00FF_00FF;
+        value = (value | (value << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+        value = (value | (value << 2)) & 0x3333_3333_3333_3333;
+        value = (value | (value << 1)) & 0x5555_5555_5555_5555;
+        value
+    }
+
+    spread(x) | (spread(y) << 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_cover_every_pixel_once() {
+        let mut expected = PixelOrder::Scanline.pixels((3, 2), (40, 21));
+        expected.sort();
+        assert_eq!(expected.len(), 38 * 20);
+
+        for &order in &[PixelOrder::Tiles, PixelOrder::Morton, PixelOrder::Spiral] {
+            let mut pixels = order.pixels((3, 2), (40, 21));
+            pixels.sort();
+            assert_eq!(pixels, expected, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn morton_and_spiral_orders() {
+        assert_eq!(morton_code(0b11, 0b01), 0b0111);
+        assert_eq!(morton_code(u32::MAX, u32::MAX), u64::MAX);
+
+        assert_eq!(&PixelOrder::Morton.pixels((0, 0), (3, 3))[..6],
+            &[(0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (3, 0)]);
+        assert_eq!(PixelOrder::Spiral.pixels((0, 0), (10, 6))[0], (5, 3));
+
+        assert_eq!("Morton".parse(), Ok(PixelOrder::Morton));
+        assert!("hilbert".parse::<PixelOrder>().is_err());
+    }
+
+    #[test]
+    fn focus_is_rendered_first() {
+        let focus: RenderFocus = "2, 1, 3, 2".parse().unwrap();
+        assert_eq!(focus, RenderFocus::Rect {top_left: (2, 1), bottom_right: (3, 2)});
+        assert!("1,2,3".parse::<RenderFocus>().is_err());
+
+        let mut pixels = PixelOrder::Scanline.pixels((0, 0), (5, 3));
+        focus.prioritize(&mut pixels, (6, 4));
+        assert_eq!(&pixels[..5], &[(2, 1), (3, 1), (2, 2), (3, 2), (0, 0)]);
+
+        let mut pixels = PixelOrder::Scanline.pixels((0, 0), (7, 7));
+        "center".parse::<RenderFocus>().unwrap().prioritize(&mut pixels, (8, 8));
+        assert_eq!(pixels[0], (2, 2));
+        assert!(!RenderFocus::Center.contains(pixels[25], (8, 8)));
+    }
+}
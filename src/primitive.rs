@@ -1,4 +1,5 @@
 mod sphere;
+mod ellipsoid;
 mod triangle;
 mod mesh;
 mod infinite_plane;
@@ -6,14 +7,18 @@ mod cube;
 mod plane;
 mod cylinder;
 mod cone;
+mod frustum;
+mod generate;
 
 pub use sphere::*;
+pub use ellipsoid::*;
 pub use triangle::*;
 pub use mesh::*;
 pub use cube::*;
 pub use plane::*;
 pub use cylinder::*;
 pub use cone::*;
+pub use frustum::*;
 
 // Internal-use only
 pub(crate) use infinite_plane::*;
@@ -31,6 +36,9 @@ macro_rules! primitive_enum {
         $($variant:ident ( $primtype:ident ),)*
     }) => {
         $(#[$m])*
+        // Triangles are stored inline (rather than boxed) to avoid an extra indirection for
+        // every triangle primitive in the scene
+        #[allow(clippy::large_enum_variant)]
         pub enum $name {
             $($variant($primtype),)*
         }
@@ -43,6 +51,16 @@ macro_rules! primitive_enum {
             }
         )*
 
+        impl $name {
+            /// Returns the name of the kind of primitive stored in this value
+            pub fn kind_name(&self) -> &'static str {
+                use $name::*;
+                match self {
+                    $($variant(_) => stringify!($variant)),*
+                }
+            }
+        }
+
         impl Bounds for $name {
             fn bounds(&self) -> BoundingBox {
                 use $name::*;
@@ -68,6 +86,7 @@ primitive_enum! {
     #[derive(Debug, Clone, PartialEq)]
     pub enum Primitive {
         Sphere(Sphere),
+        Ellipsoid(Ellipsoid),
         Triangle(Triangle),
         Mesh(Mesh),
         KDMesh(KDMesh),
@@ -77,5 +96,6 @@ primitive_enum! {
         Cube(Cube),
         Cylinder(Cylinder),
         Cone(Cone),
+        Frustum(Frustum),
     }
 }
@@ -1,7 +1,7 @@
 use std::ops::Range;
 
 use crate::ray::{Ray, RayHit, RayIntersection};
-use crate::math::{Vec3, Quadratic};
+use crate::math::{Vec3, Quadratic, tolerance};
 use crate::bounding_box::{BoundingBox, Bounds};
 
 /// The radius of the cone
@@ -71,7 +71,8 @@ fn ray_hit_body(ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
 
     let hit_point = ray.at(t);
     // Test if we intersected beyond the tip or below the cap
-    if hit_point.y > HALF_HEIGHT || hit_point.y < -HALF_HEIGHT {
+    // The tolerance accounts for floating point error so rays do not slip through the edges
+    if hit_point.y.abs() > HALF_HEIGHT + tolerance::at_scale(HEIGHT) {
         return None;
     }
 
@@ -106,8 +107,10 @@ fn ray_hit_body(ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
     Some(RayIntersection {
         ray_parameter: t,
         hit_point,
+        shadow_origin: None,
         normal,
         tex_coord: None,
+        secondary_tex_coord: None,
         normal_map_transform: None,
     })
 }
@@ -140,7 +143,8 @@ fn ray_hit_cap(ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
 
     let hit_point = ray.at(t);
     // Check if point is within the circle
-    if (hit_point.x*hit_point.x + hit_point.z*hit_point.z) > RADIUS*RADIUS {
+    let radius = RADIUS + tolerance::at_scale(HEIGHT);
+    if (hit_point.x*hit_point.x + hit_point.z*hit_point.z) > radius*radius {
         return None;
     }
 
@@ -150,8 +154,10 @@ fn ray_hit_cap(ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
     Some(RayIntersection {
         ray_parameter: t,
         hit_point,
+        shadow_origin: None,
         normal,
         tex_coord: None,
+        secondary_tex_coord: None,
         normal_map_transform: None,
     })
 }
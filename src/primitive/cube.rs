@@ -1,7 +1,7 @@
 use std::ops::Range;
 
 use crate::ray::{Ray, RayHit, RayIntersection};
-use crate::math::{EPSILON, Vec3, Mat3, Uv};
+use crate::math::{EPSILON, Vec3, Mat3, Uv, tolerance};
 use crate::bounding_box::{BoundingBox, Bounds};
 
 use super::InfinitePlane;
@@ -20,9 +20,10 @@ pub struct Cube;
 impl Cube {
     /// Returns true if the given point is anywhere within the *volume* of the cube
     pub fn contains(self, Vec3 {x, y, z}: Vec3) -> bool {
-        // Need to add epsilon when doing these checks to account for floating point error. Without
-        // this we get lots of "unfilled" spots ("shadow acne") all over the cube and its shadow.
-        let radius = L2 + EPSILON;
+        // Need to add a tolerance when doing these checks to account for floating point error.
+        // Without this we get lots of "unfilled" spots ("shadow acne") all over the cube and its
+        // shadow.
+        let radius = L2 + tolerance::at_scale(L);
         -radius <= x && x <= radius && -radius <= y && y <= radius && -radius <= z && z <= radius
     }
 }
@@ -0,0 +1,119 @@
+use std::ops::Range;
+
+use crate::ray::{Ray, RayHit, RayIntersection};
+use crate::math::{Vec3, Mat3};
+use crate::bounding_box::{BoundingBox, Bounds};
+
+use super::Sphere;
+
+/// An ellipsoid with center (0, 0, 0) and the given semi-axes (the "radius" along x, y, and z)
+///
+/// This is the same shape as a `Sphere` on a node scaled by the semi-axes, but makes squashed or
+/// stretched spheres explicit in the scene. The texture coordinates are the same as the sphere
+/// (the texture is stretched along with the surface) and the normals and normal map tangents
+/// always match the shape of the surface, no matter how the node containing it is transformed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ellipsoid {
+    semi_axes: Vec3,
+}
+
+impl Ellipsoid {
+    /// Creates a new ellipsoid with the given semi-axes
+    ///
+    /// Panics if any of the semi-axes are not greater than zero.
+    pub fn new<V: Into<Vec3>>(semi_axes: V) -> Self {
+        let semi_axes = semi_axes.into();
+        assert!(semi_axes.iter().all(|&axis| axis > 0.0), "The semi-axes of an ellipsoid must be greater than zero");
+
+        Self {semi_axes}
+    }
+
+    /// Returns the semi-axes of this ellipsoid
+    pub fn semi_axes(&self) -> Vec3 {
+        self.semi_axes
+    }
+}
+
+impl Bounds for Ellipsoid {
+    fn bounds(&self) -> BoundingBox {
+        BoundingBox::new(-self.semi_axes, self.semi_axes)
+    }
+}
+
+impl RayHit for Ellipsoid {
+    fn ray_hit(&self, ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
+        // Scaling the ray down by the semi-axes turns the ellipsoid into the unit sphere. Since
+        // both the origin and the direction are scaled, the ray parameter of every point is the
+        // same in both spaces.
+        let axes = self.semi_axes;
+        let unit_ray = Ray::new(ray.origin() / axes, ray.direction() / axes);
+        let mut hit = Sphere.ray_hit(&unit_ray, t_range)?;
+
+        let unit_point = hit.hit_point;
+        hit.hit_point = ray.at(hit.ray_parameter);
+        // The normal is the gradient of x^2/a^2 + y^2/b^2 + z^2/c^2 = 1, which is the normal of
+        // the unit sphere divided by the semi-axes (the inverse transpose of the scaling)
+        hit.normal = unit_point / axes;
+
+        // Vectors tangent to the surface are scaled along with the surface
+        hit.normal_map_transform = hit.normal_map_transform.map(|norm_trans| {
+            let tangent = (norm_trans.cols[0] * axes).normalized();
+            let bitangent = (norm_trans.cols[2] * axes).normalized();
+            Mat3::from_col_arrays([
+                tangent.into_array(),
+                hit.normal.normalized().into_array(),
+                bitangent.into_array(),
+            ])
+        });
+
+        Some(hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use crate::math::{EPSILON, INFINITY};
+    use crate::material::Material;
+    use crate::ray::RayCast;
+    use crate::scene::{SceneNode, Geometry};
+
+    #[test]
+    fn matches_scaled_sphere() {
+        let semi_axes = Vec3::new(2.0, 0.5, 1.0);
+        let mat = Arc::new(Material::default());
+        let ellipsoid = SceneNode::from(Geometry::new(Ellipsoid::new(semi_axes), mat.clone()));
+        let scaled_sphere = SceneNode::from(Geometry::new(Sphere, mat)).scaled(semi_axes);
+
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..1000 {
+            let origin = Vec3::new(rng.gen_range(-5.0, 5.0), rng.gen_range(-5.0, 5.0), 4.0);
+            let target = Vec3::new(rng.gen_range(-2.0, 2.0), rng.gen_range(-0.5, 0.5), rng.gen_range(-1.0, 1.0));
+            let ray = Ray::new(origin, (target - origin).normalized());
+
+            let mut t_range = Range {start: EPSILON, end: INFINITY};
+            let hit = ellipsoid.ray_cast(&ray, &mut t_range).map(|(hit, _)| hit);
+            let mut t_range = Range {start: EPSILON, end: INFINITY};
+            let expected = scaled_sphere.ray_cast(&ray, &mut t_range).map(|(hit, _)| hit);
+
+            match (hit, expected) {
+                (Some(hit), Some(expected)) => {
+                    assert!((hit.ray_parameter - expected.ray_parameter).abs() < EPSILON);
+                    assert!((hit.normal.normalized() - expected.normal.normalized()).magnitude() < EPSILON);
+                    assert_eq!(hit.tex_coord, expected.tex_coord);
+
+                    // The normal map transform stays orthogonal to the normal
+                    let norm_trans = hit.normal_map_transform.unwrap();
+                    assert!(norm_trans.cols[0].dot(hit.normal).abs() < EPSILON);
+                    assert!(norm_trans.cols[2].dot(hit.normal).abs() < EPSILON);
+                },
+                (hit, expected) => assert_eq!(hit.is_some(), expected.is_some()),
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::f64::consts::PI;
+use std::ops::Range;
+
+use crate::ray::{Ray, RayHit, RayIntersection};
+use crate::math::{Vec3, Mat3, Uv, Quadratic, tolerance};
+use crate::bounding_box::{BoundingBox, Bounds};
+
+const HEIGHT: f64 = 1.0;
+const HALF_HEIGHT: f64 = HEIGHT / 2.0;
+
+/// A conical frustum (a cone with its tip cut off) with center (0, 0, 0) and height = 1.0
+///
+/// The bottom of the frustum is at y = -0.5 and the top is at y = 0.5. Each end has its own
+/// radius, which makes this a generalization of `Cylinder` (equal radii) and `Cone` (a top radius
+/// of zero). Useful for lamp shades, buckets, and tower roofs.
+///
+/// Both ends are closed by flat caps. The side is texture mapped by wrapping the texture around
+/// the y-axis (from the top at v = 0.0 to the bottom at v = 1.0) and the caps are mapped by
+/// projecting the texture onto them from above.
+///
+/// It is expected that this frustum will be used via affine transformations on the node that
+/// contains it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    /// The radius of the bottom end (at y = -0.5)
+    bottom_radius: f64,
+    /// The radius of the top end (at y = 0.5)
+    top_radius: f64,
+}
+
+impl Frustum {
+    /// Creates a new frustum with the given radii for its bottom and top ends
+    ///
+    /// Panics if either radius is negative or if both radii are zero.
+    pub fn new(bottom_radius: f64, top_radius: f64) -> Self {
+        assert!(bottom_radius >= 0.0 && top_radius >= 0.0, "The radii of a frustum must not be negative");
+        assert!(bottom_radius > 0.0 || top_radius > 0.0, "At least one radius of a frustum must be greater than zero");
+
+        Self {bottom_radius, top_radius}
+    }
+
+    /// Returns the radius of the bottom end (at y = -0.5)
+    pub fn bottom_radius(&self) -> f64 {
+        self.bottom_radius
+    }
+
+    /// Returns the radius of the top end (at y = 0.5)
+    pub fn top_radius(&self) -> f64 {
+        self.top_radius
+    }
+
+    /// The largest radius of the frustum
+    fn max_radius(&self) -> f64 {
+        self.bottom_radius.max(self.top_radius)
+    }
+
+    /// The change in the radius per unit of height
+    fn slope(&self) -> f64 {
+        (self.top_radius - self.bottom_radius) / HEIGHT
+    }
+
+    /// Returns the radius of the side at the given height
+    fn radius_at(&self, y: f64) -> f64 {
+        self.bottom_radius + self.slope() * (y + HALF_HEIGHT)
+    }
+
+    /// Attempt to intersect with the side of the frustum
+    fn ray_hit_side(&self, ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
+        // The radius of the side at height y is r(y) = m*y + k, where m is the slope and k is the
+        // radius halfway up the side (at y = 0).
+        //
+        // Equation for the side: x^2 + z^2 - (m*y + k)^2 = 0
+        // Ray equation: r(t) = p + td
+        //
+        // Finding intersection: (substitute components of r into x,y,z in the side equation)
+        //     (p.x + t*d.x)^2 + (p.z + t*d.z)^2 - (m*(p.y + t*d.y) + k)^2 = 0
+        // Grouping by t:
+        //     (d.x^2 + d.z^2 - m^2*d.y^2)*t^2
+        //         + 2*(p.x*d.x + p.z*d.z - m*d.y*(m*p.y + k))*t
+        //         + (p.x^2 + p.z^2 - (m*p.y + k)^2) = 0
+        //
+        // Solving this will give us whether the ray intersects with any side of an infinitely long
+        // cone (or cylinder if m = 0). Unless this is a cylinder, a mirror image of the cone
+        // stretches past its tip. To limit the frustum to the right dimensions, we check the y
+        // value to make sure it is in [-height/2, height/2]. Since the first hit may be on the
+        // mirror image, both solutions need to be checked.
+
+        let origin = ray.origin();
+        let direction = ray.direction();
+
+        let m = self.slope();
+        let k = self.radius_at(0.0);
+        let origin_radius = m*origin.y + k;
+
+        let a = direction.x*direction.x + direction.z*direction.z - m*m*direction.y*direction.y;
+        let b = 2.0*(origin.x*direction.x + origin.z*direction.z - m*direction.y*origin_radius);
+        let c = origin.x*origin.x + origin.z*origin.z - origin_radius*origin_radius;
+
+        let equation = Quadratic {a, b, c};
+        // Find the smallest t for which this equation is satisfied within the height of the frustum
+        // The tolerance accounts for floating point error so rays do not slip through the edges
+        let max_y = HALF_HEIGHT + tolerance::at_scale(HEIGHT);
+        let t = equation.solve().iter()
+            .find(|&t| t_range.contains(&t) && ray.at(t).y.abs() <= max_y)?;
+
+        let hit_point = ray.at(t);
+        let Vec3 {x, y, z} = hit_point;
+        let radius = self.radius_at(y);
+
+        // The normal is the gradient of the side equation: (2x, -2m*(m*y + k), 2z). Dividing
+        // the x and z components by the radius keeps the normal well-behaved near the tip of a
+        // cone, where the radius approaches zero.
+        let (cos, sin) = if radius > 0.0 { (x / radius, -z / radius) } else { (1.0, 0.0) };
+        let normal = Vec3 {x: cos, y: -m, z: -sin};
+
+        let tex_coord = Uv {
+            // Same horizontal mapping as the sphere, so that the texture wraps around the y-axis
+            u: (PI + sin.atan2(cos)) / (2.0 * PI),
+            v: HALF_HEIGHT - y,
+        };
+
+        // The tangent points in the direction that u increases (around the y-axis) and the
+        // bitangent points in the direction that v increases (down the side)
+        let tangent = Vec3 {x: -sin, y: 0.0, z: -cos};
+        let bitangent = -Vec3 {x: m*cos, y: 1.0, z: -m*sin}.normalized();
+        let normal_map_transform = Mat3::from_col_arrays([
+            tangent.into_array(),
+            normal.normalized().into_array(),
+            bitangent.into_array(),
+        ]);
+
+        Some(RayIntersection {
+            ray_parameter: t,
+            hit_point,
+            shadow_origin: None,
+            normal,
+            tex_coord: Some(tex_coord),
+            secondary_tex_coord: None,
+            normal_map_transform: Some(normal_map_transform),
+        })
+    }
+
+    /// Attempt to intersect with the cap at the given height (either -HALF_HEIGHT or HALF_HEIGHT)
+    fn ray_hit_cap(&self, ray: &Ray, t_range: &Range<f64>, height: f64) -> Option<RayIntersection> {
+        let radius = self.radius_at(height);
+        // The end of a cone is a point, not a cap
+        if radius <= 0.0 {
+            return None;
+        }
+
+        // The cap is part of an axis-aligned plane, so we only need to solve the y-component of
+        // the ray equation: r.y = p.y + t*d.y with r.y = height (see `Cone` for details)
+        let origin = ray.origin();
+        let direction = ray.direction();
+
+        let t = (height - origin.y) / direction.y;
+        // Return as soon as possible to avoid extra work
+        if !t_range.contains(&t) {
+            return None;
+        }
+
+        let hit_point = ray.at(t);
+        // Check if point is within the circle
+        let radius = radius + tolerance::at_scale(HEIGHT);
+        if (hit_point.x*hit_point.x + hit_point.z*hit_point.z) > radius*radius {
+            return None;
+        }
+
+        // Both caps are mapped with the same scale so that the texture lines up between them
+        // when viewed from above. The bottom cap is flipped along z to keep the tangent space
+        // right-handed with its normal pointing down.
+        let scale = 2.0 * self.max_radius();
+        let is_top = height > 0.0;
+        let normal = if is_top { Vec3::up() } else { Vec3::down() };
+        let bitangent = if is_top { Vec3::unit_z() } else { -Vec3::unit_z() };
+        let tex_coord = Uv {
+            u: hit_point.x / scale + 0.5,
+            v: bitangent.dot(hit_point) / scale + 0.5,
+        };
+        let normal_map_transform = Mat3::from_col_arrays([
+            Vec3::unit_x().into_array(),
+            normal.into_array(),
+            bitangent.into_array(),
+        ]);
+
+        Some(RayIntersection {
+            ray_parameter: t,
+            hit_point,
+            shadow_origin: None,
+            normal,
+            tex_coord: Some(tex_coord),
+            secondary_tex_coord: None,
+            normal_map_transform: Some(normal_map_transform),
+        })
+    }
+}
+
+impl Bounds for Frustum {
+    fn bounds(&self) -> BoundingBox {
+        let radius = self.max_radius();
+        let min = Vec3 {x: -radius, y: -HALF_HEIGHT, z: -radius};
+        let max = Vec3 {x: radius, y: HALF_HEIGHT, z: radius};
+        BoundingBox::new(min, max)
+    }
+}
+
+impl RayHit for Frustum {
+    fn ray_hit(&self, ray: &Ray, init_t_range: &Range<f64>) -> Option<RayIntersection> {
+        // A frustum has three parts: the side, the bottom cap, and the top cap. We can't guarantee
+        // which order the ray will hit these parts in, so all of them need to be tested. Each hit
+        // shortens the t_range so that only closer hits are accepted afterwards.
+
+        let mut t_range = init_t_range.clone();
+        let mut found_hit = None;
+
+        let parts = [None, Some(-HALF_HEIGHT), Some(HALF_HEIGHT)];
+        for &cap_height in &parts {
+            let hit = match cap_height {
+                None => self.ray_hit_side(ray, &t_range),
+                Some(height) => self.ray_hit_cap(ray, &t_range, height),
+            };
+
+            if let Some(hit) = hit {
+                // Must find a closer hit next time to be accepted
+                t_range.end = hit.ray_parameter;
+                found_hit = Some(hit);
+            }
+        }
+
+        found_hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use crate::math::{EPSILON, INFINITY};
+    use crate::primitive::{Cylinder, Cone};
+
+    /// Asserts that the frustum is hit at the same place as the given primitive by rays that pass
+    /// through the space around the side of the frustum
+    fn assert_same_hits<P: RayHit>(frustum: Frustum, primitive: P) {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..1000 {
+            let angle = rng.gen_range(0.0, 2.0 * PI);
+            let origin = Vec3::new(angle.cos() * 3.0, rng.gen_range(-0.4, 0.4), angle.sin() * 3.0);
+            let target = Vec3::new(rng.gen_range(-0.6, 0.6), rng.gen_range(-0.4, 0.4), rng.gen_range(-0.6, 0.6));
+            let ray = Ray::new(origin, (target - origin).normalized());
+            let t_range = Range {start: EPSILON, end: INFINITY};
+
+            match (frustum.ray_hit(&ray, &t_range), primitive.ray_hit(&ray, &t_range)) {
+                (Some(hit), Some(expected)) => {
+                    assert!((hit.ray_parameter - expected.ray_parameter).abs() < EPSILON);
+                    assert!((hit.normal.normalized() - expected.normal.normalized()).magnitude() < EPSILON,
+                        "{:?} != {:?}", hit.normal, expected.normal);
+                },
+                (hit, expected) => assert_eq!(hit.is_some(), expected.is_some(), "{:?}", ray),
+            }
+        }
+    }
+
+    #[test]
+    fn generalizes_cylinder_and_cone() {
+        assert_same_hits(Frustum::new(0.5, 0.5), Cylinder);
+        assert_same_hits(Frustum::new(0.5, 0.0), Cone);
+    }
+
+    #[test]
+    fn hits_side_and_caps_from_above() {
+        let frustum = Frustum::new(0.5, 0.25);
+        let t_range = Range {start: EPSILON, end: INFINITY};
+        let hit_from_above = |x: f64| frustum.ray_hit(&Ray::new(Vec3::new(x, 2.0, 0.0), Vec3::down()), &t_range);
+
+        // Inside the top radius, the top cap is hit
+        let hit = hit_from_above(0.2).unwrap();
+        assert!((hit.hit_point.y - HALF_HEIGHT).abs() < EPSILON);
+        assert_eq!(hit.normal, Vec3::up());
+
+        // Between the radii, the side is hit halfway down where the radius is 0.375
+        let hit = hit_from_above(0.375).unwrap();
+        assert!(hit.hit_point.y.abs() < EPSILON);
+        let normal = hit.normal.normalized();
+        assert!(normal.x > 0.0 && normal.y > 0.0 && normal.z.abs() < EPSILON, "{:?}", normal);
+        let uv = hit.tex_coord.unwrap();
+        assert!((uv.v - 0.5).abs() < EPSILON);
+
+        // Outside the bottom radius, nothing is hit
+        assert!(hit_from_above(0.6).is_none());
+
+        // The side is also hit from the inside (e.g. by refracted rays)
+        let ray = Ray::new(Vec3::zero(), Vec3::unit_x());
+        let hit = frustum.ray_hit(&ray, &t_range).unwrap();
+        assert!((hit.ray_parameter - 0.375).abs() < EPSILON);
+    }
+}
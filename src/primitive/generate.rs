@@ -0,0 +1,348 @@
+//! Procedural generators for mesh data
+
+use std::f64::consts::PI;
+
+use crate::math::{Vec2, Vec3, Uv, Curve, ArcLength, OrthonormalBasis};
+
+use super::MeshData;
+
+/// The number of samples used per step to approximate the arc length of an extrusion path
+const ARC_LENGTH_SAMPLES_PER_STEP: usize = 8;
+
+/// Generates the triangles for a grid of vertices with the given number of rows and columns
+///
+/// The vertex at (row, col) must be at index `row * cols + col`. Triangles are wound so that
+/// the face normal points along (next col - col) x (next row - row).
+fn grid_triangles(rows: usize, cols: usize) -> Vec<(usize, usize, usize)> {
+    let mut triangles = Vec::with_capacity((rows - 1) * (cols - 1) * 2);
+    for row in 0..rows-1 {
+        for col in 0..cols-1 {
+            let a = row * cols + col;
+            let b = a + 1;
+            let c = a + cols;
+            let d = c + 1;
+
+            triangles.push((a, b, c));
+            triangles.push((b, d, c));
+        }
+    }
+    triangles
+}
+
+/// Returns the points of a regular polygon approximating a circle, in counter-clockwise order
+fn circle(radius: f64, segments: usize) -> Vec<Vec2> {
+    (0..segments).map(|i| {
+        let (sin, cos) = (i as f64 / segments as f64 * 2.0 * PI).sin_cos();
+        Vec2 {x: radius * cos, y: radius * sin}
+    }).collect()
+}
+
+/// A helix around the Y axis, starting at (radius, 0, 0)
+struct Helix {
+    radius: f64,
+    height: f64,
+    turns: f64,
+}
+
+impl Curve for Helix {
+    fn at(&self, t: f64) -> Vec3 {
+        let (sin, cos) = (t * self.turns * 2.0 * PI).sin_cos();
+        Vec3 {x: self.radius * cos, y: self.height * t, z: -self.radius * sin}
+    }
+
+    fn tangent(&self, t: f64) -> Vec3 {
+        let angular_speed = self.turns * 2.0 * PI;
+        let (sin, cos) = (t * angular_speed).sin_cos();
+        Vec3 {
+            x: -self.radius * sin * angular_speed,
+            y: self.height,
+            z: -self.radius * cos * angular_speed,
+        }
+    }
+}
+
+/// A (p, q) torus knot wound around a torus in the XZ plane
+///
+/// The knot winds p times around the Y axis and q times around the tube of the torus.
+struct TorusKnot {
+    p: f64,
+    q: f64,
+    /// The distance from the center of the torus to the center of its tube
+    major_radius: f64,
+    /// The radius of the tube of the torus
+    minor_radius: f64,
+}
+
+impl Curve for TorusKnot {
+    fn at(&self, t: f64) -> Vec3 {
+        let angle = t * 2.0 * PI;
+        let (p_sin, p_cos) = (self.p * angle).sin_cos();
+        let (q_sin, q_cos) = (self.q * angle).sin_cos();
+
+        let radius = self.major_radius + self.minor_radius * q_cos;
+        Vec3 {x: radius * p_cos, y: self.minor_radius * q_sin, z: -radius * p_sin}
+    }
+
+    fn tangent(&self, t: f64) -> Vec3 {
+        let angle = t * 2.0 * PI;
+        let (p_sin, p_cos) = (self.p * angle).sin_cos();
+        let (q_sin, q_cos) = (self.q * angle).sin_cos();
+
+        let radius = self.major_radius + self.minor_radius * q_cos;
+        let d_radius = -self.minor_radius * self.q * q_sin;
+        // Chain rule: d(angle)/dt = 2 * PI
+        Vec3 {
+            x: d_radius * p_cos - radius * self.p * p_sin,
+            y: self.minor_radius * self.q * q_cos,
+            z: -(d_radius * p_sin + radius * self.p * p_cos),
+        } * 2.0 * PI
+    }
+}
+
+/// Returns the fraction of the total length of the given path that each point is at
+fn length_fractions(points: &[Vec2], closed: bool) -> Vec<f64> {
+    let mut lengths = Vec::with_capacity(points.len() + 1);
+    let mut length = 0.0;
+    lengths.push(length);
+    for pair in points.windows(2) {
+        length += (pair[1] - pair[0]).magnitude();
+        lengths.push(length);
+    }
+    if closed {
+        length += (points[0] - points[points.len() - 1]).magnitude();
+        lengths.push(length);
+    }
+
+    lengths.into_iter().map(|len| if length > 0.0 { len / length } else { 0.0 }).collect()
+}
+
+impl MeshData {
+    /// Generates a surface of revolution by revolving a 2D profile around the Y axis
+    ///
+    /// Each point in the profile is (radius, height). The points should be ordered from bottom to
+    /// top so that the generated surface faces outward. The profile is revolved a full circle
+    /// using the given number of segments.
+    ///
+    /// Texture coordinates go around the Y axis in u and along the profile in v. Normals are
+    /// generated so that the mesh can be rendered with smooth shading.
+    pub fn lathe(profile: &[Vec2], segments: usize) -> Self {
+        assert!(profile.len() >= 2, "A lathe profile must have at least two points");
+        assert!(segments >= 3, "A lathe must have at least three segments");
+
+        let v_coords = length_fractions(profile, false);
+
+        // The normal of the profile at each point, in 2D
+        let profile_normals: Vec<_> = (0..profile.len()).map(|i| {
+            let prev = profile[i.saturating_sub(1)];
+            let next = profile[(i + 1).min(profile.len() - 1)];
+            let tangent = next - prev;
+            // Rotate the tangent clockwise so that it points outward for a bottom to top profile
+            Vec2 {x: tangent.y, y: -tangent.x}.normalized()
+        }).collect();
+
+        let cols = segments + 1;
+        let mut positions = Vec::with_capacity(profile.len() * cols);
+        let mut normals = Vec::with_capacity(profile.len() * cols);
+        let mut tex_coords = Vec::with_capacity(profile.len() * cols);
+        for (i, (point, normal)) in profile.iter().zip(&profile_normals).enumerate() {
+            // The first segment is repeated at the end so that the texture coordinates can wrap
+            for j in 0..cols {
+                let u = j as f64 / segments as f64;
+                let (sin, cos) = (u * 2.0 * PI).sin_cos();
+
+                positions.push(Vec3 {x: point.x * sin, y: point.y, z: point.x * cos});
+                normals.push(Vec3 {x: normal.x * sin, y: normal.y, z: normal.x * cos});
+                tex_coords.push(Uv {u, v: v_coords[i]});
+            }
+        }
+
+        // Columns go around the Y axis and rows go up the profile
+        let triangles = grid_triangles(profile.len(), cols);
+
+        MeshData::new(positions, triangles, normals, tex_coords)
+    }
+
+    /// Generates a tube by sweeping a closed 2D polygon along a path
+    ///
+    /// The polygon is placed perpendicular to the path at the given number of evenly spaced steps
+    /// along the path. The polygon should be counter-clockwise when looking back along the path
+    /// so that the generated surface faces outward. The ends of the tube are left open.
+    ///
+    /// Texture coordinates go around the polygon in u and along the path in v. Normals are
+    /// generated so that the mesh can be rendered with smooth shading, though flat shading may
+    /// look better for polygons with sharp corners.
+    pub fn extrude<C: Curve>(polygon: &[Vec2], path: C, steps: usize) -> Self {
+        assert!(polygon.len() >= 3, "An extruded polygon must have at least three points");
+        assert!(steps >= 1, "An extrusion must have at least one step");
+
+        let u_coords = length_fractions(polygon, true);
+
+        // The outward normal of the polygon at each point, in 2D
+        let polygon_normals: Vec<_> = (0..polygon.len()).map(|i| {
+            let prev = polygon[(i + polygon.len() - 1) % polygon.len()];
+            let next = polygon[(i + 1) % polygon.len()];
+            let tangent = next - prev;
+            Vec2 {x: tangent.y, y: -tangent.x}.normalized()
+        }).collect();
+
+        let path = ArcLength::new(path, (steps + 1) * ARC_LENGTH_SAMPLES_PER_STEP);
+        let curve = path.curve();
+
+        let rows = steps + 1;
+        let cols = polygon.len() + 1;
+        let mut positions = Vec::with_capacity(rows * cols);
+        let mut normals = Vec::with_capacity(rows * cols);
+        let mut tex_coords = Vec::with_capacity(rows * cols);
+
+        // The frame is propagated along the path (parallel transport) to avoid the polygon
+        // twisting around the path
+        let mut side = None;
+        for i in 0..rows {
+            let v = i as f64 / steps as f64;
+            let t = path.param_at_distance(v * path.length());
+            let center = curve.at(t);
+            let forward = curve.tangent(t).normalized();
+
+            let side_dir = match side {
+                None => OrthonormalBasis::from_normal(forward).bitangent,
+                Some(prev_side) => {
+                    let side_dir: Vec3 = prev_side - forward * forward.dot(prev_side);
+                    side_dir.normalized()
+                },
+            };
+            side = Some(side_dir);
+            // side x up = forward
+            let up = forward.cross(side_dir);
+
+            // The first point is repeated at the end so that the texture coordinates can wrap
+            for j in 0..cols {
+                let point = polygon[j % polygon.len()];
+                let normal = polygon_normals[j % polygon.len()];
+
+                positions.push(center + side_dir * point.x + up * point.y);
+                normals.push(side_dir * normal.x + up * normal.y);
+                tex_coords.push(Uv {u: u_coords[j], v});
+            }
+        }
+
+        // Columns go around the polygon and rows go along the path
+        let triangles = grid_triangles(rows, cols);
+
+        MeshData::new(positions, triangles, normals, tex_coords)
+    }
+
+    /// Generates a tube that coils around the Y axis, starting at (radius, 0, 0) and ending at
+    /// the given height after the given number of turns
+    ///
+    /// The tube is made of the given number of segments along its length and the given number of
+    /// segments around its circumference. The ends of the tube are left open.
+    pub fn helix(
+        radius: f64,
+        height: f64,
+        turns: f64,
+        tube_radius: f64,
+        segments: usize,
+        tube_segments: usize,
+    ) -> Self {
+        let path = Helix {radius, height, turns};
+        Self::extrude(&circle(tube_radius, tube_segments), path, segments)
+    }
+
+    /// Generates a tube in the shape of a (p, q) torus knot centered at the origin in the XZ plane
+    ///
+    /// The knot winds p times around the Y axis and q times through the hole of the torus it lies
+    /// on. For p and q coprime, this produces a single closed knot (e.g. p = 2, q = 3 is a trefoil
+    /// knot). The torus has the given radius and a tube radius of half that.
+    ///
+    /// The tube is made of the given number of segments along its length and the given number of
+    /// segments around its circumference.
+    pub fn torus_knot(
+        p: u32,
+        q: u32,
+        radius: f64,
+        tube_radius: f64,
+        segments: usize,
+        tube_segments: usize,
+    ) -> Self {
+        let path = TorusKnot {
+            p: p as f64,
+            q: q as f64,
+            major_radius: radius,
+            minor_radius: radius / 2.0,
+        };
+        Self::extrude(&circle(tube_radius, tube_segments), path, segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::math::{EPSILON, CubicBezier};
+    use crate::primitive::Shading;
+
+    /// Checks that every face normal is in the same direction as the vertex normals
+    fn assert_outward(mesh: &MeshData) {
+        for tri in mesh.triangles(Shading::Smooth) {
+            let face_normal = (tri.b - tri.a).cross(tri.c - tri.a);
+            if face_normal.magnitude() < EPSILON {
+                // Degenerate triangles (e.g. at the poles of a lathe) have no direction
+                continue;
+            }
+
+            let (na, nb, nc) = tri.normals.unwrap();
+            assert!(face_normal.dot(na + nb + nc) > 0.0, "face normal {:?} is not outward", face_normal);
+        }
+    }
+
+    #[test]
+    fn lathe_cylinder() {
+        let profile = [Vec2::new(1.0, 0.0), Vec2::new(1.0, 2.0)];
+        let mesh = MeshData::lathe(&profile, 16);
+
+        assert_eq!(mesh.triangle_count(), 16 * 2);
+        for tri in mesh.triangles(Shading::Smooth) {
+            let (na, _, _) = tri.normals.unwrap();
+            assert!((Vec2::new(tri.a.x, tri.a.z).magnitude() - 1.0).abs() < EPSILON);
+            assert!((na - Vec3::new(tri.a.x, 0.0, tri.a.z)).magnitude() < EPSILON);
+        }
+        assert_outward(&mesh);
+    }
+
+    #[test]
+    fn extrude_square_along_curve() {
+        let square = [
+            Vec2::new(-0.5, -0.5),
+            Vec2::new(0.5, -0.5),
+            Vec2::new(0.5, 0.5),
+            Vec2::new(-0.5, 0.5),
+        ];
+        let path = CubicBezier {
+            p0: Vec3::zero(),
+            p1: Vec3::new(0.0, 2.0, 0.0),
+            p2: Vec3::new(2.0, 2.0, 1.0),
+            p3: Vec3::new(3.0, 0.0, 0.0),
+        };
+        let mesh = MeshData::extrude(&square, path, 20);
+
+        assert_eq!(mesh.triangle_count(), 20 * 4 * 2);
+        assert_outward(&mesh);
+    }
+
+    #[test]
+    fn helix_and_torus_knot_tubes() {
+        let helix = MeshData::helix(1.0, 3.0, 2.5, 0.1, 100, 12);
+        assert_eq!(helix.triangle_count(), 100 * 12 * 2);
+        assert_outward(&helix);
+
+        let knot = MeshData::torus_knot(2, 3, 1.0, 0.1, 200, 12);
+        assert_eq!(knot.triangle_count(), 200 * 12 * 2);
+        assert_outward(&knot);
+        // Every vertex should be at most tube_radius away from the surface of the torus
+        for tri in knot.triangles(Shading::Flat) {
+            let ring = Vec2::new(tri.a.x, tri.a.z).magnitude() - 1.0;
+            let dist_to_torus = (Vec2::new(ring, tri.a.y).magnitude() - 0.5).abs();
+            assert!(dist_to_torus <= 0.1 + EPSILON);
+        }
+    }
+}
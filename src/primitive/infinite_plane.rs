@@ -35,6 +35,7 @@ impl InfinitePlane {
     }
 
     /// Returns a plane with the normal flipped
+    #[allow(dead_code)]
     pub fn flipped(&self) -> Self {
         Self {
             normal: -self.normal,
@@ -72,8 +73,10 @@ impl RayHit for InfinitePlane {
         Some(RayIntersection {
             ray_parameter: t,
             hit_point: ray.at(t),
+            shadow_origin: None,
             normal: self.normal,
             tex_coord: None,
+            secondary_tex_coord: None,
             normal_map_transform: None,
         })
     }
@@ -1,6 +1,9 @@
+use std::fmt;
+use std::mem;
 use std::ops::Range;
 use std::sync::Arc;
 use std::path::Path;
+use std::collections::{HashMap, VecDeque};
 
 use crate::math::{Vec3, Uv};
 use crate::ray::{Ray, RayHit, RayIntersection};
@@ -17,6 +20,85 @@ pub enum Shading {
     Smooth,
 }
 
+/// The axis that points up in the coordinate system of an asset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpAxis {
+    /// +y is up (the convention used by the renderer)
+    Y,
+    /// +z is up (e.g. Blender)
+    Z,
+}
+
+/// The unit and axis conventions of imported assets, used to convert them into the conventions of
+/// the scene as they are loaded
+///
+/// Create one of these per scene and use it to load every asset that comes from the same tool so
+/// that their sizes (and the falloff of lights relative to them) are consistent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImportSettings {
+    /// The number of scene units in one unit of the asset (e.g. 100.0 to import an asset modelled
+    /// in meters into a scene that uses centimeters)
+    pub unit_scale: f64,
+    /// The axis that points up in the asset. Assets are rotated so that this axis points along
+    /// +y in the scene.
+    pub up_axis: UpAxis,
+}
+
+impl Default for ImportSettings {
+    fn default() -> Self {
+        Self {
+            unit_scale: 1.0,
+            up_axis: UpAxis::Y,
+        }
+    }
+}
+
+impl ImportSettings {
+    /// Converts a position in the asset to a position in the scene
+    pub fn convert_point(&self, point: Vec3) -> Vec3 {
+        self.convert_direction(point) * self.unit_scale
+    }
+
+    /// Converts a direction (e.g. a normal) in the asset to a direction in the scene
+    ///
+    /// Directions are only rotated, not scaled.
+    pub fn convert_direction(&self, dir: Vec3) -> Vec3 {
+        match self.up_axis {
+            UpAxis::Y => dir,
+            // Rotate -90 degrees around the x-axis so that +z becomes +y and +y becomes -z. This
+            // keeps the coordinate system right-handed.
+            UpAxis::Z => Vec3 {x: dir.x, y: dir.z, z: -dir.y},
+        }
+    }
+
+    /// Loads a *single* mesh (the first mesh) from an OBJ file, converting it to the conventions
+    /// of the scene
+    pub fn load_obj<P: AsRef<Path>>(&self, path: P) -> Result<MeshData, tobj::LoadError> {
+        let (models, _) = tobj::load_obj(path.as_ref())?;
+        Ok(MeshData::from_obj_mesh(&models[0].mesh, self))
+    }
+}
+
+/// The faces and vertex normals that were flipped by `MeshData::orient_faces`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OrientationReport {
+    /// The indexes of the triangles whose winding was reversed
+    pub flipped_faces: Vec<usize>,
+    /// The indexes of the vertices whose normals were reversed to match the faces around them
+    pub flipped_normals: Vec<usize>,
+    /// The number of separate pieces of the mesh (groups of faces connected by shared edges)
+    ///
+    /// Each piece is oriented independently.
+    pub pieces: usize,
+}
+
+impl fmt::Display for OrientationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Flipped {} face(s) and {} vertex normal(s) in {} piece(s)",
+            self.flipped_faces.len(), self.flipped_normals.len(), self.pieces)
+    }
+}
+
 /// The 3D data of a mesh, can be shared between multiple Meshes
 #[derive(Debug, PartialEq)]
 pub struct MeshData {
@@ -28,6 +110,9 @@ pub struct MeshData {
     normals: Vec<Vec3>,
     /// Texture coordinates for each vertex. If provided, must have enough for each vertex.
     tex_coords: Vec<Uv>,
+    /// Texture coordinates from the secondary UV set for each vertex (e.g. lightmap UVs). If
+    /// provided, must have enough for each vertex.
+    secondary_tex_coords: Vec<Uv>,
     /// A bounding box that encompases all vertices of this mesh. Used to avoid having to test all
     /// triangles if we can already trivially know that there is no intersection.
     bounds: BoundingBox,
@@ -35,14 +120,28 @@ pub struct MeshData {
 
 impl<'a> From<&'a tobj::Mesh> for MeshData {
     fn from(mesh: &'a tobj::Mesh) -> Self {
+        Self::from_obj_mesh(mesh, &ImportSettings::default())
+    }
+}
+
+impl MeshData {
+    /// Loads a *single* mesh (the first mesh) from an OBJ file
+    ///
+    /// The mesh is loaded as-is. Use `ImportSettings::load_obj` to convert meshes that use
+    /// different unit or axis conventions from the scene.
+    pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Self, tobj::LoadError> {
+        ImportSettings::default().load_obj(path)
+    }
+
+    fn from_obj_mesh(mesh: &tobj::Mesh, settings: &ImportSettings) -> Self {
         let triangles = mesh.indices.chunks_exact(3)
             .map(|t| (t[0] as usize, t[1] as usize, t[2] as usize))
             .collect();
         let positions = mesh.positions.chunks_exact(3)
-            .map(|p| Vec3 {x: p[0] as f64, y: p[1] as f64, z: p[2] as f64})
+            .map(|p| settings.convert_point(Vec3 {x: p[0] as f64, y: p[1] as f64, z: p[2] as f64}))
             .collect();
         let normals = mesh.normals.chunks_exact(3)
-            .map(|p| Vec3 {x: p[0] as f64, y: p[1] as f64, z: p[2] as f64})
+            .map(|p| settings.convert_direction(Vec3 {x: p[0] as f64, y: p[1] as f64, z: p[2] as f64}))
             .collect();
         let tex_coords = mesh.texcoords.chunks_exact(2)
             .map(|uv| Uv {u: uv[0] as f64, v: uv[1] as f64})
@@ -50,15 +149,6 @@ impl<'a> From<&'a tobj::Mesh> for MeshData {
 
         MeshData::new(positions, triangles, normals, tex_coords)
     }
-}
-
-impl MeshData {
-    /// Loads a *single* mesh (the first mesh) from an OBJ file
-    pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Self, tobj::LoadError> {
-        let path = path.as_ref();
-        let (models, _) = tobj::load_obj(path)?;
-        Ok(MeshData::from(&models[0].mesh))
-    }
 
     pub fn new(
         positions: Vec<Vec3>,
@@ -83,10 +173,141 @@ impl MeshData {
             positions,
             normals,
             tex_coords,
+            secondary_tex_coords: Vec::new(),
             bounds: BoundingBox::new(min, max),
         }
     }
 
+    /// Adds a secondary set of texture coordinates to this mesh data
+    ///
+    /// Textures can be sampled with these coordinates by selecting `UvChannel::Secondary` in
+    /// the material. The OBJ format only supports a single UV set, so this is the only way to
+    /// provide secondary texture coordinates.
+    pub fn with_secondary_tex_coords(mut self, secondary_tex_coords: Vec<Uv>) -> Self {
+        if secondary_tex_coords.len() != self.positions.len() {
+            panic!("Secondary texture coordinates must have enough for all vertices");
+        }
+
+        self.secondary_tex_coords = secondary_tex_coords;
+        self
+    }
+
+    /// Makes the winding of every face consistent with the faces that it shares edges with, then
+    /// flips any vertex normals that point away from the faces around them
+    ///
+    /// Meshes exported with some faces wound the wrong way have black patches when they are
+    /// smooth shaded. Faces are flood filled across shared edges, so every piece of the mesh ends
+    /// up wound the same way as the majority of its faces. Vertices are matched by position so
+    /// that seams in the texture coordinates or normals do not split the mesh into pieces. Edges
+    /// shared by more than two faces are ignored.
+    pub fn orient_faces(&mut self) -> OrientationReport {
+        // Vertices with the same position are treated as the same vertex
+        let mut vertex_ids = HashMap::new();
+        let ids: Vec<_> = self.positions.iter().map(|pos| {
+            let next_id = vertex_ids.len();
+            *vertex_ids.entry(pos.map(f64::to_bits).into_array()).or_insert(next_id)
+        }).collect();
+
+        // The faces around each edge, along with whether the face goes along the edge from the
+        // vertex with the smaller id to the vertex with the larger id
+        let mut edges: HashMap<(usize, usize), Vec<(usize, bool)>> = HashMap::new();
+        for (face, &(a, b, c)) in self.triangles.iter().enumerate() {
+            for &(from, to) in &[(ids[a], ids[b]), (ids[b], ids[c]), (ids[c], ids[a])] {
+                edges.entry((from.min(to), from.max(to))).or_default().push((face, from < to));
+            }
+        }
+
+        let mut report = OrientationReport::default();
+        // Whether each face needs to be flipped (None if the face has not been reached yet)
+        let mut flips = vec![None; self.triangles.len()];
+        for start in 0..self.triangles.len() {
+            if flips[start].is_some() {
+                continue;
+            }
+            report.pieces += 1;
+
+            flips[start] = Some(false);
+            let mut piece = vec![start];
+            let mut queue = VecDeque::from(vec![start]);
+            while let Some(face) = queue.pop_front() {
+                let flipped = flips[face].expect("bug: queued faces should have an orientation");
+                let (a, b, c) = self.triangles[face];
+                for &(from, to) in &[(ids[a], ids[b]), (ids[b], ids[c]), (ids[c], ids[a])] {
+                    let faces = &edges[&(from.min(to), from.max(to))];
+                    if faces.len() != 2 {
+                        continue;
+                    }
+
+                    // Consistently wound faces go along their shared edge in opposite directions
+                    let forward = (from < to) != flipped;
+                    for &(other, other_forward) in faces {
+                        if other != face && flips[other].is_none() {
+                            flips[other] = Some(other_forward == forward);
+                            piece.push(other);
+                            queue.push_back(other);
+                        }
+                    }
+                }
+            }
+
+            // Keep the winding of the majority of the faces in this piece
+            let flipped_count = piece.iter().filter(|&&face| flips[face] == Some(true)).count();
+            if flipped_count * 2 > piece.len() {
+                for &face in &piece {
+                    flips[face] = flips[face].map(|flipped| !flipped);
+                }
+            }
+        }
+
+        for (face, flipped) in flips.into_iter().enumerate() {
+            if flipped == Some(true) {
+                let (a, b, c) = self.triangles[face];
+                self.triangles[face] = (a, c, b);
+                report.flipped_faces.push(face);
+            }
+        }
+
+        if self.has_normals() {
+            // Area-weighted sum of the normals of the faces around each vertex
+            let mut face_normals = vec![Vec3::zero(); self.positions.len()];
+            for &(a, b, c) in &self.triangles {
+                let normal = (self.positions[b] - self.positions[a]).cross(self.positions[c] - self.positions[a]);
+                for &vertex in &[a, b, c] {
+                    face_normals[vertex] += normal;
+                }
+            }
+
+            for (vertex, (normal, face_normal)) in self.normals.iter_mut().zip(face_normals).enumerate() {
+                if normal.dot(face_normal) < 0.0 {
+                    *normal = -*normal;
+                    report.flipped_normals.push(vertex);
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Returns true if this mesh data has vertex normals (and can be used with smooth shading)
+    pub fn has_normals(&self) -> bool {
+        !self.normals.is_empty() && self.normals.len() == self.positions.len()
+    }
+
+    /// Returns the number of triangles in this mesh data
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    /// Returns the estimated number of bytes used by this mesh data
+    pub fn memory_size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.triangles.capacity() * mem::size_of::<(usize, usize, usize)>()
+            + self.positions.capacity() * mem::size_of::<Vec3>()
+            + self.normals.capacity() * mem::size_of::<Vec3>()
+            + self.tex_coords.capacity() * mem::size_of::<Uv>()
+            + self.secondary_tex_coords.capacity() * mem::size_of::<Uv>()
+    }
+
     /// Iterate through all the triangles represented by this data. The shading parametering
     /// affects whther the yielded triangles are provided normals from the mesh or not.
     ///
@@ -107,7 +328,12 @@ impl MeshData {
                     None
                 } else {
                     Some((self.tex_coords[a], self.tex_coords[b], self.tex_coords[c]))
-                }
+                },
+                secondary_tex_coords: if self.secondary_tex_coords.is_empty() {
+                    None
+                } else {
+                    Some((self.secondary_tex_coords[a], self.secondary_tex_coords[b], self.secondary_tex_coords[c]))
+                },
             }
         })
     }
@@ -140,6 +366,11 @@ impl Mesh {
             shading,
         }
     }
+
+    /// Returns the mesh data used by this mesh
+    pub fn data(&self) -> &Arc<MeshData> {
+        &self.data
+    }
 }
 
 #[cfg(not(feature = "render_bounding_volumes"))]
@@ -149,9 +380,7 @@ impl RayHit for Mesh {
 
         // Test the bounding volume first. If it does not get hit we can save a lot of time that
         // we would have spent traversing the mesh triangles.
-        if data.bounds.test_hit(ray, init_t_range).is_none() {
-            return None;
-        }
+        data.bounds.test_hit(ray, init_t_range)?;
 
         let mut t_range = init_t_range.clone();
         //TODO: Parallelism via rayon
@@ -174,3 +403,55 @@ impl RayHit for Mesh {
         self.data.bounds.ray_hit(ray, t_range)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn import_converts_units_and_up_axis() {
+        let path = env::temp_dir().join(format!("portrayer-import-{}.obj", std::process::id()));
+        fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 0 2\nvn 0 0 1\nf 1//1 2//1 3//1\n").unwrap();
+
+        let settings = ImportSettings {unit_scale: 0.5, up_axis: UpAxis::Z};
+        let data = settings.load_obj(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(data.positions, vec![Vec3::zero(), Vec3::new(0.5, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)]);
+        // Normals are rotated but not scaled
+        assert_eq!(data.normals, vec![Vec3::up(); 3]);
+        assert_eq!(data.bounds, BoundingBox::new(Vec3::zero(), Vec3::new(0.5, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn inconsistent_faces_are_reoriented() {
+        // A tetrahedron with outward facing normals, except for the last face which is wound the
+        // wrong way and the last vertex which has an inward facing normal
+        let positions = vec![
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(-1.0, -1.0, 1.0),
+            Vec3::new(-1.0, 1.0, -1.0),
+            Vec3::new(1.0, -1.0, -1.0),
+        ];
+        let mut normals: Vec<_> = positions.iter().map(|pos| pos.normalized()).collect();
+        normals[3] = -normals[3];
+        let triangles = vec![(0, 1, 3), (0, 2, 1), (0, 3, 2), (1, 3, 2)];
+        let mut data = MeshData::new(positions.clone(), triangles, normals, Vec::new());
+
+        let report = data.orient_faces();
+        assert_eq!(report, OrientationReport {flipped_faces: vec![3], flipped_normals: vec![3], pieces: 1});
+
+        // Every face now faces away from the center of the tetrahedron
+        for &(a, b, c) in &data.triangles {
+            let normal = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+            assert!(normal.dot(positions[a] + positions[b] + positions[c]) > 0.0);
+        }
+        assert_eq!(data.normals[3], positions[3].normalized());
+
+        // Running again does not change anything
+        assert_eq!(data.orient_faces(), OrientationReport {pieces: 1, ..OrientationReport::default()});
+    }
+}
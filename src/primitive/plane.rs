@@ -1,7 +1,7 @@
 use std::ops::Range;
 
 use crate::ray::{Ray, RayHit, RayIntersection};
-use crate::math::{EPSILON, Vec3, Uv, Mat3};
+use crate::math::{Vec3, Uv, Mat3, tolerance};
 use crate::bounding_box::{BoundingBox, Bounds};
 
 use super::InfinitePlane;
@@ -28,7 +28,7 @@ impl Bounds for Plane {
 ///
 /// Only need to check two axes because third axis is guaranteed to be zero
 fn contains(Vec3 {x, y: _, z}: Vec3) -> bool {
-    let radius = L2 + EPSILON;
+    let radius = L2 + tolerance::at_scale(L);
     -radius <= x && x <= radius && -radius <= z && z <= radius
 }
 
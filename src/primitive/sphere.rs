@@ -98,8 +98,10 @@ impl RayHit for Sphere {
         Some(RayIntersection {
             ray_parameter: t,
             hit_point,
+            shadow_origin: None,
             normal,
             tex_coord: Some(tex_coord),
+            secondary_tex_coord: None,
             normal_map_transform: Some(normal_map_transform),
         })
     }
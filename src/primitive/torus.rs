@@ -122,8 +122,10 @@ impl RayHit for Torus {
         Some(RayIntersection {
             ray_parameter: t,
             hit_point,
+            shadow_origin: None,
             normal: unimplemented!(),
             tex_coord: None,
+            secondary_tex_coord: None,
             normal_map_transform: None,
         })
     }
@@ -1,7 +1,7 @@
 use std::ops::Range;
 
 use crate::ray::{Ray, RayHit, RayIntersection};
-use crate::math::{Vec3, Uv, Mat3};
+use crate::math::{Vec3, Uv, Mat3, OrthonormalBasis};
 use crate::bounding_box::{BoundingBox, Bounds};
 
 /// A triangle with the given 3 vertices
@@ -15,14 +15,18 @@ pub struct Triangle {
     pub normals: Option<(Vec3, Vec3, Vec3)>,
     /// The texture coordinates for a, b, and c respectively. The texture coordinate
     /// for a ray hit will only be set if these are provided.
-    pub tex_coords: Option<(Uv, Uv, Uv)>
+    pub tex_coords: Option<(Uv, Uv, Uv)>,
+    /// The texture coordinates from the secondary UV set for a, b, and c respectively.
+    ///
+    /// These are only used for sampling textures, not for normal mapping.
+    pub secondary_tex_coords: Option<(Uv, Uv, Uv)>,
 }
 
 impl Triangle {
     /// Creates a new flat shaded triangle. Normals will be computed from the given
     /// vertices and will be same all across the face.
     pub fn flat(a: Vec3, b: Vec3, c: Vec3) -> Self {
-        Self {a, b, c, normals: None, tex_coords: None}
+        Self {a, b, c, normals: None, tex_coords: None, secondary_tex_coords: None}
     }
 }
 
@@ -37,6 +41,23 @@ impl Bounds for Triangle {
 
 impl RayHit for Triangle {
     fn ray_hit(&self, ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
+        self.hit_with_normals(ray, t_range, self.normals)
+    }
+}
+
+impl Triangle {
+    /// The same as `ray_hit`, but ignores the vertex normals (if any) so that the normal is the
+    /// same all across the face
+    pub fn flat_ray_hit(&self, ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
+        self.hit_with_normals(ray, t_range, None)
+    }
+
+    fn hit_with_normals(
+        &self,
+        ray: &Ray,
+        t_range: &Range<f64>,
+        normals: Option<(Vec3, Vec3, Vec3)>,
+    ) -> Option<RayIntersection> {
         // Full formulas provided in Peter Shirley's ray tracing chapter (pg 208)
         // http://www.cs.utah.edu/~shirley/books/fcg2/rt.pdf
         // Can be derived using Cramer's rule
@@ -70,7 +91,7 @@ impl RayHit for Triangle {
         }
 
         let gamma = (i * ak_jb + h * jc_al + g * bl_ck) / m;
-        if gamma < 0.0 || gamma > 1.0 {
+        if !(0.0..=1.0).contains(&gamma) {
             return None;
         }
 
@@ -79,26 +100,29 @@ impl RayHit for Triangle {
             return None;
         }
 
-        let normal = match self.normals {
+        let hit_point = ray.at(t);
+        let (normal, shadow_origin) = match normals {
             Some((na, nb, nc)) => {
                 let alpha = 1.0 - beta - gamma;
-                na * alpha + nb * beta + nc * gamma
+                let normal = na * alpha + nb * beta + nc * gamma;
+                let shadow_origin = self.shadow_terminator_offset(hit_point, (alpha, beta, gamma),
+                    (na, nb, nc));
+                (normal, Some(shadow_origin))
             },
-            None => (self.b - self.a).cross(self.c - self.a),
+            None => ((self.b - self.a).cross(self.c - self.a), None),
         };
 
-        let tex_coord = match self.tex_coords {
-            Some((uv_a, uv_b, uv_c)) => {
-                let alpha = 1.0 - beta - gamma;
-                let uv = uv_a * alpha + uv_b * beta + uv_c * gamma;
-                // Need to reverse uv because we've been using a top-to-bottom convention where the
-                // rest of the world uses a bottom to top convention
-                //TODO: Consider reversing this everywhere else in the code instead so that we
-                // follow the rest of the world in our UV coordinate conventions
-                Some(Uv {u: uv.u, v: 1.0 - uv.v})
-            },
-            None => None,
+        let interpolate_uv = |(uv_a, uv_b, uv_c): (Uv, Uv, Uv)| {
+            let alpha = 1.0 - beta - gamma;
+            let uv = uv_a * alpha + uv_b * beta + uv_c * gamma;
+            // Need to reverse uv because we've been using a top-to-bottom convention where the
+            // rest of the world uses a bottom to top convention
+            //TODO: Consider reversing this everywhere else in the code instead so that we
+            // follow the rest of the world in our UV coordinate conventions
+            Uv {u: uv.u, v: 1.0 - uv.v}
         };
+        let tex_coord = self.tex_coords.map(interpolate_uv);
+        let secondary_tex_coord = self.secondary_tex_coords.map(interpolate_uv);
 
         let normal_map_transform = match self.tex_coords {
             Some((uv_a, uv_b, uv_c)) => {
@@ -128,6 +152,16 @@ impl RayHit for Triangle {
                 let bitangent = (bitangent / coeff).normalized();
                 let normal = normal.normalized();
 
+                // Triangles whose texture coordinates are all in a line (or the same point) do
+                // not have a tangent direction, so any basis around the normal will do
+                let is_finite = |v: Vec3| v.iter().all(|x| x.is_finite());
+                let (tangent, bitangent) = if is_finite(tangent) && is_finite(bitangent) {
+                    (tangent, bitangent)
+                } else {
+                    let basis = OrthonormalBasis::from_normal(normal);
+                    (basis.tangent, basis.bitangent)
+                };
+
                 Some(Mat3::from_col_arrays([
                     tangent.into_array(),
                     normal.into_array(),
@@ -139,10 +173,97 @@ impl RayHit for Triangle {
 
         Some(RayIntersection {
             ray_parameter: t,
-            hit_point: ray.at(t),
+            hit_point,
+            shadow_origin,
             normal,
             tex_coord,
+            secondary_tex_coord,
             normal_map_transform,
         })
     }
+
+    /// Moves a hit point off of the flat face of the triangle to where the curved surface described
+    /// by the vertex normals would be
+    ///
+    /// Smooth shading bends the normal towards the light before the flat face actually faces it,
+    /// so shadow rays cast from the flat face hit the neighbouring triangles. This produces the
+    /// jagged "shadow terminator" artifact on low-poly meshes. Casting shadow rays from the
+    /// offset point avoids that without adding any geometry.
+    ///
+    /// Uses the method from "Hacking the Shadow Terminator" (Hanika, 2021)
+    fn shadow_terminator_offset(
+        &self,
+        hit_point: Vec3,
+        (alpha, beta, gamma): (f64, f64, f64),
+        (na, nb, nc): (Vec3, Vec3, Vec3),
+    ) -> Vec3 {
+        // Project the hit point onto the tangent plane of each vertex and only keep the
+        // projections that are above the hit point (i.e. for convex parts of the surface)
+        let project = |vertex: Vec3, normal: Vec3| {
+            let normal = normal.normalized();
+            let dist = (hit_point - vertex).dot(normal).min(0.0);
+            hit_point - normal * dist
+        };
+
+        project(self.a, na) * alpha + project(self.b, nb) * beta + project(self.c, nc) * gamma
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::math::{EPSILON, INFINITY};
+
+    #[test]
+    fn shadow_terminator_offset() {
+        let a = Vec3::new(-1.0, 0.0, 1.0);
+        let b = Vec3::new(1.0, 0.0, 1.0);
+        let c = Vec3::new(0.0, 0.0, -1.0);
+        let ray = Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::down());
+        let t_range = Range {start: EPSILON, end: INFINITY};
+
+        // Flat triangles are shaded as-is
+        let flat = Triangle::flat(a, b, c);
+        assert!(flat.ray_hit(&ray, &t_range).unwrap().shadow_origin.is_none());
+
+        // Normals that match the face do not move the point
+        let up = Vec3::up();
+        let tri = Triangle {normals: Some((up, up, up)), ..flat.clone()};
+        let hit = tri.ray_hit(&ray, &t_range).unwrap();
+        assert!((hit.shadow_origin.unwrap() - hit.hit_point).magnitude() < EPSILON);
+
+        // Normals bent outwards (like a convex surface) move the point above the face
+        let tri = Triangle {normals: Some((
+            Vec3::new(-1.0, 1.0, 1.0).normalized(),
+            Vec3::new(1.0, 1.0, 1.0).normalized(),
+            Vec3::new(0.0, 1.0, -1.0).normalized(),
+        )), ..flat};
+        let hit = tri.ray_hit(&ray, &t_range).unwrap();
+        let shadow_origin = hit.shadow_origin.unwrap();
+        assert!(shadow_origin.y > EPSILON, "{:?}", shadow_origin);
+        assert!((shadow_origin.x - hit.hit_point.x).abs() < EPSILON);
+        // The flat hit point is unchanged so the surface still renders in the same place
+        assert!(hit.hit_point.y.abs() < EPSILON);
+    }
+
+    #[test]
+    fn degenerate_tex_coords_have_a_valid_normal_map_transform() {
+        let ray = Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::down());
+        let t_range = Range {start: EPSILON, end: INFINITY};
+
+        // Every vertex has the same texture coordinate
+        let uv = Uv {u: 0.5, v: 0.5};
+        let tri = Triangle {
+            tex_coords: Some((uv, uv, uv)),
+            ..Triangle::flat(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0))
+        };
+        let hit = tri.ray_hit(&ray, &t_range).unwrap();
+
+        let norm_trans = hit.normal_map_transform.unwrap();
+        assert!(norm_trans.into_row_array().iter().all(|x| x.is_finite()), "{:?}", norm_trans);
+        // Unperturbed normals from the normal map still point along the surface normal
+        let normal = norm_trans * Vec3::up();
+        assert!((normal - Vec3::up()).magnitude() < EPSILON, "{:?}", normal);
+    }
 }
@@ -1,9 +1,12 @@
 use std::ops::Range;
 use std::sync::Arc;
+#[cfg(feature = "nan_checks")]
+use std::fmt;
 
-use crate::math::{EPSILON, INFINITY, Vec3, Vec3Ext, Mat4, Mat3, Rgb, Uv};
+use crate::math::{INFINITY, Vec3, Vec3Ext, Mat4, Mat3, Rgb, Uv};
+use crate::math::tolerance;
 use crate::scene::Scene;
-use crate::material::Material;
+use crate::material::{Material, Media, LightComponents, SplitColor};
 
 /// Represents the result of a ray intersection and stores information about it
 #[derive(Debug)]
@@ -15,6 +18,12 @@ pub struct RayIntersection {
     /// The point of intersection
     pub hit_point: Vec3,
 
+    /// The point that shadow rays should be cast from, if different from the hit point
+    ///
+    /// Smooth shaded surfaces offset this point to where the curved surface would be in order to
+    /// avoid shadow terminator artifacts (see `Triangle`).
+    pub shadow_origin: Option<Vec3>,
+
     /// The normal at the point of intersection.
     /// IMPORTANT: This is NOT guaranteed to be a unit vector for the sake of efficiency and
     /// floating point correctness. (Normalizing too many times accrues too much floating point
@@ -26,6 +35,11 @@ pub struct RayIntersection {
     /// Set to None if the surface does not support texture mapping.
     pub tex_coord: Option<Uv>,
 
+    /// The texture coordinate of the hit point from the secondary UV set (if any)
+    ///
+    /// Set to None if the surface does not have a secondary UV set.
+    pub secondary_tex_coord: Option<Uv>,
+
     /// The matrix to compute the normal from a normal in a normal map
     ///
     /// The normal applied to this matrix will have a right-handed, y-up coordinate system where
@@ -35,6 +49,28 @@ pub struct RayIntersection {
     pub normal_map_transform: Option<Mat3>,
 }
 
+impl RayIntersection {
+    /// Panics if any of the values in this intersection are NaN or infinite
+    ///
+    /// The given context is included in the panic message so that it is possible to figure out
+    /// where in the scene the invalid value came from.
+    #[cfg(feature = "nan_checks")]
+    pub fn assert_finite<C: fmt::Display>(&self, context: C) {
+        let Self {ray_parameter, hit_point, shadow_origin, normal, tex_coord, secondary_tex_coord, normal_map_transform} = self;
+
+        let is_finite = ray_parameter.is_finite()
+            && hit_point.iter().all(|v| v.is_finite())
+            && shadow_origin.map(|p| p.iter().all(|v| v.is_finite())).unwrap_or(true)
+            && normal.iter().all(|v| v.is_finite())
+            && tex_coord.map(|uv| uv.u.is_finite() && uv.v.is_finite()).unwrap_or(true)
+            && secondary_tex_coord.map(|uv| uv.u.is_finite() && uv.v.is_finite()).unwrap_or(true)
+            && normal_map_transform.map(|m| m.into_row_array().iter().all(|v| v.is_finite())).unwrap_or(true);
+
+        assert!(is_finite, "bug: ray intersection with {} contains a NaN or infinite value: {:?}",
+            context, self);
+    }
+}
+
 /// Abstracts the ray hitting a single primitive
 pub trait RayHit {
     /// Returns a value if the given ray has hit this object and the parameter is in the given range
@@ -43,7 +79,7 @@ pub trait RayHit {
 
 impl<T: RayHit> RayHit for Arc<T> {
     fn ray_hit(&self, ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
-        (&*self as &T).ray_hit(ray, t_range)
+        (**self).ray_hit(ray, t_range)
     }
 }
 
@@ -74,13 +110,13 @@ pub trait RayCast {
 
 impl<T: RayCast> RayCast for Arc<T> {
     fn ray_cast(&self, ray: &Ray, t_range: &mut Range<f64>) -> Option<(RayIntersection, Arc<Material>)> {
-        (&*self as &T).ray_cast(ray, t_range)
+        (**self).ray_cast(ray, t_range)
     }
 }
 
 impl<T: RayCast> RayCast for Vec<T> {
     fn ray_cast(&self, ray: &Ray, t_range: &mut Range<f64>) -> Option<(RayIntersection, Arc<Material>)> {
-        (&*self as &[T]).ray_cast(ray, t_range)
+        (**self).ray_cast(ray, t_range)
     }
 }
 
@@ -121,6 +157,15 @@ impl Ray {
         self.direction
     }
 
+    /// Returns the range of ray parameter values that should be searched for hits of this ray
+    ///
+    /// The range starts slightly after the origin of the ray so that rays cast from a surface do
+    /// not hit that same surface because of floating point error ("shadow acne"). How far after
+    /// the origin depends on the scale of the origin (see `math::tolerance`).
+    pub fn t_range(&self) -> Range<f64> {
+        Range {start: tolerance::at_point(self.origin), end: INFINITY}
+    }
+
     /// Computes the position in this ray at the given ray parameter value
     pub fn at(&self, t: f64) -> Vec3 {
         self.origin + self.direction * t
@@ -134,16 +179,83 @@ impl Ray {
         }
     }
 
+    /// Returns the distance from the origin of this ray to the nearest object it hits, or None if
+    /// nothing is hit
+    ///
+    /// Visible lights are ignored, so this is the length of the ray as seen by materials.
+    pub fn length<R: RayCast>(&self, scene: &Scene<R>) -> Option<f64> {
+        let mut t_range = self.t_range();
+        scene.root.ray_cast(self, &mut t_range)
+            .map(|(hit, _)| (hit.hit_point - self.origin).magnitude())
+    }
+
     /// Compute the color of the nearest object to the casted ray. Returns the given background
     /// color if no object is hit by this ray.
+    ///
+    /// The ray is assumed to start outside of every refractive surface (in air).
     pub fn color<R: RayCast>(&self, scene: &Scene<R>, background: Rgb, recursion_depth: u32) -> Rgb {
-        let mut t_range = Range {start: EPSILON, end: INFINITY};
+        self.color_in(scene, background, &Media::default(), 1.0, recursion_depth)
+    }
+
+    /// Compute the color of the nearest object to the casted ray, given the refractive media that
+    /// the ray starts inside of
+    ///
+    /// The throughput is the fraction of the returned color that will end up in the final color
+    /// of the pixel (e.g. 0.25 for a ray reflected by two surfaces with a reflectivity of 0.5).
+    /// Reflected and refracted rays that would barely contribute to the pixel are not cast.
+    pub fn color_in<R: RayCast>(
+        &self,
+        scene: &Scene<R>,
+        background: Rgb,
+        media: &Media,
+        throughput: f64,
+        recursion_depth: u32,
+    ) -> Rgb {
+        self.split_color_in(scene, background, media, throughput, recursion_depth, LightComponents::All).total()
+    }
+
+    /// Compute the given components of the light seen along the casted ray like `Ray::color`, but
+    /// split into its direct and indirect light (see `SplitColor`)
+    ///
+    /// The ray is assumed to start outside of every refractive surface (in air).
+    pub fn split_color<R: RayCast>(&self, scene: &Scene<R>, background: Rgb, components: LightComponents) -> SplitColor {
+        self.split_color_in(scene, background, &Media::default(), 1.0, 0, components)
+    }
+
+    fn split_color_in<R: RayCast>(
+        &self,
+        scene: &Scene<R>,
+        background: Rgb,
+        media: &Media,
+        throughput: f64,
+        recursion_depth: u32,
+        components: LightComponents,
+    ) -> SplitColor {
+        let mut t_range = self.t_range();
         let hit = scene.root.ray_cast(self, &mut t_range);
 
-        match hit {
-            Some((hit, mat)) => mat.hit_color(scene, background, self.direction, hit.hit_point,
-                hit.normal, hit.tex_coord, hit.normal_map_transform, recursion_depth),
-            None => background,
+        // Any visible light closer than the nearest hit is seen instead of the hit
+        let light_hit = scene.lights.iter()
+            .filter_map(|light| light.ray_hit(self, &t_range).map(|t| (t, light)))
+            .min_by(|(t1, _), (t2, _)| t1.partial_cmp(t2).expect("bug: light hit should not be NaN"));
+        if let Some((t, light)) = light_hit {
+            return SplitColor {direct: light.color_at(self.at(t), self.direction), indirect: Rgb::black()};
         }
+
+        let (hit, mat) = match hit {
+            Some(hit) => hit,
+            None => return SplitColor {direct: background, indirect: Rgb::black()},
+        };
+
+        // Continue straight through surfaces that are inside of a medium with a higher priority
+        let entering = self.direction.dot(hit.normal) < 0.0;
+        if let Some(media) = media.skip_surface(&mat, entering) {
+            return Ray::new(hit.hit_point, self.direction).split_color_in(scene, background, &media, throughput,
+                recursion_depth + 1, components);
+        }
+
+        mat.split_hit_color(scene, background, self.direction, hit.hit_point,
+            hit.shadow_origin.unwrap_or(hit.hit_point), (hit.hit_point - self.origin).magnitude(), hit.normal, hit.tex_coord,
+            hit.secondary_tex_coord, hit.normal_map_transform, media, throughput, recursion_depth, components)
     }
 }
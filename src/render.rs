@@ -1,53 +1,829 @@
 use std::io;
 use std::env;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::ops::Range;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use vek::ops::Clamp;
 use rayon::prelude::*;
-use image::Pixel;
-use rand::{Rng, thread_rng};
+use rayon::{ThreadPool, ThreadPoolBuilder, ThreadPoolBuildError};
+use rand::Rng;
 
-use crate::math::{GAMMA, Uv, Rgb};
-use crate::scene::{Scene, HierScene};
+use crate::math::{EPSILON, GAMMA, Vec3, Uv, Rgb, RgbExt, CompensatedSum, CompensatedRgb, sample_seed, seed_sample_rng, sample_rng};
+use crate::scene::{Scene, HierScene, SceneNode};
 #[cfg(any(feature = "kdtree", feature = "flat_scene"))]
 use crate::flat_scene::FlatScene;
 #[cfg(feature = "kdtree")]
-use crate::kdtree::KDTreeScene;
-use crate::ray::RayCast;
+use crate::kdtree::{KDTreeScene, KDTreeCache};
+use crate::ray::{Ray, RayCast};
+use crate::material::{Material, MaterialOverrides, LightComponents, SplitColor};
+use crate::light::Light;
+use crate::shadow_map::ShadowMap;
+use crate::bake::bake_radiance_probes;
 use crate::camera::{CameraSettings, Camera};
+use crate::fog::Fog;
+use crate::animation::FrameNoise;
 use crate::texture::TextureSource;
-use crate::reporter::Reporter;
+use crate::reporter::{Reporter, RenderStage, SampleRecord};
+use crate::stats::{ExposureStats, false_color};
+use crate::pixel_order::{PixelOrder, RenderFocus, TILE_SIZE};
+use crate::overlay::Overlay;
+use crate::aov::{Aov, AovPasses};
+use crate::upsample::{Guide, HalfResLight, half_size};
+use crate::error::{ErrorCode, format_warning};
 
-/// Ray traces a single pixel through the scene
+/// The running estimate of the color of a pixel from the samples taken so far
+///
+/// The totals use compensated summation so that bright pixels with many samples do not lose
+/// precision.
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelEstimate {
+    /// The sum of the colors of every sample
+    total: CompensatedRgb,
+    /// The sum of the luminance of every sample
+    total_luminance: CompensatedSum,
+    /// The sum of the squared luminance of every sample
+    total_luminance_sq: CompensatedSum,
+    samples: usize,
+}
+
+impl PixelEstimate {
+    fn from_sample(color: Rgb) -> Self {
+        let luminance = color.luminance();
+        Self {
+            total: color.into(),
+            total_luminance: luminance.into(),
+            total_luminance_sq: (luminance * luminance).into(),
+            samples: 1,
+        }
+    }
+
+    /// Combines the samples of this estimate with the samples of the given estimate
+    fn merged(self, other: Self) -> Self {
+        Self {
+            total: self.total.merged(other.total),
+            total_luminance: self.total_luminance.merged(other.total_luminance),
+            total_luminance_sq: self.total_luminance_sq.merged(other.total_luminance_sq),
+            samples: self.samples + other.samples,
+        }
+    }
+
+    /// Returns the average color of the samples
+    fn color(&self) -> Rgb {
+        self.total.value() / self.samples as f64
+    }
+
+    /// Returns the estimated variance of the average luminance of the samples (how far the
+    /// luminance of the pixel is likely to be from its true value)
+    fn variance(&self) -> f64 {
+        if self.samples < 2 {
+            return 0.0;
+        }
+
+        let n = self.samples as f64;
+        let mean = self.total_luminance.value() / n;
+        let sample_variance = (self.total_luminance_sq.value() / n - mean * mean).max(0.0) * n / (n - 1.0);
+        sample_variance / n
+    }
+}
+
+/// Combines the items of the given parallel iterator with the given operation
+///
+/// A parallel reduction combines the items in whatever groups the threads happened to take them
+/// in, so floating point results can differ slightly between runs. If `deterministic` is true,
+/// the items are collected first and then combined one at a time in order, which gives the same
+/// result every time (at the cost of storing every item).
+fn reduce_items<I, T, ID, OP>(items: I, deterministic: bool, identity: ID, op: OP) -> T
+    where I: IndexedParallelIterator<Item=T>,
+          T: Send,
+          ID: Fn() -> T + Sync + Send,
+          OP: Fn(T, T) -> T + Sync + Send,
+{
+    if deterministic {
+        let items: Vec<_> = items.collect();
+        items.into_iter().fold(identity(), op)
+    } else {
+        items.reduce(identity, op)
+    }
+}
+
+/// Limits on how bright the light of a single sample can be, used to remove "fireflies" (rare,
+/// very bright samples) without taking more samples
+///
+/// The direct and indirect light of each sample (see `SplitColor`) are limited separately, so
+/// fireflies from reflections and refractions can be removed without dimming the highlights of
+/// surfaces lit directly by a light. Clamping throws away light, so clamped parts of the image are
+/// slightly darker than they should be.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SampleClamp {
+    /// The brightest that any color channel of the direct light of a sample can be (unlimited if
+    /// None)
+    pub direct: Option<f64>,
+    /// The brightest that any color channel of the indirect light of a sample can be (unlimited if
+    /// None)
+    pub indirect: Option<f64>,
+}
+
+impl SampleClamp {
+    /// Reads the limits from the `CLAMP_DIRECT` and `CLAMP_INDIRECT` environment variables,
+    /// ignoring invalid values
+    pub fn from_env() -> Self {
+        let limit = |name| env::var(name).ok()
+            .and_then(|val| val.parse::<f64>().ok())
+            .filter(|&val| val > 0.0);
+
+        Self {direct: limit("CLAMP_DIRECT"), indirect: limit("CLAMP_INDIRECT")}
+    }
+
+    /// Returns true if neither the direct nor the indirect light is limited
+    pub fn is_unlimited(&self) -> bool {
+        self.direct.is_none() && self.indirect.is_none()
+    }
+
+    /// Scales down the direct and indirect light of the given color until none of their color
+    /// channels are brighter than their limits, keeping their hue
+    pub fn apply(&self, color: SplitColor) -> SplitColor {
+        let clamp = |light: Rgb, limit: Option<f64>| match limit {
+            Some(limit) if light.reduce_partial_max() > limit => light * (limit / light.reduce_partial_max()),
+            _ => light,
+        };
+
+        SplitColor {
+            direct: clamp(color.direct, self.direct),
+            indirect: clamp(color.indirect, self.indirect),
+        }
+    }
+}
+
+/// Ray traces a single pixel through the scene, returning the HDR color in linear space
+///
+/// If provided, a record of each sample is passed to `report_sample`. The light of each sample is
+/// limited by `clamp`. If `deterministic` is true, the samples are always added up in the same
+/// order (see `reduce_items`). If a seed is given, the random numbers of each sample are seeded
+/// with `sample_seed`.
+#[allow(clippy::too_many_arguments)]
 fn render_single_pixel<R: RayCast + Send + Sync, T: TextureSource>(
-    (x, y): (usize, usize),
+    pixel: (usize, usize),
     scene: &Scene<R>,
     camera: &Camera,
     width: f64,
     height: f64,
     samples: usize,
     background: &T,
+    report_sample: Option<&(dyn Fn(SampleRecord) + Sync)>,
+    clamp: SampleClamp,
+    deterministic: bool,
+    seed: Option<u64>,
 ) -> Rgb {
+    sample_pixel(pixel, scene, camera, width, height, 0..samples, background, report_sample,
+        clamp, deterministic, seed).color()
+}
+
+/// Ray traces the given range of samples of a single pixel through the scene
+///
+/// The sample indexes identify the samples passed to `report_sample` and are used to seed each
+/// sample if a seed is given.
+#[allow(clippy::too_many_arguments)]
+fn sample_pixel<R: RayCast + Send + Sync, T: TextureSource>(
+    (x, y): (usize, usize),
+    scene: &Scene<R>,
+    camera: &Camera,
+    width: f64,
+    height: f64,
+    samples: Range<usize>,
+    background: &T,
+    report_sample: Option<&(dyn Fn(SampleRecord) + Sync)>,
+    clamp: SampleClamp,
+    deterministic: bool,
+    seed: Option<u64>,
+) -> PixelEstimate {
     let background_color = background.at(Uv {
         u: x as f64 / width,
         v: y as f64 / height,
     });
 
-    let total_color: Rgb = (0..samples).into_par_iter().panic_fuse().map(|_| {
+    let estimates = samples.into_par_iter().panic_fuse().map(|sample| {
+        // Every random number used by the sample comes from its own seed (if any), so the sample is
+        // the same no matter which thread or machine traces it
+        seed_sample_rng(seed.map(|seed| sample_seed(seed, (x, y), sample)));
+
         // Choose a random point in the pixel square
-        let mut rng = thread_rng();
-        let (x, y) = (x as f64 + rng.gen::<f64>(), y as f64 + rng.gen::<f64>());
-        let ray = camera.ray_at((x, y));
+        let mut rng = sample_rng();
+        let position = (rng.gen::<f64>(), rng.gen::<f64>());
+        let pixel_pos = (x as f64 + position.0, y as f64 + position.1);
+
+        // Anything attached to the front of the camera blocks the entire scene
+        let radiance = camera.occluder_color_at(pixel_pos).unwrap_or_else(|| {
+            let ray = camera.ray_at(pixel_pos);
+            let color = if clamp.is_unlimited() {
+                ray.color(scene, background_color, 0)
+            } else {
+                clamp.apply(ray.split_color(scene, background_color, LightComponents::All)).total()
+            };
+            match camera.fog() {
+                Some(fog) => fog.sample(scene, &ray).apply(color),
+                None => color,
+            }
+        });
+        if let Some(report_sample) = report_sample {
+            report_sample(SampleRecord {pixel: (x, y), sample, position, radiance});
+        }
+        PixelEstimate::from_sample(radiance)
+    });
+    reduce_items(estimates, deterministic, PixelEstimate::default, PixelEstimate::merged)
+}
+
+/// Ray traces the given components of the light seen through a square block of pixels (see
+/// `LightComponents`), returning the HDR color in linear space
+///
+/// The block is `footprint` pixels wide with the given pixel at its top left corner, clipped to
+/// the edges of the image. Each sample is seeded like the sample of the given pixel with the same
+/// index. If provided, a record of each sample is passed to `report_sample` with the pixel of the
+/// block that the sample landed in.
+#[allow(clippy::too_many_arguments)]
+fn render_light_components<R: RayCast + Send + Sync, T: TextureSource>(
+    (x, y): (usize, usize),
+    footprint: usize,
+    scene: &Scene<R>,
+    camera: &Camera,
+    width: f64,
+    height: f64,
+    samples: Range<usize>,
+    background: &T,
+    components: LightComponents,
+    report_sample: Option<&(dyn Fn(SampleRecord) + Sync)>,
+    clamp: SampleClamp,
+    deterministic: bool,
+    seed: Option<u64>,
+) -> Rgb {
+    let background_color = background.at(Uv {
+        u: x as f64 / width,
+        v: y as f64 / height,
+    });
+
+    let estimates = samples.into_par_iter().panic_fuse().map(|sample| {
+        seed_sample_rng(seed.map(|seed| sample_seed(seed, (x, y), sample)));
+
+        // Choose a random point in the block
+        let mut rng = sample_rng();
+        let offset = (footprint as f64 * rng.gen::<f64>(), footprint as f64 * rng.gen::<f64>());
+        let pixel_pos = ((x as f64 + offset.0).min(width), (y as f64 + offset.1).min(height));
+
+        // Anything attached to the front of the camera blocks the entire scene
+        let color = match camera.occluder_color_at(pixel_pos) {
+            Some(color) => SplitColor {direct: color, indirect: Rgb::black()},
+            None => {
+                let ray = camera.ray_at(pixel_pos);
+                let color = clamp.apply(ray.split_color(scene, background_color, components));
+                // The light scattered by the fog is direct light
+                match camera.fog() {
+                    Some(fog) => {
+                        let fog = fog.sample(scene, &ray);
+                        SplitColor {direct: fog.apply(color.direct), indirect: color.indirect * fog.transmittance}
+                    },
+                    None => color,
+                }
+            },
+        };
+        let radiance = color.select(components);
+        if let Some(report_sample) = report_sample {
+            let pixel = (
+                (x + offset.0 as usize).min(width as usize - 1),
+                (y + offset.1 as usize).min(height as usize - 1),
+            );
+            let position = (pixel_pos.0 - pixel.0 as f64, pixel_pos.1 - pixel.1 as f64);
+            report_sample(SampleRecord {pixel, sample, position, radiance});
+        }
+        PixelEstimate::from_sample(radiance)
+    });
+    reduce_items(estimates, deterministic, PixelEstimate::default, PixelEstimate::merged).color()
+}
+
+/// Renders the direct light of the given pixels at full resolution and their indirect light at
+/// half resolution, upsampling the indirect light based on the surfaces seen by each pixel (see
+/// `HalfResLight`)
+///
+/// If provided, the samples of the direct light and of the indirect light are passed to
+/// `report_sample` as separate records. The indirect samples use the sample indexes after the
+/// ones used by the direct light.
+#[allow(clippy::too_many_arguments)]
+fn render_half_res_indirect<R: RayCast + Send + Sync, T: TextureSource + Sync, P: Reporter + Sync>(
+    pixels: &[(usize, usize)],
+    scene: &Scene<R>,
+    camera: &Camera,
+    width: f64,
+    height: f64,
+    samples: usize,
+    background: &T,
+    report_sample: Option<&(dyn Fn(SampleRecord) + Sync)>,
+    clamp: SampleClamp,
+    reporter: &P,
+    deterministic: bool,
+    seed: Option<u64>,
+) -> Vec<((usize, usize), Rgb)> {
+    let size = (width as usize, height as usize);
+    let passes = AovPasses::from_camera(scene, camera, size);
+
+    // Upsampling a pixel uses the half resolution pixel of its block and the ones around it
+    let (half_width, half_height) = half_size(size);
+    let mut blocks: Vec<_> = pixels.iter().flat_map(|&(x, y)| {
+        let (bx, by) = (x / 2, y / 2);
+        (by.saturating_sub(1)..(by + 2).min(half_height))
+            .flat_map(move |y| (bx.saturating_sub(1)..(bx + 2).min(half_width)).map(move |x| (x, y)))
+    }).collect();
+    blocks.sort_unstable();
+    blocks.dedup();
+
+    // The indirect light uses the sample indexes after the ones used by the direct light so that
+    // the two are seeded differently
+    let indirect: Vec<_> = blocks.into_par_iter().map(|(x, y)| {
+        let color = render_light_components((2 * x, 2 * y), 2, scene, camera, width, height,
+            samples..2 * samples, background, LightComponents::Indirect, report_sample, clamp, deterministic, seed);
+        ((x, y), color)
+    }).collect();
+    let indirect = HalfResLight::new(&passes, indirect);
+
+    pixels.par_iter().map(|&pixel| {
+        let direct = render_light_components(pixel, 1, scene, camera, width, height, 0..samples,
+            background, LightComponents::Direct, report_sample, clamp, deterministic, seed);
+        reporter.report_finished_pixels(1);
+
+        (pixel, direct + indirect.upsample(pixel, Guide::at(&passes, pixel)))
+    }).collect()
+}
+
+/// The number of rounds of extra samples given to the noisiest tiles during adaptive sampling
+const ADAPTIVE_ROUNDS: usize = 3;
+
+/// Divides the given number of samples between tiles based on their estimated error, returning
+/// the number of samples to give each pixel of each tile
+///
+/// Each tile is given by its estimated error and its number of pixels. Tiles with no error get
+/// no samples.
+fn allocate_samples(tiles: &[(f64, usize)], budget: usize) -> Vec<usize> {
+    let total_error: f64 = tiles.iter().map(|&(error, pixels)| error * pixels as f64).sum();
+    if total_error <= 0.0 {
+        return vec![0; tiles.len()];
+    }
+
+    tiles.iter()
+        .map(|&(error, _)| (budget as f64 * error / total_error).round() as usize)
+        .collect()
+}
+
+/// Renders the given pixels with the given average number of samples per pixel, spending more of
+/// the samples on the tiles of the image with the most noise
+///
+/// A quarter of the samples are spent on an initial pass over every pixel to estimate the noise
+/// (variance) of each tile. The rest are given out over several rounds, with each round favoring
+/// the tiles that are still the noisiest (e.g. glass and glossy surfaces). Pixels are reported as
+/// finished gradually over the rounds.
+#[allow(clippy::too_many_arguments)]
+fn render_adaptive<R: RayCast + Send + Sync, T: TextureSource + Sync, P: Reporter + Sync>(
+    pixels: &[(usize, usize)],
+    scene: &Scene<R>,
+    camera: &Camera,
+    width: f64,
+    height: f64,
+    samples: usize,
+    background: &T,
+    report_sample: Option<&(dyn Fn(SampleRecord) + Sync)>,
+    clamp: SampleClamp,
+    reporter: &P,
+    deterministic: bool,
+    seed: Option<u64>,
+) -> Vec<((usize, usize), Rgb)> {
+    let initial_samples = (samples / 4).max(1);
+    let mut estimates: Vec<_> = pixels.par_iter().panic_fuse().map(|&pixel| {
+        sample_pixel(pixel, scene, camera, width, height, 0..initial_samples, background, report_sample,
+            clamp, deterministic, seed)
+    }).collect();
+
+    // Group the pixels into tiles, keeping the tiles in the order their first pixel was rendered
+    let mut tiles: Vec<Vec<usize>> = Vec::new();
+    let mut tile_indexes = HashMap::new();
+    for (i, &(x, y)) in pixels.iter().enumerate() {
+        let tile = *tile_indexes.entry((x / TILE_SIZE, y / TILE_SIZE)).or_insert_with(|| {
+            tiles.push(Vec::new());
+            tiles.len() - 1
+        });
+        tiles[tile].push(i);
+    }
 
-        ray.color(scene, background_color, 0)
-    }).reduce(|| Rgb::black(), |x, y| x + y);
+    let remaining_samples = (samples * pixels.len()).saturating_sub(initial_samples * pixels.len());
+    let mut finished = 0;
+    for round in 0..ADAPTIVE_ROUNDS {
+        // The estimated error of each tile is the standard deviation of its average pixel
+        let tile_errors: Vec<_> = tiles.iter().map(|tile| {
+            let variance: f64 = tile.iter().map(|&i| estimates[i].variance()).sum();
+            ((variance / tile.len() as f64).sqrt(), tile.len())
+        }).collect();
+        let allocations = allocate_samples(&tile_errors, remaining_samples / ADAPTIVE_ROUNDS);
 
-    let color = total_color / samples as f64;
+        // Render the noisiest tiles first
+        let mut order: Vec<_> = (0..tiles.len()).collect();
+        order.sort_by(|&a, &b| tile_errors[b].0.partial_cmp(&tile_errors[a].0)
+            .expect("bug: tile errors should never be NaN"));
+        let work: Vec<_> = order.into_iter()
+            .filter(|&tile| allocations[tile] > 0)
+            .flat_map(|tile| {
+                let extra_samples = allocations[tile];
+                tiles[tile].iter().map(move |&i| (i, extra_samples))
+            })
+            .collect();
 
+        let extra: Vec<_> = work.into_par_iter().panic_fuse().map(|(i, extra_samples)| {
+            let first_sample = estimates[i].samples;
+            let estimate = sample_pixel(pixels[i], scene, camera, width, height,
+                first_sample..first_sample + extra_samples, background, report_sample, clamp, deterministic, seed);
+            (i, estimate)
+        }).collect();
+        for (i, estimate) in extra {
+            estimates[i] = estimates[i].merged(estimate);
+        }
+
+        let total_finished = pixels.len() * (round + 1) / ADAPTIVE_ROUNDS;
+        reporter.report_finished_pixels((total_finished - finished) as u64);
+        finished = total_finished;
+    }
+
+    pixels.iter().zip(estimates).map(|(&pixel, estimate)| (pixel, estimate.color())).collect()
+}
+
+/// Computes the average ray length of the primary rays of a single pixel, returning a grey color
+/// that is white for hits at the camera and fades to black for hits at the given far distance
+///
+/// Rays that miss the scene are black. Averaging the samples antialiases the edges of objects.
+fn render_ray_length_pixel<R: RayCast + Send + Sync>(
+    (x, y): (usize, usize),
+    scene: &Scene<R>,
+    camera: &Camera,
+    samples: usize,
+    far: f64,
+    deterministic: bool,
+    seed: Option<u64>,
+) -> Rgb {
+    let nearness = (0..samples).into_par_iter().panic_fuse().map(|sample| {
+        seed_sample_rng(seed.map(|seed| sample_seed(seed, (x, y), sample)));
+        let mut rng = sample_rng();
+        let ray = camera.ray_at((x as f64 + rng.gen::<f64>(), y as f64 + rng.gen::<f64>()));
+
+        match ray.length(scene) {
+            Some(length) => 1.0 - (length / far).min(1.0),
+            None => 0.0,
+        }
+    });
+    let total_nearness = reduce_items(nearness, deterministic, || 0.0, |a, b| a + b);
+
+    Rgb::from(total_nearness / samples as f64)
+}
+
+/// The number of stops above and below the reference illuminance covered by the colors of the
+/// illuminance heatmap
+const ILLUMINANCE_STOPS: f64 = 4.0;
+/// The reference illuminance used if no other value is provided
+const DEFAULT_ILLUMINANCE: f64 = 1.0;
+
+/// Returns the direct illuminance (the luminance of the light arriving from every light, ignoring
+/// the material) at the given point on a surface with the given normal
+///
+/// Area lights are sampled at a single random point, so averaging several samples gives soft
+/// shadows just like the final render.
+fn direct_illuminance<R: RayCast>(scene: &Scene<R>, point: Vec3, normal: Vec3) -> f64 {
+    let mut rng = sample_rng();
+    let normal = normal.normalized();
+
+    scene.lights.iter().map(|light| {
+        let light_pos = if light.area.is_empty() {
+            light.position
+        } else {
+            light.sample_position(&mut rng)
+        };
+
+        let hit_to_light = light_pos - point;
+        let light_dist = hit_to_light.magnitude();
+        let light_dir = hit_to_light / light_dist;
+        let normal_light = normal.dot(light_dir);
+        if normal_light <= 0.0 {
+            return 0.0;
+        }
+
+        let is_shadowed = match &light.shadow_map {
+            Some(shadow_map) => shadow_map.is_shadowed(point),
+            None => {
+                let shadow_ray = Ray::new(point, light_dir);
+                let mut shadow_t_range = shadow_ray.t_range();
+                // Anything on the other side of the light does not cast a shadow
+                shadow_t_range.end = light_dist;
+                scene.root.ray_cast(&shadow_ray, &mut shadow_t_range).is_some()
+            },
+        };
+        if is_shadowed {
+            return 0.0;
+        }
+
+        let light_color = light.color_at(light_pos, -light_dir);
+        light_color.luminance() * normal_light / light.falloff.at_distance(light_dist)
+    }).sum()
+}
+
+/// Returns the color of the illuminance heatmap for the given number of stops above (positive)
+/// or below (negative) the reference illuminance
+///
+/// The colors go from blue (dark) through cyan, green (the reference), and yellow to red (bright).
+fn illuminance_heatmap(stops: f64) -> Rgb {
+    const COLORS: [Rgb; 5] = [
+        Rgb {r: 0.0, g: 0.0, b: 1.0},
+        Rgb {r: 0.0, g: 1.0, b: 1.0},
+        Rgb {r: 0.0, g: 1.0, b: 0.0},
+        Rgb {r: 1.0, g: 1.0, b: 0.0},
+        Rgb {r: 1.0, g: 0.0, b: 0.0},
+    ];
+
+    let t = ((stops + ILLUMINANCE_STOPS) / (2.0 * ILLUMINANCE_STOPS)).clamp(0.0, 1.0);
+    let scaled = t * (COLORS.len() - 1) as f64;
+    let i = (scaled.floor() as usize).min(COLORS.len() - 2);
+    Rgb::lerp(COLORS[i], COLORS[i + 1], scaled - i as f64)
+}
+
+/// Renders a heatmap of the direct illuminance seen by the primary rays of each of the given
+/// pixels, with isolines drawn in white at every stop (doubling) of illuminance
+///
+/// The heatmap is green at the given reference illuminance (see `illuminance_heatmap`). Pixels
+/// that see nothing are black. Useful for placing lights without doing a full render.
+fn render_illuminance<R: RayCast + Send + Sync, P: Reporter + Sync>(
+    pixels: &[(usize, usize)],
+    scene: &Scene<R>,
+    camera: &Camera,
+    samples: usize,
+    reference: f64,
+    reporter: &P,
+    seed: Option<u64>,
+) -> Vec<((usize, usize), Rgb)> {
+    // Avoids taking the log of zero for unlit surfaces
+    const DELTA: f64 = 1e-9;
+
+    let stops: HashMap<_, _> = pixels.par_iter().panic_fuse().map(|&(x, y)| {
+        let mut hits = 0;
+        let total: f64 = (0..samples).map(|sample| {
+            seed_sample_rng(seed.map(|seed| sample_seed(seed, (x, y), sample)));
+            let mut rng = sample_rng();
+            let ray = camera.ray_at((x as f64 + rng.gen::<f64>(), y as f64 + rng.gen::<f64>()));
+            let mut t_range = ray.t_range();
+            match scene.root.ray_cast(&ray, &mut t_range) {
+                Some((hit, _)) => {
+                    hits += 1;
+                    direct_illuminance(scene, hit.hit_point, hit.normal)
+                },
+                None => 0.0,
+            }
+        }).sum();
+
+        reporter.report_finished_pixels(1);
+
+        // Only pixels where most of the samples hit something are counted as surfaces
+        let stops = if hits * 2 > samples {
+            Some(((total / hits as f64 + DELTA) / reference).log2())
+        } else {
+            None
+        };
+        ((x, y), stops)
+    }).collect();
+
+    pixels.iter().map(|&(x, y)| {
+        let color = match stops[&(x, y)] {
+            Some(pixel_stops) => {
+                // Isolines are drawn wherever a neighbouring pixel is in a different stop
+                let band = pixel_stops.floor();
+                let is_isoline = [(x + 1, y), (x, y + 1)].iter().any(|neighbor| {
+                    matches!(stops.get(neighbor), Some(Some(other)) if other.floor() != band)
+                });
+
+                if is_isoline {
+                    Rgb::white()
+                } else {
+                    illuminance_heatmap(pixel_stops)
+                }
+            },
+            None => Rgb::black(),
+        };
+
+        ((x, y), color)
+    }).collect()
+}
+
+/// The number of pixels sampled along each axis of the image by the auto exposure probe
+const EXPOSURE_PROBE_SIZE: usize = 32;
+/// The number of samples taken for each pixel of the auto exposure probe
+const EXPOSURE_PROBE_SAMPLES: usize = 4;
+/// The average luminance targeted by auto exposure if no other value is provided (middle grey)
+const DEFAULT_TARGET_LUMINANCE: f64 = 0.18;
+
+/// Renders a quick, low resolution probe of the scene and returns the exposure (a multiplier for
+/// every color) that would give the final image the given average luminance
+///
+/// The log-average of the luminance is used so that a few very bright pixels (e.g. visible
+/// lights) do not dominate the result.
+#[allow(clippy::too_many_arguments)]
+fn probe_exposure<R: RayCast + Send + Sync, T: TextureSource + Sync>(
+    scene: &Scene<R>,
+    camera: &Camera,
+    (width, height): (usize, usize),
+    background: &T,
+    target_luminance: f64,
+    clamp: SampleClamp,
+    deterministic: bool,
+    seed: Option<u64>,
+) -> f64 {
+    // Avoids taking the log of zero for black pixels
+    const DELTA: f64 = 1e-4;
+
+    let step_x = (width / EXPOSURE_PROBE_SIZE).max(1);
+    let step_y = (height / EXPOSURE_PROBE_SIZE).max(1);
+    let probe_pixels: Vec<_> = (0..height).step_by(step_y)
+        .flat_map(|y| (0..width).step_by(step_x).map(move |x| (x, y)))
+        .collect();
+
+    let log_luminances = probe_pixels.par_iter().map(|&pixel| {
+        let color = render_single_pixel(pixel, scene, camera, width as f64, height as f64,
+            EXPOSURE_PROBE_SAMPLES, background, None, clamp, deterministic, seed);
+        (DELTA + color.luminance()).ln()
+    });
+    let total_log_luminance = reduce_items(log_luminances, deterministic, || 0.0, |a, b| a + b);
+    let average_luminance = (total_log_luminance / probe_pixels.len() as f64).exp();
+
+    target_luminance / average_luminance
+}
+
+/// The number of radiance probes along each axis of the scene when RADIANCE_PROBES is set
+const PROBE_GRID_RESOLUTION: usize = 8;
+/// The number of rays traced by each radiance probe when RADIANCE_PROBES is set
+const PROBE_SAMPLES: usize = 64;
+
+/// The most samples taken for each pixel of a thumbnail
+const THUMBNAIL_SAMPLES: usize = 8;
+/// The time after which a thumbnail stops adding samples (every pixel always gets one sample)
+const THUMBNAIL_TIME_LIMIT: Duration = Duration::from_millis(250);
+
+impl<R: RayCast + Send + Sync> Scene<R> {
+    /// Renders a small, low-sample preview of this scene and returns it as an in-memory image
+    ///
+    /// Every pixel gets one sample, then more samples are added in passes over the whole image
+    /// until either a handful of samples have been taken or a short time limit is reached. Useful
+    /// for asset browsers or for checking the average color of a scene in tests. Nothing is read
+    /// from or written to disk, environment variables are ignored, and the background is black.
+    pub fn thumbnail(&self, camera: CameraSettings, width: usize, height: usize) -> image::RgbImage {
+        let start = Instant::now();
+        let size = (width as f64, height as f64);
+        let camera = Camera::new(camera, size);
+        let background = |_: Uv| Rgb::black();
+
+        let pixels: Vec<_> = (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).collect();
+        let mut estimates = vec![PixelEstimate::default(); pixels.len()];
+        for sample in 0..THUMBNAIL_SAMPLES {
+            if sample > 0 && start.elapsed() >= THUMBNAIL_TIME_LIMIT {
+                break;
+            }
+
+            estimates.par_iter_mut().zip(&pixels).for_each(|(estimate, &pixel)| {
+                // A single sample is always combined the same way
+                let pass = sample_pixel(pixel, self, &camera, size.0, size.1, sample..sample+1,
+                    &background, None, SampleClamp::default(), false, None);
+                *estimate = estimate.merged(pass);
+            });
+        }
+
+        let mut image = image::RgbImage::new(width as u32, height as u32);
+        for (&(x, y), estimate) in pixels.iter().zip(&estimates) {
+            image.put_pixel(x as u32, y as u32, to_pixel(estimate.color()));
+        }
+        image
+    }
+}
+
+/// Converts an HDR color in linear space to a pixel of the output image
+pub(crate) fn to_pixel(color: Rgb) -> image::Rgb<u8> {
     let color = color.map(|c| c.powf(1.0/GAMMA));
 
     // Clamp to 0.0 to 1.0 or else we will get invalid pixels in the output PNG
-    Clamp::<f64>::clamp01(color)
+    let color = Clamp::<f64>::clamp01(color);
+
+    // Convert into the type supported by the image library
+    image::Rgb([
+        (color.r * 255.0) as u8,
+        (color.g * 255.0) as u8,
+        (color.b * 255.0) as u8,
+    ])
+}
+
+/// How much a render lowers the priority of its threads when `ThreadPoolSettings::low_priority`
+/// is set (the Unix "nice" value added to each thread)
+const LOW_PRIORITY_NICENESS: i32 = 10;
+
+/// Settings for a dedicated thread pool used for rendering instead of rayon's global pool
+///
+/// Useful for running renders alongside other work or restricting them on shared machines (e.g.
+/// in CI).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadPoolSettings {
+    /// The maximum number of threads used for rendering (rayon's default if None)
+    pub threads: Option<usize>,
+    /// If true, the render threads ask the operating system to schedule them after other work
+    ///
+    /// This is only a hint and is only supported on Unix.
+    pub low_priority: bool,
+}
+
+impl ThreadPoolSettings {
+    /// Reads the settings from the `THREADS` and `LOW_PRIORITY` environment variables, ignoring
+    /// invalid values
+    ///
+    /// Returns None if neither variable is set, in which case rayon's global pool should be used.
+    pub fn from_env() -> Option<Self> {
+        let threads = env::var("THREADS").ok()
+            .and_then(|val| val.parse::<usize>().ok())
+            .filter(|&val| val > 0);
+        let low_priority = env::var("LOW_PRIORITY").is_ok();
+
+        if threads.is_none() && !low_priority {
+            return None;
+        }
+        Some(Self {threads, low_priority})
+    }
+
+    /// Creates a new thread pool with these settings
+    pub fn build(&self) -> Result<ThreadPool, ThreadPoolBuildError> {
+        let mut builder = ThreadPoolBuilder::new()
+            .thread_name(|index| format!("portrayer-render-{}", index));
+        if let Some(threads) = self.threads {
+            builder = builder.num_threads(threads);
+        }
+        if self.low_priority {
+            builder = builder.start_handler(|_| lower_thread_priority());
+        }
+
+        builder.build()
+    }
+}
+
+/// The thread pool created for the settings in the environment, reused by every render that
+/// requests the same settings
+static ENV_THREAD_POOL: Mutex<Option<(ThreadPoolSettings, Arc<ThreadPool>)>> = Mutex::new(None);
+
+/// Returns the dedicated thread pool requested by the environment (see
+/// `ThreadPoolSettings::from_env`), or None if rayon's global pool should be used
+///
+/// The pool is only created once for as long as the settings stay the same.
+fn env_thread_pool() -> Option<Arc<ThreadPool>> {
+    let settings = ThreadPoolSettings::from_env()?;
+
+    let mut cached = ENV_THREAD_POOL.lock().expect("bug: thread pool cache lock was poisoned");
+    match &*cached {
+        Some((cached_settings, thread_pool)) if *cached_settings == settings => Some(thread_pool.clone()),
+        _ => {
+            let thread_pool = Arc::new(settings.build().expect("Unable to create the render thread pool"));
+            *cached = Some((settings, thread_pool.clone()));
+            Some(thread_pool)
+        },
+    }
+}
+
+/// Lowers the scheduling priority of the current thread
+///
+/// On Linux, each thread has its own "nice" value, so only the calling thread is affected.
+#[cfg(unix)]
+fn lower_thread_priority() {
+    use std::os::raw::c_int;
+
+    extern "C" {
+        fn nice(inc: c_int) -> c_int;
+    }
+
+    // Failing to lower the priority is harmless, so the result is ignored
+    unsafe { nice(LOW_PRIORITY_NICENESS); }
+}
+
+#[cfg(not(unix))]
+fn lower_thread_priority() {}
+
+/// Runs the given stage of rendering, reporting when it starts and ends
+fn run_stage<P: Reporter, T, F: FnOnce() -> T>(reporter: &P, stage: RenderStage, f: F) -> T {
+    reporter.report_stage_start(stage);
+    let start = Instant::now();
+    let result = f();
+    reporter.report_stage_end(stage, start.elapsed());
+    result
+}
+
+/// Converts a pixel of the output image back to a color in linear space
+///
+/// Used when an existing image is loaded since the original HDR values are lost when the image is
+/// saved.
+fn from_pixel(pixel: &image::Rgb<u8>) -> Rgb {
+    let [r, g, b] = pixel.data;
+    Rgb {r: r as f64, g: g as f64, b: b as f64}.map(|c| (c / 255.0).powf(GAMMA))
 }
 
 /// Represents a 2D slice of an image
@@ -63,6 +839,23 @@ pub struct ImageSliceMut<'a> {
     ///
     /// This is guaranteed to be inside the image, but not guaranteed to be greater than top_left
     bottom_right: (usize, usize),
+    /// Geometry attached to the front of the camera (see `Camera::with_occluder`)
+    lens_occluder: Option<Arc<SceneNode>>,
+    /// Fog between the camera and the scene (see `Camera::with_fog`)
+    fog: Option<Fog>,
+    /// The frame of an animation that is being rendered (if any), used to seed the render
+    frame: Option<usize>,
+    /// How the seed of the render changes between the frames of an animation
+    frame_noise: FrameNoise,
+    /// The limits on the light of each sample to use instead of the environment (if any)
+    sample_clamp: Option<SampleClamp>,
+    /// The thread pool to render with instead of rayon's global pool (if any)
+    thread_pool: Option<Arc<ThreadPool>>,
+    /// Replacement materials applied to the scene before rendering
+    material_overrides: MaterialOverrides,
+    /// Keeps the k-d tree of the scene between renders so it can be refitted (if any)
+    #[cfg(feature = "kdtree")]
+    kdtree_cache: Option<Arc<KDTreeCache>>,
 }
 
 impl<'a> From<&'a mut Image> for ImageSliceMut<'a> {
@@ -86,7 +879,97 @@ impl<'a> ImageSliceMut<'a> {
                 x1, y1, x2, y2, width, height);
         }
 
-        Self {image, top_left, bottom_right}
+        Self {
+            image,
+            top_left,
+            bottom_right,
+            lens_occluder: None,
+            fog: None,
+            frame: None,
+            frame_noise: FrameNoise::default(),
+            sample_clamp: None,
+            thread_pool: None,
+            material_overrides: MaterialOverrides::new(),
+            #[cfg(feature = "kdtree")]
+            kdtree_cache: None,
+        }
+    }
+
+    /// Attaches the given geometry to the front of the camera when rendering, returning the
+    /// updated slice
+    ///
+    /// Useful for framing effects like a lens hood or matte box without adding them to the scene.
+    /// See `Camera::with_occluder` for details.
+    pub fn with_lens_occluder(mut self, occluder: Arc<SceneNode>) -> Self {
+        self.lens_occluder = Some(occluder);
+        self
+    }
+
+    /// Fills the space in front of the camera with the given fog when rendering, returning the
+    /// updated slice
+    ///
+    /// Light shafts from the lights in the scene can be seen through the fog (see `Fog`).
+    pub fn with_fog(mut self, fog: Fog) -> Self {
+        self.fog = Some(fog);
+        self
+    }
+
+    /// Marks this render as the given frame of an animation, returning the updated slice
+    ///
+    /// When the `SEED` environment variable is set, each frame is seeded differently (see
+    /// `math::frame_seed`) so the noise does not stay in place from frame to frame. Use
+    /// `with_frame_noise` or set `FIXED_NOISE` to seed every frame the same way instead.
+    pub fn with_frame(mut self, frame: usize) -> Self {
+        self.frame = Some(frame);
+        self
+    }
+
+    /// Sets how the seed changes between the frames of an animation (see `with_frame`),
+    /// returning the updated slice
+    ///
+    /// The `FIXED_NOISE` environment variable overrides this with `FrameNoise::Fixed`.
+    pub fn with_frame_noise(mut self, frame_noise: FrameNoise) -> Self {
+        self.frame_noise = frame_noise;
+        self
+    }
+
+    /// Limits the direct and indirect light of each sample (see `SampleClamp`), returning the
+    /// updated slice
+    ///
+    /// Takes priority over the `CLAMP_DIRECT` and `CLAMP_INDIRECT` environment variables.
+    pub fn with_sample_clamp(mut self, sample_clamp: SampleClamp) -> Self {
+        self.sample_clamp = Some(sample_clamp);
+        self
+    }
+
+    /// Renders with the given thread pool instead of rayon's global pool, returning the updated
+    /// slice
+    ///
+    /// The same pool can be shared between many renders (see `ThreadPoolSettings`). Takes
+    /// precedence over the `THREADS` and `LOW_PRIORITY` environment variables.
+    pub fn with_thread_pool(mut self, thread_pool: Arc<ThreadPool>) -> Self {
+        self.thread_pool = Some(thread_pool);
+        self
+    }
+
+    /// Replaces the tagged materials in the scene with the given materials when rendering,
+    /// returning the updated slice
+    ///
+    /// The scene itself is not modified. See `MaterialOverrides` for details.
+    pub fn with_material_overrides(mut self, overrides: MaterialOverrides) -> Self {
+        self.material_overrides = overrides;
+        self
+    }
+
+    /// Reuses the k-d tree kept in the given cache when rendering, returning the updated slice
+    ///
+    /// Pass the same cache to the render of every frame of an animation so that the k-d tree only
+    /// has to be refitted when nothing but the transforms of the nodes have changed. See
+    /// `KDTreeCache` for details.
+    #[cfg(feature = "kdtree")]
+    pub fn with_kdtree_cache(mut self, cache: Arc<KDTreeCache>) -> Self {
+        self.kdtree_cache = Some(cache);
+        self
     }
 
     /// Render the given scene onto the entirety of this image
@@ -95,12 +978,148 @@ impl<'a> ImageSliceMut<'a> {
         scene: &HierScene,
         camera: CameraSettings,
         background: T,
+    ) {
+        // Use a dedicated thread pool if one was provided or requested
+        let thread_pool = self.thread_pool.clone().or_else(env_thread_pool);
+
+        match thread_pool {
+            Some(thread_pool) => thread_pool.install(|| self.render_in_pool::<R, _>(scene, camera, background)),
+            None => self.render_in_pool::<R, _>(scene, camera, background),
+        }
+    }
+
+    /// Renders the given scene onto the entirety of this image using the current thread pool
+    fn render_in_pool<R: Reporter + Send + Sync, T: TextureSource + Send + Sync>(
+        &mut self,
+        scene: &HierScene,
+        camera: CameraSettings,
+        background: T,
     ) {
         let width = self.image.width() as f64;
         let height = self.image.height() as f64;
-        let camera = Camera::new(camera, (width, height));
+        let mut camera = Camera::new(camera, (width, height));
+        if let Some(occluder) = &self.lens_occluder {
+            camera = camera.with_occluder(occluder.clone());
+        }
+        if let Some(fog) = &self.fog {
+            camera = camera.with_fog(fog.clone());
+        }
+
+        // Print a summary of the scene if requested so generated scenes can be sanity checked
+        if env::var("SCENE_STATS").is_ok() {
+            println!("{}", scene.stats());
+        }
+        // Warn about overlapping coplanar faces if requested since they cause speckled artifacts
+        if env::var("SCENE_CHECKS").is_ok() {
+            for overlap in scene.coplanar_overlaps(EPSILON) {
+                println!("{}", format_warning(ErrorCode::InvalidScene, &overlap.to_string()));
+            }
+            // Also warn about materials that reflect more light than they receive
+            for warning in scene.energy_violations() {
+                println!("{}", format_warning(ErrorCode::InvalidScene, &warning.to_string()));
+            }
+            // Also list the nodes that were never given a material
+            for node in scene.default_material_nodes() {
+                println!("{}", format_warning(ErrorCode::InvalidScene, &format!("{} uses the default material", node)));
+            }
+        }
 
         let reporter = R::new((self.image.width() * self.image.height()) as u64);
+        reporter.report_stage_start(RenderStage::Prepare);
+        let prepare_start = Instant::now();
+
+        // Apply the material overrides before anything else so that they are treated as if they
+        // were part of the scene
+        let overridden_scene;
+        let scene = if self.material_overrides.is_empty() {
+            scene
+        } else {
+            overridden_scene = HierScene {
+                root: Arc::new(scene.root.with_material_overrides(&self.material_overrides)),
+                lights: scene.lights.clone(),
+                ambient: scene.ambient,
+            };
+            &overridden_scene
+        };
+
+        // Replace every material with clay (except for the nodes with the given names) if
+        // requested, to help isolate the shading of specific assets
+        let clay_scene;
+        let scene = match env::var("CLAY") {
+            Ok(keep) => {
+                let keep: Vec<_> = keep.split(',').map(str::trim).filter(|name| !name.is_empty()).collect();
+                let clay = Arc::new(Material::clay());
+                clay_scene = HierScene {
+                    root: Arc::new(scene.root.with_material_override(&clay, &keep)),
+                    lights: scene.lights.clone(),
+                    ambient: scene.ambient,
+                };
+                &clay_scene
+            },
+            Err(_) => scene,
+        };
+
+        // Clamp every material so that it conserves energy if requested
+        let conserving_scene;
+        let scene = if env::var("CONSERVE_ENERGY").is_ok() {
+            conserving_scene = HierScene {
+                root: Arc::new(scene.root.with_energy_conserving_materials()),
+                lights: scene.lights.clone(),
+                ambient: scene.ambient,
+            };
+            &conserving_scene
+        } else {
+            scene
+        };
+
+        // Approximate shadows with shadow maps of the given resolution instead of casting shadow
+        // rays if requested, ignoring invalid resolutions
+        let shadow_map_scene;
+        let scene = match env::var("SHADOW_MAPS").ok().and_then(|val| val.parse::<usize>().ok()) {
+            Some(resolution) if resolution > 0 => {
+                let lights = scene.lights.iter().map(|light| Light {
+                    shadow_map: Some(Arc::new(ShadowMap::new(scene, light.position, resolution))),
+                    ..light.clone()
+                }).collect();
+                shadow_map_scene = HierScene {
+                    root: scene.root.clone(),
+                    lights,
+                    ambient: scene.ambient,
+                };
+                &shadow_map_scene
+            },
+            _ => scene,
+        };
+
+        // Seed the random numbers of every sample if requested so that the image can be reproduced
+        // exactly (e.g. by distributed workers rendering different slices), ignoring invalid seeds.
+        // Each frame of an animation gets its own seed unless fixed-pattern noise is requested.
+        let frame_noise = if env::var("FIXED_NOISE").is_ok() { FrameNoise::Fixed } else { self.frame_noise };
+        let seed = env::var("SEED").ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(|seed| match self.frame {
+                Some(frame) => frame_noise.seed(seed, frame),
+                None => seed,
+            });
+
+        // Shade the surfaces reached after the given number of bounces with baked radiance
+        // probes instead of tracing more rays if requested
+        let probe_scene;
+        let scene = match (env::var("RADIANCE_PROBES").ok().and_then(|val| val.parse::<u32>().ok()), scene.root.parent_bounds()) {
+            (Some(probe_depth), Some(bounds)) => {
+                let resolution = (PROBE_GRID_RESOLUTION, PROBE_GRID_RESOLUTION, PROBE_GRID_RESOLUTION);
+                let probes = bake_radiance_probes(scene, (bounds.min(), bounds.max()), resolution,
+                    Rgb::black(), PROBE_SAMPLES, seed);
+                probe_scene = HierScene {
+                    root: Arc::new(scene.root.with_radiance_probes(&Arc::new(probes), probe_depth)),
+                    lights: scene.lights.clone(),
+                    ambient: scene.ambient,
+                };
+                &probe_scene
+            },
+            _ => scene,
+        };
+        reporter.report_stage_end(RenderStage::Prepare, prepare_start.elapsed());
 
         // Attempt to get the number of samples from an environment variable, and ignore the value
         // otherwise
@@ -112,48 +1131,230 @@ impl<'a> ImageSliceMut<'a> {
             // Default value if not all conditions are met
             .unwrap_or(100);
 
-        // Only render the sliced pixels
-        let (x1, y1) = self.top_left;
-        let (x2, y2) = self.bottom_right;
-        let x_range = x1..=x2;
-        let y_range = y1..=y2;
+        // Automatically choose an exposure from a quick preview render if requested
+        let auto_exposure = env::var("AUTO_EXPOSURE").ok().map(|val| {
+            // Must be a valid positive number, otherwise the default is used
+            val.parse::<f64>().ok()
+                .filter(|&val| val > 0.0)
+                .unwrap_or(DEFAULT_TARGET_LUMINANCE)
+        });
+
+        // Output a false color image showing the exposure of each pixel instead of the actual
+        // colors if requested
+        let show_false_color = env::var("FALSE_COLOR").is_ok();
+
+        // Output the length of the primary rays (up to the given far distance) instead of the
+        // actual colors if requested, ignoring invalid distances
+        let ray_length_far = env::var("RAY_LENGTH").ok()
+            .and_then(|val| val.parse::<f64>().ok())
+            .filter(|&val| val > 0.0);
+
+        // Output one of the passes used for compositing (see `Aov`) instead of the actual colors if
+        // requested, ignoring invalid names
+        let aov = env::var("AOV").ok()
+            .and_then(|val| val.parse::<Aov>().ok());
+
+        // Output a heatmap of the direct illuminance of each surface (with isolines at every stop)
+        // instead of the actual colors if requested
+        let illuminance_reference = env::var("ILLUMINANCE").ok().map(|val| {
+            // Must be a valid positive number, otherwise the default is used
+            val.parse::<f64>().ok()
+                .filter(|&val| val > 0.0)
+                .unwrap_or(DEFAULT_ILLUMINANCE)
+        });
+
+        // Limit the direct and indirect light of each sample separately if requested to remove
+        // fireflies
+        let clamp = self.sample_clamp.unwrap_or_else(SampleClamp::from_env);
+
+        // Spend more samples on the noisiest parts of the image if requested
+        let adaptive_sampling = env::var("ADAPTIVE_SAMPLING").is_ok();
+
+        // Render the indirect light (reflections and refractions) at half resolution and upsample
+        // it with the normals and depths of the surfaces seen by each pixel if requested
+        let half_res_indirect = env::var("HALF_RES_INDIRECT").is_ok();
+
+        // Only one of the modes above is used to render the image. If several are requested, the
+        // first one in this list takes priority and the others are ignored with a warning.
+        let render_modes = [
+            ("AOV", aov.is_some()),
+            ("ILLUMINANCE", illuminance_reference.is_some()),
+            ("RAY_LENGTH", ray_length_far.is_some()),
+            ("ADAPTIVE_SAMPLING", adaptive_sampling),
+            ("HALF_RES_INDIRECT", half_res_indirect),
+        ];
+        let mut requested_modes = render_modes.iter().filter(|&&(_, requested)| requested).map(|&(name, _)| name);
+        if let Some(used) = requested_modes.next() {
+            for ignored in requested_modes {
+                println!("{}", format_warning(ErrorCode::InvalidSettings,
+                    &format!("{} is ignored because {} is set", ignored, used)));
+            }
+        }
+
+        // Always add up samples in the same order if requested so that the same samples always
+        // give bit-identical colors, regardless of how the work is split between threads. Seeded
+        // samples are only useful if they are always added up the same way.
+        let deterministic = env::var("DETERMINISTIC").is_ok() || seed.is_some();
+
+        // Report every sample to the reporter if requested
+        let record_samples = env::var("SAMPLE_RECORDS").is_ok();
+        let report_sample = |record| reporter.report_sample(record);
+        let report_sample: Option<&(dyn Fn(SampleRecord) + Sync)> = if record_samples {
+            Some(&report_sample)
+        } else {
+            None
+        };
+
+        // Attempt to get the order to render pixels in from an environment variable, and ignore
+        // the value otherwise
+        let pixel_order = env::var("PIXEL_ORDER").ok()
+            .and_then(|val| val.parse::<PixelOrder>().ok())
+            .unwrap_or_default();
+        // Optionally render part of the image before the rest of it, also ignoring invalid values
+        let focus = env::var("RENDER_FOCUS").ok()
+            .and_then(|val| val.parse::<RenderFocus>().ok());
 
         #[cfg(feature = "flat_scene")]
-        let scene = &FlatScene::from(scene);
+        let scene = &run_stage(&reporter, RenderStage::Flatten, || FlatScene::from(scene));
         #[cfg(feature = "kdtree")]
-        let flat_scene = FlatScene::from(scene);
+        let flat_scene = run_stage(&reporter, RenderStage::Flatten, || FlatScene::from(scene));
         #[cfg(feature = "kdtree")]
-        let scene = &KDTreeScene::from(flat_scene);
-        self.image.buffer.par_chunks_mut(3)
-            .map(image::Rgb::from_slice_mut)
-            .enumerate()
-            .panic_fuse()
-            .for_each(|(i, pixel)| {
-                let x = i % width as usize;
-                let y = i / width as usize;
-
-                // Skip any pixels not in the range
-                if !x_range.contains(&x) || !y_range.contains(&y) {
-                    return;
+        let kdtree_scene = run_stage(&reporter, RenderStage::BuildKDTree, || match &self.kdtree_cache {
+            Some(cache) => cache.update(flat_scene),
+            None => Arc::new(KDTreeScene::from(flat_scene)),
+        });
+        #[cfg(feature = "kdtree")]
+        let scene = &*kdtree_scene;
+
+        if let Some(aov) = aov {
+            let size = (self.image.width(), self.image.height());
+            let passes = run_stage(&reporter, RenderStage::Render, || {
+                AovPasses::from_camera(scene, &camera, size)
+            });
+            let pass = passes.image(aov);
+            let pixels = pixel_order.pixels(self.top_left, self.bottom_right);
+            reporter.report_finished_pixels(pixels.len() as u64);
+
+            run_stage(&reporter, RenderStage::PostProcess, || {
+                for (x, y) in pixels {
+                    let pixel = pass.get_pixel(x as u32, y as u32);
+                    self.image.hdr_buffer[y * size.0 + x] = from_pixel(pixel);
+                    self.image.buffer.put_pixel(x as u32, y as u32, *pixel);
                 }
+            });
+            return;
+        }
 
-                let color = render_single_pixel((x, y), scene, &camera, width, height, samples, &background);
+        let exposure = match auto_exposure {
+            Some(target_luminance) => {
+                let size = (self.image.width(), self.image.height());
+                run_stage(&reporter, RenderStage::AutoExposure, || {
+                    probe_exposure(scene, &camera, size, &background, target_luminance, clamp, deterministic, seed)
+                })
+            },
+            None => 1.0,
+        };
+
+        // Only render the sliced pixels
+        let mut pixels = pixel_order.pixels(self.top_left, self.bottom_right);
+        if let Some(focus) = focus {
+            focus.prioritize(&mut pixels, (self.image.width(), self.image.height()));
+        }
+        reporter.report_stage_start(RenderStage::Render);
+        let render_start = Instant::now();
+        let colors: Vec<_> = if let Some(reference) = illuminance_reference {
+            render_illuminance(&pixels, scene, &camera, samples, reference, &reporter, seed)
+        } else if adaptive_sampling && ray_length_far.is_none() {
+            render_adaptive(&pixels, scene, &camera, width, height, samples, &background,
+                report_sample, clamp, &reporter, deterministic, seed).into_iter()
+                .map(|(pixel, color)| (pixel, color * exposure))
+                .collect()
+        } else if half_res_indirect && ray_length_far.is_none() {
+            render_half_res_indirect(&pixels, scene, &camera, width, height, samples, &background,
+                report_sample, clamp, &reporter, deterministic, seed).into_iter()
+                .map(|(pixel, color)| (pixel, color * exposure))
+                .collect()
+        } else {
+            // Bridging from a sequential iterator hands out pixels to each thread in order, so
+            // pixels are started in (roughly) the order they are provided
+            pixels.into_iter()
+                .par_bridge()
+                .panic_fuse()
+                .map(|(x, y)| {
+                    let color = match ray_length_far {
+                        Some(far) => render_ray_length_pixel((x, y), scene, &camera, samples, far,
+                            deterministic, seed),
+                        None => render_single_pixel((x, y), scene, &camera, width, height, samples,
+                            &background, report_sample, clamp, deterministic, seed),
+                    };
 
-                // Convert into the type supported by the image library and write the pixel
-                *pixel = image::Rgb([
-                    (color.r * 255.0) as u8,
-                    (color.g * 255.0) as u8,
-                    (color.b * 255.0) as u8,
-                ]);
+                    reporter.report_finished_pixels(1);
 
-                reporter.report_finished_pixels(1);
-            });
+                    ((x, y), color * exposure)
+                })
+                .collect()
+        };
+        reporter.report_stage_end(RenderStage::Render, render_start.elapsed());
+
+        run_stage(&reporter, RenderStage::PostProcess, || {
+            for ((x, y), color) in colors {
+                self.image.hdr_buffer[y * width as usize + x] = color;
+                let pixel = if show_false_color {
+                    to_pixel(false_color(color))
+                } else {
+                    to_pixel(color)
+                };
+                self.image.buffer.put_pixel(x as u32, y as u32, pixel);
+            }
+        });
+
+        // Print the exposure of the image if requested to help with choosing light intensities
+        if env::var("EXPOSURE_STATS").is_ok() {
+            if auto_exposure.is_some() {
+                println!("Auto exposure: {:.4}", exposure);
+            }
+            println!("{}", self.image.exposure_stats());
+        }
     }
 }
 
+/// What `Image::with_mode` does with an existing file at the path of the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageMode {
+    /// Always start with a blank image, replacing any existing file when the image is saved
+    Overwrite,
+    /// Start with the contents of the existing file (if it has the same dimensions) so that
+    /// rendering a slice of the image preserves the rest of it
+    Resume,
+    /// Never replace an existing file. The image is saved with the first sequence number that is
+    /// not taken, e.g. `render.png` is saved as `render_0001.png`, then `render_0002.png`, etc.
+    Numbered,
+}
+
+/// The number of digits in the sequence numbers added by `ImageMode::Numbered`
+const SEQUENCE_DIGITS: usize = 4;
+
+/// Returns the first path that does not exist yet of the form `{stem}_{number}.{extension}`,
+/// starting at 1
+fn next_numbered_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|ext| ext.to_string_lossy().into_owned());
+
+    (1..).map(|number| {
+        let name = format!("{}_{:0digits$}", stem, number, digits=SEQUENCE_DIGITS);
+        match &extension {
+            Some(extension) => path.with_file_name(format!("{}.{}", name, extension)),
+            None => path.with_file_name(name),
+        }
+    }).find(|path| !path.exists()).expect("bug: ran out of sequence numbers")
+}
+
 pub struct Image {
     path: PathBuf,
     buffer: image::RgbImage,
+    /// The rendered colors of each pixel in linear space, before they are gamma corrected and
+    /// clamped to fit in the output image
+    hdr_buffer: Vec<Rgb>,
 }
 
 impl Image {
@@ -161,10 +1362,31 @@ impl Image {
     ///
     /// If the file does not exist or if the dimensions are different, a new file will be created
     /// with the given path and dimensions. This allows you to preserve the image if only drawing
-    /// on a limited slice of it.
+    /// on a limited slice of it. This is the same as `ImageMode::Resume` (see `Image::with_mode`).
     pub fn new<P: AsRef<Path>>(path: P, width: usize, height: usize) -> image::ImageResult<Self> {
+        Self::with_mode(path, width, height, ImageMode::Resume)
+    }
+
+    /// Creates an image that will be saved at the given path (or a numbered version of it),
+    /// handling any existing file at that path as specified by the given mode
+    pub fn with_mode<P: AsRef<Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+        mode: ImageMode,
+    ) -> image::ImageResult<Self> {
         let path = path.as_ref();
-        let buffer = match image::open(path) {
+        let path = match mode {
+            ImageMode::Overwrite => {
+                let buffer = image::RgbImage::new(width as u32, height as u32);
+                return Ok(Self::from_buffer(path.to_path_buf(), buffer));
+            },
+            ImageMode::Resume => path.to_path_buf(),
+            // A path that does not exist yet always gets a blank image
+            ImageMode::Numbered => next_numbered_path(path),
+        };
+
+        let buffer = match image::open(&path) {
             Ok(image) => {
                 let buffer = image.to_rgb();
                 if buffer.width() == width as u32 && buffer.height() == height as u32 {
@@ -181,10 +1403,18 @@ impl Image {
             Err(err) => return Err(err),
         };
 
-        Ok(Self {
-            path: path.to_path_buf(),
-            buffer,
-        })
+        Ok(Self::from_buffer(path, buffer))
+    }
+
+    fn from_buffer(path: PathBuf, buffer: image::RgbImage) -> Self {
+        let hdr_buffer = buffer.pixels().map(from_pixel).collect();
+
+        Self {path, buffer, hdr_buffer}
+    }
+
+    /// Returns the path that this image is saved to by `Image::save`
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     /// Returns the width of this image
@@ -197,6 +1427,35 @@ impl Image {
         self.buffer.height() as usize
     }
 
+    /// Returns the HDR colors (in linear space) of each pixel of this image, row by row
+    ///
+    /// Pixels that have not been rendered yet contain the colors loaded from the existing image
+    /// (if any), so they will never be brighter than 1.0.
+    pub fn hdr_pixels(&self) -> &[Rgb] {
+        &self.hdr_buffer
+    }
+
+    /// Replaces every pixel of this image with the given HDR colors (in linear space), row by row
+    ///
+    /// Useful for saving colors computed outside of the renderer (e.g. by a `ShadeCache`). Panics
+    /// if the number of colors does not match the number of pixels.
+    pub fn set_hdr_pixels(&mut self, colors: &[Rgb]) {
+        assert_eq!(colors.len(), self.hdr_buffer.len(),
+            "The number of colors must match the number of pixels in the image");
+
+        self.hdr_buffer.copy_from_slice(colors);
+        for (pixel, &color) in self.buffer.pixels_mut().zip(colors) {
+            *pixel = to_pixel(color);
+        }
+    }
+
+    /// Computes exposure statistics of the rendered HDR colors
+    ///
+    /// Useful for choosing the brightness of lights or tone mapping settings.
+    pub fn exposure_stats(&self) -> ExposureStats {
+        ExposureStats::from_pixels(&self.hdr_buffer)
+    }
+
     /// Attempts to save/update the image
     pub fn save(&self) -> io::Result<()> {
         self.save_as(&self.path)
@@ -207,8 +1466,60 @@ impl Image {
         self.buffer.save(path)
     }
 
+    /// Draws the markers of the given overlay on top of this image
+    ///
+    /// Only the saved image is changed. The HDR colors of each pixel are left as they were
+    /// rendered.
+    pub fn draw_overlay(&mut self, overlay: &Overlay) {
+        overlay.draw(&mut self.buffer);
+    }
+
+    /// Returns a new image containing a copy of the area of this image between the given (x, y)
+    /// pairs (inclusive), which will be saved at the given path
+    ///
+    /// Panics if either position is outside of this image or if bottom_right is above or to the
+    /// left of top_left.
+    pub fn crop<P: AsRef<Path>>(
+        &self,
+        path: P,
+        (x1, y1): (usize, usize),
+        (x2, y2): (usize, usize),
+    ) -> Self {
+        assert!(x1 <= x2 && y1 <= y2 && x2 < self.width() && y2 < self.height(),
+            "The positions {{x: {}, y: {}}} and {{x: {}, y: {}}} do not form an area within an image with width = {} and height = {}",
+            x1, y1, x2, y2, self.width(), self.height());
+
+        let (width, height) = (x2 - x1 + 1, y2 - y1 + 1);
+        let buffer = image::RgbImage::from_fn(width as u32, height as u32, |x, y| {
+            *self.buffer.get_pixel(x1 as u32 + x, y1 as u32 + y)
+        });
+        let hdr_buffer = (y1..=y2)
+            .flat_map(|y| self.hdr_buffer[y * self.width() + x1..=y * self.width() + x2].iter().copied())
+            .collect();
+
+        Self {path: path.as_ref().to_path_buf(), buffer, hdr_buffer}
+    }
+
+    /// Copies every pixel of the given image into this image with the top left of the given image
+    /// at the given (x, y) position
+    ///
+    /// Useful for combining slices rendered separately (e.g. by different machines) into a single
+    /// image. Panics if the given image does not fit within this image at that position.
+    pub fn paste(&mut self, other: &Image, (x, y): (usize, usize)) {
+        assert!(x + other.width() <= self.width() && y + other.height() <= self.height(),
+            "An image with width = {} and height = {} does not fit at {{x: {}, y: {}}} in an image with width = {} and height = {}",
+            other.width(), other.height(), x, y, self.width(), self.height());
+
+        let width = self.width();
+        for (row, colors) in other.hdr_buffer.chunks(other.width()).enumerate() {
+            let start = (y + row) * width + x;
+            self.hdr_buffer[start..start + colors.len()].copy_from_slice(colors);
+        }
+        image::imageops::replace(&mut self.buffer, &other.buffer, x as u32, y as u32);
+    }
+
     /// Returns a mutable slice to the area of the image between the given (x, y) pairs
-    pub fn slice_mut(&mut self, top_left: (usize, usize), bottom_right: (usize, usize)) -> ImageSliceMut {
+    pub fn slice_mut(&mut self, top_left: (usize, usize), bottom_right: (usize, usize)) -> ImageSliceMut<'_> {
         ImageSliceMut::new(self, top_left, bottom_right)
     }
 
@@ -222,3 +1533,345 @@ impl Image {
         ImageSliceMut::from(self).render::<R, _>(scene, camera, background)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::math::Radians;
+    use crate::light::Falloff;
+    use crate::scene::{SceneNode, Geometry};
+    use crate::primitive::Sphere;
+    use crate::texture::Texture;
+    use crate::reporter::NullProgress;
+
+    #[test]
+    fn every_sample_is_reported() {
+        let mat = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, mat))),
+            lights: Vec::new(),
+            ambient: Rgb::white(),
+        };
+        let camera = Camera::new(CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 4.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        }, (8.0, 8.0));
+        let background = Texture::from(|_| Rgb::black());
+
+        let records = Mutex::new(Vec::new());
+        let report_sample = |record| records.lock().unwrap().push(record);
+        let color = render_single_pixel((3, 5), &scene, &camera, 8.0, 8.0, 16, &background,
+            Some(&report_sample), SampleClamp::default(), false, None);
+
+        let mut records = records.into_inner().unwrap();
+        records.sort_by_key(|record| record.sample);
+        assert_eq!(records.iter().map(|record| record.sample).collect::<Vec<_>>(), (0..16).collect::<Vec<_>>());
+        for record in &records {
+            assert_eq!(record.pixel, (3, 5));
+            assert!((0.0..1.0).contains(&record.position.0) && (0.0..1.0).contains(&record.position.1));
+        }
+
+        // The color of the pixel is the average of the reported samples
+        let average = records.iter().fold(Rgb::black(), |total, record| total + record.radiance) / 16.0;
+        assert!((average - color).map(f64::abs).reduce_partial_max() < 1e-12);
+    }
+
+    #[test]
+    fn half_res_indirect_samples_are_reported() {
+        let mat = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, mat))),
+            lights: Vec::new(),
+            ambient: Rgb::white(),
+        };
+        let camera = Camera::new(CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 4.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        }, (8.0, 8.0));
+        let background = Texture::from(|_| Rgb::black());
+
+        let records = Mutex::new(Vec::new());
+        let report_sample = |record| records.lock().unwrap().push(record);
+        render_half_res_indirect(&[(3, 5)], &scene, &camera, 8.0, 8.0, 4, &background,
+            Some(&report_sample), SampleClamp::default(), &NullProgress, false, None);
+
+        // The direct light of the pixel and the indirect light of the 3x3 half resolution pixels
+        // around it
+        let records = records.into_inner().unwrap();
+        let (direct, indirect): (Vec<&SampleRecord>, Vec<_>) = records.iter().partition(|record| record.sample < 4);
+        assert_eq!(direct.len(), 4);
+        assert!(direct.iter().all(|record| record.pixel == (3, 5)));
+        assert_eq!(indirect.len(), 9 * 4);
+        for record in &records {
+            assert!(record.pixel.0 < 8 && record.pixel.1 < 8);
+            assert!((0.0..=1.0).contains(&record.position.0) && (0.0..=1.0).contains(&record.position.1));
+        }
+    }
+
+    #[test]
+    fn direct_and_indirect_light_are_clamped_separately() {
+        let clamp = SampleClamp {direct: Some(2.0), indirect: Some(0.5)};
+        let color = SplitColor {direct: Rgb::new(4.0, 1.0, 0.0), indirect: Rgb::new(0.2, 0.4, 0.1)};
+        let clamped = clamp.apply(color);
+        // Colors keep their hue when they are scaled down
+        assert_eq!(clamped.direct, Rgb::new(2.0, 0.5, 0.0));
+        assert_eq!(clamped.indirect, color.indirect);
+        assert_eq!(SampleClamp::default().apply(color), color);
+
+        // A half mirror that reflects a very bright background
+        let mat = Arc::new(Material {diffuse: Rgb::from(0.5), reflectivity: 0.5, ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, mat))),
+            lights: Vec::new(),
+            ambient: Rgb::white(),
+        };
+        let camera = Camera::new(CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 4.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        }, (8.0, 8.0));
+        let background = Texture::from(|_| Rgb::from(100.0));
+
+        let render = |clamp| render_single_pixel((4, 4), &scene, &camera, 8.0, 8.0, 4, &background,
+            None, clamp, false, None);
+        let unclamped = render(SampleClamp::default());
+        let clamped = render(SampleClamp {direct: None, indirect: Some(1.0)});
+        assert!(unclamped.r > 10.0, "{:?}", unclamped);
+        // Only the reflection is dimmed
+        assert!((clamped - Rgb::from(0.5 + 1.0)).map(f64::abs).reduce_partial_max() < 1e-9, "{:?}", clamped);
+    }
+
+    #[test]
+    fn seeded_samples_are_reproducible() {
+        let mat = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, mat))),
+            lights: Vec::new(),
+            ambient: Rgb::white(),
+        };
+        let camera = Camera::new(CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 4.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        }, (8.0, 8.0));
+        let background = Texture::from(|_| Rgb::black());
+
+        let sample_positions = |seed| {
+            let records = Mutex::new(Vec::new());
+            let report_sample = |record: SampleRecord| records.lock().unwrap().push((record.sample, record.position));
+            render_single_pixel((3, 5), &scene, &camera, 8.0, 8.0, 16, &background,
+                Some(&report_sample), SampleClamp::default(), true, seed);
+            let mut records = records.into_inner().unwrap();
+            records.sort_by_key(|&(sample, _)| sample);
+            records
+        };
+
+        // The same seed always gives the same samples, no matter which threads take them
+        assert_eq!(sample_positions(Some(7)), sample_positions(Some(7)));
+        assert_ne!(sample_positions(Some(7)), sample_positions(Some(8)));
+        assert_ne!(sample_positions(None), sample_positions(None));
+    }
+
+    #[test]
+    fn noisy_tiles_get_more_samples() {
+        // Samples with no variance do not need any more samples
+        let constant = (0..4).map(|_| PixelEstimate::from_sample(Rgb::white()))
+            .fold(PixelEstimate::default(), PixelEstimate::merged);
+        assert_eq!(constant.variance(), 0.0);
+        assert_eq!(constant.color(), Rgb::white());
+
+        let noisy = [Rgb::black(), Rgb::white(), Rgb::black(), Rgb::white()].iter()
+            .map(|&color| PixelEstimate::from_sample(color))
+            .fold(PixelEstimate::default(), PixelEstimate::merged);
+        assert!(noisy.variance() > 0.0);
+
+        let tiles = [(0.0, 256), (noisy.variance().sqrt(), 256), (noisy.variance().sqrt() * 3.0, 64)];
+        let allocations = allocate_samples(&tiles, 1000);
+        assert_eq!(allocations[0], 0);
+        assert!(allocations[1] > 0 && allocations[2] > allocations[1]);
+        let total: usize = allocations.iter().zip(&tiles).map(|(samples, &(_, pixels))| samples * pixels).sum();
+        assert!((total as f64 - 1000.0).abs() <= 512.0);
+
+        assert_eq!(allocate_samples(&[(0.0, 16)], 1000), vec![0]);
+    }
+
+    #[test]
+    fn thumbnail_renders_in_memory() {
+        let mat = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, mat))),
+            lights: Vec::new(),
+            ambient: Rgb::white(),
+        };
+        let camera = CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 4.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        };
+
+        let thumbnail = scene.thumbnail(camera, 16, 12);
+        assert_eq!(thumbnail.dimensions(), (16, 12));
+        // The sphere is in the middle of the image and the background is black
+        assert_eq!(thumbnail.get_pixel(8, 6).data, [255, 255, 255]);
+        assert_eq!(thumbnail.get_pixel(0, 0).data, [0, 0, 0]);
+
+        let average = thumbnail.pixels().map(|pixel| pixel.data[0] as f64).sum::<f64>() / (16.0 * 12.0);
+        assert!(average > 0.0 && average < 255.0);
+    }
+
+    #[test]
+    fn illuminance_falls_off_and_is_shadowed() {
+        let mat = Arc::new(Material::default());
+        let scene = HierScene {
+            // A small sphere blocks the light from reaching the point below it
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, mat)).scaled(0.5).translated((0.0, 2.0, 0.0))),
+            lights: vec![Light {
+                position: Vec3::new(0.0, 4.0, 0.0),
+                color: Rgb::white(),
+                falloff: Falloff {c0: 0.0, c1: 0.0, c2: 1.0},
+                ..Light::default()
+            }],
+            ambient: Rgb::black(),
+        };
+
+        assert_eq!(direct_illuminance(&scene, Vec3::zero(), Vec3::up()), 0.0);
+        // Facing away from the light
+        assert_eq!(direct_illuminance(&scene, Vec3::new(3.0, 0.0, 0.0), Vec3::down()), 0.0);
+        // Inverse square falloff and Lambert's cosine law
+        let illuminance = direct_illuminance(&scene, Vec3::new(4.0, 0.0, 0.0), Vec3::up());
+        let dist = 32.0f64.sqrt();
+        assert!((illuminance - (4.0 / dist) / (dist * dist)).abs() < 1e-12);
+
+        // Geometry beyond the light (e.g. a ceiling above it) does not cast a shadow
+        let ceiling = SceneNode::from(Geometry::new(Sphere, Arc::new(Material::default()))).translated((0.0, 6.0, 0.0));
+        let scene = HierScene {root: Arc::new(SceneNode::from(vec![Arc::new(ceiling)])), ..scene};
+        assert!((direct_illuminance(&scene, Vec3::zero(), Vec3::up()) - 1.0 / 16.0).abs() < 1e-12);
+
+        assert_eq!(illuminance_heatmap(0.0), Rgb::green());
+        assert_eq!(illuminance_heatmap(-ILLUMINANCE_STOPS), Rgb::blue());
+        assert_eq!(illuminance_heatmap(100.0), Rgb::red());
+    }
+
+    #[test]
+    fn deterministic_reduction_is_bit_identical() {
+        // Values with very different magnitudes so that the order they are added in matters
+        let value = |i: usize| 1.0 / (i as f64 + 1.0) + if i % 7 == 0 { 1e8 } else { 0.0 };
+        let expected = (0..10_000).map(value).fold(0.0, |a, b| a + b);
+        let total = || reduce_items((0..10_000usize).into_par_iter().map(value), true, || 0.0, |a, b| a + b);
+
+        // The global pool and a pool with a different number of threads split up the work
+        // differently, but add it up in the same order
+        let thread_pool = ThreadPoolSettings {threads: Some(3), ..ThreadPoolSettings::default()}
+            .build().unwrap();
+        assert_eq!(thread_pool.install(total).to_bits(), expected.to_bits());
+        assert_eq!(total().to_bits(), expected.to_bits());
+    }
+
+    #[test]
+    fn stages_are_reported_in_order() {
+        #[derive(Default)]
+        struct StageRecorder(Mutex<Vec<(RenderStage, Option<Duration>)>>);
+        impl Reporter for StageRecorder {
+            fn new(_pixels: u64) -> Self {
+                Self::default()
+            }
+
+            fn report_finished_pixels(&self, _finished: u64) {}
+
+            fn report_stage_start(&self, stage: RenderStage) {
+                self.0.lock().unwrap().push((stage, None));
+            }
+
+            fn report_stage_end(&self, stage: RenderStage, elapsed: Duration) {
+                self.0.lock().unwrap().push((stage, Some(elapsed)));
+            }
+        }
+
+        let reporter = StageRecorder::new(0);
+        let value = run_stage(&reporter, RenderStage::Flatten, || {
+            std::thread::sleep(Duration::from_millis(5));
+            3
+        });
+        assert_eq!(value, 3);
+        run_stage(&reporter, RenderStage::PostProcess, || {});
+
+        let stages = reporter.0.into_inner().unwrap();
+        let order: Vec<_> = stages.iter().map(|&(stage, elapsed)| (stage, elapsed.is_some())).collect();
+        assert_eq!(order, vec![
+            (RenderStage::Flatten, false),
+            (RenderStage::Flatten, true),
+            (RenderStage::PostProcess, false),
+            (RenderStage::PostProcess, true),
+        ]);
+        assert!(stages[1].1.unwrap() >= Duration::from_millis(5));
+        assert_eq!(RenderStage::BuildKDTree.to_string(), "Build k-d tree");
+    }
+
+    #[test]
+    fn image_modes_handle_existing_files() {
+        let dir = env::temp_dir().join(format!("portrayer-image-modes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("render.png");
+        image::RgbImage::from_pixel(4, 2, image::Rgb([255, 255, 255])).save(&path).unwrap();
+
+        let resumed = Image::with_mode(&path, 4, 2, ImageMode::Resume).unwrap();
+        assert_eq!(resumed.hdr_pixels()[0], Rgb::white());
+        let overwritten = Image::with_mode(&path, 4, 2, ImageMode::Overwrite).unwrap();
+        assert_eq!(overwritten.hdr_pixels()[0], Rgb::black());
+        assert_eq!(overwritten.path(), path);
+
+        let first = Image::with_mode(&path, 4, 2, ImageMode::Numbered).unwrap();
+        assert_eq!(first.path(), dir.join("render_0001.png"));
+        assert_eq!(first.hdr_pixels()[0], Rgb::black());
+        first.save().unwrap();
+        let second = Image::with_mode(&path, 4, 2, ImageMode::Numbered).unwrap();
+        assert_eq!(second.path(), dir.join("render_0002.png"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cropped_slices_paste_back_in_place() {
+        let mut image = Image::with_mode("does-not-exist/crop.png", 5, 4, ImageMode::Overwrite).unwrap();
+        let colors: Vec<_> = (0..20).map(|i| Rgb::from(i as f64 / 20.0)).collect();
+        image.set_hdr_pixels(&colors);
+
+        let slice = image.crop("does-not-exist/slice.png", (1, 2), (3, 3));
+        assert_eq!((slice.width(), slice.height()), (3, 2));
+        assert_eq!(slice.hdr_pixels()[0], colors[11]);
+        assert_eq!(slice.hdr_pixels()[5], colors[18]);
+        assert_eq!(slice.path(), Path::new("does-not-exist/slice.png"));
+
+        let mut combined = Image::with_mode("does-not-exist/combined.png", 5, 4, ImageMode::Overwrite).unwrap();
+        combined.paste(&slice, (1, 2));
+        for (i, &color) in combined.hdr_pixels().iter().enumerate() {
+            let (x, y) = (i % 5, i / 5);
+            let expected = if (1..=3).contains(&x) && y >= 2 { colors[i] } else { Rgb::black() };
+            assert_eq!(color, expected, "pixel at (x={}, y={})", x, y);
+        }
+        assert_eq!(combined.buffer.get_pixel(3, 3), image.buffer.get_pixel(3, 3));
+    }
+
+    #[test]
+    fn dedicated_thread_pool_limits_threads() {
+        let settings = ThreadPoolSettings {threads: Some(2), low_priority: true};
+        let thread_pool = settings.build().unwrap();
+        assert_eq!(thread_pool.current_num_threads(), 2);
+
+        // Parallel work run by the pool stays on its threads
+        let names: Vec<_> = thread_pool.install(|| (0..16).into_par_iter().map(|_| {
+            std::thread::current().name().map(String::from)
+        }).collect());
+        assert!(names.iter().all(|name| name.as_ref().is_some_and(|name| name.starts_with("portrayer-render-"))));
+    }
+}
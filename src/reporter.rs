@@ -1,22 +1,98 @@
+use std::fmt;
 use std::env;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 use std::thread::{self, JoinHandle};
 use std::time::{Instant, Duration};
 
 use indicatif::{ProgressBar, ProgressStyle};
 
+use crate::math::Rgb;
+use crate::error::ascii_output;
+
+/// A record of a single sample (ray) taken while rendering a pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleRecord {
+    /// The (x, y) pixel that the sample belongs to
+    pub pixel: (usize, usize),
+    /// The index of the sample within the pixel (from 0 to the number of samples - 1)
+    pub sample: usize,
+    /// The position of the sample within the pixel, from (0.0, 0.0) at the top left of the pixel
+    /// to (1.0, 1.0) at the bottom right
+    ///
+    /// Every sample is currently weighted equally (a box filter), so all of the samples in the
+    /// pixel contribute equally to its final color regardless of their position.
+    pub position: (f64, f64),
+    /// The HDR color (in linear space) traced by the sample, before any exposure is applied
+    pub radiance: Rgb,
+}
+
+/// One of the stages of rendering an image, in the order they happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RenderStage {
+    /// Applying the changes to the scene requested by environment variables (e.g. `CLAY`),
+    /// including building shadow maps
+    Prepare,
+    /// Flattening the scene hierarchy (only with the `flat_scene` or `kdtree` features)
+    Flatten,
+    /// Building (or refitting, see `KDTreeCache`) the k-d tree of the scene (only with the `kdtree`
+    /// feature)
+    BuildKDTree,
+    /// Rendering the quick preview used to choose the exposure (only with `AUTO_EXPOSURE`)
+    AutoExposure,
+    /// Tracing the rays of every pixel
+    Render,
+    /// Writing the rendered colors into the image (including any false colors)
+    PostProcess,
+}
+
+impl fmt::Display for RenderStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use RenderStage::*;
+        match self {
+            Prepare => write!(f, "Prepare"),
+            Flatten => write!(f, "Flatten"),
+            BuildKDTree => write!(f, "Build k-d tree"),
+            AutoExposure => write!(f, "Auto exposure"),
+            Render => write!(f, "Render"),
+            PostProcess => write!(f, "Post-process"),
+        }
+    }
+}
+
 /// Used to report progress about rendering
 pub trait Reporter {
     fn new(pixels: u64) -> Self;
     fn report_finished_pixels(&self, finished: u64);
+
+    /// Called when the given stage of rendering starts
+    ///
+    /// Stages that are not needed for a particular render (e.g. `RenderStage::BuildKDTree` without
+    /// the `kdtree` feature) are skipped entirely.
+    fn report_stage_start(&self, _stage: RenderStage) {}
+
+    /// Called when the given stage of rendering ends, along with how long it took
+    fn report_stage_end(&self, _stage: RenderStage, _elapsed: Duration) {}
+
+    /// Receives a record of every sample taken while rendering
+    ///
+    /// Only called if the `SAMPLE_RECORDS` environment variable is set since reporting every
+    /// sample is expensive. Samples are reported from many threads at once and in no particular
+    /// order. Useful for analyzing convergence and noise outside of the renderer.
+    fn report_sample(&self, _record: SampleRecord) {}
 }
 
 /// A low-overhead progress reporter with rich progress bar output
+///
+/// If the `STAGE_TIMES` environment variable is set, the time taken by each stage of rendering is
+/// printed once rendering is done. If the `ASCII_OUTPUT` environment variable is set, the progress
+/// bar is drawn with plain ASCII characters and no colors.
 pub struct RenderProgress {
     thread_handle: Option<JoinHandle<()>>,
     stop: Arc<AtomicBool>,
     pixels_completed: Arc<AtomicU64>,
+    /// The time taken by each finished stage, in the order the stages finished
+    stage_times: Mutex<Vec<(RenderStage, Duration)>>,
 }
 
 impl Reporter for RenderProgress {
@@ -49,8 +125,14 @@ impl Reporter for RenderProgress {
                 },
                 _ => {
                     let progress = ProgressBar::new(pixels);
-                    progress.set_style(ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] {wide_bar:.cyan/blue} {percent}% (eta: {eta})"));
+                    if ascii_output() {
+                        progress.set_style(ProgressStyle::default_bar()
+                        .template("[{elapsed_precise}] [{wide_bar}] {percent}% (eta: {eta})")
+                        .progress_chars("=> "));
+                    } else {
+                        progress.set_style(ProgressStyle::default_bar()
+                        .template("[{elapsed_precise}] {wide_bar:.cyan/blue} {percent}% (eta: {eta})"));
+                    }
 
                     while !stop_t.load(Ordering::SeqCst) {
                         progress.set_position(pixels_completed_t.load(Ordering::SeqCst));
@@ -67,6 +149,7 @@ impl Reporter for RenderProgress {
             thread_handle: Some(thread_handle),
             stop,
             pixels_completed,
+            stage_times: Mutex::default(),
         }
     }
 
@@ -74,12 +157,26 @@ impl Reporter for RenderProgress {
         // Trying to keep this as cheap as possible to not affect performance
         self.pixels_completed.fetch_add(finished, Ordering::SeqCst);
     }
+
+    fn report_stage_end(&self, stage: RenderStage, elapsed: Duration) {
+        self.stage_times.lock().expect("bug: stage times lock was poisoned").push((stage, elapsed));
+    }
 }
 
 impl Drop for RenderProgress {
     fn drop(&mut self) {
         self.stop.store(true, Ordering::Relaxed);
         self.thread_handle.take().unwrap().join().unwrap();
+
+        // Printed after the progress bar is cleared so the two do not overlap
+        if env::var("STAGE_TIMES").is_ok() {
+            let stage_times = self.stage_times.get_mut().expect("bug: stage times lock was poisoned");
+            let total: Duration = stage_times.iter().map(|&(_, elapsed)| elapsed).sum();
+            for &(stage, elapsed) in stage_times.iter() {
+                println!("{:<15} {:>10.3}s", format!("{}:", stage), elapsed.as_secs_f64());
+            }
+            println!("{:<15} {:>10.3}s", "Total:", total.as_secs_f64());
+        }
     }
 }
 
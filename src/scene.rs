@@ -1,11 +1,19 @@
+use std::fmt;
+use std::ptr;
 use std::sync::Arc;
+use std::collections::HashMap;
 use std::ops::Range;
 
-use crate::math::{Mat4, Vec3, Vec3Ext, Rgb, Radians};
+use rand::Rng;
+
+use crate::math::{Mat4, Vec3, Vec3Ext, Rgb, Radians, UvTransform, tolerance, sample_rng};
 use crate::ray::{RayCast, Ray, RayIntersection, RayHit};
-use crate::primitive::Primitive;
-use crate::material::Material;
+use crate::primitive::{Primitive, Plane};
+use crate::material::{Material, MaterialOverrides};
 use crate::light::Light;
+use crate::texture::{Texture, TextureSource};
+use crate::bounding_box::{BoundingBox, Bounds};
+use crate::bake::RadianceProbes;
 
 /// A hierarchical scene
 pub type HierScene = Scene<Arc<SceneNode>>;
@@ -17,10 +25,155 @@ pub struct Scene<R> {
     pub ambient: Rgb,
 }
 
+/// A procedural perturbation of the normals of a geometry
+///
+/// A lighter-weight alternative to normal maps for bumpy surfaces that are easy to describe
+/// mathematically (e.g. ripples on water or the "orange peel" texture of paint). The function is
+/// given the hit point and the normal (which may not be normalized) in the model space of the
+/// geometry and returns the perturbed normal, also in model space. Since it runs whenever a ray
+/// hits the geometry, it should be cheap.
+pub struct NormalShader(Box<dyn Fn(Vec3, Vec3) -> Vec3 + Send + Sync>);
+
+impl fmt::Debug for NormalShader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NormalShader")
+    }
+}
+
+// Functions cannot be compared, so shaders are only equal if they are the same shader
+impl PartialEq for NormalShader {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl<F> From<F> for NormalShader where F: Fn(Vec3, Vec3) -> Vec3 + Send + Sync + 'static {
+    fn from(f: F) -> Self {
+        NormalShader(Box::new(f))
+    }
+}
+
+impl NormalShader {
+    /// Circular ripples spreading out from the origin across the XZ plane, like a drop falling
+    /// into water
+    ///
+    /// The surface is bumped as if its height was `amplitude * sin(2π * r / wavelength)` where r
+    /// is the distance from the Y axis.
+    pub fn ripples(amplitude: f64, wavelength: f64) -> Self {
+        let k = 2.0 * std::f64::consts::PI / wavelength;
+        Self::from(move |point: Vec3, normal: Vec3| {
+            let r = (point.x*point.x + point.z*point.z).sqrt();
+            if r == 0.0 {
+                return normal;
+            }
+
+            // The gradient of the height of the ripples
+            let slope = amplitude * k * (k * r).cos();
+            let gradient = Vec3 {x: slope * point.x / r, y: 0.0, z: slope * point.z / r};
+
+            // Tilt the normal away from the gradient, only using the part of the gradient that is
+            // along the surface
+            let normal = normal.normalized();
+            normal - (gradient - normal * gradient.dot(normal))
+        })
+    }
+
+    /// Returns the perturbed normal at the given hit point (both in model space)
+    pub fn perturb(&self, point: Vec3, normal: Vec3) -> Vec3 {
+        (self.0)(point, normal)
+    }
+}
+
+/// The shape and material of a scene node
+///
+/// Geometry is cloned for every instance of a node when a scene is flattened, so anything large
+/// stored here (directly or in a primitive) must be stored in an Arc to keep cloning cheap.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Geometry {
     pub primitive: Primitive,
     pub material: Arc<Material>,
+    /// Optionally perturbs the normals of the primitive (see `NormalShader`)
+    pub normal_shader: Option<Arc<NormalShader>>,
+    /// Optionally alternates between `material` and another material in a checker pattern
+    pub checker: Option<Checker>,
+    /// Optionally cuts holes in the primitive (see `Cutout`)
+    pub cutout: Option<Cutout>,
+}
+
+/// A checker pattern of two materials, chosen per hit using the texture coordinates of the
+/// primitive (before any UV transform on the scene node is applied)
+///
+/// Unlike a checker texture, each tile can have completely different material properties (e.g.
+/// alternating polished marble and matte stone) and no texture lookup is needed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checker {
+    /// The material used for every other tile, starting with the tile next to the tile that
+    /// contains (0, 0)
+    pub material: Arc<Material>,
+    /// The number of tiles along each texture coordinate from 0.0 to 1.0
+    pub tiles: f64,
+}
+
+/// How the mask of a `Cutout` decides which hits are cut out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CutoutMode {
+    /// Hits where the mask is below the given value are cut out, giving hard edges
+    Threshold(f64),
+    /// The mask is the probability that each hit is kept
+    ///
+    /// Each ray decides randomly, so averaging many samples gives partially transparent (soft)
+    /// edges. This is much cheaper than blending the colors of every layer behind the surface,
+    /// which matters in dense foliage where rays pass through many overlapping leaf cards.
+    Stochastic,
+}
+
+/// Cuts holes in a geometry using a mask texture (e.g. the outline of a leaf on a flat card)
+///
+/// The red channel of the mask is the coverage at each point, from 0.0 (a hole) to 1.0 (solid).
+/// Like `Checker`, the mask is sampled with the texture coordinates of the primitive, before any
+/// UV transform of the scene node or the material is applied. Hits without texture coordinates
+/// are never cut out. Shadow rays see the same holes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cutout {
+    pub mask: Arc<Texture>,
+    pub mode: CutoutMode,
+}
+
+impl Cutout {
+    /// Returns true if the given hit should be ignored
+    fn is_cut(&self, hit: &RayIntersection) -> bool {
+        let coverage = match hit.tex_coord {
+            Some(uv) => self.mask.at(uv).r,
+            None => return false,
+        };
+
+        match self.mode {
+            CutoutMode::Threshold(threshold) => coverage < threshold,
+            CutoutMode::Stochastic => sample_rng().gen::<f64>() >= coverage,
+        }
+    }
+}
+
+impl RayHit for Geometry {
+    fn ray_hit(&self, ray: &Ray, t_range: &Range<f64>) -> Option<RayIntersection> {
+        let mut t_range = t_range.clone();
+        let mut hit = loop {
+            let hit = self.primitive.ray_hit(ray, &t_range)?;
+            match &self.cutout {
+                // Keep looking for the next hit on the primitive behind the hole
+                Some(cutout) if cutout.is_cut(&hit) => {
+                    t_range.start = hit.ray_parameter + tolerance::at_scale(hit.ray_parameter);
+                },
+                _ => break hit,
+            }
+        };
+
+        if let Some(shader) = &self.normal_shader {
+            hit.normal = shader.perturb(hit.hit_point, hit.normal);
+        }
+
+        Some(hit)
+    }
 }
 
 impl Geometry {
@@ -28,8 +181,65 @@ impl Geometry {
         Self {
             primitive: primitive.into(),
             material,
+            normal_shader: None,
+            checker: None,
+            cutout: None,
         }
     }
+
+    /// Creates geometry that uses the default material of the scene it is rendered in
+    ///
+    /// Useful for procedurally generated scenes where most geometry shares a single material. Use
+    /// `HierScene::with_default_material` to choose the material and
+    /// `HierScene::default_material_nodes` to find any geometry that is still missing a material.
+    pub fn without_material<P: Into<Primitive>>(primitive: P) -> Self {
+        Self::new(primitive, Material::scene_default())
+    }
+
+    /// Creates a plane that alternates between the two given materials in a checker pattern with
+    /// the given number of tiles along each side
+    pub fn checker_floor(material: Arc<Material>, other: Arc<Material>, tiles: f64) -> Self {
+        Self::new(Plane, material).with_checker(other, tiles)
+    }
+
+    /// Alternates between the material of this geometry and the given material in a checker
+    /// pattern with the given number of tiles per unit of texture coordinates
+    ///
+    /// Hits with no texture coordinates always use the material of this geometry.
+    pub fn with_checker(mut self, other: Arc<Material>, tiles: f64) -> Self {
+        self.checker = Some(Checker {material: other, tiles});
+        self
+    }
+
+    /// Returns the material that should be used for the given hit on this geometry
+    ///
+    /// The hit must be in the model space of this geometry
+    pub fn material_at(&self, hit: &RayIntersection) -> &Arc<Material> {
+        match (&self.checker, hit.tex_coord) {
+            (Some(Checker {material, tiles}), Some(uv)) => {
+                let tile = (uv.u * tiles).floor() + (uv.v * tiles).floor();
+                if tile.rem_euclid(2.0) == 1.0 {
+                    material
+                } else {
+                    &self.material
+                }
+            },
+            _ => &self.material,
+        }
+    }
+
+    /// Cuts holes in this geometry wherever the red channel of the given mask is low, as decided
+    /// by the given mode (see `Cutout`)
+    pub fn with_cutout(mut self, mask: Arc<Texture>, mode: CutoutMode) -> Self {
+        self.cutout = Some(Cutout {mask, mode});
+        self
+    }
+
+    /// Perturbs the normals of this geometry with the given shader whenever it is hit
+    pub fn with_normal_shader<S: Into<NormalShader>>(mut self, shader: S) -> Self {
+        self.normal_shader = Some(Arc::new(shader.into()));
+        self
+    }
 }
 
 #[derive(Debug, Default)]
@@ -44,12 +254,23 @@ pub struct SceneNode {
     normal_trans: Mat4,
     /// Any child nodes that are hierarchically "underneath" this node
     children: Vec<Arc<SceneNode>>,
+    /// A bounding box around the geometry of this node and all of its children in the local
+    /// coordinate system of this node (i.e. before trans is applied)
+    ///
+    /// Cached so that rays that miss this entire subtree can be rejected without testing every
+    /// child. None if this node has no geometry and no children.
+    bounds: Option<BoundingBox>,
+    /// An optional name used to find this node in the scene
+    name: Option<String>,
+    /// Applied to the texture coordinates of every hit on this node and its children
+    uv_transform: Option<UvTransform>,
 }
 
 // Create a node with the given geometry
 impl From<Geometry> for SceneNode {
     fn from(geometry: Geometry) -> Self {
         Self {
+            bounds: Some(geometry.primitive.bounds()),
             geometry: Some(geometry),
             ..Default::default()
         }
@@ -59,20 +280,14 @@ impl From<Geometry> for SceneNode {
 // Create a node from multiple children
 impl From<Vec<Arc<SceneNode>>> for SceneNode {
     fn from(children: Vec<Arc<SceneNode>>) -> Self {
-        Self {
-            children,
-            ..Default::default()
-        }
+        Self::default().with_children(children)
     }
 }
 
 // Create a node from a single child
 impl From<Arc<SceneNode>> for SceneNode {
     fn from(child: Arc<SceneNode>) -> Self {
-        Self {
-            children: vec![child],
-            ..Default::default()
-        }
+        Self::default().with_child(child)
     }
 }
 
@@ -83,6 +298,12 @@ impl RayCast for SceneNode {
         // system of the current node
         let local_ray = ray.transformed(self.inverse_trans());
 
+        // Skip this entire subtree if the ray does not hit anything within it
+        match &self.bounds {
+            Some(bounds) => bounds.test_hit(&local_ray, t_range)?,
+            None => return None,
+        };
+
         // These will be used to transform the hit point and normal back into the
         // previous coordinate system
         let trans = self.trans();
@@ -92,22 +313,29 @@ impl RayCast for SceneNode {
         let mut hit_mat = None;
 
         // Check if the ray intersects this node's geometry (if any)
-        if let Some(Geometry {primitive, material}) = self.geometry() {
-            if let Some(mut hit) = primitive.ray_hit(&local_ray, t_range) {
+        if let Some(geometry) = self.geometry() {
+            if let Some(mut hit) = geometry.ray_hit(&local_ray, t_range) {
+                let material = geometry.material_at(&hit).clone();
                 hit.hit_point = hit.hit_point.transformed_point(trans);
+                hit.shadow_origin = hit.shadow_origin.map(|p| p.transformed_point(trans));
                 hit.normal = hit.normal.transformed_direction(normal_trans);
 
+                #[cfg(feature = "nan_checks")]
+                hit.assert_finite(format_args!("{} in scene node with transform {:?}",
+                    geometry.primitive.kind_name(), trans));
+
                 // Only allow further intersections if they are closer to the ray origin
                 // than this one
                 t_range.end = hit.ray_parameter;
 
-                hit_mat = Some((hit, material.clone()));
+                hit_mat = Some((hit, material));
             }
         }
 
         // Recurse into children and attempt to find a closer match
         if let Some((mut child_hit, child_mat)) = self.children().ray_cast(&local_ray, t_range) {
             child_hit.hit_point = child_hit.hit_point.transformed_point(trans);
+            child_hit.shadow_origin = child_hit.shadow_origin.map(|p| p.transformed_point(trans));
             child_hit.normal = child_hit.normal.transformed_direction(normal_trans);
 
             // No need to set t_range.end since it is set in the recursive base case of this method
@@ -115,6 +343,10 @@ impl RayCast for SceneNode {
             hit_mat = Some((child_hit, child_mat));
         }
 
+        if let (Some(uv_transform), Some((hit, _))) = (&self.uv_transform, &mut hit_mat) {
+            apply_uv_transform(hit, uv_transform);
+        }
+
         hit_mat
     }
 }
@@ -147,18 +379,225 @@ impl SceneNode {
         &self.children
     }
 
+    /// Returns the name of this node (if any)
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Gives this node the given name and returns the updated node
+    pub fn named<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Returns the transformation applied to the texture coordinates of this node and its
+    /// children (if any)
+    pub fn uv_transform(&self) -> Option<&UvTransform> {
+        self.uv_transform.as_ref()
+    }
+
+    /// Transforms the texture coordinates of this node and all of its children with the given
+    /// transformation and returns the updated node
+    ///
+    /// Any UV transform of the children is applied first. Useful for making instances that
+    /// share a textured material look different from each other, e.g. with
+    /// `UvTransform::random`. Only the primary UV set is transformed.
+    pub fn with_uv_transform(mut self, uv_transform: UvTransform) -> Self {
+        self.uv_transform = Some(uv_transform);
+        self
+    }
+
+    /// Returns a copy of this node where every geometry uses the given material, except for
+    /// nodes with any of the given names (and everything underneath them), which keep their
+    /// original materials
+    ///
+    /// Useful for isolating the shading of a few assets, e.g. by replacing every other material
+    /// with `Material::clay()`. Instanced nodes (the same node added to the scene multiple times)
+    /// are only copied once and remain shared in the copy.
+    pub fn with_material_override(&self, material: &Arc<Material>, keep: &[&str]) -> Self {
+        self.map_geometry(keep, &mut |geometry| {
+            Geometry {material: material.clone(), checker: None, ..geometry.clone()}
+        }, &mut HashMap::new())
+    }
+
+    /// Returns a copy of this node where every material with a tag in the given table is replaced
+    /// with the material for that tag (see `MaterialOverrides`)
+    ///
+    /// Instanced nodes remain shared in the copy.
+    pub fn with_material_overrides(&self, overrides: &MaterialOverrides) -> Self {
+        self.map_materials(&mut |current| overrides.get(current).unwrap_or(current).clone())
+    }
+
+    /// Returns a copy of this node where every material is replaced with a version of it that
+    /// conserves energy (see `Material::energy_conserving`)
+    ///
+    /// Materials and instanced nodes that are shared in this node remain shared in the copy.
+    pub fn with_energy_conserving_materials(&self) -> Self {
+        let mut materials: HashMap<*const Material, Arc<Material>> = HashMap::new();
+        let mut conserving = |material: &Arc<Material>| materials.entry(Arc::as_ptr(material))
+            .or_insert_with(|| Arc::new(material.energy_conserving()))
+            .clone();
+
+        self.map_materials(&mut conserving)
+    }
+
+    /// Returns a copy of this node where every material uses the given radiance probes for the
+    /// surfaces reached after more than the given number of bounces (see
+    /// `Material::radiance_probes`)
+    ///
+    /// Materials and instanced nodes that are shared in this node remain shared in the copy.
+    pub fn with_radiance_probes(&self, probes: &Arc<RadianceProbes>, probe_depth: u32) -> Self {
+        let mut materials: HashMap<*const Material, Arc<Material>> = HashMap::new();
+        let mut with_probes = |material: &Arc<Material>| materials.entry(Arc::as_ptr(material))
+            .or_insert_with(|| Arc::new(Material {
+                radiance_probes: Some(probes.clone()),
+                probe_depth,
+                ..(**material).clone()
+            }))
+            .clone();
+
+        self.map_materials(&mut with_probes)
+    }
+
+    /// Returns a copy of this node where every geometry created without a material (see
+    /// `Geometry::without_material`) uses the given material instead
+    ///
+    /// Instanced nodes remain shared in the copy.
+    pub fn with_default_material(&self, material: &Arc<Material>) -> Self {
+        self.map_materials(&mut |current| if Material::is_scene_default(current) {
+            material.clone()
+        } else {
+            current.clone()
+        })
+    }
+
+    /// Returns a copy of this node with the given function applied to every material, including
+    /// the second material of any checker patterns
+    ///
+    /// Instanced nodes remain shared in the copy.
+    pub fn map_materials(&self, f: &mut impl FnMut(&Arc<Material>) -> Arc<Material>) -> Self {
+        self.map_geometry(&[], &mut |geometry| Geometry {
+            material: f(&geometry.material),
+            checker: geometry.checker.as_ref().map(|checker| Checker {
+                material: f(&checker.material),
+                ..checker.clone()
+            }),
+            ..geometry.clone()
+        }, &mut HashMap::new())
+    }
+
+    /// Returns a copy of this node with the given function applied to every geometry, except for
+    /// nodes with any of the given names (and everything underneath them), which are unchanged
+    ///
+    /// The copies of the child nodes that have been made so far are stored by their address so
+    /// that instanced nodes are only copied once.
+    pub(crate) fn map_geometry<F: FnMut(&Geometry) -> Geometry>(
+        &self,
+        keep: &[&str],
+        map: &mut F,
+        copies: &mut HashMap<*const SceneNode, Arc<SceneNode>>,
+    ) -> Self {
+        let is_kept = self.name().map(|name| keep.contains(&name)).unwrap_or(false);
+
+        let geometry = self.geometry.as_ref().map(|geometry| if is_kept {
+            geometry.clone()
+        } else {
+            map(geometry)
+        });
+
+        let children = if is_kept {
+            // Nothing underneath this node needs to change, so the children can be shared
+            self.children.clone()
+        } else {
+            self.children.iter().map(|child| {
+                let key = Arc::as_ptr(child);
+                if let Some(copy) = copies.get(&key) {
+                    return copy.clone();
+                }
+
+                let copy = Arc::new(child.map_geometry(keep, map, copies));
+                copies.insert(key, copy.clone());
+                copy
+            }).collect()
+        };
+
+        Self {
+            geometry,
+            trans: self.trans,
+            invtrans: self.invtrans,
+            normal_trans: self.normal_trans,
+            children,
+            bounds: self.bounds.clone(),
+            name: self.name.clone(),
+            uv_transform: self.uv_transform,
+        }
+    }
+
+    /// Returns a copy of this node with its children replaced by the given children
+    ///
+    /// The cached bounding box is recomputed since the children may have moved.
+    pub(crate) fn with_replaced_children(&self, children: Vec<Arc<SceneNode>>) -> Self {
+        let node = Self {
+            geometry: self.geometry.clone(),
+            trans: self.trans,
+            invtrans: self.invtrans,
+            normal_trans: self.normal_trans,
+            children: Vec::new(),
+            bounds: self.geometry.as_ref().map(|geometry| geometry.primitive.bounds()),
+            name: self.name.clone(),
+            uv_transform: self.uv_transform,
+        };
+        node.with_children(children)
+    }
+
+    /// Returns a copy of this node without its geometry or any of its children
+    ///
+    /// The name and transform are kept so that the node can still be found and edited.
+    pub(crate) fn emptied(&self) -> Self {
+        Self {
+            geometry: None,
+            trans: self.trans,
+            invtrans: self.invtrans,
+            normal_trans: self.normal_trans,
+            children: Vec::new(),
+            bounds: None,
+            name: self.name.clone(),
+            uv_transform: self.uv_transform,
+        }
+    }
+
+    /// Returns a bounding box around this node and all of its children in the coordinate system
+    /// of its parent (i.e. after trans is applied)
+    ///
+    /// Returns None if this node has no geometry and no children.
+    pub(crate) fn parent_bounds(&self) -> Option<BoundingBox> {
+        self.bounds.clone().map(|bounds| self.trans * bounds)
+    }
+
     /// Add the given child to this node and return the updated node
     pub fn with_child<C: Into<Arc<SceneNode>>>(mut self, child: C) -> Self {
-        self.children.push(child.into());
+        let child = child.into();
+        self.extend_bounds(child.parent_bounds());
+        self.children.push(child);
         self
     }
 
     /// Add the given children to this node and return the updated node
     pub fn with_children<I: IntoIterator<Item=Arc<SceneNode>>>(mut self, children: I) -> Self {
-        self.children.extend(children);
+        for child in children {
+            self = self.with_child(child);
+        }
         self
     }
 
+    /// Grows the cached bounding box of this node to include the given bounds (if any)
+    fn extend_bounds(&mut self, bounds: Option<BoundingBox>) {
+        self.bounds = match (self.bounds.take(), bounds) {
+            (Some(current), Some(bounds)) => Some(current.union(&bounds)),
+            (current, bounds) => current.or(bounds),
+        };
+    }
+
     /// Scale the node by the given vector and return the node
     pub fn scaled<V: Into<Vec3>>(mut self, scale: V) -> Self {
         self.set_transform(self.trans.scaled_3d(scale));
@@ -204,3 +643,337 @@ impl SceneNode {
         self.normal_trans = self.invtrans.transposed();
     }
 }
+
+/// Queries for tools that need to know what is where in a scene without rendering an image (e.g.
+/// placement helpers or light solvers)
+///
+/// These use the same intersection code (and acceleration structures) as rendering. Lights are
+/// never hit, even if they are visible.
+impl<R: RayCast> Scene<R> {
+    /// Returns the nearest intersection of the given ray with the scene and the material that was
+    /// hit, or None if nothing is hit
+    pub fn first_hit(&self, ray: &Ray) -> Option<(RayIntersection, Arc<Material>)> {
+        let mut t_range = ray.t_range();
+        self.root.ray_cast(ray, &mut t_range)
+    }
+
+    /// Returns true if nothing in the scene is in the way of the straight line between the two
+    /// given points
+    ///
+    /// Surfaces that either point is on do not block the line.
+    pub fn visible(&self, p1: Vec3, p2: Vec3) -> bool {
+        let offset = p2 - p1;
+        let dist = offset.magnitude();
+
+        let ray = Ray::new(p1, offset / dist);
+        let mut t_range = ray.t_range();
+        t_range.end = dist - tolerance::at_point(p2);
+        if t_range.is_empty() {
+            return true;
+        }
+
+        self.root.ray_cast(&ray, &mut t_range).is_none()
+    }
+}
+
+impl HierScene {
+    /// Returns a copy of this scene where every geometry created without a material (see
+    /// `Geometry::without_material`) uses the given material instead
+    pub fn with_default_material(&self, material: Arc<Material>) -> Self {
+        Self {
+            root: Arc::new(self.root.with_default_material(&material)),
+            lights: self.lights.clone(),
+            ambient: self.ambient,
+        }
+    }
+}
+
+/// Transforms the texture coordinates of the given hit and rotates its normal map transform to
+/// match
+pub(crate) fn apply_uv_transform(hit: &mut RayIntersection, uv_transform: &UvTransform) {
+    hit.tex_coord = hit.tex_coord.map(|uv| uv_transform.apply(uv));
+    hit.normal_map_transform = hit.normal_map_transform
+        .map(|norm_trans| uv_transform.apply_normal_map_transform(norm_trans));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::math::{EPSILON, INFINITY, Uv};
+    use crate::primitive::{Sphere, Cube, Cylinder, Cone};
+    use crate::camera::{Camera, CameraSettings};
+    use crate::flat_scene::FlatScene;
+
+    #[test]
+    fn visibility_queries() {
+        let mat = Arc::new(Material::default());
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, mat.clone()))),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+
+        let (hit, hit_mat) = scene.first_hit(&Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0))).unwrap();
+        assert!((hit.hit_point - Vec3::new(0.0, 0.0, 1.0)).magnitude() < EPSILON, "{:?}", hit.hit_point);
+        assert!(Arc::ptr_eq(&hit_mat, &mat));
+        assert!(scene.first_hit(&Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::up())).is_none());
+
+        assert!(!scene.visible(Vec3::new(-3.0, 0.0, 0.0), Vec3::new(3.0, 0.0, 0.0)));
+        assert!(scene.visible(Vec3::new(-3.0, 2.0, 0.0), Vec3::new(3.0, 2.0, 0.0)));
+        // Stops short of the sphere
+        assert!(scene.visible(Vec3::new(-3.0, 0.0, 0.0), Vec3::new(-2.0, 0.0, 0.0)));
+        // Points on the surface can see each other over the top of it
+        assert!(scene.visible(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 3.0)));
+        assert!(scene.visible(Vec3::new(1.0, 1.0, 1.0), Vec3::new(1.0, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn bounds_culling_equivalence() {
+        // Test that culling subtrees with the cached bounding boxes does not change which
+        // objects are hit. The flat scene does not use these bounding boxes at all.
+
+        let mat = Arc::new(Material::default());
+        let shape = |prim: Primitive| Arc::new(SceneNode::from(Geometry::new(prim, mat.clone())));
+        let group = Arc::new(SceneNode::from(vec![
+            shape(Sphere.into()),
+            Arc::new(SceneNode::from(shape(Cube.into())).scaled((2.0, 0.5, 1.0)).translated((2.0, 0.0, 0.0))),
+            Arc::new(SceneNode::from(shape(Cylinder.into())).rotated_x(Radians::from_degrees(45.0)).translated((-2.0, 1.0, 0.0))),
+            Arc::new(SceneNode::from(shape(Cone.into())).translated((0.0, 2.0, -1.0))),
+        ]).rotated_y(Radians::from_degrees(30.0)));
+
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(shape(Plane.into())).scaled(20.0).translated((0.0, -1.0, 0.0))),
+                Arc::new(SceneNode::from(group.clone()).translated((-3.0, 0.0, -2.0))),
+                Arc::new(SceneNode::from(group.clone()).scaled(0.5).translated((3.0, 0.5, 1.0))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let flat_scene = FlatScene::from(&scene);
+
+        let cam = CameraSettings {
+            eye: (0.0, 4.0, 12.0).into(),
+            center: (0.0, 0.0, 0.0).into(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(50.0),
+        };
+        let (width, height) = (160.0, 120.0);
+        let camera = Camera::new(cam, (width, height));
+
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let ray = camera.ray_at((x as f64, y as f64));
+
+                let mut t_range = Range {start: EPSILON, end: INFINITY};
+                let hier_hit = scene.root.ray_cast(&ray, &mut t_range).map(|(hit, _)| hit.ray_parameter);
+                let mut t_range = Range {start: EPSILON, end: INFINITY};
+                let flat_hit = flat_scene.root.ray_cast(&ray, &mut t_range).map(|(hit, _)| hit.ray_parameter);
+
+                // The flat scene combines transforms differently, so allow for some floating
+                // point error
+                match (hier_hit, flat_hit) {
+                    (Some(hier_t), Some(flat_t)) => assert!((hier_t - flat_t).abs() < EPSILON,
+                        "pixels at (x={}, y={}) were not the same: {} != {}", x, y, hier_t, flat_t),
+                    _ => assert_eq!(hier_hit, flat_hit, "pixels at (x={}, y={}) were not the same", x, y),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normal_shader_perturbs_hits_in_model_space() {
+        let mat = Arc::new(Material::default());
+        // The ripples have peaks at r = 0.25 and troughs at r = 0.75, so they are steepest at r = 0.5
+        let water = Geometry::new(Plane, mat).with_normal_shader(NormalShader::ripples(0.05, 1.0));
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(water).scaled(4.0).translated((10.0, 0.0, 0.0))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let flat_scene = FlatScene::from(&scene);
+
+        let normal_at = |x: f64, root: &dyn RayCast| {
+            let ray = Ray::new(Vec3::new(x, 1.0, 0.0), Vec3::down());
+            let mut t_range = Range {start: EPSILON, end: INFINITY};
+            root.ray_cast(&ray, &mut t_range).unwrap().0.normal.normalized()
+        };
+
+        // Ripples are centered on the geometry, not the world origin
+        assert_eq!(normal_at(10.0, &scene.root), Vec3::up());
+        // The model space distance from the center is 0.5 (going down from a peak), so the normal
+        // is tilted outwards
+        let tilted = normal_at(12.0, &scene.root);
+        assert!(tilted.x > 0.1 && tilted.y > 0.0 && tilted.z.abs() < EPSILON, "{:?}", tilted);
+        assert!((normal_at(12.0, &flat_scene.root) - tilted).magnitude() < EPSILON);
+    }
+
+    #[test]
+    fn instances_have_their_own_uv_transforms() {
+        let mat = Arc::new(Material::default());
+        let tile = Arc::new(SceneNode::from(Geometry::new(Plane, mat)));
+        let offset = |u, v| UvTransform {offset: Uv {u, v}, ..UvTransform::default()};
+        let rotated = UvTransform {rotation: Radians::from_degrees(90.0), ..UvTransform::default()};
+
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(tile.clone())),
+                Arc::new(SceneNode::from(tile.clone()).translated((2.0, 0.0, 0.0)).with_uv_transform(offset(0.5, 0.0))),
+                // The child's transform is applied before its parent's transform
+                Arc::new(SceneNode::from(Arc::new(SceneNode::from(tile).with_uv_transform(offset(0.25, 0.0))))
+                    .translated((4.0, 0.0, 0.0)).with_uv_transform(rotated)),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let flat_scene = FlatScene::from(&scene);
+
+        let tex_coord_at = |x: f64, root: &dyn RayCast| {
+            let ray = Ray::new(Vec3::new(x, 1.0, 0.0), Vec3::down());
+            let mut t_range = Range {start: EPSILON, end: INFINITY};
+            root.ray_cast(&ray, &mut t_range).unwrap().0.tex_coord.unwrap()
+        };
+
+        // Each ray hits the center of a tile, where the untransformed texture coordinate is (0.5, 0.5)
+        for &(x, expected) in &[(0.0, Uv {u: 0.5, v: 0.5}), (2.0, Uv {u: 1.0, v: 0.5}), (4.0, Uv {u: -0.5, v: 0.75})] {
+            for root in &[&scene.root as &dyn RayCast, &flat_scene.root] {
+                let tex_coord = tex_coord_at(x, *root);
+                assert!((tex_coord - expected).map(f64::abs).reduce_partial_max() < EPSILON, "{:?} != {:?}", tex_coord, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn material_override_keeps_named_nodes() {
+        let red = Arc::new(Material {diffuse: Rgb::red(), ..Material::default()});
+        let clay = Arc::new(Material::clay());
+
+        let shape = Arc::new(SceneNode::from(Geometry::new(Sphere, red.clone())));
+        let robot = Arc::new(SceneNode::from(vec![shape.clone()]).named("robot"));
+        let root = SceneNode::from(vec![shape.clone(), shape.clone(), robot]);
+
+        let clay_root = root.with_material_override(&clay, &["robot"]);
+        let material = |node: &SceneNode| node.geometry().unwrap().material.clone();
+
+        let children = clay_root.children();
+        assert_eq!(material(&children[0]), clay);
+        // Instances of the same node are still shared
+        assert!(Arc::ptr_eq(&children[0], &children[1]));
+        // The named node and its children keep their materials
+        assert_eq!(children[2].name(), Some("robot"));
+        assert_eq!(material(&children[2].children()[0]), red);
+        // The original scene is unchanged
+        assert_eq!(material(&root.children()[0]), red);
+    }
+
+    #[test]
+    fn material_overrides_replace_tagged_materials() {
+        let stone = Arc::new(Material {tag: Some("stone".to_string()), ..Material::default()});
+        let wood = Arc::new(Material {tag: Some("wood".to_string()), ..Material::default()});
+        let marble = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+
+        let wall = Arc::new(SceneNode::from(Geometry::checker_floor(stone.clone(), wood.clone(), 4.0)));
+        let root = SceneNode::from(vec![wall.clone(), wall]);
+
+        let overrides = MaterialOverrides::new().with_override("stone", marble.clone());
+        let marble_root = root.with_material_overrides(&overrides);
+        let children = marble_root.children();
+        assert!(Arc::ptr_eq(&children[0], &children[1]));
+        let geometry = children[0].geometry().unwrap();
+        assert_eq!(geometry.material, marble);
+        // Materials without an override are kept
+        assert!(Arc::ptr_eq(&geometry.checker.as_ref().unwrap().material, &wood));
+        // The original scene is unchanged
+        assert_eq!(root.children()[0].geometry().unwrap().material, stone);
+    }
+
+    #[test]
+    fn default_material_is_replaced() {
+        let red = Arc::new(Material {diffuse: Rgb::red(), ..Material::default()});
+        let blue = Arc::new(Material {diffuse: Rgb::blue(), ..Material::default()});
+
+        let shape = Arc::new(SceneNode::from(Geometry::without_material(Sphere)));
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                shape.clone(),
+                shape,
+                Arc::new(SceneNode::from(Geometry::new(Cube, red.clone()))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let material = |node: &SceneNode| node.geometry().unwrap().material.clone();
+        assert!(Material::is_scene_default(&material(&scene.root.children()[0])));
+
+        let blue_scene = scene.with_default_material(blue.clone());
+        let children = blue_scene.root.children();
+        assert_eq!(material(&children[0]), blue);
+        assert!(Arc::ptr_eq(&children[0], &children[1]));
+        // Explicit materials are kept
+        assert_eq!(material(&children[2]), red);
+    }
+
+    #[test]
+    fn cutouts_let_rays_through_holes() {
+        let mat = Arc::new(Material::default());
+        // The left half of the card is a hole and the right half is partially covered
+        let mask = Arc::new(Texture::from(|uv: Uv| Rgb::from(if uv.u < 0.5 { 0.0 } else { 0.25 })));
+        let card = |mode| Arc::new(SceneNode::from(Geometry::new(Plane, mat.clone())
+            .with_cutout(mask.clone(), mode)).scaled(2.0).translated((0.0, 1.0, 0.0)));
+        let floor = Arc::new(SceneNode::from(Geometry::new(Plane, mat.clone())).scaled(10.0));
+
+        let hit_height = |root: &SceneNode, x: f64| {
+            let ray = Ray::new(Vec3::new(x, 2.0, 0.0), Vec3::down());
+            let mut t_range = Range {start: EPSILON, end: INFINITY};
+            root.ray_cast(&ray, &mut t_range).unwrap().0.hit_point.y
+        };
+
+        let threshold = SceneNode::from(vec![card(CutoutMode::Threshold(0.2)), floor.clone()]);
+        assert!(hit_height(&threshold, -0.5).abs() < EPSILON);
+        assert!((hit_height(&threshold, 0.5) - 1.0).abs() < EPSILON);
+
+        // About a quarter of the rays hit the partially covered half of the card
+        let stochastic = SceneNode::from(vec![card(CutoutMode::Stochastic), floor]);
+        assert!(hit_height(&stochastic, -0.5).abs() < EPSILON);
+        let hits = (0..4000).filter(|_| hit_height(&stochastic, 0.5) > 0.5).count();
+        assert!((800..1200).contains(&hits), "{} hits", hits);
+    }
+
+    #[test]
+    fn checker_floor_alternates_materials() {
+        let black = Arc::new(Material {diffuse: Rgb::black(), ..Material::default()});
+        let white = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+
+        // Each tile is one unit wide. The UV transform must not move the tiles.
+        let floor = SceneNode::from(Geometry::checker_floor(black.clone(), white.clone(), 4.0))
+            .scaled(4.0)
+            .with_uv_transform(UvTransform {offset: Uv {u: 0.3, v: 0.0}, ..UvTransform::default()});
+        let scene = HierScene {
+            root: Arc::new(floor),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let flat_scene = FlatScene::from(&scene);
+
+        let material_at = |x: f64, z: f64, root: &dyn RayCast| {
+            let ray = Ray::new(Vec3::new(x, 1.0, z), Vec3::down());
+            let mut t_range = Range {start: EPSILON, end: INFINITY};
+            root.ray_cast(&ray, &mut t_range).unwrap().1
+        };
+
+        for root in &[&scene.root as &dyn RayCast, &flat_scene.root] {
+            assert_eq!(material_at(-1.5, -1.5, *root), black);
+            assert_eq!(material_at(-0.5, -1.5, *root), white);
+            assert_eq!(material_at(-0.5, -0.5, *root), black);
+            assert_eq!(material_at(1.5, 0.5, *root), white);
+        }
+
+        // Overriding the material replaces both materials of the checker
+        let clay = Arc::new(Material::clay());
+        let clay_root = scene.root.with_material_override(&clay, &[]);
+        assert_eq!(material_at(-0.5, -1.5, &clay_root), clay);
+    }
+}
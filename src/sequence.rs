@@ -0,0 +1,119 @@
+//! Assembling a sequence of rendered frames (e.g. a turntable of a model) into an animation
+//!
+//! Render each frame to its own image (e.g. "frame-000.png", "frame-001.png", etc.) and then pass
+//! the paths of the frames, in order, to one of the functions in this module.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use gif::SetParameter;
+
+/// Assembles the given frames (in order) into an animated GIF that loops forever
+///
+/// GIFs can only store 256 colors per frame, so the colors of each frame are quantized. GIF
+/// frame delays are in hundredths of a second, so the given frame rate is rounded to the nearest
+/// supported delay. All frames must have the same dimensions.
+pub fn write_gif<P: AsRef<Path>, Q: AsRef<Path>>(frames: &[P], output: Q, fps: f64) -> image::ImageResult<()> {
+    // Frame delay in units of 10 ms
+    let delay = (100.0 / fps).round().max(1.0) as u16;
+
+    let mut encoder = None;
+    for path in frames {
+        let frame = image::open(path)?.to_rgb();
+        let (width, height) = frame.dimensions();
+
+        // The encoder can only be created once the size of the frames is known
+        let encoder = match &mut encoder {
+            Some((encoder, size)) => {
+                if *size != (width, height) {
+                    return Err(image::ImageError::DimensionError);
+                }
+                encoder
+            },
+            None => {
+                let file = BufWriter::new(File::create(output.as_ref())?);
+                let mut gif_encoder = gif::Encoder::new(file, width as u16, height as u16, &[])?;
+                gif_encoder.set(gif::Repeat::Infinite)?;
+                &mut encoder.get_or_insert((gif_encoder, (width, height))).0
+            },
+        };
+
+        let mut gif_frame = gif::Frame::from_rgb(width as u16, height as u16, &frame.into_raw());
+        gif_frame.delay = delay;
+        encoder.write_frame(&gif_frame)?;
+    }
+
+    Ok(())
+}
+
+/// Encodes the given frames (in order) into an MP4 video using `ffmpeg`
+///
+/// The `ffmpeg` command must be installed and available in the PATH. The video is encoded with
+/// H.264 so that it can be played almost anywhere. Any existing file at the output path is
+/// overwritten.
+#[cfg(feature = "mp4")]
+pub fn write_mp4<P: AsRef<Path>, Q: AsRef<Path>>(frames: &[P], output: Q, fps: f64) -> std::io::Result<()> {
+    use std::fs;
+    use std::io::{self, Write};
+    use std::process::{Command, Stdio};
+
+    let mut ffmpeg = Command::new("ffmpeg")
+        // Overwrite the output and only print errors
+        .args(["-y", "-loglevel", "error"])
+        // Read the frames as a stream of images from stdin
+        .args(["-f", "image2pipe", "-framerate", &fps.to_string(), "-i", "-"])
+        // yuv420p is needed for the video to play in most video players
+        .args(["-c:v", "libx264", "-pix_fmt", "yuv420p"])
+        .arg(output.as_ref())
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    {
+        let stdin = ffmpeg.stdin.as_mut().expect("bug: ffmpeg stdin should be piped");
+        for path in frames {
+            stdin.write_all(&fs::read(path)?)?;
+        }
+    }
+    // Drop stdin so ffmpeg knows that there are no more frames
+    drop(ffmpeg.stdin.take());
+
+    let status = ffmpeg.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("ffmpeg failed with {}", status)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn gif_contains_every_frame() {
+        let dir = env::temp_dir().join(format!("portrayer-sequence-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let frames: Vec<_> = (0..3u8).map(|i| {
+            let path = dir.join(format!("frame-{}.png", i));
+            image::RgbImage::from_pixel(8, 4, image::Rgb([i * 100, 0, 0])).save(&path).unwrap();
+            path
+        }).collect();
+        let output = dir.join("turntable.gif");
+
+        write_gif(&frames, &output, 25.0).unwrap();
+        let decoder = image::gif::Decoder::new(File::open(&output).unwrap()).unwrap();
+        let decoded = image::AnimationDecoder::into_frames(decoder).collect_frames().unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[2].buffer().get_pixel(0, 0).data[0], 200);
+
+        // Frames must all be the same size
+        image::RgbImage::new(4, 4).save(&frames[1]).unwrap();
+        assert!(write_gif(&frames, &output, 25.0).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
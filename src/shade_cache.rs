@@ -0,0 +1,238 @@
+//! An experimental cache of the primary ray hits of an image so that the image can be quickly
+//! re-shaded after changing only the materials in the scene
+
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::math::{Vec3, Mat3, Uv, Rgb};
+use crate::scene::Scene;
+use crate::ray::RayCast;
+use crate::camera::{CameraSettings, Camera};
+use crate::material::{Material, Media};
+use crate::texture::TextureSource;
+
+/// The geometry found by the primary ray of a single pixel
+#[derive(Debug, Clone)]
+struct CachedHit {
+    /// The direction of the primary ray
+    ray_dir: Vec3,
+    hit_point: Vec3,
+    /// The point that shadow rays are cast from
+    shadow_origin: Vec3,
+    /// The distance from the camera to the hit point
+    ray_length: f64,
+    normal: Vec3,
+    tex_coord: Option<Uv>,
+    secondary_tex_coord: Option<Uv>,
+    normal_map_transform: Option<Mat3>,
+    /// The index of the material that was hit in the cache's list of materials
+    material_id: usize,
+}
+
+/// What the primary ray of a single pixel saw
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+enum CachedPixel {
+    /// The ray hit geometry which needs to be shaded
+    Hit(CachedHit),
+    /// The ray hit a visible light or nothing at all, so the color never changes when materials
+    /// are edited
+    Fixed(Rgb),
+}
+
+/// Stores the geometry hit by the primary ray through the center of each pixel (hit point,
+/// normal, texture coordinates, and material)
+///
+/// When only material parameters change, the image can be re-shaded from the cache without having
+/// to find the primary hits again. Secondary rays (shadows, reflections, refractions, etc.) are
+/// still cast during shading, so those effects remain correct as long as the geometry of the scene
+/// has not changed.
+///
+/// Each distinct material in the scene (compared by pointer) gets an ID equal to its index in
+/// `materials()`. To edit a material, copy that list, replace the material at its ID with an
+/// edited copy, and pass the edited list to `shade`.
+///
+/// Since only one ray is cast per pixel, the shaded image is not antialiased. This is meant for
+/// quick previews while tweaking materials, not for final renders.
+#[derive(Debug, Clone)]
+pub struct ShadeCache {
+    width: usize,
+    height: usize,
+    /// The background color behind each pixel, row by row
+    backgrounds: Vec<Rgb>,
+    /// The primary hit of each pixel, row by row
+    pixels: Vec<CachedPixel>,
+    /// The unique materials that were hit, indexed by material ID
+    materials: Vec<Arc<Material>>,
+}
+
+impl ShadeCache {
+    /// Casts a primary ray through the center of each pixel of an image with the given size and
+    /// caches whatever the ray hits
+    pub fn new<R: RayCast + Sync, T: TextureSource + Sync>(
+        scene: &Scene<R>,
+        camera: CameraSettings,
+        (width, height): (usize, usize),
+        background: &T,
+    ) -> Self {
+        let camera = Camera::new(camera, (width as f64, height as f64));
+
+        let backgrounds: Vec<_> = (0..width * height).map(|i| background.at(Uv {
+            u: (i % width) as f64 / width as f64,
+            v: (i / width) as f64 / height as f64,
+        })).collect();
+
+        let hits: Vec<_> = (0..width * height).into_par_iter().map(|i| {
+            let (x, y) = ((i % width) as f64 + 0.5, (i / width) as f64 + 0.5);
+            let ray = camera.ray_at((x, y));
+
+            let mut t_range = ray.t_range();
+            let hit = scene.root.ray_cast(&ray, &mut t_range);
+
+            // Any visible light closer than the nearest hit is seen instead of the hit
+            let light_hit = scene.lights.iter()
+                .filter_map(|light| light.ray_hit(&ray, &t_range).map(|t| (t, light)))
+                .min_by(|(t1, _), (t2, _)| t1.partial_cmp(t2).expect("bug: light hit should not be NaN"));
+            if let Some((t, light)) = light_hit {
+                return Err(light.color_at(ray.at(t), ray.direction()));
+            }
+
+            match hit {
+                Some((hit, mat)) => Ok((ray.direction(), (hit.hit_point - ray.origin()).magnitude(), hit, mat)),
+                None => Err(backgrounds[i]),
+            }
+        }).collect();
+
+        // Assign material IDs sequentially so that they are the same every time the cache is built
+        let mut materials: Vec<Arc<Material>> = Vec::new();
+        let pixels = hits.into_iter().map(|hit| match hit {
+            Ok((ray_dir, ray_length, hit, mat)) => {
+                let material_id = match materials.iter().position(|other| Arc::ptr_eq(other, &mat)) {
+                    Some(id) => id,
+                    None => {
+                        materials.push(mat);
+                        materials.len() - 1
+                    },
+                };
+
+                CachedPixel::Hit(CachedHit {
+                    ray_dir,
+                    hit_point: hit.hit_point,
+                    shadow_origin: hit.shadow_origin.unwrap_or(hit.hit_point),
+                    ray_length,
+                    normal: hit.normal,
+                    tex_coord: hit.tex_coord,
+                    secondary_tex_coord: hit.secondary_tex_coord,
+                    normal_map_transform: hit.normal_map_transform,
+                    material_id,
+                })
+            },
+            Err(color) => CachedPixel::Fixed(color),
+        }).collect();
+
+        Self {width, height, backgrounds, pixels, materials}
+    }
+
+    /// Returns the width of the cached image
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the height of the cached image
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the unique materials hit by the primary rays, indexed by material ID
+    pub fn materials(&self) -> &[Arc<Material>] {
+        &self.materials
+    }
+
+    /// Returns the ID of the material seen at the given pixel, or None if no geometry was hit
+    pub fn material_id(&self, (x, y): (usize, usize)) -> Option<usize> {
+        match &self.pixels[y * self.width + x] {
+            CachedPixel::Hit(hit) => Some(hit.material_id),
+            CachedPixel::Fixed(_) => None,
+        }
+    }
+
+    /// Shades every pixel using the given materials, returning the HDR color (in linear space) of
+    /// each pixel, row by row
+    ///
+    /// The materials are indexed by material ID and must be the same length as `materials()`. The
+    /// scene must be the same one (or have the same geometry as the one) used to build the cache.
+    pub fn shade<R: RayCast + Sync>(&self, scene: &Scene<R>, materials: &[Arc<Material>]) -> Vec<Rgb> {
+        assert_eq!(materials.len(), self.materials.len(),
+            "The number of materials must match the number of materials in the shade cache");
+
+        self.pixels.par_iter().zip(self.backgrounds.par_iter()).map(|(pixel, &background)| match pixel {
+            CachedPixel::Hit(hit) => materials[hit.material_id].hit_color(scene, background,
+                hit.ray_dir, hit.hit_point, hit.shadow_origin, hit.ray_length, hit.normal, hit.tex_coord,
+                hit.secondary_tex_coord, hit.normal_map_transform, &Media::default(), 1.0, 0),
+            &CachedPixel::Fixed(color) => color,
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::math::Radians;
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::light::Light;
+    use crate::primitive::{Sphere, Cube};
+    use crate::texture::Texture;
+
+    #[test]
+    fn reshading_matches_full_render() {
+        let red = Arc::new(Material {diffuse: Rgb::red(), ..Material::default()});
+        let blue = Arc::new(Material {diffuse: Rgb::blue(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(Geometry::new(Sphere, red.clone())).translated((-1.0, 0.0, 0.0))),
+                Arc::new(SceneNode::from(Geometry::new(Cube, blue.clone())).translated((1.0, 0.0, 0.0))),
+            ])),
+            lights: vec![Light {
+                position: Vec3::new(0.0, 5.0, 5.0),
+                color: Rgb::white(),
+                ..Light::default()
+            }],
+            ambient: Rgb::black(),
+        };
+        let camera = CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 6.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        };
+        let background = Texture::from(|_| Rgb::black());
+        let size = (16, 8);
+
+        let cache = ShadeCache::new(&scene, camera, size, &background);
+        assert_eq!(cache.materials().len(), 2);
+
+        // Shading with the original materials is the same as rendering from scratch
+        let colors = cache.shade(&scene, cache.materials());
+        let full_camera = Camera::new(camera, (size.0 as f64, size.1 as f64));
+        let full_render: Vec<_> = (0..size.0 * size.1).map(|i| {
+            let (x, y) = ((i % size.0) as f64 + 0.5, (i / size.0) as f64 + 0.5);
+            full_camera.ray_at((x, y)).color(&scene, Rgb::black(), 0)
+        }).collect();
+        assert_eq!(colors, full_render);
+
+        // Editing a material only changes the pixels that use it
+        let sphere_id = cache.material_id((6, 4)).unwrap();
+        let mut materials = cache.materials().to_vec();
+        materials[sphere_id] = Arc::new(Material {diffuse: Rgb::green(), ..Material::default()});
+        let edited = cache.shade(&scene, &materials);
+        let sphere_pixel = 4 * size.0 + 6;
+        assert!(edited[sphere_pixel].g > 0.0 && edited[sphere_pixel].r == 0.0);
+        for (i, (&before, &after)) in colors.iter().zip(&edited).enumerate() {
+            if cache.material_id((i % size.0, i / size.0)) != Some(sphere_id) {
+                assert_eq!(before, after);
+            }
+        }
+    }
+}
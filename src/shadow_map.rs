@@ -0,0 +1,156 @@
+//! Shadow maps for quickly approximating shadows in preview renders
+//!
+//! Instead of casting a shadow ray every time a surface is shaded, the distance from each light
+//! to the nearest surface in every direction is computed once ahead of time. A point is in shadow
+//! if it is further from the light than the nearest surface in its direction. Shadow maps are
+//! only an approximation: area lights cast hard shadows from their center and small details can
+//! be lost between the texels of the map.
+
+use rayon::prelude::*;
+
+use crate::math::{Vec3, INFINITY};
+use crate::scene::Scene;
+use crate::ray::{Ray, RayCast};
+
+/// A cube of depth maps around a light, storing the distance to the nearest surface in each
+/// direction from the light
+#[derive(Clone)]
+pub struct ShadowMap {
+    /// The position that the depths were measured from
+    position: Vec3,
+    /// The width and height of each face of the cube
+    resolution: usize,
+    /// The distance to the nearest surface through each texel of each face (or infinity if there
+    /// is no surface), face by face and row by row
+    depths: Vec<f64>,
+}
+
+// The depths are omitted since there can be millions of them
+impl std::fmt::Debug for ShadowMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ShadowMap")
+            .field("position", &self.position)
+            .field("resolution", &self.resolution)
+            .finish()
+    }
+}
+
+impl ShadowMap {
+    /// Computes a shadow map by casting a ray from the given position through the center of each
+    /// texel of a cube map with the given resolution (per face)
+    pub fn new<R: RayCast + Sync>(scene: &Scene<R>, position: Vec3, resolution: usize) -> Self {
+        let resolution = resolution.max(1);
+
+        let depths = (0..6 * resolution * resolution).into_par_iter().map(|index| {
+            let face = index / (resolution * resolution);
+            let texel = index % (resolution * resolution);
+            let (x, y) = (texel % resolution, texel / resolution);
+
+            // Map the center of the texel to coordinates between -1 and 1 on the face
+            let s = (x as f64 + 0.5) / resolution as f64 * 2.0 - 1.0;
+            let t = (y as f64 + 0.5) / resolution as f64 * 2.0 - 1.0;
+
+            let ray = Ray::new(position, face_direction(face, s, t).normalized());
+            let mut t_range = ray.t_range();
+            match scene.root.ray_cast(&ray, &mut t_range) {
+                Some((hit, _)) => (hit.hit_point - position).magnitude(),
+                None => INFINITY,
+            }
+        }).collect();
+
+        Self {position, resolution, depths}
+    }
+
+    /// Returns true if the given point is further from the light than the nearest surface in its
+    /// direction
+    pub fn is_shadowed(&self, point: Vec3) -> bool {
+        let to_point = point - self.position;
+        let dist = to_point.magnitude();
+        let (face, s, t) = face_coordinates(to_point);
+
+        let texel = |coord: f64| (((coord + 1.0) / 2.0 * self.resolution as f64) as usize).min(self.resolution - 1);
+        let depth = self.depths[face * self.resolution * self.resolution + texel(t) * self.resolution + texel(s)];
+
+        // Each texel covers a range of depths on surfaces that are not facing the light, so the
+        // bias grows with the size of a texel at the depth of the surface to avoid "shadow acne"
+        let bias = depth * 4.0 / self.resolution as f64;
+        dist > depth + bias
+    }
+}
+
+/// Returns the (not normalized) direction through the given coordinates (between -1 and 1) on
+/// the given face of the cube
+///
+/// The faces are in the order +x, -x, +y, -y, +z, -z.
+fn face_direction(face: usize, s: f64, t: f64) -> Vec3 {
+    match face {
+        0 => Vec3::new(1.0, t, s),
+        1 => Vec3::new(-1.0, t, s),
+        2 => Vec3::new(s, 1.0, t),
+        3 => Vec3::new(s, -1.0, t),
+        4 => Vec3::new(s, t, 1.0),
+        5 => Vec3::new(s, t, -1.0),
+        _ => unreachable!("bug: a cube only has 6 faces"),
+    }
+}
+
+/// Returns the face of the cube that the given direction passes through and the coordinates
+/// (between -1 and 1) on that face. The inverse of `face_direction`.
+fn face_coordinates(dir: Vec3) -> (usize, f64, f64) {
+    let abs = dir.map(f64::abs);
+    if abs.x >= abs.y && abs.x >= abs.z {
+        let face = if dir.x >= 0.0 { 0 } else { 1 };
+        (face, dir.z / abs.x, dir.y / abs.x)
+    } else if abs.y >= abs.z {
+        let face = if dir.y >= 0.0 { 2 } else { 3 };
+        (face, dir.x / abs.y, dir.z / abs.y)
+    } else {
+        let face = if dir.z >= 0.0 { 4 } else { 5 };
+        (face, dir.x / abs.z, dir.y / abs.z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::math::Rgb;
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::material::Material;
+    use crate::primitive::{Plane, Cube};
+
+    #[test]
+    fn face_coordinates_invert_face_direction() {
+        for face in 0..6 {
+            for &(s, t) in &[(0.0, 0.0), (0.5, -0.25), (-0.9, 0.9)] {
+                let (found_face, found_s, found_t) = face_coordinates(face_direction(face, s, t) * 3.0);
+                assert_eq!((found_face, found_s, found_t), (face, s, t));
+            }
+        }
+    }
+
+    #[test]
+    fn shadow_map_matches_shadow_rays() {
+        let mat = Arc::new(Material {diffuse: Rgb::white(), ..Material::default()});
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(Geometry::new(Plane, mat.clone())).scaled(10.0)),
+                Arc::new(SceneNode::from(Geometry::new(Cube, mat)).translated((0.0, 1.0, 0.0))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let light_pos = Vec3::new(0.0, 5.0, 0.0);
+        let shadow_map = ShadowMap::new(&scene, light_pos, 256);
+
+        for &(x, z) in &[(0.0, 0.0), (0.3, -0.3), (3.0, 0.0), (-2.0, 4.0), (1.0, 1.0)] {
+            let point = Vec3::new(x, 0.0, z);
+            let ray = Ray::new(point, (light_pos - point).normalized());
+            let mut t_range = ray.t_range();
+            let expected = scene.root.ray_cast(&ray, &mut t_range).is_some();
+            assert_eq!(shadow_map.is_shadowed(point), expected, "({}, {})", x, z);
+        }
+    }
+}
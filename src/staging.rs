@@ -0,0 +1,320 @@
+//! Helpers for quickly staging models in a scene (e.g. for previews and mockups)
+
+use std::sync::Arc;
+
+use crate::math::{Vec3, Rgb, Uv, OrthonormalBasis};
+use crate::texture::Texture;
+use crate::material::Material;
+use crate::primitive::Plane;
+use crate::scene::{SceneNode, Geometry};
+use crate::light::{Light, Parallelogram};
+
+/// A large ground plane with a procedural grid pattern that fades out with distance
+///
+/// Useful as a "studio backdrop" for staging a model without having to model a floor. The grid is
+/// centered at the origin in the XZ plane.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroundGrid {
+    /// The length/width of the ground plane
+    pub size: f64,
+    /// The distance between grid lines
+    pub cell_size: f64,
+    /// The thickness of each grid line
+    pub line_width: f64,
+    /// The color of the ground between the grid lines
+    pub base_color: Rgb,
+    /// The color of the grid lines
+    pub line_color: Rgb,
+    /// If provided, every other cell will be this color, creating a checkerboard pattern
+    pub checker_color: Option<Rgb>,
+    /// The distance from the center of the grid at which the pattern has completely faded into
+    /// the base color. The fade helps hide the edge of the ground plane.
+    pub fade_distance: f64,
+    /// Fake shadows painted onto the ground under objects so they look like they are resting on
+    /// the ground, even without soft shadows or ambient occlusion
+    pub contact_shadows: Vec<ContactShadow>,
+}
+
+impl Default for GroundGrid {
+    fn default() -> Self {
+        Self {
+            size: 100.0,
+            cell_size: 1.0,
+            line_width: 0.02,
+            base_color: Rgb {r: 0.5, g: 0.5, b: 0.5},
+            line_color: Rgb {r: 0.2, g: 0.2, b: 0.2},
+            checker_color: None,
+            fade_distance: 40.0,
+            contact_shadows: Vec::new(),
+        }
+    }
+}
+
+impl GroundGrid {
+    /// Returns the color of the grid at the given (x, z) position relative to the center of
+    /// the grid
+    pub fn color_at(&self, x: f64, z: f64) -> Rgb {
+        // Distance to the nearest grid line along each axis
+        let line_dist = |coord: f64| {
+            let offset = coord.rem_euclid(self.cell_size);
+            offset.min(self.cell_size - offset)
+        };
+
+        let pattern_color = if line_dist(x).min(line_dist(z)) <= self.line_width / 2.0 {
+            self.line_color
+        } else {
+            match self.checker_color {
+                Some(checker_color) => {
+                    let cell_x = (x / self.cell_size).floor() as i64;
+                    let cell_z = (z / self.cell_size).floor() as i64;
+                    if (cell_x + cell_z).rem_euclid(2) == 0 {
+                        self.base_color
+                    } else {
+                        checker_color
+                    }
+                },
+                None => self.base_color,
+            }
+        };
+
+        let dist = (x*x + z*z).sqrt();
+        let fade = (dist / self.fade_distance).clamp(0.0, 1.0);
+        let color = Rgb::lerp(pattern_color, self.base_color, fade);
+
+        let light = self.contact_shadows.iter()
+            .map(|shadow| 1.0 - shadow.darkness_at(x, z))
+            .product::<f64>();
+        color * light
+    }
+
+    /// Returns a texture that maps the grid onto the unit plane primitive scaled by size
+    pub fn texture(&self) -> Texture {
+        let grid = self.clone();
+        Texture::from(move |uv: Uv| {
+            // The plane primitive maps (-0.5, -0.5) to (0.5, 0.5) in model space to uv
+            grid.color_at((uv.u - 0.5) * grid.size, (uv.v - 0.5) * grid.size)
+        })
+    }
+
+    /// Returns a matte material textured with the grid
+    pub fn material(&self) -> Arc<Material> {
+        Arc::new(Material {
+            texture: Some(Arc::new(self.texture())),
+            ..Material::default()
+        })
+    }
+
+    /// Returns a scene node with the ground plane, ready to be added to a scene
+    pub fn node(&self) -> SceneNode {
+        SceneNode::from(Geometry::new(Plane, self.material()))
+            .scaled(self.size)
+    }
+}
+
+/// A cheap, fake shadow painted onto the ground as a dark gradient under an object
+///
+/// Much faster than soft shadows or ambient occlusion, so useful for previews where the only goal
+/// is to make objects look like they are resting on the ground instead of floating above it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContactShadow {
+    /// The center of the shadow on the ground (the y coordinate is ignored)
+    pub center: Vec3,
+    /// The distance from the center to the edge of the shadow along the x axis
+    pub radius_x: f64,
+    /// The distance from the center to the edge of the shadow along the z axis
+    pub radius_z: f64,
+    /// How dark the shadow is at its center (0.0 is invisible, 1.0 is black)
+    pub opacity: f64,
+}
+
+impl ContactShadow {
+    /// The size of the shadow relative to the footprint of the object casting it
+    const SPREAD: f64 = 1.5;
+
+    /// Creates a contact shadow under the given node, assuming the ground is at y = 0.0
+    ///
+    /// The shadow covers the footprint of the node's bounding box and gets lighter the higher the
+    /// node is above the ground. Returns None if the node has no geometry.
+    pub fn under(node: &SceneNode, opacity: f64) -> Option<Self> {
+        let bounds = node.parent_bounds()?;
+        let (min, max) = (bounds.min(), bounds.max());
+
+        let radius_x = (max.x - min.x) / 2.0 * Self::SPREAD;
+        let radius_z = (max.z - min.z) / 2.0 * Self::SPREAD;
+        // Objects further from the ground than their own size cast no contact shadow
+        let height = min.y.max(0.0);
+        let size = radius_x.max(radius_z);
+        let fade = if size > 0.0 { (1.0 - height / size).clamp(0.0, 1.0) } else { 0.0 };
+
+        Some(Self {
+            center: (min + max) / 2.0,
+            radius_x,
+            radius_z,
+            opacity: opacity * fade,
+        })
+    }
+
+    /// Returns how much this shadow darkens the ground at the given (x, z) position (0.0 is not
+    /// at all, 1.0 is completely black)
+    pub fn darkness_at(&self, x: f64, z: f64) -> f64 {
+        if self.radius_x <= 0.0 || self.radius_z <= 0.0 {
+            return 0.0;
+        }
+
+        let dx = (x - self.center.x) / self.radius_x;
+        let dz = (z - self.center.z) / self.radius_z;
+        let dist = (dx*dx + dz*dz).sqrt();
+
+        // Quadratic falloff so the shadow is darkest near the center of the object
+        let falloff = (1.0 - dist).max(0.0);
+        self.opacity * falloff * falloff
+    }
+}
+
+/// A classic three-point lighting rig: a bright key light to one side of the camera, a dimmer fill
+/// light on the other side to soften the shadows, and a rim light behind the target to separate
+/// it from the background
+///
+/// All lights are area lights (for soft shadows) that are positioned and sized relative to the
+/// bounds of the target so that the same rig works for targets of any size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreePointLighting {
+    /// The color and intensity of the key light
+    pub key: Rgb,
+    /// The color and intensity of the fill light
+    pub fill: Rgb,
+    /// The color and intensity of the rim light
+    pub rim: Rgb,
+    /// The distance of each light from the center of the target, as a multiple of the radius of
+    /// the target's bounding box
+    pub distance: f64,
+    /// The side length of each light, as a multiple of the radius of the target's bounding box
+    pub light_size: f64,
+}
+
+impl Default for ThreePointLighting {
+    fn default() -> Self {
+        Self {
+            key: Rgb {r: 0.8, g: 0.8, b: 0.8},
+            fill: Rgb {r: 0.3, g: 0.3, b: 0.3},
+            rim: Rgb {r: 0.5, g: 0.5, b: 0.5},
+            distance: 3.0,
+            light_size: 0.5,
+        }
+    }
+}
+
+impl ThreePointLighting {
+    /// Returns the key, fill, and rim lights (in that order) for the given target node as seen
+    /// from a camera at the given eye position
+    ///
+    /// The node's transform is taken into account, so this works best when the node is the one
+    /// added to the root of the scene. Returns no lights if the node has no geometry.
+    pub fn lights(&self, target: &SceneNode, eye: Vec3) -> Vec<Light> {
+        match target.parent_bounds() {
+            Some(bounds) => self.lights_for_bounds(bounds.min(), bounds.max(), eye),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the key, fill, and rim lights (in that order) for a target with the given bounds
+    /// as seen from a camera at the given eye position
+    pub fn lights_for_bounds(&self, min: Vec3, max: Vec3, eye: Vec3) -> Vec<Light> {
+        let center = (min + max) / 2.0;
+        let radius = (max - min).magnitude() / 2.0;
+
+        // The horizontal direction the camera is looking in, used so that the lights are always
+        // above the target regardless of the camera's height
+        let view = Vec3 {y: 0.0, ..center - eye};
+        let view = if view.magnitude_squared() > 0.0 { view.normalized() } else { Vec3::forward_rh() };
+        let right = view.cross(Vec3::up());
+        let up = Vec3::up();
+
+        let light = |color, dir: Vec3| {
+            let dir = dir.normalized();
+            // Orient the light so that it faces the target
+            let basis = OrthonormalBasis::from_normal(-dir);
+            let half_size = self.light_size * radius / 2.0;
+
+            Light {
+                position: center + dir * self.distance * radius,
+                color,
+                area: Parallelogram {
+                    a: basis.bitangent * half_size,
+                    b: basis.tangent * half_size,
+                },
+                ..Light::default()
+            }
+        };
+
+        vec![
+            // Key: in front of the target, off to the right of the camera and above
+            light(self.key, -view + right + up),
+            // Fill: in front of the target, off to the left of the camera and slightly above
+            light(self.fill, -view - right + up * 0.5),
+            // Rim: behind the target and above
+            light(self.rim, view + up),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::primitive::Cube;
+
+    #[test]
+    fn grid_lines_fade_out() {
+        let grid = GroundGrid {
+            checker_color: Some(Rgb::white()),
+            ..GroundGrid::default()
+        };
+
+        assert_eq!(grid.color_at(0.0, 0.0), grid.line_color);
+        assert_eq!(grid.color_at(0.5, 0.5), grid.base_color);
+        // Diagonal neighbour of the cell at the origin is the same color, adjacent cell is not
+        assert_eq!(grid.color_at(-0.5, -0.5), grid.base_color);
+        assert_ne!(grid.color_at(1.5, 0.5), grid.base_color);
+        assert_eq!(grid.color_at(grid.fade_distance, 0.0), grid.base_color);
+    }
+
+    #[test]
+    fn three_point_lights_surround_target() {
+        let eye = Vec3::new(0.0, 1.0, 10.0);
+        let lights = ThreePointLighting::default()
+            .lights_for_bounds(Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 2.0, 1.0), eye);
+        let [key, fill, rim] = match &lights[..] {
+            [key, fill, rim] => [key, fill, rim],
+            _ => panic!("expected three lights"),
+        };
+
+        // Key and fill are on opposite sides of the camera, in front of the target
+        assert!(key.position.x > 0.0 && fill.position.x < 0.0);
+        assert!(key.position.z > 0.0 && fill.position.z > 0.0);
+        // Rim is behind the target and all lights are above it
+        assert!(rim.position.z < 0.0);
+        assert!(lights.iter().all(|light| light.position.y > 1.0 && !light.area.is_empty()));
+    }
+
+    #[test]
+    fn contact_shadow_darkens_ground_under_node() {
+        let cube = SceneNode::from(Geometry::new(Cube, Arc::new(Material::default())))
+            .translated((2.0, 0.5, 0.0));
+        let floating = SceneNode::from(Geometry::new(Cube, Arc::new(Material::default())))
+            .translated((2.0, 5.0, 0.0));
+
+        let shadow = ContactShadow::under(&cube, 0.8).unwrap();
+        assert!(shadow.darkness_at(2.0, 0.0) > 0.7);
+        assert_eq!(shadow.darkness_at(-2.0, 0.0), 0.0);
+        assert_eq!(ContactShadow::under(&floating, 0.8).unwrap().darkness_at(2.0, 0.0), 0.0);
+
+        let grid = GroundGrid {
+            contact_shadows: vec![shadow],
+            ..GroundGrid::default()
+        };
+        let lit = GroundGrid::default().color_at(2.5, 0.5);
+        let shadowed = grid.color_at(2.5, 0.5);
+        assert!(shadowed.r < lit.r);
+    }
+}
@@ -0,0 +1,542 @@
+//! Utilities for inspecting the contents of a scene and the rendered image.
+
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+use std::collections::{HashSet, HashMap, BTreeMap};
+
+use crate::scene::{HierScene, SceneNode, Geometry};
+use crate::primitive::Primitive;
+use crate::material::Material;
+use crate::math::{Rgb, RgbExt};
+
+/// Formats a number of bytes using the largest unit that keeps the value above 1.0
+struct ByteSize(usize);
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        if unit == 0 {
+            write!(f, "{} {}", self.0, UNITS[unit])
+        } else {
+            write!(f, "{:.2} {}", value, UNITS[unit])
+        }
+    }
+}
+
+/// The estimated memory usage of a scene, broken down by subsystem
+///
+/// All values are in bytes. Any data shared between multiple nodes (e.g. via instancing) is only
+/// counted once, just like it is only stored once in memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The memory used by the scene nodes themselves (transforms, lists of children, etc.)
+    ///
+    /// This is the per-instance overhead of the scene hierarchy.
+    pub nodes: usize,
+    /// The memory used by the vertex and triangle data of meshes
+    pub meshes: usize,
+    /// The memory used by acceleration structures (e.g. the k-d trees of each KDMesh)
+    pub acceleration_structures: usize,
+    /// The memory used by materials, not including their textures
+    pub materials: usize,
+    /// The memory used by textures and normal maps
+    pub textures: usize,
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Nodes:                   {}", ByteSize(self.nodes))?;
+        writeln!(f, "Meshes:                  {}", ByteSize(self.meshes))?;
+        writeln!(f, "Acceleration Structures: {}", ByteSize(self.acceleration_structures))?;
+        writeln!(f, "Materials:               {}", ByteSize(self.materials))?;
+        writeln!(f, "Textures:                {}", ByteSize(self.textures))?;
+        write!(f, "Total:                   {}", ByteSize(self.total()))
+    }
+}
+
+impl MemoryUsage {
+    /// Returns the total memory used by all subsystems
+    pub fn total(&self) -> usize {
+        self.nodes + self.meshes + self.acceleration_structures + self.materials + self.textures
+    }
+}
+
+/// Keeps track of which shared values have already been counted
+#[derive(Default)]
+struct MemoryCounter {
+    usage: MemoryUsage,
+    nodes: HashSet<*const SceneNode>,
+    meshes: HashSet<*const ()>,
+    trees: HashSet<*const ()>,
+    materials: HashSet<*const Material>,
+    textures: HashSet<*const ()>,
+}
+
+impl MemoryCounter {
+    fn count_node(&mut self, node: &Arc<SceneNode>) {
+        // Instanced nodes only exist once in memory
+        if !self.nodes.insert(Arc::as_ptr(node)) {
+            return;
+        }
+
+        self.usage.nodes += mem::size_of::<SceneNode>()
+            + mem::size_of_val(node.children());
+
+        if let Some(geometry) = node.geometry() {
+            self.count_geometry(geometry);
+        }
+
+        for child in node.children() {
+            self.count_node(child);
+        }
+    }
+
+    fn count_geometry(&mut self, geometry: &Geometry) {
+        let Geometry {primitive, material, checker, cutout, ..} = geometry;
+        self.count_primitive(primitive);
+        self.count_material(material);
+        if let Some(checker) = checker {
+            self.count_material(&checker.material);
+        }
+        if let Some(cutout) = cutout {
+            if self.textures.insert(Arc::as_ptr(&cutout.mask) as *const ()) {
+                self.usage.textures += cutout.mask.memory_size();
+            }
+        }
+    }
+
+    fn count_primitive(&mut self, primitive: &Primitive) {
+        match primitive {
+            Primitive::Mesh(mesh) => {
+                let data = mesh.data();
+                if self.meshes.insert(Arc::as_ptr(data) as *const ()) {
+                    self.usage.meshes += data.memory_size();
+                }
+            },
+            Primitive::KDMesh(mesh) => {
+                let tree = mesh.tree();
+                if self.trees.insert(Arc::as_ptr(tree) as *const ()) {
+                    self.usage.acceleration_structures += tree.memory_size();
+                }
+            },
+            // All other primitives are stored directly in the node
+            _ => {},
+        }
+    }
+
+    fn count_material(&mut self, material: &Arc<Material>) {
+        if !self.materials.insert(Arc::as_ptr(material)) {
+            return;
+        }
+
+        self.usage.materials += mem::size_of::<Material>();
+
+        let textures = [&material.texture, &material.glossy_map, &material.refraction_map, &material.lightmap];
+        let decal_textures = material.decals.iter()
+            .flat_map(|decal| Some(&decal.texture).into_iter().chain(&decal.mask));
+        let terrain_textures = material.terrain.iter()
+            .flat_map(|terrain| terrain.layers.iter().filter_map(|layer| layer.texture.as_ref()));
+        for texture in textures.iter().filter_map(|tex| tex.as_ref()).chain(decal_textures).chain(terrain_textures) {
+            if self.textures.insert(Arc::as_ptr(texture) as *const ()) {
+                self.usage.textures += texture.memory_size();
+            }
+        }
+        if let Some(normals) = &material.normals {
+            if self.textures.insert(Arc::as_ptr(normals) as *const ()) {
+                self.usage.textures += normals.memory_size();
+            }
+        }
+    }
+}
+
+/// A summary of the contents of a scene
+///
+/// Unless otherwise noted, counts include every instance of an instanced node. That is, the counts
+/// are the same as they would be if the scene were flattened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SceneStats {
+    /// The number of unique scene nodes (instanced nodes are only counted once)
+    pub unique_nodes: usize,
+    /// The total number of scene nodes, including every instance of instanced nodes
+    pub nodes: usize,
+    /// The maximum depth of the scene hierarchy (a scene with only a root node has depth 1)
+    pub max_depth: usize,
+    /// The number of primitives of each kind in the scene
+    pub primitives: BTreeMap<&'static str, usize>,
+    /// The total number of triangles in all meshes and triangle primitives
+    pub triangles: usize,
+    /// The number of lights in the scene
+    pub lights: usize,
+    /// The estimated memory used by textures and normal maps (in bytes)
+    pub texture_memory: usize,
+}
+
+impl fmt::Display for SceneStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Nodes:          {} ({} unique)", self.nodes, self.unique_nodes)?;
+        writeln!(f, "Max Depth:      {}", self.max_depth)?;
+        writeln!(f, "Triangles:      {}", self.triangles)?;
+        writeln!(f, "Lights:         {}", self.lights)?;
+        writeln!(f, "Texture Memory: {}", ByteSize(self.texture_memory))?;
+        write!(f, "Primitives:")?;
+        for (kind, count) in &self.primitives {
+            write!(f, "\n  {}: {}", kind, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Accumulates the statistics of every node in the scene
+#[derive(Default)]
+struct StatsCounter {
+    stats: SceneStats,
+    unique_nodes: HashSet<*const SceneNode>,
+    /// Caches the number of triangles in each k-d tree since that is expensive to compute
+    kd_triangles: HashMap<*const (), usize>,
+}
+
+impl StatsCounter {
+    fn count_node(&mut self, node: &Arc<SceneNode>, depth: usize) {
+        self.unique_nodes.insert(Arc::as_ptr(node));
+        self.stats.nodes += 1;
+        self.stats.max_depth = self.stats.max_depth.max(depth);
+
+        if let Some(Geometry {primitive, ..}) = node.geometry() {
+            *self.stats.primitives.entry(primitive.kind_name()).or_insert(0) += 1;
+
+            self.stats.triangles += match primitive {
+                Primitive::Triangle(_) => 1,
+                Primitive::Mesh(mesh) => mesh.data().triangle_count(),
+                Primitive::KDMesh(mesh) => *self.kd_triangles
+                    .entry(Arc::as_ptr(mesh.tree()) as *const ())
+                    .or_insert_with(|| mesh.triangle_count()),
+                _ => 0,
+            };
+        }
+
+        for child in node.children() {
+            self.count_node(child, depth + 1);
+        }
+    }
+}
+
+impl HierScene {
+    /// Returns a summary of the contents of this scene
+    ///
+    /// Useful for sanity checking generated scenes before rendering them.
+    pub fn stats(&self) -> SceneStats {
+        let mut counter = StatsCounter::default();
+        counter.count_node(&self.root, 1);
+
+        let StatsCounter {mut stats, unique_nodes, ..} = counter;
+        stats.unique_nodes = unique_nodes.len();
+        stats.lights = self.lights.len();
+        stats.texture_memory = self.memory_usage().textures;
+
+        stats
+    }
+
+    /// Estimates the memory used by this scene, broken down by subsystem
+    ///
+    /// Useful for figuring out what is using up memory when composing large scenes.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut counter = MemoryCounter::default();
+        counter.count_node(&self.root);
+
+        for light in &self.lights {
+            if let Some(gobo) = &light.gobo {
+                if counter.textures.insert(Arc::as_ptr(&gobo.texture) as *const ()) {
+                    counter.usage.textures += gobo.texture.memory_size();
+                }
+            }
+        }
+
+        counter.usage
+    }
+}
+
+/// Exposure statistics of the HDR colors of a rendered image
+///
+/// All luminance values are in linear space, before gamma correction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExposureStats {
+    /// The total number of pixels
+    pub pixels: usize,
+    /// The minimum luminance of any pixel
+    pub min_luminance: f64,
+    /// The maximum luminance of any pixel
+    pub max_luminance: f64,
+    /// The mean luminance of all pixels
+    pub mean_luminance: f64,
+    /// The maximum value of each channel across all pixels
+    pub channel_max: Rgb,
+    /// The mean value of each channel across all pixels
+    pub channel_mean: Rgb,
+    /// The number of pixels in each bin of a histogram of luminance in stops (powers of 2)
+    ///
+    /// Bin i counts the pixels with a luminance between 2^(HISTOGRAM_MIN_STOP + i) and
+    /// 2^(HISTOGRAM_MIN_STOP + i + 1). Pixels darker or brighter than the range of the histogram
+    /// are counted in the first or last bin respectively.
+    pub histogram: Vec<usize>,
+    /// The percentage of pixels (0.0 to 100.0) with at least one channel brighter than 1.0
+    ///
+    /// These pixels will be clipped in the output image.
+    pub clipped_percent: f64,
+}
+
+impl fmt::Display for ExposureStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Luminance:    min {:.4}, mean {:.4}, max {:.4}",
+            self.min_luminance, self.mean_luminance, self.max_luminance)?;
+        writeln!(f, "Channel Mean: r {:.4}, g {:.4}, b {:.4}",
+            self.channel_mean.r, self.channel_mean.g, self.channel_mean.b)?;
+        writeln!(f, "Channel Max:  r {:.4}, g {:.4}, b {:.4}",
+            self.channel_max.r, self.channel_max.g, self.channel_max.b)?;
+        writeln!(f, "Clipped:      {:.2}%", self.clipped_percent)?;
+        write!(f, "Histogram (stops):")?;
+        for (i, count) in self.histogram.iter().enumerate() {
+            write!(f, "\n  {:+3}: {}", Self::HISTOGRAM_MIN_STOP + i as i32, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl ExposureStats {
+    /// The stop (power of 2) of luminance at the start of the first bin of the histogram
+    pub const HISTOGRAM_MIN_STOP: i32 = -10;
+    /// The number of bins (stops) in the histogram
+    pub const HISTOGRAM_BINS: usize = 16;
+
+    /// Computes the exposure statistics of the given HDR colors (in linear space)
+    pub fn from_pixels(pixels: &[Rgb]) -> Self {
+        let mut stats = Self {
+            pixels: pixels.len(),
+            min_luminance: if pixels.is_empty() { 0.0 } else { f64::INFINITY },
+            max_luminance: 0.0,
+            mean_luminance: 0.0,
+            channel_max: Rgb::black(),
+            channel_mean: Rgb::black(),
+            histogram: vec![0; Self::HISTOGRAM_BINS],
+            clipped_percent: 0.0,
+        };
+
+        let mut clipped = 0;
+        for &color in pixels {
+            let luminance = color.luminance();
+            stats.min_luminance = stats.min_luminance.min(luminance);
+            stats.max_luminance = stats.max_luminance.max(luminance);
+            stats.mean_luminance += luminance;
+            stats.channel_max = Rgb::partial_max(stats.channel_max, color);
+            stats.channel_mean += color;
+
+            if color.reduce_partial_max() > 1.0 {
+                clipped += 1;
+            }
+
+            // Black pixels have a stop of -infinity and so end up in the first bin
+            let stop = luminance.log2().floor() as i64 - Self::HISTOGRAM_MIN_STOP as i64;
+            let bin = stop.clamp(0, Self::HISTOGRAM_BINS as i64 - 1) as usize;
+            stats.histogram[bin] += 1;
+        }
+
+        if !pixels.is_empty() {
+            let count = pixels.len() as f64;
+            stats.mean_luminance /= count;
+            stats.channel_mean /= count;
+            stats.clipped_percent = clipped as f64 / count * 100.0;
+        }
+
+        stats
+    }
+}
+
+/// The luminance of a middle grey surface (18% reflectance) in linear space
+const MIDDLE_GREY: f64 = 0.18;
+
+/// Maps the given HDR color (in linear space) to a false color that shows its exposure
+///
+/// Like the false color modes of cinema cameras, most of the image is shown in greyscale and only
+/// certain ranges of exposure (measured in stops relative to middle grey) are colored:
+///
+/// * purple - crushed blacks (more than 5 stops below middle grey)
+/// * blue - deep shadows (2.5 to 5 stops below middle grey)
+/// * green - middle grey (within half a stop)
+/// * pink - a stop above middle grey (typical exposure of skin tones)
+/// * yellow - highlights close to clipping
+/// * red - clipped (at least one channel is brighter than 1.0)
+///
+/// The returned color is in linear space.
+pub fn false_color(color: Rgb) -> Rgb {
+    if color.reduce_partial_max() > 1.0 {
+        return Rgb::red();
+    }
+
+    let luminance = color.luminance();
+    let stops = (luminance / MIDDLE_GREY).log2();
+    if stops < -5.0 {
+        Rgb::new(0.5, 0.0, 0.5)
+    } else if stops < -2.5 {
+        Rgb::blue()
+    } else if (-0.5..0.5).contains(&stops) {
+        Rgb::green()
+    } else if (0.5..1.5).contains(&stops) {
+        Rgb::new(1.0, 0.4, 0.7)
+    } else if luminance >= 0.9 {
+        Rgb::yellow()
+    } else {
+        Rgb::broadcast(luminance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::primitive::{Sphere, Mesh, MeshData, Shading};
+    use crate::kdtree::KDMesh;
+    use crate::flat_scene::FlatScene;
+    use crate::math::{Vec3, Rgb};
+
+    #[test]
+    fn instanced_meshes_counted_once() {
+        let mat = Arc::new(Material::default());
+        let data = Arc::new(MeshData::new(
+            vec![Vec3::zero(), Vec3::unit_x(), Vec3::unit_y()],
+            vec![(0, 1, 2)],
+            Vec::new(),
+            Vec::new(),
+        ));
+        let mesh = Arc::new(SceneNode::from(Geometry::new(Mesh::new(data.clone(), Shading::Flat), mat.clone())));
+
+        let single = HierScene {
+            root: Arc::new(SceneNode::from(vec![mesh.clone()])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let instanced = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(mesh.clone()).translated((1.0, 0.0, 0.0))),
+                Arc::new(SceneNode::from(mesh.clone()).translated((2.0, 0.0, 0.0))),
+                Arc::new(SceneNode::from(Geometry::new(Sphere, mat.clone()))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+
+        let single_usage = single.memory_usage();
+        let instanced_usage = instanced.memory_usage();
+        assert_eq!(single_usage.meshes, data.memory_size());
+        assert_eq!(instanced_usage.meshes, data.memory_size());
+        assert_eq!(instanced_usage.materials, mem::size_of::<Material>());
+        assert!(instanced_usage.nodes > single_usage.nodes);
+    }
+
+    #[test]
+    fn flattening_shares_instanced_meshes() {
+        let mat = Arc::new(Material::default());
+        let data = Arc::new(MeshData::new(
+            vec![Vec3::zero(), Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z()],
+            vec![(0, 1, 2), (0, 2, 3), (0, 3, 1), (1, 3, 2)],
+            Vec::new(),
+            Vec::new(),
+        ));
+        let kd_mesh = Arc::new(SceneNode::from(Geometry::new(KDMesh::new(&data, Shading::Flat), mat.clone())));
+        let mesh = Arc::new(SceneNode::from(Geometry::new(Mesh::new(data, Shading::Flat), mat)));
+
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from((0..1000).map(|i| {
+                let node = if i % 2 == 0 { &kd_mesh } else { &mesh };
+                Arc::new(SceneNode::from(node.clone()).translated((i as f64, 0.0, 0.0)))
+            }).collect::<Vec<_>>())),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let hier_usage = scene.memory_usage();
+
+        // Only the nodes are duplicated when flattening. The meshes, trees, and materials of every
+        // flattened node must still be shared.
+        let flat_scene = FlatScene::from(&scene);
+        assert_eq!(flat_scene.root.len(), 1000);
+        let mut counter = MemoryCounter::default();
+        for node in &flat_scene.root {
+            counter.count_geometry(node.geometry());
+        }
+        assert_eq!(counter.usage.meshes, hier_usage.meshes);
+        assert_eq!(counter.usage.acceleration_structures, hier_usage.acceleration_structures);
+        assert_eq!(counter.usage.materials, hier_usage.materials);
+        assert_eq!(counter.trees.len(), 1);
+        assert_eq!(counter.meshes.len(), 1);
+    }
+
+    #[test]
+    fn stats_include_instances() {
+        let mat = Arc::new(Material::default());
+        let data = Arc::new(MeshData::new(
+            vec![Vec3::zero(), Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z()],
+            vec![(0, 1, 2), (0, 2, 3)],
+            Vec::new(),
+            Vec::new(),
+        ));
+        let mesh = Arc::new(SceneNode::from(Geometry::new(Mesh::new(data, Shading::Flat), mat.clone())));
+
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(mesh.clone()).translated((1.0, 0.0, 0.0))),
+                Arc::new(SceneNode::from(mesh.clone()).translated((2.0, 0.0, 0.0))),
+                Arc::new(SceneNode::from(Geometry::new(Sphere, mat.clone()))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+
+        let stats = scene.stats();
+        assert_eq!(stats.nodes, 6);
+        assert_eq!(stats.unique_nodes, 5);
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(stats.triangles, 4);
+        assert_eq!(stats.primitives.get("Mesh"), Some(&2));
+        assert_eq!(stats.primitives.get("Sphere"), Some(&1));
+    }
+
+    #[test]
+    fn exposure_stats_histogram() {
+        let pixels = [
+            Rgb::black(),
+            Rgb::white(),
+            Rgb::white() * 4.0,
+            Rgb {r: 2.0, g: 0.0, b: 0.0},
+        ];
+
+        let stats = ExposureStats::from_pixels(&pixels);
+        assert_eq!(stats.min_luminance, 0.0);
+        assert_eq!(stats.max_luminance, 4.0);
+        assert_eq!(stats.channel_max, Rgb::new(4.0, 4.0, 4.0));
+        assert_eq!(stats.clipped_percent, 50.0);
+        assert_eq!(stats.histogram.iter().sum::<usize>(), pixels.len());
+        // Black is in the first bin, white (stop 0) is in bin 10, and 4x white (stop 2) is in bin 12
+        let zero_stop = (-ExposureStats::HISTOGRAM_MIN_STOP) as usize;
+        assert_eq!(stats.histogram[0], 1);
+        assert_eq!(stats.histogram[zero_stop], 1);
+        assert_eq!(stats.histogram[zero_stop + 2], 1);
+    }
+
+    #[test]
+    fn false_color_bands() {
+        assert_eq!(false_color(Rgb::black()), Rgb::new(0.5, 0.0, 0.5));
+        assert_eq!(false_color(Rgb::broadcast(MIDDLE_GREY)), Rgb::green());
+        assert_eq!(false_color(Rgb::broadcast(MIDDLE_GREY * 2.0)), Rgb::new(1.0, 0.4, 0.7));
+        assert_eq!(false_color(Rgb::broadcast(0.95)), Rgb::yellow());
+        assert_eq!(false_color(Rgb {r: 1.5, g: 0.0, b: 0.0}), Rgb::red());
+        // Exposures that are not in any of the bands are shown in greyscale
+        assert_eq!(false_color(Rgb::broadcast(0.05)), Rgb::broadcast(0.05));
+    }
+}
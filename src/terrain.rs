@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use crate::math::{Vec3, Uv, Rgb, Radians};
+use crate::texture::{Texture, TextureSource};
+
+/// A single layer of a terrain (e.g. grass, rock, or snow) and where on the terrain it appears
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerrainLayer {
+    /// The color of the layer
+    ///
+    /// Ignored if a texture is provided
+    pub diffuse: Rgb,
+    /// The texture to sample the color of the layer from (sampled with the primary UV channel)
+    pub texture: Option<Arc<Texture>>,
+    /// The lowest and highest world space heights (y) where the layer appears
+    pub heights: (f64, f64),
+    /// The smallest and largest angles between the surface normal and +y where the layer appears
+    ///
+    /// An angle of zero is flat ground and an angle of 90 degrees is a vertical cliff.
+    pub slopes: (Radians, Radians),
+}
+
+impl TerrainLayer {
+    /// Creates a layer with the given color that appears at every height and slope
+    pub fn new(diffuse: Rgb) -> Self {
+        Self {
+            diffuse,
+            texture: None,
+            heights: (-f64::INFINITY, f64::INFINITY),
+            slopes: (Radians::from_degrees(0.0), Radians::from_degrees(180.0)),
+        }
+    }
+
+    /// Only show this layer between the given heights, returning the updated layer
+    pub fn with_heights(mut self, min: f64, max: f64) -> Self {
+        self.heights = (min, max);
+        self
+    }
+
+    /// Only show this layer between the given slopes, returning the updated layer
+    pub fn with_slopes(mut self, min: Radians, max: Radians) -> Self {
+        self.slopes = (min, max);
+        self
+    }
+}
+
+/// Blends the colors of several layers across a terrain based on the height and slope of each
+/// point on it, so terrain like a hill can be shaded without painting a huge mask by hand
+///
+/// Each layer fades out over the given distances outside of its height and slope ranges.
+/// Overlapping layers are averaged together. Anywhere that no layer completely covers shows the
+/// diffuse color of the material underneath.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerrainBlend {
+    /// The layers of the terrain
+    pub layers: Vec<TerrainLayer>,
+    /// The distance (in world space) over which a layer fades out past its heights
+    pub height_falloff: f64,
+    /// The angle over which a layer fades out past its slopes
+    pub slope_falloff: Radians,
+}
+
+impl TerrainBlend {
+    /// Returns the color of the terrain at the given point (in world space) with the given
+    /// shading normal (in world space, MUST be normalized)
+    ///
+    /// The given base color is used wherever the layers do not completely cover the terrain.
+    pub fn color_at(&self, base: Rgb, point: Vec3, normal: Vec3, tex_coord: Option<Uv>) -> Rgb {
+        let slope = normal.y.clamp(-1.0, 1.0).acos();
+
+        let mut total = Rgb::black();
+        let mut total_weight = 0.0;
+        for layer in &self.layers {
+            let weight = band_weight(point.y, layer.heights, self.height_falloff)
+                * band_weight(slope, (layer.slopes.0.get(), layer.slopes.1.get()), self.slope_falloff.get());
+            if weight <= 0.0 {
+                continue;
+            }
+
+            let color = match (&layer.texture, tex_coord) {
+                (Some(tex), Some(tex_coord)) => tex.at(tex_coord),
+                (Some(_), None) => panic!("Texture mapping is not supported for this primitive!"),
+                (None, _) => layer.diffuse,
+            };
+            total += color * weight;
+            total_weight += weight;
+        }
+
+        if total_weight > 1.0 {
+            total / total_weight
+        } else {
+            total + base * (1.0 - total_weight)
+        }
+    }
+}
+
+/// Returns 1.0 if the given value is within the given range, fading out linearly to 0.0 over the
+/// given distance outside of the range
+fn band_weight(value: f64, (min, max): (f64, f64), falloff: f64) -> f64 {
+    let dist = (min - value).max(value - max);
+    if dist <= 0.0 {
+        1.0
+    } else if falloff <= 0.0 {
+        0.0
+    } else {
+        (1.0 - dist / falloff).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hill() -> TerrainBlend {
+        TerrainBlend {
+            layers: vec![
+                TerrainLayer::new(Rgb::green()).with_heights(-f64::INFINITY, 10.0)
+                    .with_slopes(Radians::from_degrees(0.0), Radians::from_degrees(30.0)),
+                TerrainLayer::new(Rgb::red())
+                    .with_slopes(Radians::from_degrees(30.0), Radians::from_degrees(90.0)),
+                TerrainLayer::new(Rgb::white()).with_heights(10.0, f64::INFINITY)
+                    .with_slopes(Radians::from_degrees(0.0), Radians::from_degrees(30.0)),
+            ],
+            height_falloff: 2.0,
+            slope_falloff: Radians::from_degrees(0.0),
+        }
+    }
+
+    #[test]
+    fn layers_follow_height_and_slope() {
+        let hill = hill();
+        let flat = Vec3::up();
+        let cliff = Vec3::new(1.0, 1.0, 0.0).normalized();
+        let color = |y, normal| hill.color_at(Rgb::blue(), Vec3::new(0.0, y, 0.0), normal, None);
+
+        assert_eq!(color(0.0, flat), Rgb::green());
+        assert_eq!(color(20.0, flat), Rgb::white());
+        assert_eq!(color(0.0, cliff), Rgb::red());
+        assert_eq!(color(20.0, cliff), Rgb::red());
+        // Grass and snow fade into each other around the snow line
+        assert_eq!(color(11.0, flat), (Rgb::green() * 0.5 + Rgb::white()) / 1.5);
+        // Overhangs are not covered by any layer
+        assert_eq!(color(0.0, Vec3::down()), Rgb::blue());
+    }
+
+    #[test]
+    fn band_weight_fades_outside_of_range() {
+        assert_eq!(band_weight(0.5, (0.0, 1.0), 1.0), 1.0);
+        assert_eq!(band_weight(1.5, (0.0, 1.0), 1.0), 0.5);
+        assert_eq!(band_weight(-0.25, (0.0, 1.0), 1.0), 0.75);
+        assert_eq!(band_weight(3.0, (0.0, 1.0), 1.0), 0.0);
+        assert_eq!(band_weight(1.5, (0.0, 1.0), 0.0), 0.0);
+    }
+}
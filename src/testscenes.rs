@@ -0,0 +1,306 @@
+//! Standard scenes built entirely in code, for use in tests, benchmarks, and documentation images
+//!
+//! None of these scenes need any external assets, so they can be rendered anywhere. The scenes are
+//! kept small so they render quickly at low resolutions.
+
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::math::{Radians, Vec3, Uv, Rgb, RgbExt};
+use crate::scene::{HierScene, SceneNode, Geometry};
+use crate::primitive::{Plane, Cube, Sphere};
+use crate::material::Material;
+use crate::light::{Light, Parallelogram};
+use crate::camera::{CameraSettings, Camera};
+use crate::texture::{TextureSource, SkyGradient, sky_gradient};
+
+/// A scene along with the camera and background that it is meant to be rendered with
+#[derive(Debug)]
+pub struct TestScene {
+    pub scene: HierScene,
+    pub camera: CameraSettings,
+    pub background: SkyGradient,
+}
+
+/// The classic Cornell box: a white room with a red wall on the left, a green wall on the right,
+/// two white blocks on the floor, and a square area light in the ceiling
+///
+/// The room spans from -1.0 to 1.0 along every axis and is open towards +z, where the camera is.
+/// The light is visible to the camera.
+pub fn cornell_box() -> TestScene {
+    let white = Arc::new(Material {diffuse: Rgb::from(0.73), ..Material::default()});
+    let red = Arc::new(Material {diffuse: Rgb {r: 0.65, g: 0.05, b: 0.05}, ..Material::default()});
+    let green = Arc::new(Material {diffuse: Rgb {r: 0.12, g: 0.45, b: 0.15}, ..Material::default()});
+
+    // Planes face up, so each wall is rotated to face into the room
+    let wall = |material: &Arc<Material>| SceneNode::from(Geometry::new(Plane, material.clone())).scaled(2.0);
+
+    let scene = HierScene {
+        root: Arc::new(SceneNode::from(vec![
+            Arc::new(wall(&white).translated((0.0, -1.0, 0.0)).named("floor")),
+            Arc::new(wall(&white).rotated_x(Radians::from_degrees(180.0)).translated((0.0, 1.0, 0.0)).named("ceiling")),
+            Arc::new(wall(&white).rotated_x(Radians::from_degrees(90.0)).translated((0.0, 0.0, -1.0)).named("back wall")),
+            Arc::new(wall(&red).rotated_z(Radians::from_degrees(-90.0)).translated((-1.0, 0.0, 0.0)).named("left wall")),
+            Arc::new(wall(&green).rotated_z(Radians::from_degrees(90.0)).translated((1.0, 0.0, 0.0)).named("right wall")),
+
+            Arc::new(SceneNode::from(Geometry::new(Cube, white.clone()))
+                .scaled((0.6, 1.2, 0.6))
+                .rotated_y(Radians::from_degrees(17.0))
+                .translated((-0.35, -0.4, -0.35))
+                .named("tall block")),
+            Arc::new(SceneNode::from(Geometry::new(Cube, white.clone()))
+                .scaled(0.6)
+                .rotated_y(Radians::from_degrees(-17.0))
+                .translated((0.35, -0.7, 0.3))
+                .named("short block")),
+        ])),
+        lights: vec![
+            Light {
+                // Just below the ceiling so the light is not hidden by it
+                position: Vec3::new(0.0, 0.99, 0.0),
+                color: Rgb::from(0.9),
+                area: Parallelogram {
+                    a: Vec3::unit_x() * 0.25,
+                    b: Vec3::unit_z() * 0.25,
+                },
+                visible: true,
+                ..Light::default()
+            },
+        ],
+        ambient: Rgb::from(0.1),
+    };
+
+    TestScene {
+        scene,
+        camera: CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 3.9),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(40.0),
+        },
+        background: sky_gradient(Rgb::black(), Rgb::black()),
+    }
+}
+
+/// A sphere with the given diffuse albedo surrounded by a uniform white environment ("furnace")
+///
+/// There are no lights. The ambient light is the same as the background, so a sphere with an
+/// albedo of 1.0 reflects exactly as much light as it receives and disappears into the background.
+/// Any visible sphere means energy is being lost (darker) or created (brighter).
+pub fn furnace(albedo: f64) -> TestScene {
+    furnace_with(Arc::new(Material {diffuse: Rgb::from(albedo), ..Material::default()}))
+}
+
+/// The furnace scene (see `furnace`) with the given material on the sphere
+///
+/// Any material that reflects or transmits all of the light it receives (e.g. a perfect mirror,
+/// clear glass, or a mix of white diffuse and mirror that adds up to 1.0) should disappear.
+pub fn furnace_with(material: Arc<Material>) -> TestScene {
+    TestScene {
+        scene: HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Sphere, material))),
+            lights: Vec::new(),
+            ambient: Rgb::white(),
+        },
+        camera: CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 4.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(40.0),
+        },
+        background: sky_gradient(Rgb::white(), Rgb::white()),
+    }
+}
+
+/// Renders the furnace scene (see `furnace_with`) with the given material on the sphere and
+/// returns how far the sphere is from disappearing into the background
+///
+/// The result is the largest difference in luminance between any pixel of an image with the given
+/// size and the background, relative to the luminance of the background. Zero means that the
+/// material conserves energy exactly. Each pixel averages the given number of samples through its
+/// center, so stochastic materials (e.g. glossy reflection) need more samples to converge.
+pub fn furnace_error(material: Arc<Material>, (width, height): (usize, usize), samples: usize) -> f64 {
+    let TestScene {scene, camera, background} = furnace_with(material);
+    let camera = Camera::new(camera, (width as f64, height as f64));
+    // The background is uniform, so any texture coordinate will do
+    let background = background.at(Uv {u: 0.0, v: 0.0});
+    let samples = samples.max(1);
+
+    (0..width * height).into_par_iter().map(|i| {
+        let ray = camera.ray_at(((i % width) as f64 + 0.5, (i / width) as f64 + 0.5));
+        let total = (0..samples).fold(Rgb::black(), |total, _| total + ray.color(&scene, background, 0));
+        let color = total / samples as f64;
+
+        (color.luminance() - background.luminance()).abs() / background.luminance()
+    }).reduce(|| 0.0, f64::max)
+}
+
+/// A row of the given number of glossy spheres on a floor, going from a perfect mirror on the
+/// left to the roughest reflection on the right
+///
+/// The roughness is the `glossy_side_length` of each sphere, which increases evenly up to the
+/// given maximum.
+pub fn roughness_chart(spheres: usize, max_glossy_side_length: f64) -> TestScene {
+    let floor = Arc::new(Material {diffuse: Rgb::from(0.8), ..Material::default()});
+    let floor_other = Arc::new(Material {diffuse: Rgb::from(0.2), ..Material::default()});
+
+    let spacing = 2.5;
+    let row_width = spacing * spheres.saturating_sub(1) as f64;
+    let mut nodes = vec![
+        Arc::new(SceneNode::from(Geometry::checker_floor(floor, floor_other, 8.0))
+            .scaled(row_width + 8.0)
+            .translated((0.0, -1.0, 0.0))
+            .named("floor")),
+    ];
+    nodes.extend((0..spheres).map(|i| {
+        let roughness = if spheres > 1 { i as f64 / (spheres - 1) as f64 } else { 0.0 };
+        let material = Arc::new(Material {
+            diffuse: Rgb::from(0.1),
+            specular: Rgb::from(0.3),
+            shininess: 50.0,
+            reflectivity: 0.8,
+            glossy_side_length: roughness * max_glossy_side_length,
+            glossy_samples: 4,
+            ..Material::default()
+        });
+
+        Arc::new(SceneNode::from(Geometry::new(Sphere, material))
+            .translated((i as f64 * spacing - row_width / 2.0, 0.0, 0.0))
+            .named(format!("sphere {}", i)))
+    }));
+
+    TestScene {
+        scene: HierScene {
+            root: Arc::new(SceneNode::from(nodes)),
+            lights: vec![
+                Light {
+                    position: Vec3::new(-row_width / 2.0, 8.0, 6.0),
+                    color: Rgb::from(0.8),
+                    ..Light::default()
+                },
+            ],
+            ambient: Rgb::from(0.2),
+        },
+        camera: CameraSettings {
+            eye: Vec3::new(0.0, 2.0, row_width / 2.0 + 8.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(40.0),
+        },
+        background: SkyGradient::clear_day(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ray::Ray;
+    use crate::material::WINDOW_GLASS_REFRACTION_INDEX;
+    use crate::math::{sample_seed, seed_sample_rng};
+
+    #[test]
+    fn cornell_box_walls_are_colored() {
+        let TestScene {scene, camera, ..} = cornell_box();
+
+        let material_seen = |direction: Vec3| {
+            let (_, material) = scene.first_hit(&Ray::new(camera.eye, direction)).unwrap();
+            material.diffuse
+        };
+        assert_eq!(material_seen(Vec3::new(-1.0, 0.0, -3.9)).r, 0.65);
+        assert_eq!(material_seen(Vec3::new(1.0, 0.0, -3.9)).g, 0.45);
+        // The room is closed except towards the camera
+        for &direction in &[Vec3::up(), Vec3::down(), -Vec3::unit_z()] {
+            assert!(scene.first_hit(&Ray::new(Vec3::zero(), direction)).is_some());
+        }
+        assert!(scene.first_hit(&Ray::new(Vec3::new(0.0, 0.5, 0.5), Vec3::unit_z())).is_none());
+    }
+
+    /// Returns the light reflected by a point on a diffuse surface of the Cornell box, found by
+    /// integrating the direct light over the area of the light with the midpoint rule
+    ///
+    /// There is no global illumination, so this is the only light that should reach the point.
+    fn integrated_direct_radiance(scene: &HierScene, point: Vec3, normal: Vec3, albedo: Rgb) -> Rgb {
+        let light = &scene.lights[0];
+        let steps = 64;
+        let coord = |i: usize| (i as f64 + 0.5) / steps as f64 * 2.0 - 1.0;
+
+        let total: f64 = (0..steps * steps).map(|i| {
+            let light_pos = light.position + light.area.a * coord(i % steps) + light.area.b * coord(i / steps);
+            normal.dot((light_pos - point).normalized()).max(0.0)
+        }).sum();
+        albedo * (scene.ambient + light.color * total / (steps * steps) as f64)
+    }
+
+    /// A self-consistency check: the Monte Carlo estimate of the light reaching a few points of the
+    /// walls must converge to the same lighting model integrated deterministically
+    ///
+    /// This is not a comparison against published Cornell box measurements. Those include the
+    /// light bouncing between the walls, which the renderer does not simulate, so they can not
+    /// be matched yet.
+    //TODO: Compare against published Cornell box reference radiance (within a tolerance derived
+    // from the measurement error) once there is a global illumination integrator to test
+    #[test]
+    fn cornell_box_direct_light_converges_to_integral() {
+        let TestScene {scene, camera, ..} = cornell_box();
+        let samples = 4096;
+
+        let points = [
+            // The back wall, above the blocks
+            (Vec3::new(0.0, 0.5, -1.0), Vec3::unit_z(), Rgb::from(0.73)),
+            // The red wall
+            (Vec3::new(-1.0, 0.0, 0.0), Vec3::unit_x(), Rgb {r: 0.65, g: 0.05, b: 0.05}),
+            // The green wall
+            (Vec3::new(1.0, 0.0, 0.0), -Vec3::unit_x(), Rgb {r: 0.12, g: 0.45, b: 0.15}),
+        ];
+        for (index, &(point, normal, albedo)) in points.iter().enumerate() {
+            let ray = Ray::new(camera.eye, point - camera.eye);
+            // Seeded so that the test is not flaky
+            let total = (0..samples).fold(Rgb::black(), |total, sample| {
+                seed_sample_rng(Some(sample_seed(1049, (index, 0), sample)));
+                total + ray.color(&scene, Rgb::black(), 0)
+            });
+            seed_sample_rng(None);
+            let color = total / samples as f64;
+
+            let expected = integrated_direct_radiance(&scene, point, normal, albedo);
+            let error = ((color - expected) / expected).map(f64::abs).reduce_partial_max();
+            assert!(error < 0.01, "{:?}: expected {:?}, got {:?}", point, expected, color);
+        }
+    }
+
+    #[test]
+    fn energy_conserving_materials_pass_furnace_test() {
+        let size = (16, 16);
+        assert!(furnace_error(Arc::new(Material {diffuse: Rgb::white(), ..Material::default()}), size, 1) < 1e-9);
+        let mirror = Material {diffuse: Rgb::black(), reflectivity: 1.0, ..Material::default()};
+        assert!(furnace_error(Arc::new(mirror.clone()), size, 1) < 1e-9);
+        let half_mirror = Material {diffuse: Rgb::from(0.5), reflectivity: 0.5, ..Material::default()};
+        assert!(furnace_error(Arc::new(half_mirror), size, 1) < 1e-9);
+        let glossy = Material {glossy_side_length: 0.5, glossy_samples: 4, ..mirror.clone()};
+        assert!(furnace_error(Arc::new(glossy), size, 4) < 1e-9);
+        // Rays that would barely contribute to the pixel are not cast, so some light is lost
+        // inside of the glass
+        let glass = Material {refraction_index: WINDOW_GLASS_REFRACTION_INDEX, ..mirror};
+        assert!(furnace_error(Arc::new(glass), size, 1) < 1e-2);
+    }
+
+    #[test]
+    fn furnace_test_catches_energy_violations() {
+        let size = (16, 16);
+        // Reflects 150% of the light it receives
+        let bright = Material {diffuse: Rgb::white(), reflectivity: 0.5, ..Material::default()};
+        assert!(furnace_error(Arc::new(bright), size, 1) > 0.4);
+        assert!((furnace_error(Arc::new(Material {diffuse: Rgb::from(0.5), ..Material::default()}), size, 1) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn roughness_increases_along_chart() {
+        let chart = roughness_chart(5, 0.5);
+        let roughness: Vec<_> = chart.scene.root.children()[1..].iter()
+            .map(|node| node.geometry().unwrap().material.glossy_side_length)
+            .collect();
+        assert_eq!(roughness, [0.0, 0.125, 0.25, 0.375, 0.5]);
+    }
+}
@@ -1,7 +1,12 @@
 use std::fmt;
+use std::env;
+use std::mem;
 use std::path::Path;
 
+use image::{RgbImage, GenericImageView};
+
 use crate::math::{GAMMA, Uv, Rgb, Vec3, Mat3};
+use crate::error::{ErrorCode, format_warning};
 
 pub trait TextureSource {
     /// Sample the texture at the given point.
@@ -18,10 +23,82 @@ impl<T> TextureSource for T where T: Fn(Uv) -> Rgb {
     }
 }
 
+/// Selects which set of texture coordinates is used to sample a texture
+///
+/// Meshes may have a secondary UV set in addition to their primary UV set. This allows, for
+/// example, a baked lightmap to use a different (non-overlapping) UV layout than the color texture.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UvChannel {
+    /// The primary texture coordinates (e.g. the ones loaded from an OBJ file)
+    #[default]
+    Primary,
+    /// The secondary texture coordinates (only supported by meshes)
+    Secondary,
+}
+
+/// A rectangular region of texture coordinates
+///
+/// Coordinates outside of the range 0.0 to 1.0 wrap around, just like when a texture is sampled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvRect {
+    /// The corner of the region with the smallest texture coordinates
+    pub min: Uv,
+    /// The corner of the region with the largest texture coordinates
+    pub max: Uv,
+}
+
+impl UvRect {
+    /// The region covering the entire texture once
+    pub fn full() -> Self {
+        Self {
+            min: Uv {u: 0.0, v: 0.0},
+            max: Uv {u: 1.0, v: 1.0},
+        }
+    }
+}
+
+/// A vertical gradient, typically used as the background of a scene
+///
+/// The colors are in linear space and are not affected by any other setting, so the background
+/// looks the same no matter how the exposure of the rest of the image is adjusted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkyGradient {
+    /// The color at the top of the texture (v = 0.0)
+    pub top: Rgb,
+    /// The color at the bottom of the texture (v = 1.0)
+    pub bottom: Rgb,
+}
+
+impl SkyGradient {
+    /// The grey-blue to blue gradient used in most of the examples
+    pub fn dusk() -> Self {
+        sky_gradient(Rgb {r: 0.2, g: 0.4, b: 0.6}, Rgb::blue())
+    }
+
+    /// A light blue to deep blue gradient, like a clear day
+    pub fn clear_day() -> Self {
+        sky_gradient(Rgb {r: 0.529, g: 0.808, b: 0.922}, Rgb {r: 0.086, g: 0.38, b: 0.745})
+    }
+}
+
+impl TextureSource for SkyGradient {
+    fn at(&self, uv: Uv) -> Rgb {
+        self.top * (1.0 - uv.v) + self.bottom * uv.v
+    }
+}
+
+/// Creates a vertical gradient from the given top color to the given bottom color
+pub fn sky_gradient(top: Rgb, bottom: Rgb) -> SkyGradient {
+    SkyGradient {top, bottom}
+}
+
+/// The number of points sampled along each side of a region of a texture created from a function
+const REGION_SAMPLES: usize = 16;
+
 /// A type that encapsulates all supported texture types
 pub enum Texture {
     /// A texture created from a function
-    FnTex(Box<Fn(Uv) -> Rgb + Send + Sync>),
+    FnTex(Box<dyn Fn(Uv) -> Rgb + Send + Sync>),
     /// A texture created from an image
     Image(ImageTexture),
 }
@@ -40,9 +117,10 @@ impl PartialEq for Texture {
     fn eq(&self, other: &Self) -> bool {
         use Texture::*;
         match (self, other) {
-            //TODO: Not actually used in tests so I will implement this when it is needed
-            (FnTex(_), FnTex(_)) => unimplemented!(),
-            (Image(img), Image(img2)) => img.eq(&img2),
+            // Functions can not be compared, so only a texture is equal to itself (e.g. when two
+            // materials share the same Arc)
+            (FnTex(_), FnTex(_)) => std::ptr::eq(self, other),
+            (Image(img), Image(img2)) => img.eq(img2),
             _ => false,
         }
     }
@@ -60,6 +138,43 @@ impl From<ImageTexture> for Texture {
     }
 }
 
+impl Texture {
+    /// Returns the estimated number of bytes used by this texture
+    ///
+    /// Textures created from functions are assumed to not use any additional memory.
+    pub fn memory_size(&self) -> usize {
+        use Texture::*;
+        mem::size_of::<Self>() + match self {
+            FnTex(_) => 0,
+            Image(img) => img.buffer.memory_size(),
+        }
+    }
+
+    /// Returns the average color (in linear space) of the given region of this texture
+    ///
+    /// Image textures average every texel in the region after converting it to linear space, so
+    /// the result is the color the region would appear to have from far away. Textures created
+    /// from functions are sampled on an evenly spaced grid of points. Useful for choosing an
+    /// exposure, picking an ambient color that matches an environment, or checking that a
+    /// texture was loaded correctly.
+    pub fn sample_region(&self, rect: UvRect) -> Rgb {
+        use Texture::*;
+        match self {
+            FnTex(f) => {
+                let size = rect.max - rect.min;
+                let total = (0..REGION_SAMPLES).flat_map(|i| (0..REGION_SAMPLES).map(move |j| (i, j)))
+                    .map(|(i, j)| f.at(Uv {
+                        u: rect.min.u + size.u * (i as f64 + 0.5) / REGION_SAMPLES as f64,
+                        v: rect.min.v + size.v * (j as f64 + 0.5) / REGION_SAMPLES as f64,
+                    }))
+                    .fold(Rgb::black(), |total, color| total + color);
+                total / (REGION_SAMPLES * REGION_SAMPLES) as f64
+            },
+            Image(img) => img.sample_region(rect),
+        }
+    }
+}
+
 impl TextureSource for Texture {
     fn at(&self, uv: Uv) -> Rgb {
         use Texture::*;
@@ -70,7 +185,62 @@ impl TextureSource for Texture {
     }
 }
 
+/// Collects warnings about assets that could not be loaded and were replaced with placeholders
+///
+/// Useful for still rendering a scene with a few bad paths so it can be inspected.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AssetWarnings {
+    warnings: Vec<String>,
+    /// The kind of failure behind each warning
+    codes: Vec<ErrorCode>,
+}
+
+impl fmt::Display for AssetWarnings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, warning) in self.warnings.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", format_warning(self.codes[i], warning))?;
+        }
+        Ok(())
+    }
+}
+
+impl AssetWarnings {
+    /// Returns true if no assets were replaced with placeholders
+    pub fn is_empty(&self) -> bool {
+        self.warnings.is_empty()
+    }
+
+    /// Returns the warnings collected so far, in the order they occurred
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Returns the kind of failure behind each warning, in the same order as `warnings()`
+    pub fn codes(&self) -> &[ErrorCode] {
+        &self.codes
+    }
+
+    fn missing<P: AsRef<Path>>(&mut self, path: P, err: image::ImageError) {
+        self.codes.push(ErrorCode::from_image_error(&err));
+        self.warnings.push(format!("failed to load '{}', using placeholder: {}", path.as_ref().display(), err));
+    }
+}
+
+/// Returns the maximum width/height of loaded images from the `MAX_TEXTURE_SIZE` environment
+/// variable, ignoring invalid values
+fn max_texture_size() -> Option<u32> {
+    env::var("MAX_TEXTURE_SIZE").ok()
+        .and_then(|val| val.parse::<u32>().ok())
+        .filter(|&val| val > 0)
+}
+
 /// A buffer that directly loads the pixel values without doing any correction
+///
+/// Pixels are kept in 8-bit per channel and only converted to floating point when they are
+/// sampled, which uses much less memory than storing `Rgb` values.
 struct RgbImageBuffer {
     buffer: image::RgbImage,
 }
@@ -94,10 +264,55 @@ impl PartialEq for RgbImageBuffer {
 }
 
 impl RgbImageBuffer {
+    /// Returns the number of bytes used by the pixels of this buffer
+    pub fn memory_size(&self) -> usize {
+        self.buffer.len() * mem::size_of::<u8>()
+    }
+
     /// Creates an image buffer from the image file at the given path
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, image::ImageError> {
-        let img = image::open(path)?.to_rgb();
-        Ok(Self::from(img))
+        Ok(Self::from_image(&image::open(path)?))
+    }
+
+    /// Creates an image buffer by decoding the given bytes of an image file
+    ///
+    /// The image format is guessed from the contents of the bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, image::ImageError> {
+        Ok(Self::from_image(&image::load_from_memory(bytes)?))
+    }
+
+    /// Creates an image buffer from an already decoded image
+    ///
+    /// The image is downsampled if it is larger than the `MAX_TEXTURE_SIZE` environment variable.
+    pub fn from_image(img: &image::DynamicImage) -> Self {
+        Self::from_image_with_max_size(img, max_texture_size())
+    }
+
+    /// Creates an image buffer from an already decoded image, downsampling it (preserving its
+    /// aspect ratio) so that neither its width nor its height is greater than the given size
+    fn from_image_with_max_size(img: &image::DynamicImage, max_size: Option<u32>) -> Self {
+        match max_size {
+            Some(max_size) if img.width() > max_size || img.height() > max_size => {
+                Self::from(img.resize(max_size, max_size, image::FilterType::Triangle).to_rgb())
+            },
+            _ => Self::from(img.to_rgb()),
+        }
+    }
+
+    /// Returns the colors of every pixel in the given region, without doing any correction
+    ///
+    /// The pixels are chosen the same way as `at`, so every pixel that `at` could return for a
+    /// point in the region is included exactly once per time the region wraps around.
+    fn pixels_in(&self, rect: UvRect) -> impl Iterator<Item=Rgb> + '_ {
+        let width = self.buffer.width() as i64;
+        let height = self.buffer.height() as i64;
+        let xs = (rect.min.u * (width - 1) as f64) as i64..=(rect.max.u * (width - 1) as f64) as i64;
+        let ys = (rect.min.v * (height - 1) as f64) as i64..=(rect.max.v * (height - 1) as f64) as i64;
+
+        ys.flat_map(move |y| xs.clone().map(move |x| (x, y))).map(move |(x, y)| {
+            let [r, g, b] = self.buffer.get_pixel(x.rem_euclid(width) as u32, y.rem_euclid(height) as u32).data;
+            Rgb {r: r as f64, g: g as f64, b: b as f64} / 255.0
+        })
     }
 }
 
@@ -145,6 +360,13 @@ impl TextureSource for RgbImageBuffer {
 /// All colors are converted from sRGB space (gamma corrected) to linear space using a gamma of 2.2
 /// before they are used in the scene. These colors are then converted back to sRGB space at the
 /// end of the rendering process.
+///
+/// Small textures can be embedded directly into the executable so that they do not depend on the
+/// assets directory being present:
+///
+/// ```rust,ignore
+/// let tex = ImageTexture::from_bytes(include_bytes!("../assets/shrub.png"))?;
+/// ```
 #[derive(Debug, PartialEq)]
 pub struct ImageTexture {
     buffer: RgbImageBuffer,
@@ -157,6 +379,60 @@ impl ImageTexture {
             buffer: RgbImageBuffer::open(path)?,
         })
     }
+
+    /// Creates an image texture by decoding the given bytes of an image file (e.g. the bytes
+    /// returned from `include_bytes!`)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, image::ImageError> {
+        Ok(Self {
+            buffer: RgbImageBuffer::from_bytes(bytes)?,
+        })
+    }
+
+    /// Creates an image texture from an already decoded image
+    pub fn from_image(img: &image::DynamicImage) -> Self {
+        Self {
+            buffer: RgbImageBuffer::from_image(img),
+        }
+    }
+
+    /// Creates an image texture from the image file at the given path, or returns a placeholder
+    /// texture if the image could not be loaded. A warning is added for every placeholder used.
+    pub fn open_or_placeholder<P: AsRef<Path>>(path: P, warnings: &mut AssetWarnings) -> Self {
+        Self::open(&path).unwrap_or_else(|err| {
+            warnings.missing(path, err);
+            Self::placeholder()
+        })
+    }
+
+    /// A magenta and black checkerboard texture that is used in place of missing textures
+    ///
+    /// Chosen to be as obvious as possible in the final image.
+    pub fn placeholder() -> Self {
+        const SQUARES: u32 = 8;
+        let img = RgbImage::from_fn(SQUARES, SQUARES, |x, y| if (x + y) % 2 == 0 {
+            image::Rgb([255, 0, 255])
+        } else {
+            image::Rgb([0, 0, 0])
+        });
+
+        Self {
+            buffer: RgbImageBuffer::from(img),
+        }
+    }
+
+    /// Returns the average color (in linear space) of the given region of this texture
+    ///
+    /// See `Texture::sample_region` for more details.
+    pub fn sample_region(&self, rect: UvRect) -> Rgb {
+        // Each pixel is converted to linear space before averaging since averaging the
+        // gamma-corrected values would make the result too dark
+        let (total, count) = self.buffer.pixels_in(rect)
+            .fold((Rgb::black(), 0), |(total, count), color| (total + color.map(|c| c.powf(GAMMA)), count + 1));
+        if count == 0 {
+            return Rgb::black();
+        }
+        total / count as f64
+    }
 }
 
 impl TextureSource for ImageTexture {
@@ -184,6 +460,45 @@ impl NormalMap {
         })
     }
 
+    /// Creates a normal map by decoding the given bytes of an image file (e.g. the bytes returned
+    /// from `include_bytes!`)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, image::ImageError> {
+        Ok(Self {
+            buffer: RgbImageBuffer::from_bytes(bytes)?,
+        })
+    }
+
+    /// Creates a normal map from an already decoded image
+    pub fn from_image(img: &image::DynamicImage) -> Self {
+        Self {
+            buffer: RgbImageBuffer::from_image(img),
+        }
+    }
+
+    /// Creates a normal map from the image file at the given path, or returns a placeholder
+    /// normal map if the image could not be loaded. A warning is added for every placeholder used.
+    pub fn open_or_placeholder<P: AsRef<Path>>(path: P, warnings: &mut AssetWarnings) -> Self {
+        Self::open(&path).unwrap_or_else(|err| {
+            warnings.missing(path, err);
+            Self::placeholder()
+        })
+    }
+
+    /// A normal map where every normal is perpendicular to the surface
+    ///
+    /// Used in place of missing normal maps so that the surface is shaded as if it had no normal
+    /// map at all.
+    pub fn placeholder() -> Self {
+        Self {
+            buffer: RgbImageBuffer::from(RgbImage::from_pixel(1, 1, image::Rgb([128, 128, 255]))),
+        }
+    }
+
+    /// Returns the estimated number of bytes used by this normal map
+    pub fn memory_size(&self) -> usize {
+        mem::size_of::<Self>() + self.buffer.memory_size()
+    }
+
     /// Loads a normal from the buffer and transforms it so that it is in the same right-handed
     /// coordinate system as the rest of the ray tracer. A normal perpendicular to the surface will
     /// point along the +Y axis.
@@ -220,3 +535,86 @@ impl NormalMap {
         normal_to_rh * norm
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{ColorType, png::PNGEncoder};
+
+    #[test]
+    fn sky_gradient_blends_top_to_bottom() {
+        let sky = sky_gradient(Rgb::white(), Rgb::black());
+        assert_eq!(sky.at(Uv {u: 0.3, v: 0.0}), Rgb::white());
+        assert_eq!(sky.at(Uv {u: 0.7, v: 1.0}), Rgb::black());
+        assert_eq!(sky.at(Uv {u: 0.5, v: 0.25}), Rgb::broadcast(0.75));
+
+        // Matches the closure that the examples used to write out by hand
+        let uv = Uv {u: 0.1, v: 0.6};
+        let by_hand = Rgb {r: 0.2, g: 0.4, b: 0.6} * (1.0 - uv.v) + Rgb::blue() * uv.v;
+        assert_eq!(SkyGradient::dusk().at(uv), by_hand);
+    }
+
+    #[test]
+    fn from_bytes_matches_from_image() {
+        let img = RgbImage::from_fn(4, 4, |x, y| image::Rgb([x as u8 * 60, y as u8 * 60, 255]));
+        let mut bytes = Vec::new();
+        PNGEncoder::new(&mut bytes).encode(&img, img.width(), img.height(), ColorType::RGB(8))
+            .expect("bug: failed to encode png");
+
+        let decoded = ImageTexture::from_bytes(&bytes).expect("bug: failed to decode png");
+        let expected = ImageTexture::from_image(&image::DynamicImage::ImageRgb8(img));
+        assert_eq!(decoded, expected);
+
+        let invalid = ImageTexture::from_bytes(b"not an image");
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn missing_textures_use_placeholders() {
+        let mut warnings = AssetWarnings::default();
+        let tex = ImageTexture::open_or_placeholder("does/not/exist.png", &mut warnings);
+        let normals = NormalMap::open_or_placeholder("does/not/exist_normals.png", &mut warnings);
+
+        assert_eq!(tex, ImageTexture::placeholder());
+        assert_eq!(normals, NormalMap::placeholder());
+        assert_eq!(warnings.warnings().len(), 2);
+        assert!(warnings.warnings()[0].contains("does/not/exist.png"));
+        assert_eq!(warnings.codes(), &[ErrorCode::MissingAsset, ErrorCode::MissingAsset]);
+        assert!(warnings.to_string().starts_with("warning[E0100]: failed to load 'does/not/exist.png'"));
+    }
+
+    #[test]
+    fn large_textures_are_downsampled() {
+        let img = image::DynamicImage::ImageRgb8(RgbImage::from_fn(64, 16, |x, _| {
+            if x < 32 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) }
+        }));
+
+        let buffer = RgbImageBuffer::from_image_with_max_size(&img, Some(16));
+        assert_eq!(buffer.buffer.dimensions(), (16, 4));
+        assert_eq!(buffer.memory_size(), 16 * 4 * 3);
+        // The contents of the image are preserved
+        assert_eq!(buffer.at(Uv {u: 0.1, v: 0.5}), Rgb::red());
+        assert_eq!(buffer.at(Uv {u: 0.9, v: 0.5}), Rgb::blue());
+
+        // Images that are already small enough are unchanged
+        let buffer = RgbImageBuffer::from_image_with_max_size(&img, Some(64));
+        assert_eq!(buffer.buffer.dimensions(), (64, 16));
+    }
+
+    #[test]
+    fn sampled_regions_are_averaged_in_linear_space() {
+        // Half of the placeholder's pixels are magenta and half are black
+        let tex = Texture::from(ImageTexture::placeholder());
+        let average = tex.sample_region(UvRect::full());
+        assert!((average - Rgb {r: 0.5, g: 0.0, b: 0.5}).map(f64::abs).reduce_partial_max() < 1e-12);
+
+        // A region containing a single pixel is just the color of that pixel
+        let corner = tex.sample_region(UvRect {min: Uv {u: 0.0, v: 0.0}, max: Uv {u: 0.1, v: 0.1}});
+        assert_eq!(corner, Rgb {r: 1.0, g: 0.0, b: 1.0});
+
+        let gradient = Texture::from(|uv: Uv| Rgb {r: uv.u, g: uv.v, b: 1.0});
+        let average = gradient.sample_region(UvRect {min: Uv {u: 0.0, v: 0.5}, max: Uv {u: 1.0, v: 1.0}});
+        assert!((average - Rgb {r: 0.5, g: 0.75, b: 1.0}).map(f64::abs).reduce_partial_max() < 1e-12);
+    }
+}
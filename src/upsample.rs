@@ -0,0 +1,221 @@
+//! Joint bilateral upsampling of light rendered at half resolution, guided by the geometry seen by
+//! each pixel at full resolution (see `AovPasses`)
+//!
+//! Each full resolution pixel blends the nearest half resolution pixels, but only the ones that
+//! see a similar surface (a similar normal and depth). This keeps the upsampled light from
+//! bleeding across the edges of objects.
+
+use crate::math::{EPSILON, Vec3, Rgb};
+use crate::aov::AovPasses;
+
+/// How sharply the weight of a half resolution pixel falls off as its normal turns away from the
+/// normal of the full resolution pixel (the power applied to the cosine between them)
+const NORMAL_SHARPNESS: f64 = 32.0;
+
+/// The difference in depth (relative to the depth of the full resolution pixel) at which the
+/// weight of a half resolution pixel has fallen to about a third
+const DEPTH_TOLERANCE: f64 = 0.05;
+
+/// The surface seen by a pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Guide {
+    /// Normalized and facing the camera
+    pub normal: Vec3,
+    /// The distance from the camera
+    pub depth: f64,
+}
+
+impl Guide {
+    /// Returns the surface seen by the given pixel of the given passes, or None if the pixel sees
+    /// nothing
+    pub fn at(passes: &AovPasses, pixel: (usize, usize)) -> Option<Self> {
+        Some(Self {normal: passes.normal(pixel)?, depth: passes.depth(pixel)?})
+    }
+
+    /// Returns how much a half resolution pixel seeing the given surface should contribute to a
+    /// full resolution pixel seeing the given surface, from 0.0 to 1.0
+    ///
+    /// Pixels that both see nothing have the full weight. A pixel that sees nothing never
+    /// contributes to a pixel that sees something (and vice versa).
+    pub fn weight(pixel: Option<Self>, sample: Option<Self>) -> f64 {
+        match (pixel, sample) {
+            (Some(pixel), Some(sample)) => {
+                let normal_weight = pixel.normal.dot(sample.normal).max(0.0).powf(NORMAL_SHARPNESS);
+                let depth_change = (pixel.depth - sample.depth) / (DEPTH_TOLERANCE * pixel.depth).max(EPSILON);
+                normal_weight * (-depth_change * depth_change).exp()
+            },
+            (None, None) => 1.0,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Light rendered at half the resolution of an image, where each half resolution pixel covers a
+/// 2x2 block of full resolution pixels
+#[derive(Debug, Clone)]
+pub struct HalfResLight {
+    width: usize,
+    height: usize,
+    /// The light of each half resolution pixel (None if it was not rendered), row by row
+    colors: Vec<Option<Rgb>>,
+    /// The average surface seen by the pixels of each block, row by row
+    guides: Vec<Option<Guide>>,
+}
+
+impl HalfResLight {
+    /// Creates half resolution light for an image with the size of the given passes from the
+    /// light of the given half resolution pixels
+    ///
+    /// Half resolution pixels that are not given are never used by `HalfResLight::upsample`.
+    pub fn new<I>(passes: &AovPasses, colors: I) -> Self
+        where I: IntoIterator<Item=((usize, usize), Rgb)>,
+    {
+        let (width, height) = half_size((passes.width(), passes.height()));
+
+        let mut light_colors = vec![None; width * height];
+        for ((x, y), color) in colors {
+            light_colors[y * width + x] = Some(color);
+        }
+
+        let guides = (0..width * height).map(|i| {
+            let (x, y) = (i % width, i / width);
+            let block: Vec<_> = block_pixels((x, y), (passes.width(), passes.height()))
+                .filter_map(|pixel| Guide::at(passes, pixel))
+                .collect();
+            if block.is_empty() {
+                return None;
+            }
+
+            let normal = block.iter().fold(Vec3::zero(), |total, guide| total + guide.normal);
+            let depth = block.iter().map(|guide| guide.depth).sum::<f64>() / block.len() as f64;
+            Some(Guide {
+                // Opposite normals in the same block are too different to share light anyway
+                normal: if normal.magnitude_squared() > EPSILON { normal.normalized() } else { block[0].normal },
+                depth,
+            })
+        }).collect();
+
+        Self {width, height, colors: light_colors, guides}
+    }
+
+    /// Returns the light at the given full resolution pixel, seeing the given surface
+    ///
+    /// The four nearest half resolution pixels are blended bilinearly, weighted by how similar the
+    /// surfaces seen by them are to the given surface (see `Guide::weight`). If none of them are
+    /// similar, they are blended without considering the surfaces so the pixel is not left black.
+    pub fn upsample(&self, (x, y): (usize, usize), guide: Option<Guide>) -> Rgb {
+        // The position of the center of the pixel in half resolution pixels
+        let half_x = ((x as f64 + 0.5) / 2.0 - 0.5).max(0.0);
+        let half_y = ((y as f64 + 0.5) / 2.0 - 0.5).max(0.0);
+        let (x0, y0) = (half_x.floor() as usize, half_y.floor() as usize);
+        let (fx, fy) = (half_x - x0 as f64, half_y - y0 as f64);
+
+        let neighbors = [
+            (x0, y0, (1.0 - fx) * (1.0 - fy)),
+            (x0 + 1, y0, fx * (1.0 - fy)),
+            (x0, y0 + 1, (1.0 - fx) * fy),
+            (x0 + 1, y0 + 1, fx * fy),
+        ];
+        let samples: Vec<_> = neighbors.iter()
+            .filter(|&&(nx, ny, _)| nx < self.width && ny < self.height)
+            .filter_map(|&(nx, ny, weight)| {
+                let index = ny * self.width + nx;
+                self.colors[index].map(|color| (color, weight, self.guides[index]))
+            })
+            .collect();
+
+        let blend = |weight_of: &dyn Fn(f64, Option<Guide>) -> f64| {
+            let (total, total_weight) = samples.iter().fold((Rgb::black(), 0.0), |(total, total_weight), &(color, weight, sample)| {
+                let weight = weight_of(weight, sample);
+                (total + color * weight, total_weight + weight)
+            });
+            if total_weight > EPSILON { Some(total / total_weight) } else { None }
+        };
+
+        blend(&|weight, sample| weight * Guide::weight(guide, sample))
+            .or_else(|| blend(&|weight, _| weight))
+            .unwrap_or_else(Rgb::black)
+    }
+}
+
+/// Returns the size of the half resolution version of an image with the given size
+pub fn half_size((width, height): (usize, usize)) -> (usize, usize) {
+    (width.div_ceil(2), height.div_ceil(2))
+}
+
+/// Returns the full resolution pixels of the given half resolution pixel that are inside of an
+/// image with the given size
+pub fn block_pixels((x, y): (usize, usize), (width, height): (usize, usize)) -> impl Iterator<Item=(usize, usize)> {
+    (2 * y..(2 * y + 2).min(height))
+        .flat_map(move |py| (2 * x..(2 * x + 2).min(width)).map(move |px| (px, py)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::math::Radians;
+    use crate::scene::{HierScene, SceneNode, Geometry};
+    use crate::material::Material;
+    use crate::primitive::Cube;
+    use crate::camera::CameraSettings;
+
+    #[test]
+    fn weights_follow_the_surface() {
+        let floor = Some(Guide {normal: Vec3::up(), depth: 10.0});
+        assert_eq!(Guide::weight(floor, floor), 1.0);
+        assert_eq!(Guide::weight(None, None), 1.0);
+        assert_eq!(Guide::weight(floor, None), 0.0);
+        assert_eq!(Guide::weight(None, floor), 0.0);
+
+        // A wall seen at the same depth and a floor seen much further away share almost no light
+        let wall = Some(Guide {normal: Vec3::unit_z(), depth: 10.0});
+        assert!(Guide::weight(floor, wall) < 1e-6);
+        let far_floor = Some(Guide {normal: Vec3::up(), depth: 15.0});
+        assert!(Guide::weight(floor, far_floor) < 1e-6);
+        // Nearby points on the same surface share most of their light
+        let near_floor = Some(Guide {normal: Vec3::up(), depth: 10.1});
+        assert!(Guide::weight(floor, near_floor) > 0.9);
+    }
+
+    #[test]
+    fn light_does_not_bleed_across_edges() {
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(Geometry::new(Cube, Arc::new(Material::default())))),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        let camera = CameraSettings {
+            eye: Vec3::new(0.0, 0.0, 5.0),
+            center: Vec3::zero(),
+            up: Vec3::up(),
+            fovy: Radians::from_degrees(45.0),
+        };
+        let passes = AovPasses::new(&scene, camera, (31, 31));
+
+        // Only the half resolution pixels that see the cube have any light
+        let (width, height) = half_size((31, 31));
+        let light = HalfResLight::new(&passes, (0..width * height).map(|i| {
+            let block = (i % width, i / width);
+            let sees_cube = block_pixels(block, (31, 31)).any(|pixel| passes.depth(pixel).is_some());
+            (block, if sees_cube { Rgb::white() } else { Rgb::black() })
+        }));
+
+        for y in 0..31 {
+            for x in 0..31 {
+                let guide = Guide::at(&passes, (x, y));
+                let expected = if guide.is_some() { Rgb::white() } else { Rgb::black() };
+                assert_eq!(light.upsample((x, y), guide), expected, "{:?}", (x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_stay_inside_image() {
+        assert_eq!(half_size((5, 4)), (3, 2));
+        assert_eq!(block_pixels((0, 0), (5, 4)).collect::<Vec<_>>(), [(0, 0), (1, 0), (0, 1), (1, 1)]);
+        assert_eq!(block_pixels((2, 1), (5, 4)).collect::<Vec<_>>(), [(4, 2), (4, 3)]);
+    }
+}
@@ -0,0 +1,407 @@
+//! Checks for common mistakes in scenes that cause rendering artifacts
+
+use std::fmt;
+use std::sync::Arc;
+use std::collections::HashSet;
+
+use crate::math::{Vec3, Vec3Ext, Mat4};
+use crate::scene::{HierScene, SceneNode};
+use crate::primitive::Primitive;
+use crate::material::{Material, EnergyViolation};
+
+/// The minimum value of the dot product between the normals of two faces for them to be
+/// considered parallel (about 0.1 degrees)
+const PARALLEL_THRESHOLD: f64 = 0.999_998;
+
+/// Identifies a node in the scene by the index of each child on the way down from the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodePath {
+    /// The index of the child taken at each level of the hierarchy (empty for the root)
+    pub indices: Vec<usize>,
+    /// The name of the node (if any)
+    pub name: Option<String>,
+}
+
+impl fmt::Display for NodePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "root")?;
+        for index in &self.indices {
+            write!(f, "/{}", index)?;
+        }
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Two flat faces of different nodes that overlap on (almost) the same plane
+///
+/// Rays hit these faces at the same distance, so which face is seen is decided by floating point
+/// error. This shows up as flickering or speckles ("z-fighting"). To fix it, translate one of the
+/// nodes slightly along the normal so that one face is clearly in front of the other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoplanarOverlap {
+    /// The overlapping nodes
+    pub nodes: [NodePath; 2],
+    /// The normal (in world space) of the face of the first node
+    pub normal: Vec3,
+    /// The distance between the planes of the two faces
+    pub distance: f64,
+}
+
+impl fmt::Display for CoplanarOverlap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Coplanar faces of {} and {} overlap (distance: {:e}, normal: ({:.3}, {:.3}, {:.3}))",
+            self.nodes[0], self.nodes[1], self.distance, self.normal.x, self.normal.y, self.normal.z)
+    }
+}
+
+/// A material used in the scene that does not conserve energy
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialEnergyWarning {
+    /// The first node found that uses the material
+    pub node: NodePath,
+    /// What is wrong with the material
+    pub violation: EnergyViolation,
+}
+
+impl fmt::Display for MaterialEnergyWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Material of {} does not conserve energy: {}", self.node, self.violation)
+    }
+}
+
+/// A flat parallelogram-shaped face of a primitive in world space
+#[derive(Debug, Clone)]
+struct Face {
+    /// The index of the node that this face belongs to
+    node: usize,
+    center: Vec3,
+    /// Half of each side of the parallelogram, with u x v pointing in the direction of the normal
+    u: Vec3,
+    v: Vec3,
+    /// The (normalized) normal of the face
+    normal: Vec3,
+    /// True if the face can be seen from both sides
+    two_sided: bool,
+}
+
+impl Face {
+    /// Transforms a face of a unit primitive given in model space to world space
+    fn new(node: usize, trans: Mat4, center: Vec3, u: Vec3, v: Vec3, two_sided: bool) -> Self {
+        let u = u.transformed_direction(trans);
+        let v = v.transformed_direction(trans);
+        Self {
+            node,
+            center: center.transformed_point(trans),
+            u,
+            v,
+            normal: u.cross(v).normalized(),
+            two_sided,
+        }
+    }
+
+    /// Returns the corners of this face in order around the parallelogram
+    fn corners(&self) -> [Vec3; 4] {
+        let Face {center, u, v, ..} = *self;
+        [center - u - v, center + u - v, center + u + v, center - u + v]
+    }
+
+    /// Returns true if this face and the given face (assumed to be on the same plane) overlap by
+    /// more than the given tolerance
+    ///
+    /// Faces that only touch along their edges (e.g. tiles next to each other) do not overlap.
+    fn overlaps(&self, other: &Face, tolerance: f64) -> bool {
+        let (corners, other_corners) = (self.corners(), other.corners());
+
+        // Separating axis theorem: the faces do not overlap if there is an edge of either face
+        // that separates them
+        let edges = [self.u, self.v, other.u, other.v];
+        edges.iter().all(|&edge| {
+            let axis = edge.cross(self.normal).normalized();
+            let project = |corners: &[Vec3; 4]| corners.iter().map(|&corner| corner.dot(axis))
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), x| (min.min(x), max.max(x)));
+
+            let (min, max) = project(&corners);
+            let (other_min, other_max) = project(&other_corners);
+            max.min(other_max) - min.max(other_min) > tolerance
+        })
+    }
+}
+
+/// Collects the flat faces of every node in the scene
+#[derive(Default)]
+struct FaceCollector {
+    faces: Vec<Face>,
+    paths: Vec<NodePath>,
+}
+
+impl FaceCollector {
+    fn collect_node(&mut self, node: &SceneNode, parent_trans: Mat4, path: &mut Vec<usize>) {
+        let trans = parent_trans * node.trans();
+
+        if let Some(geometry) = node.geometry() {
+            let index = self.paths.len();
+            self.paths.push(NodePath {indices: path.clone(), name: node.name().map(String::from)});
+
+            match &geometry.primitive {
+                Primitive::Plane(_) => {
+                    // The unit plane has its normal facing up (z x x = y)
+                    let face = Face::new(index, trans, Vec3::zero(), Vec3::unit_z() * 0.5, Vec3::unit_x() * 0.5, true);
+                    self.faces.push(face);
+                },
+                Primitive::Cube(_) => {
+                    let axes = [Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z()];
+                    for axis in 0..3 {
+                        for &sign in &[1.0, -1.0] {
+                            // The next two axes (cyclically) are perpendicular to the face and
+                            // their cross product is the axis of the face
+                            let u = axes[(axis + 1) % 3] * 0.5;
+                            let v = axes[(axis + 2) % 3] * 0.5 * sign;
+                            let center = axes[axis] * 0.5 * sign;
+                            self.faces.push(Face::new(index, trans, center, u, v, false));
+                        }
+                    }
+                },
+                // Only the flat faces of planes and cubes are checked
+                _ => {},
+            }
+        }
+
+        for (i, child) in node.children().iter().enumerate() {
+            path.push(i);
+            self.collect_node(child, trans, path);
+            path.pop();
+        }
+    }
+}
+
+impl HierScene {
+    /// Finds every pair of nodes with flat faces that overlap on (almost) the same plane, facing
+    /// the same direction
+    ///
+    /// Faces are considered coplanar if their planes are within the given distance of each other.
+    /// Only planes and cubes are checked since they are the primitives most often stacked against
+    /// each other (e.g. thin cubes used as posters on walls). Every face is compared with every
+    /// other face, so this can be slow for scenes with many nodes.
+    pub fn coplanar_overlaps(&self, max_distance: f64) -> Vec<CoplanarOverlap> {
+        let mut collector = FaceCollector::default();
+        collector.collect_node(&self.root, Mat4::identity(), &mut Vec::new());
+        let FaceCollector {faces, paths} = collector;
+
+        let mut overlaps = Vec::new();
+        for (i, face) in faces.iter().enumerate() {
+            for other in &faces[i+1..] {
+                if face.node == other.node {
+                    continue;
+                }
+
+                // Faces facing opposite directions are back to back (e.g. two cubes next to each
+                // other), so neither one can be seen where they overlap
+                let alignment = face.normal.dot(other.normal);
+                let two_sided = face.two_sided || other.two_sided;
+                if alignment < PARALLEL_THRESHOLD && !(two_sided && -alignment >= PARALLEL_THRESHOLD) {
+                    continue;
+                }
+
+                let distance = (other.center - face.center).dot(face.normal).abs();
+                if distance > max_distance || !face.overlaps(other, max_distance) {
+                    continue;
+                }
+
+                overlaps.push(CoplanarOverlap {
+                    nodes: [paths[face.node].clone(), paths[other.node].clone()],
+                    normal: face.normal,
+                    distance,
+                });
+            }
+        }
+
+        overlaps
+    }
+}
+
+impl HierScene {
+    /// Finds every material in the scene that does not conserve energy (see
+    /// `Material::energy_violations`)
+    ///
+    /// Each material is only checked once, even if it is used by many nodes. Use
+    /// `SceneNode::with_energy_conserving_materials` to fix every material at once.
+    pub fn energy_violations(&self) -> Vec<MaterialEnergyWarning> {
+        fn check_node(
+            node: &SceneNode,
+            path: &mut Vec<usize>,
+            seen: &mut HashSet<*const Material>,
+            warnings: &mut Vec<MaterialEnergyWarning>,
+        ) {
+            if let Some(geometry) = node.geometry() {
+                let checker_material = geometry.checker.as_ref().map(|checker| &checker.material);
+                for material in Some(&geometry.material).into_iter().chain(checker_material) {
+                    if !seen.insert(Arc::as_ptr(material)) {
+                        continue;
+                    }
+
+                    for violation in material.energy_violations() {
+                        warnings.push(MaterialEnergyWarning {
+                            node: NodePath {indices: path.clone(), name: node.name().map(String::from)},
+                            violation,
+                        });
+                    }
+                }
+            }
+
+            for (i, child) in node.children().iter().enumerate() {
+                path.push(i);
+                check_node(child, path, seen, warnings);
+                path.pop();
+            }
+        }
+
+        let mut warnings = Vec::new();
+        check_node(&self.root, &mut Vec::new(), &mut HashSet::new(), &mut warnings);
+        warnings
+    }
+
+    /// Finds every node with geometry that uses the default material because it was created
+    /// without one (see `Geometry::without_material`)
+    ///
+    /// Instanced nodes are listed once for every instance. Use `HierScene::with_default_material`
+    /// to choose the material used by these nodes.
+    pub fn default_material_nodes(&self) -> Vec<NodePath> {
+        fn find_nodes(node: &SceneNode, path: &mut Vec<usize>, paths: &mut Vec<NodePath>) {
+            if let Some(geometry) = node.geometry() {
+                let checker_material = geometry.checker.as_ref().map(|checker| &checker.material);
+                if Some(&geometry.material).into_iter().chain(checker_material).any(Material::is_scene_default) {
+                    paths.push(NodePath {indices: path.clone(), name: node.name().map(String::from)});
+                }
+            }
+
+            for (i, child) in node.children().iter().enumerate() {
+                path.push(i);
+                find_nodes(child, path, paths);
+                path.pop();
+            }
+        }
+
+        let mut paths = Vec::new();
+        find_nodes(&self.root, &mut Vec::new(), &mut paths);
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::math::Rgb;
+    use crate::scene::Geometry;
+    use crate::material::Material;
+    use crate::primitive::{Plane, Cube, Sphere};
+
+    #[test]
+    fn finds_coplanar_overlapping_faces() {
+        let mat = Arc::new(Material::default());
+        let node = |primitive: Primitive| SceneNode::from(Geometry::new(primitive, mat.clone()));
+
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                // A wall with its front face at z = 0
+                Arc::new(node(Cube.into()).scaled((10.0, 10.0, 1.0)).translated((0.0, 0.0, -0.5)).named("wall")),
+                // A thin cube (e.g. a poster) whose back face is inside the wall and whose front
+                // face is on the wall
+                Arc::new(node(Cube.into()).scaled((2.0, 3.0, 0.1)).translated((1.0, 1.0, -0.05))),
+                // A poster made of a plane facing the wall, slightly in front of the wall
+                Arc::new(node(Plane.into()).rotated_x(crate::math::Radians::from_degrees(-90.0))
+                    .scaled(2.0).translated((-3.0, 0.0, 1e-7))),
+                // Tiles that only touch along an edge and cubes that are back to back are fine
+                Arc::new(node(Cube.into()).translated((0.0, 0.0, 5.0))),
+                Arc::new(node(Cube.into()).translated((1.0, 0.0, 5.0))),
+                // Curved primitives are ignored
+                Arc::new(node(Sphere.into()).translated((0.0, 0.0, 0.5))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+
+        let overlaps = scene.coplanar_overlaps(1e-5);
+        let pairs: Vec<_> = overlaps.iter()
+            .map(|overlap| (overlap.nodes[0].indices.clone(), overlap.nodes[1].indices.clone()))
+            .collect();
+        assert_eq!(pairs, vec![(vec![0], vec![1]), (vec![0], vec![2])]);
+        assert_eq!(overlaps[0].nodes[0].to_string(), "root/0 (wall)");
+        assert!((overlaps[1].distance - 1e-7).abs() < 1e-12);
+
+        // Moving the plane further away from the wall fixes it
+        assert_eq!(scene.coplanar_overlaps(1e-8).len(), 1);
+    }
+
+    #[test]
+    fn finds_materials_that_do_not_conserve_energy() {
+        let mirror = Arc::new(Material {
+            diffuse: Rgb {r: 0.5, g: 0.2, b: 0.2},
+            specular: Rgb::from(0.1),
+            reflectivity: 1.0,
+            ..Material::default()
+        });
+        let negative = Arc::new(Material {diffuse: Rgb {r: -0.1, g: 0.5, b: 0.5}, ..Material::default()});
+        let fine = Arc::new(Material {diffuse: Rgb::from(0.5), reflectivity: 0.5, ..Material::default()});
+
+        let shape = |mat: &Arc<Material>| Arc::new(SceneNode::from(Geometry::new(Sphere, mat.clone())));
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                shape(&fine),
+                shape(&mirror),
+                // Only reported once
+                shape(&mirror),
+                Arc::new(SceneNode::from(shape(&negative)).named("negative")),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+
+        let warnings = scene.energy_violations();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].node.indices, vec![1]);
+        assert!((match warnings[0].violation {
+            EnergyViolation::ExceedsOne(total) => total,
+            violation => panic!("unexpected violation: {:?}", violation),
+        } - 1.6).abs() < 1e-12);
+        assert_eq!(warnings[1].to_string(), "Material of root/3/0 does not conserve energy: diffuse is negative");
+
+        // Clamping fixes every material while keeping the reflectivity of the mirror
+        let fixed = HierScene {
+            root: Arc::new(scene.root.with_energy_conserving_materials()),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+        assert_eq!(fixed.energy_violations(), Vec::new());
+        let fixed_mirror = &fixed.root.children()[1].geometry().unwrap().material;
+        assert_eq!(fixed_mirror.reflectivity, 1.0);
+        assert_eq!(fixed_mirror.diffuse, Rgb::black());
+        // Shared materials are still shared
+        assert!(Arc::ptr_eq(fixed_mirror, &fixed.root.children()[2].geometry().unwrap().material));
+        assert_eq!(*fixed.root.children()[0].geometry().unwrap().material, *fine);
+    }
+
+    #[test]
+    fn finds_nodes_using_the_default_material() {
+        let mat = Arc::new(Material::default());
+        let scene = HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                Arc::new(SceneNode::from(Geometry::new(Sphere, mat.clone()))),
+                Arc::new(SceneNode::from(Geometry::without_material(Cube)).named("crate")),
+                Arc::new(SceneNode::from(Geometry::checker_floor(mat.clone(), Material::scene_default(), 4.0))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        };
+
+        let nodes = scene.default_material_nodes();
+        assert_eq!(nodes.iter().map(|node| node.to_string()).collect::<Vec<_>>(), vec!["root/1 (crate)", "root/2"]);
+        // A material equal to the default material is not the default material
+        assert!(!Material::is_scene_default(&mat));
+
+        assert_eq!(scene.with_default_material(mat).default_material_nodes(), Vec::new());
+    }
+}
@@ -0,0 +1,264 @@
+//! Cross-sections of the geometry of a scene, for exporting to volume tools or for previewing 3D
+//! prints
+//!
+//! Rather than rendering with a camera, each column of voxels is found by casting a single ray
+//! straight through the scene along +z and keeping track of which surfaces it has entered. The
+//! meshes in the scene are assumed to be closed and to have normals that point outwards.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::math::{Vec3, Rgb};
+use crate::scene::HierScene;
+use crate::ray::{Ray, RayCast};
+use crate::texture::TextureSource;
+use crate::math::tolerance;
+use crate::render::to_pixel;
+
+/// The most surfaces that will be found along a single column of voxels
+///
+/// Prevents a column from taking forever if it hits a huge number of overlapping surfaces.
+const MAX_COLUMN_HITS: usize = 1024;
+
+/// The axis that a stack of slices is taken along
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceAxis {
+    X,
+    Y,
+    Z,
+}
+
+/// What each pixel of a slice shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceContent {
+    /// White where the voxel is inside of the geometry, black otherwise
+    Occupancy,
+    /// The diffuse color of the geometry that the voxel is inside of, black otherwise
+    Color,
+}
+
+/// A grid of voxels covering a region of a scene, each of which is either inside or outside of
+/// the geometry in the scene
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelGrid {
+    /// The corners of the region covered by the grid
+    min: Vec3,
+    max: Vec3,
+    /// The number of voxels along x, y, and z
+    resolution: (usize, usize, usize),
+    /// The diffuse color of the geometry at each voxel (None if the voxel is empty), with x
+    /// changing fastest, then y, then z
+    voxels: Vec<Option<Rgb>>,
+}
+
+impl VoxelGrid {
+    /// Finds which voxels are inside of the geometry of the given scene
+    ///
+    /// The grid spans from the given min corner to the given max corner with the given number of
+    /// voxels along x, y, and z. Each voxel is sampled at its center.
+    pub fn new(scene: &HierScene, (min, max): (Vec3, Vec3), (nx, ny, nz): (usize, usize, usize)) -> Self {
+        let size = (max - min) / Vec3::new(nx as f64, ny as f64, nz as f64);
+        let center = |index: usize, axis_min: f64, axis_size: f64| axis_min + (index as f64 + 0.5) * axis_size;
+
+        // Start the columns outside of everything so that every surface that is entered is seen
+        let start_z = scene.root.parent_bounds()
+            .map(|bounds| bounds.min().z.min(min.z))
+            .unwrap_or(min.z) - 1.0;
+
+        let columns: Vec<Vec<Option<Rgb>>> = (0..nx * ny).into_par_iter().map(|index| {
+            let (x, y) = (center(index % nx, min.x, size.x), center(index / nx, min.y, size.y));
+            let hits = column_hits(scene, Vec3::new(x, y, start_z));
+
+            // Walk along the column, keeping track of how many surfaces we are inside of
+            let mut next_hit = hits.iter().peekable();
+            let mut depth = 0i32;
+            let mut color = Rgb::black();
+            (0..nz).map(|z| {
+                let z = center(z, min.z, size.z);
+                while let Some((_, entering, hit_color)) = next_hit.next_if(|&&(hit_z, _, _)| hit_z <= z) {
+                    if *entering {
+                        depth += 1;
+                        color = *hit_color;
+                    } else {
+                        depth = (depth - 1).max(0);
+                    }
+                }
+
+                if depth > 0 { Some(color) } else { None }
+            }).collect()
+        }).collect();
+
+        let mut voxels = vec![None; nx * ny * nz];
+        for (index, column) in columns.into_iter().enumerate() {
+            for (z, voxel) in column.into_iter().enumerate() {
+                voxels[z * nx * ny + index] = voxel;
+            }
+        }
+
+        Self {min, max, resolution: (nx, ny, nz), voxels}
+    }
+
+    /// Returns the number of voxels along x, y, and z
+    pub fn resolution(&self) -> (usize, usize, usize) {
+        self.resolution
+    }
+
+    /// Returns the corners of the region covered by the grid
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        (self.min, self.max)
+    }
+
+    /// Returns the diffuse color of the geometry at the given voxel, or None if the voxel is empty
+    pub fn at(&self, (x, y, z): (usize, usize, usize)) -> Option<Rgb> {
+        let (nx, ny, _) = self.resolution;
+        self.voxels[(z * ny + y) * nx + x]
+    }
+
+    /// Returns the number of voxels that are inside of the geometry
+    pub fn occupied(&self) -> usize {
+        self.voxels.iter().filter(|voxel| voxel.is_some()).count()
+    }
+
+    /// Returns the given slice of the grid along the given axis as an image
+    ///
+    /// Slices along z have +x to the right and +y up. Slices along x have +z to the right and +y
+    /// up. Slices along y have +x to the right and +z down.
+    pub fn slice(&self, axis: SliceAxis, index: usize, content: SliceContent) -> image::RgbImage {
+        let (nx, ny, nz) = self.resolution;
+        let (width, height) = match axis {
+            SliceAxis::X => (nz, ny),
+            SliceAxis::Y => (nx, nz),
+            SliceAxis::Z => (nx, ny),
+        };
+
+        image::RgbImage::from_fn(width as u32, height as u32, |col, row| {
+            let (col, row) = (col as usize, row as usize);
+            let voxel = match axis {
+                SliceAxis::X => (index, height - 1 - row, col),
+                SliceAxis::Y => (col, index, row),
+                SliceAxis::Z => (col, height - 1 - row, index),
+            };
+
+            match (self.at(voxel), content) {
+                (None, _) => image::Rgb([0, 0, 0]),
+                (Some(_), SliceContent::Occupancy) => image::Rgb([255, 255, 255]),
+                (Some(color), SliceContent::Color) => to_pixel(color),
+            }
+        })
+    }
+
+    /// Saves every slice of the grid along the given axis as a numbered PNG in the given
+    /// directory (slice_0000.png, slice_0001.png, etc.), returning the paths that were written
+    pub fn save_slices<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        axis: SliceAxis,
+        content: SliceContent,
+    ) -> io::Result<Vec<PathBuf>> {
+        let (nx, ny, nz) = self.resolution;
+        let slices = match axis {
+            SliceAxis::X => nx,
+            SliceAxis::Y => ny,
+            SliceAxis::Z => nz,
+        };
+
+        (0..slices).map(|index| {
+            let path = dir.as_ref().join(format!("slice_{:04}.png", index));
+            self.slice(axis, index, content).save(&path)?;
+            Ok(path)
+        }).collect()
+    }
+}
+
+/// Returns every surface hit by a ray cast along +z from the given point, in order, as the z
+/// coordinate of the hit, whether the ray entered the surface, and the color of the surface
+fn column_hits(scene: &HierScene, origin: Vec3) -> Vec<(f64, bool, Rgb)> {
+    let ray = Ray::new(origin, Vec3::unit_z());
+    let mut start = ray.t_range().start;
+
+    let mut hits = Vec::new();
+    while hits.len() < MAX_COLUMN_HITS {
+        let mut t_range = ray.t_range();
+        t_range.start = start;
+        let (hit, mat) = match scene.root.ray_cast(&ray, &mut t_range) {
+            Some(hit) => hit,
+            None => break,
+        };
+
+        let color = match (&mat.texture, hit.tex_coord) {
+            (Some(tex), Some(tex_coord)) => tex.at(tex_coord),
+            _ => mat.diffuse,
+        };
+        hits.push((hit.hit_point.z, hit.normal.z < 0.0, color));
+
+        start = hit.ray_parameter + tolerance::at_scale(hit.ray_parameter);
+    }
+
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::scene::{SceneNode, Geometry};
+    use crate::material::Material;
+    use crate::primitive::{Cube, Sphere};
+
+    fn test_scene() -> HierScene {
+        let red = Arc::new(Material {diffuse: Rgb::red(), ..Material::default()});
+        let blue = Arc::new(Material {diffuse: Rgb::blue(), ..Material::default()});
+        HierScene {
+            root: Arc::new(SceneNode::from(vec![
+                // Covers x, y, and z in -1..0
+                Arc::new(SceneNode::from(Geometry::new(Cube, red)).translated((-0.5, -0.5, -0.5))),
+                Arc::new(SceneNode::from(Geometry::new(Sphere, blue)).scaled(0.5).translated((0.5, 0.5, 0.5))),
+            ])),
+            lights: Vec::new(),
+            ambient: Rgb::black(),
+        }
+    }
+
+    #[test]
+    fn voxels_inside_geometry_are_occupied() {
+        let grid = VoxelGrid::new(&test_scene(), (Vec3::broadcast(-1.0), Vec3::broadcast(1.0)), (4, 4, 4));
+        assert_eq!(grid.resolution(), (4, 4, 4));
+
+        // The cube fills the 8 voxels in the -x, -y, -z corner
+        for x in 0..2 {
+            for y in 0..2 {
+                for z in 0..2 {
+                    assert_eq!(grid.at((x, y, z)), Some(Rgb::red()), "{:?}", (x, y, z));
+                }
+            }
+        }
+        // The sphere contains the centers of the 8 voxels in the +x, +y, +z corner
+        assert_eq!(grid.at((2, 2, 2)), Some(Rgb::blue()));
+        assert_eq!(grid.at((3, 3, 3)), Some(Rgb::blue()));
+        assert_eq!(grid.at((3, 0, 3)), None);
+        assert_eq!(grid.occupied(), 16);
+    }
+
+    #[test]
+    fn slices_are_saved_as_images() {
+        let grid = VoxelGrid::new(&test_scene(), (Vec3::broadcast(-1.0), Vec3::broadcast(1.0)), (4, 4, 4));
+
+        let slice = grid.slice(SliceAxis::Z, 0, SliceContent::Occupancy);
+        // +y is up, so the cube is in the bottom left corner
+        assert_eq!(slice.get_pixel(0, 3), &image::Rgb([255, 255, 255]));
+        assert_eq!(slice.get_pixel(0, 0), &image::Rgb([0, 0, 0]));
+
+        let dir = env::temp_dir().join(format!("portrayer-voxel-slices-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths = grid.save_slices(&dir, SliceAxis::X, SliceContent::Color).unwrap();
+        assert_eq!(paths.len(), 4);
+        assert!(paths.iter().all(|path| path.exists()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}